@@ -0,0 +1,170 @@
+use crate::{ingest_page, RedditClient};
+use chrono::{Duration, NaiveDate, NaiveDateTime};
+use common::*;
+use std::collections::HashSet;
+
+/// Reddit stops every listing after this many posts, however it's paged
+const LISTING_CAP: usize = 1000;
+
+/// Where a subreddit's older posts can be found when there's no archive for them
+pub struct Backfill<'a> {
+    /// The /top time ranges to walk, like `all` or `year`
+    pub tops: &'a [String],
+    /// Also searches back to this day in windows of `window_days`, with cloudsearch
+    /// timestamp queries
+    pub since: Option<NaiveDate>,
+    pub window_days: i64,
+}
+
+/// How walking one listing ended
+enum Walked {
+    Done,
+    /// Posts came back from outside the searched window, so the timestamps were ignored
+    WindowIgnored,
+}
+
+/// Which of `ids` are already in posts
+async fn saved_ids(ids: &[i64]) -> Result<HashSet<i64>, UserError> {
+    Ok(PG_POOL
+        .get()
+        .await?
+        .query(
+            "SELECT reddit_id_int FROM posts WHERE reddit_id_int = ANY($1)",
+            &[&ids],
+        )
+        .await?
+        .into_iter()
+        .map(|row| row.get("reddit_id_int"))
+        .collect())
+}
+
+/// Ingests every page of the listing at `base_url` that hasn't been seen yet this backfill
+async fn walk(
+    client: &mut RedditClient,
+    base_url: &str,
+    seen: &mut HashSet<i64>,
+    window: Option<(NaiveDateTime, NaiveDateTime)>,
+) -> Result<Walked, UserError> {
+    let mut after: Option<String> = None;
+    let mut walked = 0;
+
+    loop {
+        let url = match &after {
+            Some(after) => format!("{}&after={}", base_url, after),
+            None => base_url.to_string(),
+        };
+
+        let (listing, date) = client.get_sub_listing(&url).await?;
+
+        let posts = listing
+            .data
+            .children
+            .into_iter()
+            .map(|child| child.data.finalize())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if let Some((start, end)) = window {
+            if posts
+                .iter()
+                .any(|post| post.created_utc < start || post.created_utc >= end)
+            {
+                return Ok(Walked::WindowIgnored);
+            }
+        }
+
+        walked += posts.len();
+
+        let unseen = posts
+            .into_iter()
+            .filter(|post| seen.insert(post.id_int))
+            .collect::<Vec<_>>();
+        let saved = saved_ids(&unseen.iter().map(|post| post.id_int).collect::<Vec<_>>()).await?;
+        let unseen_count = unseen.len();
+
+        let new = unseen
+            .into_iter()
+            .filter(|post| {
+                let had = saved.contains(&post.id_int);
+                if had {
+                    record_event(post.id_int, IngestDecision::AlreadyHad, None, None);
+                }
+                !had
+            })
+            .collect::<Vec<_>>();
+
+        info!(
+            "Backfilling {} new of {} posts from {}",
+            new.len(),
+            unseen_count,
+            url
+        );
+        ingest_page(new.into_iter(), date).await;
+
+        match listing.data.after {
+            Some(next) if walked < LISTING_CAP => after = Some(next),
+            _ => return Ok(Walked::Done),
+        }
+    }
+}
+
+/// Ingests as much of `subreddit`'s history as Reddit's listings still give out, for months
+/// there are no archives of
+pub async fn backfill(
+    client: &mut RedditClient,
+    subreddit: &str,
+    backfill: &Backfill<'_>,
+) -> Result<(), UserError> {
+    let mut seen = HashSet::new();
+
+    for t in backfill.tops {
+        info!("Backfilling /r/{}/top of {}", subreddit, t);
+        walk(
+            client,
+            &format!(
+                "https://api.reddit.com/r/{}/top?t={}&limit=100",
+                subreddit, t
+            ),
+            &mut seen,
+            None,
+        )
+        .await?;
+    }
+
+    let since = match backfill.since {
+        Some(since) => since.and_hms(0, 0, 0),
+        None => return Ok(()),
+    };
+
+    let window = Duration::days(backfill.window_days);
+    let mut end = chrono::Utc::now().naive_utc();
+    while end > since {
+        let start = (end - window).max(since);
+        info!("Backfilling /r/{} from {} to {}", subreddit, start, end);
+
+        let walked = walk(
+            client,
+            &format!(
+                "https://api.reddit.com/r/{}/search?q=timestamp:{}..{}&syntax=cloudsearch\
+                 &restrict_sr=on&sort=top&limit=100",
+                subreddit,
+                start.timestamp(),
+                end.timestamp() - 1
+            ),
+            &mut seen,
+            Some((start, end)),
+        )
+        .await?;
+
+        if let Walked::WindowIgnored = walked {
+            warn!(
+                "Reddit ignored the timestamps searching /r/{}; only its top listings were backfilled",
+                subreddit
+            );
+            break;
+        }
+
+        end = start;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,115 @@
+use common::*;
+use std::collections::{HashSet, VecDeque};
+
+/// How many of the latest post IDs are remembered to skip the overlap between passes
+const RECENT_IDS: usize = 2000;
+
+/// Where `all` is in its pass down r/all/new, kept in listing_cursors so a restart carries
+/// on from the last page it finished
+pub struct ListingCursor {
+    name: &'static str,
+    /// The page after the last one finished, or None to start a pass from the top
+    pub after: Option<String>,
+    /// The newest post seen by the last finished pass; a pass ends when it gets back to it
+    pub stop_at: Option<i64>,
+    /// The newest post seen by this pass
+    pub pass_newest: Option<i64>,
+    pub modhash: Option<String>,
+}
+
+impl ListingCursor {
+    pub async fn load(name: &'static str) -> Result<Self, UserError> {
+        let row = PG_POOL
+            .get()
+            .await?
+            .query_opt(
+                "SELECT after, stop_at, pass_newest, modhash FROM listing_cursors \
+                 WHERE name = $1",
+                &[&name],
+            )
+            .await?;
+
+        Ok(match row {
+            Some(row) => Self {
+                name,
+                after: row.get("after"),
+                stop_at: row.get("stop_at"),
+                pass_newest: row.get("pass_newest"),
+                modhash: row.get("modhash"),
+            },
+            None => Self {
+                name,
+                after: None,
+                stop_at: None,
+                pass_newest: None,
+                modhash: None,
+            },
+        })
+    }
+
+    pub async fn save(&self) -> Result<(), UserError> {
+        PG_POOL
+            .get()
+            .await?
+            .execute(
+                "INSERT INTO listing_cursors (name, after, stop_at, pass_newest, modhash, updated) \
+                 VALUES ($1, $2, $3, $4, $5, now() AT TIME ZONE 'utc') \
+                 ON CONFLICT (name) DO UPDATE SET after = EXCLUDED.after, \
+                 stop_at = EXCLUDED.stop_at, pass_newest = EXCLUDED.pass_newest, \
+                 modhash = EXCLUDED.modhash, updated = EXCLUDED.updated",
+                &[
+                    &self.name,
+                    &self.after,
+                    &self.stop_at,
+                    &self.pass_newest,
+                    &self.modhash,
+                ],
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Notes a page's posts, returning whether the pass has got back to where the last one
+    /// started
+    pub fn page(&mut self, ids: &[i64]) -> bool {
+        if self.after.is_none() {
+            self.pass_newest = ids.iter().copied().max().or(self.pass_newest);
+        }
+
+        self.stop_at
+            .map(|stop_at| ids.iter().any(|id| *id <= stop_at))
+            .unwrap_or(false)
+    }
+
+    /// Goes back to the top for the next pass, which stops at this one's newest post
+    pub fn finish_pass(&mut self) {
+        self.after = None;
+        self.stop_at = self.stop_at.max(self.pass_newest);
+    }
+}
+
+/// The latest post IDs seen, oldest dropped first
+#[derive(Default)]
+pub struct RecentIds {
+    order: VecDeque<i64>,
+    ids: HashSet<i64>,
+}
+
+impl RecentIds {
+    /// Returns false if `id` was already seen
+    pub fn insert(&mut self, id: i64) -> bool {
+        if !self.ids.insert(id) {
+            return false;
+        }
+
+        self.order.push_back(id);
+        if self.order.len() > RECENT_IDS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+
+        true
+    }
+}
@@ -1,11 +1,20 @@
-use chrono::{DateTime, NaiveDateTime};
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
+use clap::Parser;
+use common::concurrency::BufferLimitedExt;
 use common::*;
 use futures::prelude::*;
+use once_cell::sync::Lazy;
 use std::borrow::Cow;
 use std::error::Error;
 use tokio::time::{Duration, Instant};
 use tracing_futures::Instrument;
 
+mod backfill;
+use backfill::Backfill;
+
+mod cursor;
+use cursor::{ListingCursor, RecentIds};
+
 mod reddit_api;
 use reddit_api::SubredditListing;
 
@@ -16,6 +25,11 @@ struct RedditClient {
 }
 
 const INTERVAL: Duration = Duration::from_secs(5);
+/// The longest wait between failed listing requests, before jitter
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+static SUBREDDITS: Lazy<SubredditFilter> =
+    Lazy::new(|| SubredditFilter::for_binary("all").unwrap());
 
 impl RedditClient {
     pub fn new() -> Self {
@@ -33,7 +47,8 @@ impl RedditClient {
         &mut self,
         url: &str,
     ) -> Result<(SubredditListing, NaiveDateTime), UserError> {
-        tokio::time::delay_until(self.next_request).await;
+        tokio::time::sleep_until(self.next_request).await;
+        REDDIT_PACE.wait().await;
 
         let mut req = self.client.get(url);
 
@@ -46,8 +61,11 @@ impl RedditClient {
         let resp = req
             .send()
             .map_err(map_ue!("Couldn't access Reddit API"))
-            .await?
-            .error_for_status()?;
+            .await?;
+
+        REDDIT_PACE.update(resp.status(), resp.headers());
+
+        let resp = resp.error_for_status()?;
 
         let date = DateTime::parse_from_rfc2822(resp.headers()["date"].to_str()?)?.naive_utc();
 
@@ -66,19 +84,40 @@ impl RedditClient {
 }
 
 async fn ingest_post(post: Submission) -> bool {
+    wait_for_bandwidth().await;
+
+    let _in_flight = HEALTH.start();
+
+    let claim = match claim_post(post.id_int).await {
+        Ok(Some(claim)) => claim,
+        Ok(None) => {
+            info!("another ingester has it");
+            return false;
+        }
+        Err(e) => {
+            eprintln!("failed to claim: {:?}", e);
+            std::process::exit(1);
+        }
+    };
+
     let post_url_res = post.choose_url();
 
     let save_res = match post_url_res {
         Ok(post_url) => save_hash(post_url.as_str(), HashDest::Images).await,
         Err(e) => Err(e),
     };
+    let save_res = match save_res {
+        Err(ue) => post.save_fallback_hash(ue).await,
+        res => res,
+    };
+    claim.release().await;
 
     let image_id = match save_res {
         Ok(hash_gotten) => Ok(hash_gotten.id),
         Err(ue) => match ue.source {
             Source::Internal => {
                 eprintln!(
-                    "{}{}{}\n{:#?}\n{:#?}",
+                    "{}{}{}\n{:?}\n{:#?}",
                     ue.file.unwrap_or(""),
                     ue.line
                         .map(|line| Cow::Owned(format!("#{}", line)))
@@ -100,15 +139,15 @@ async fn ingest_post(post: Submission) -> bool {
                             e.source().and_then(|he| he.downcast_ref::<hyper::Error>());
 
                         e.status()
-                            .map(|status| format!("http_{}", status.as_str()).into())
+                            .map(|status| SaveError::Http(status.as_u16()))
                             .or_else(|| {
                                 if e.is_timeout() {
-                                    Some("timeout".into())
+                                    Some(SaveError::Timeout)
                                 } else {
                                     None
                                 }
                             })
-                            .or_else(|| hyper_error.map(|_| "hyper".into()))
+                            .or_else(|| hyper_error.map(|_| SaveError::Hyper))
                     }
                     None => None,
                 };
@@ -121,7 +160,7 @@ async fn ingest_post(post: Submission) -> bool {
                         .as_ref()
                         .map(|se| Cow::Owned(format!(" ({})", se)))
                         .unwrap_or_else(|| Cow::Borrowed("")),
-                    ue.error
+                    ue
                 );
 
                 Err(save_error)
@@ -130,17 +169,18 @@ async fn ingest_post(post: Submission) -> bool {
     };
 
     let image_id_ok = image_id.is_ok();
+    HEALTH.record(image_id_ok);
 
     match post.save(image_id).await {
-        Ok(already_have) => {
+        Ok(saved) => {
             if image_id_ok {
-                if already_have {
-                    info!("already have");
-                } else {
-                    info!("successfully saved");
+                match saved {
+                    Saved::Inserted => info!("successfully saved"),
+                    Saved::Updated => info!("already have; updated"),
+                    Saved::Skipped => info!("already have"),
                 }
             }
-            already_have
+            saved.already_have()
         }
         Err(e) => {
             eprintln!("failed to save: {:?}", e);
@@ -149,72 +189,178 @@ async fn ingest_post(post: Submission) -> bool {
     }
 }
 
-const ALL_BASE_URL: &str = "https://api.reddit.com/r/all/new?limit=100";
+/// Ingests the desirable posts of a listing page fetched at `date`, returning whether any
+/// were already saved
+async fn ingest_page(posts: impl Iterator<Item = Submission>, date: NaiveDateTime) -> bool {
+    futures::stream::iter(
+        posts
+            .filter(|post| post.desirable() && post.allowed_by(&SUBREDDITS))
+            .map(|mut post| {
+                tokio::spawn(async move {
+                    post.updated = Some(date);
+                    let span = info_span!(
+                        "ingest_post",
+                        id = post.id.as_str(),
+                        date = post.created_utc.to_string().as_str(),
+                        url = post.url.as_str()
+                    );
+                    ingest_post(post).instrument(span).await
+                })
+            }),
+    )
+    .buffer_limited()
+    .fold(false, |a, b| async move { a || b.unwrap() })
+    .await
+}
 
-async fn get_latest(client: &mut RedditClient) -> Result<(), UserError> {
-    let mut all_url = ALL_BASE_URL.to_string();
-    let mut count = 0;
+const ALL_BASE_URL: &str = "https://api.reddit.com/r/all/new?limit=100";
 
-    loop {
-        let (listing, date) = client.get_sub_listing(&all_url).await?;
-
-        info!("Downloading new listing; recieved {} posts so far", count);
-
-        count += listing.data.dist;
-
-        let old = futures::stream::iter(
-            listing
-                .data
-                .children
-                .into_iter()
-                .filter_map(|child| {
-                    let reddit_api::Child { data } = child;
-                    let post = data.finalize().unwrap();
-                    if post.desirable() {
-                        Some(post)
-                    } else {
-                        None
-                    }
-                })
-                .map(|mut post| {
-                    tokio::spawn(async move {
-                        post.updated = Some(date);
-                        let span = info_span!(
-                            "ingest_post",
-                            id = post.id.as_str(),
-                            date = post.created_utc.to_string().as_str(),
-                            url = post.url.as_str()
-                        );
-                        ingest_post(post).instrument(span).await
-                    })
-                }),
-        )
-        .buffer_unordered(CONFIG.worker_count)
-        .fold(false, |a, b| async move { a || b.unwrap() })
-        .await;
+/// Ingests the next page of r/all/new, moving the cursor past it once it's done
+async fn next_page(
+    client: &mut RedditClient,
+    cursor: &mut ListingCursor,
+    recent: &mut RecentIds,
+) -> Result<(), UserError> {
+    let all_url = match &cursor.after {
+        Some(after) => format!("{}&after={}", ALL_BASE_URL, after),
+        None => ALL_BASE_URL.to_string(),
+    };
 
-        if old {
-            info!(
-                "found posts we already have after {} posts; going to start!",
-                count
-            );
-            break Ok(());
+    let (listing, date) = client.get_sub_listing(&all_url).await?;
+
+    let posts = listing
+        .data
+        .children
+        .into_iter()
+        .map(|child| child.data.finalize())
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let ids = posts.iter().map(|post| post.id_int).collect::<Vec<_>>();
+    let reached = cursor.page(&ids);
+    let stop_at = cursor.stop_at;
+
+    info!(
+        "Downloading new listing of {} posts{}",
+        listing.data.dist,
+        if cursor.after.is_none() {
+            " from the top"
+        } else {
+            ""
+        }
+    );
+
+    let old = ingest_page(
+        posts
+            .into_iter()
+            // The last pass and the first pages of this one overlap
+            .filter(|post| stop_at.map(|stop_at| post.id_int > stop_at).unwrap_or(true))
+            .filter(|post| recent.insert(post.id_int)),
+        date,
+    )
+    .await;
+
+    match listing.data.after {
+        Some(after) if !reached && !old => cursor.after = Some(after),
+        _ => {
+            info!("Finished a pass; going back to the top");
+            cursor.finish_pass();
         }
+    }
+    cursor.modhash = client.last_modhash.clone();
+    cursor.save().await
+}
+
+/// How long to wait after `failures` listing requests in a row failed: doubling each time,
+/// give or take half so restarted instances don't retry in step
+fn backoff(failures: u32) -> Duration {
+    use rand::Rng;
+
+    let wait = INTERVAL
+        .checked_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+        .unwrap_or(MAX_BACKOFF)
+        .min(MAX_BACKOFF);
+    wait.mul_f64(rand::thread_rng().gen_range(0.5..1.5))
+}
+
+#[derive(Parser)]
+#[command(about = "Ingests r/all/new as it's posted, or backfills subreddits from their listings")]
+struct Cli {
+    /// Ingest these subreddits' top posts and exit instead, for months without archives
+    #[arg(long, value_name = "SUBREDDIT")]
+    backfill: Vec<String>,
+    /// Which /top time ranges to backfill from; each gives at most 1000 posts
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "all,year,month",
+        value_parser = ["hour", "day", "week", "month", "year", "all"]
+    )]
+    top: Vec<String>,
+    /// Also backfill by searching back to this day (YYYY-MM-DD), where Reddit still honors
+    /// cloudsearch timestamps
+    #[arg(long)]
+    since: Option<NaiveDate>,
+    /// How many days each search while backfilling covers
+    #[arg(long, default_value_t = 7, value_parser = clap::value_parser!(i64).range(1..))]
+    window_days: i64,
+}
 
-        all_url = format!(
-            "{}&after={}&count={}",
-            ALL_BASE_URL, listing.data.after, count
-        );
+/// Backfills each of `subreddits` in turn, going on to the next if one fails
+async fn run_backfill(subreddits: &[String], options: Backfill<'_>) -> Result<(), UserError> {
+    let mut client = RedditClient::new();
+
+    for subreddit in subreddits {
+        if let Err(ue) = backfill::backfill(&mut client, subreddit, &options).await {
+            error!("Couldn't finish backfilling /r/{}: {}", subreddit, ue);
+        }
     }
+
+    flush_ingest_events().await
 }
 
 #[tokio::main]
 async fn main() -> Result<(), UserError> {
     tracing_subscriber::fmt::init();
 
+    let args = Cli::parse_from(take_path_args());
+
+    Lazy::force(&SUBREDDITS);
+    reload_config_on_sighup()?;
+
+    if !args.backfill.is_empty() {
+        start_ingest_events("all");
+        return run_backfill(
+            &args.backfill,
+            Backfill {
+                tops: &args.top,
+                since: args.since,
+                window_days: args.window_days,
+            },
+        )
+        .await;
+    }
+
+    serve_health("all").await?;
+    start_ingest_events("all");
+
     let mut client = RedditClient::new();
+    let mut cursor = ListingCursor::load("all").await?;
+    client.last_modhash = cursor.modhash.clone();
+    let mut recent = RecentIds::default();
 
+    let mut failures = 0;
     loop {
-        get_latest(&mut client).await?;
+        match next_page(&mut client, &mut cursor, &mut recent).await {
+            Ok(()) => failures = 0,
+            Err(ue) => {
+                failures += 1;
+                let wait = backoff(failures);
+                error!(
+                    "Couldn't get the next page of r/all: {}; trying again in {:?}",
+                    ue, wait
+                );
+                tokio::time::sleep(wait).await;
+            }
+        }
     }
 }
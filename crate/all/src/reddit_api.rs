@@ -9,7 +9,8 @@ pub struct Child {
 #[derive(Deserialize)]
 pub struct Data {
     pub children: Vec<Child>,
-    pub after: String,
+    /// None at the end of the listing
+    pub after: Option<String>,
     pub dist: u32,
     pub modhash: String,
 }
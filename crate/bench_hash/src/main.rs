@@ -0,0 +1,170 @@
+use clap::Parser;
+use common::*;
+use image::DynamicImage;
+use std::path::{Path, PathBuf};
+
+/// Every subdirectory of the dataset is one image: an original and its derived variants.
+/// Pairs within a subdirectory should match, and pairs across subdirectories shouldn't.
+#[derive(Parser)]
+#[command(author, version, about, long_about = "none")]
+struct Cli {
+    #[arg(long, short)]
+    csv: bool,
+    #[arg(long, short = 'd', default_value_t = 16)]
+    max_distance: u32,
+    dataset: PathBuf,
+}
+
+struct Labelled {
+    group: usize,
+    image: DynamicImage,
+}
+
+struct Row {
+    hash: &'static str,
+    threshold: u32,
+    true_pos: u64,
+    false_pos: u64,
+    false_neg: u64,
+}
+
+impl Row {
+    fn precision(&self) -> f64 {
+        if self.true_pos + self.false_pos == 0 {
+            1.
+        } else {
+            self.true_pos as f64 / (self.true_pos + self.false_pos) as f64
+        }
+    }
+
+    fn recall(&self) -> f64 {
+        if self.true_pos + self.false_neg == 0 {
+            1.
+        } else {
+            self.true_pos as f64 / (self.true_pos + self.false_neg) as f64
+        }
+    }
+}
+
+fn load_dataset(dataset: &Path) -> Result<Vec<Labelled>, UserError> {
+    let mut groups = std::fs::read_dir(dataset)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    groups.retain(|path| path.is_dir());
+    // So that runs over the same dataset are comparable
+    groups.sort();
+
+    let mut images = Vec::new();
+
+    for (group, dir) in groups.iter().enumerate() {
+        let mut paths = std::fs::read_dir(dir)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        paths.sort();
+
+        for path in paths.into_iter().filter(|path| path.is_file()) {
+            match load_image(&std::fs::read(&path)?) {
+                Ok(image) => images.push(Labelled { group, image }),
+                Err(ue) => warn!("Skipping {}: {}", path.display(), ue.user_msg),
+            }
+        }
+    }
+
+    Ok(images)
+}
+
+fn evaluate<H: PerceptualHash>(
+    name: &'static str,
+    images: &[Labelled],
+    max_distance: u32,
+) -> Result<Vec<Row>, UserError> {
+    let hashes = images
+        .iter()
+        .map(|labelled| Ok((labelled.group, H::from_image(&labelled.image)?)))
+        .collect::<Result<Vec<_>, UserError>>()?;
+
+    // How many pairs of each kind are at each distance
+    let mut same = vec![0u64; H::BITS as usize + 1];
+    let mut different = vec![0u64; H::BITS as usize + 1];
+
+    for (i, &(group_a, hash_a)) in hashes.iter().enumerate() {
+        for &(group_b, hash_b) in &hashes[i + 1..] {
+            let distance = hash_a.distance(hash_b) as usize;
+            if group_a == group_b {
+                same[distance] += 1;
+            } else {
+                different[distance] += 1;
+            }
+        }
+    }
+
+    let total_same: u64 = same.iter().sum();
+
+    Ok((0..=max_distance.min(H::BITS))
+        .map(|threshold| {
+            let within = ..=threshold as usize;
+            let true_pos: u64 = same[within].iter().sum();
+            let false_pos: u64 = different[within].iter().sum();
+
+            Row {
+                hash: name,
+                threshold,
+                true_pos,
+                false_pos,
+                false_neg: total_same - true_pos,
+            }
+        })
+        .collect())
+}
+
+fn main() -> Result<(), UserError> {
+    tracing_subscriber::fmt::init();
+
+    let args = Cli::parse();
+
+    let images = load_dataset(&args.dataset)?;
+
+    if images.is_empty() {
+        return Err(ue!("no images found in dataset"));
+    }
+
+    let rows = evaluate::<Hash>("dhash64", &images, args.max_distance)?
+        .into_iter()
+        .chain(evaluate::<Hash128>("dhash128", &images, args.max_distance)?)
+        .collect::<Vec<_>>();
+
+    if args.csv {
+        println!("hash,threshold,true_pos,false_pos,false_neg,precision,recall");
+        for row in &rows {
+            println!(
+                "{},{},{},{},{},{:.4},{:.4}",
+                row.hash,
+                row.threshold,
+                row.true_pos,
+                row.false_pos,
+                row.false_neg,
+                row.precision(),
+                row.recall()
+            );
+        }
+    } else {
+        println!(
+            "{:<10} {:>9} {:>10} {:>10} {:>10} {:>9} {:>9}",
+            "hash", "threshold", "true_pos", "false_pos", "false_neg", "precision", "recall"
+        );
+        for row in &rows {
+            println!(
+                "{:<10} {:>9} {:>10} {:>10} {:>10} {:>9.4} {:>9.4}",
+                row.hash,
+                row.threshold,
+                row.true_pos,
+                row.false_pos,
+                row.false_neg,
+                row.precision(),
+                row.recall()
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,178 @@
+use super::*;
+use chrono::{NaiveDate, Utc};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// How often recorded bytes are written to Postgres and the day's total is reread
+const FLUSH_INTERVAL: Duration = Duration::from_secs(30);
+
+struct Usage {
+    /// Bytes per host not yet written to Postgres
+    pending: HashMap<String, i64>,
+    flushed: Instant,
+    /// Every process's bytes for `day`, as of the last flush
+    day: NaiveDate,
+    day_total: i64,
+}
+
+impl Usage {
+    fn total(&self, today: NaiveDate) -> i64 {
+        let flushed = if self.day == today { self.day_total } else { 0 };
+        flushed + self.pending.values().sum::<i64>()
+    }
+
+    /// Puts back bytes whose flush failed, to be written with the next one
+    fn restore(&mut self, pending: HashMap<String, i64>) {
+        for (host, bytes) in pending {
+            *self.pending.entry(host).or_insert(0) += bytes;
+        }
+    }
+}
+
+static USAGE: Lazy<Mutex<Usage>> = Lazy::new(|| {
+    Mutex::new(Usage {
+        pending: HashMap::new(),
+        flushed: Instant::now(),
+        day: Utc::today().naive_utc(),
+        day_total: 0,
+    })
+});
+
+fn today() -> NaiveDate {
+    Utc::today().naive_utc()
+}
+
+/// Counts bytes downloaded from `host` towards today's total
+pub async fn record_bandwidth(host: &str, bytes: usize) {
+    let due = {
+        let mut usage = USAGE.lock().unwrap();
+        *usage.pending.entry(host.to_string()).or_insert(0) += bytes as i64;
+        usage.flushed.elapsed() >= FLUSH_INTERVAL
+    };
+
+    if due {
+        if let Err(e) = flush_bandwidth().await {
+            warn!("Couldn't save bandwidth usage: {}", e);
+        }
+    }
+}
+
+/// Writes pending bytes to Postgres and rereads today's total across every process
+pub async fn flush_bandwidth() -> Result<(), UserError> {
+    let (pending, day) = {
+        let mut usage = USAGE.lock().unwrap();
+        usage.flushed = Instant::now();
+        (std::mem::take(&mut usage.pending), today())
+    };
+
+    let flushed = async {
+        let mut client = PG_POOL.get().await?;
+        let trans = client.transaction().await?;
+
+        for (host, bytes) in &pending {
+            trans
+                .execute(
+                    "INSERT INTO bandwidth_usage (day, host, bytes) VALUES ($1, $2, $3) \
+                     ON CONFLICT (day, host) DO UPDATE SET bytes = bandwidth_usage.bytes + $3",
+                    &[&day, host, bytes],
+                )
+                .await?;
+        }
+
+        let day_total: i64 = trans
+            .query_one(
+                "SELECT COALESCE(SUM(bytes), 0)::bigint AS total FROM bandwidth_usage \
+                 WHERE day = $1",
+                &[&day],
+            )
+            .await?
+            .get("total");
+
+        trans.commit().await?;
+
+        Ok::<_, UserError>(day_total)
+    }
+    .await;
+
+    let mut usage = USAGE.lock().unwrap();
+    match flushed {
+        Ok(day_total) => {
+            usage.day = day;
+            usage.day_total = day_total;
+            Ok(())
+        }
+        // Otherwise the cap would undercount for as long as Postgres is failing
+        Err(ue) => {
+            usage.restore(pending);
+            Err(ue)
+        }
+    }
+}
+
+/// Whether today's downloads have reached `daily_bandwidth_cap`
+pub fn over_bandwidth_cap() -> bool {
+    match CONFIG.load().daily_bandwidth_cap {
+        Some(cap) => USAGE.lock().unwrap().total(today()) >= cap,
+        None => false,
+    }
+}
+
+/// Sleeps until the next UTC day for as long as the cap is reached
+pub async fn wait_for_bandwidth() {
+    while over_bandwidth_cap() {
+        let now = Utc::now().naive_utc();
+        let tomorrow = (now.date() + chrono::Duration::days(1)).and_hms(0, 0, 0);
+
+        warn!(
+            "Daily bandwidth cap reached; pausing until {} UTC",
+            tomorrow
+        );
+        tokio::time::sleep((tomorrow - now).to_std().unwrap_or(FLUSH_INTERVAL)).await;
+
+        if let Err(e) = flush_bandwidth().await {
+            warn!("Couldn't reread bandwidth usage: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn total() {
+        let today = NaiveDate::from_ymd(2022, 10, 10);
+        let mut usage = Usage {
+            pending: vec![("a.com".to_string(), 10), ("b.com".to_string(), 5)]
+                .into_iter()
+                .collect(),
+            flushed: Instant::now(),
+            day: today,
+            day_total: 100,
+        };
+        assert_eq!(usage.total(today), 115);
+
+        // Yesterday's flushed total doesn't count against today
+        usage.day = today.pred();
+        assert_eq!(usage.total(today), 15);
+    }
+
+    #[test]
+    fn restore() {
+        let mut usage = Usage {
+            pending: vec![("a.com".to_string(), 10)].into_iter().collect(),
+            flushed: Instant::now(),
+            day: NaiveDate::from_ymd(2022, 10, 10),
+            day_total: 0,
+        };
+        usage.restore(
+            vec![("a.com".to_string(), 3), ("b.com".to_string(), 5)]
+                .into_iter()
+                .collect(),
+        );
+
+        assert_eq!(usage.pending["a.com"], 13);
+        assert_eq!(usage.pending["b.com"], 5);
+    }
+}
@@ -1,8 +1,25 @@
 use super::*;
-use serde::Deserialize;
+use std::fmt::{self, Display, Formatter};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
-#[allow(dead_code)]
-#[derive(Debug, Deserialize)]
+/// How long the ban lists are trusted before they're read again
+const BANNED_REFRESH: Duration = Duration::from_secs(60);
+
+/// The last copy of the ban lists read, kept even once it's stale in case they can't be read
+struct Cached {
+    checked: Option<Instant>,
+    lists: Arc<BanLists>,
+}
+
+static LISTS: Lazy<Mutex<Cached>> = Lazy::new(|| {
+    Mutex::new(Cached {
+        checked: None,
+        lists: Arc::new(BanLists::default()),
+    })
+});
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum Banned {
     HostEnd(String),
     Host(String),
@@ -25,6 +42,277 @@ impl Banned {
             Full(link) => url == *link,
         }
     }
+
+    /// The variant's name, as it's written in RON
+    pub fn kind(&self) -> &'static str {
+        use Banned::*;
+        match self {
+            HostEnd(_) => "HostEnd",
+            Host(_) => "Host",
+            AnyScheme(_) => "AnyScheme",
+            Full(_) => "Full",
+        }
+    }
+
+    pub fn value(&self) -> &str {
+        use Banned::*;
+        match self {
+            HostEnd(value) | Host(value) | AnyScheme(value) | Full(value) => value,
+        }
+    }
+
+    pub fn from_parts(kind: &str, value: String) -> Result<Self, UserError> {
+        use Banned::*;
+        if value.is_empty() {
+            return Err(ue!("nothing to ban", Source::User));
+        }
+        match kind {
+            "HostEnd" => Ok(HostEnd(value)),
+            "Host" => Ok(Host(value)),
+            "AnyScheme" => Ok(AnyScheme(value)),
+            "Full" => Ok(Full(value)),
+            _ => Err(ue!(format!("unknown ban kind {}", kind), Source::User)),
+        }
+    }
+}
+
+/// Written as in RON, like `HostEnd("fbcdn.net")`
+impl Display for Banned {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}({:?})", self.kind(), self.value())
+    }
+}
+
+/// Both lists, as they're imported and exported; named like tidder.ron's root so a config from
+/// before they were moved to the database can be imported as it is
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename = "Config")]
+pub struct BanLists {
+    #[serde(default)]
+    pub banned: Vec<Banned>,
+    /// Hosts whose circuits never hold links back, matched by suffix
+    #[serde(default)]
+    pub no_blacklist: Vec<String>,
+}
+
+/// A row of banned_links
+#[derive(Debug, Serialize)]
+pub struct BannedLink {
+    pub id: i64,
+    pub kind: String,
+    pub value: String,
+    pub added_at: NaiveDateTime,
+}
+
+pub async fn banned_links() -> Result<Vec<BannedLink>, UserError> {
+    Ok(PG_POOL
+        .get()
+        .await?
+        .query(
+            "SELECT id, kind, value, added_at FROM banned_links ORDER BY added_at DESC",
+            &[],
+        )
+        .await?
+        .into_iter()
+        .map(|row| BannedLink {
+            id: row.get("id"),
+            kind: row.get("kind"),
+            value: row.get("value"),
+            added_at: row.get("added_at"),
+        })
+        .collect())
+}
+
+/// Makes the next check read the lists again, so changes apply to this process at once
+fn forget_lists() {
+    LISTS.lock().unwrap().checked = None;
+}
+
+/// Returns false if it was already banned
+pub async fn ban_link(banned: &Banned) -> Result<bool, UserError> {
+    let added = PG_POOL
+        .get()
+        .await?
+        .execute(
+            "INSERT INTO banned_links (kind, value) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+            &[&banned.kind(), &banned.value()],
+        )
+        .await?;
+
+    forget_lists();
+
+    Ok(added > 0)
+}
+
+/// Returns false if there was no such ban
+pub async fn unban_link(id: i64) -> Result<bool, UserError> {
+    let removed = PG_POOL
+        .get()
+        .await?
+        .execute("DELETE FROM banned_links WHERE id = $1", &[&id])
+        .await?;
+
+    forget_lists();
+
+    Ok(removed > 0)
+}
+
+/// Like `unban_link`, but by what's banned
+pub async fn unban(banned: &Banned) -> Result<bool, UserError> {
+    let removed = PG_POOL
+        .get()
+        .await?
+        .execute(
+            "DELETE FROM banned_links WHERE kind = $1 AND value = $2",
+            &[&banned.kind(), &banned.value()],
+        )
+        .await?;
+
+    forget_lists();
+
+    Ok(removed > 0)
+}
+
+/// Returns false if `host_end` was already exempt
+pub async fn add_no_blacklist(host_end: &str) -> Result<bool, UserError> {
+    if host_end.is_empty() {
+        return Err(ue!("no host given", Source::User));
+    }
+
+    let added = PG_POOL
+        .get()
+        .await?
+        .execute(
+            "INSERT INTO no_blacklist (host_end) VALUES ($1) ON CONFLICT DO NOTHING",
+            &[&host_end],
+        )
+        .await?;
+
+    forget_lists();
+
+    Ok(added > 0)
+}
+
+/// Returns false if `host_end` wasn't exempt
+pub async fn remove_no_blacklist(host_end: &str) -> Result<bool, UserError> {
+    let removed = PG_POOL
+        .get()
+        .await?
+        .execute("DELETE FROM no_blacklist WHERE host_end = $1", &[&host_end])
+        .await?;
+
+    forget_lists();
+
+    Ok(removed > 0)
+}
+
+/// Adds everything in `lists` that isn't there already, returning how many bans and hosts
+/// were added
+pub async fn import_lists(lists: &BanLists) -> Result<(u64, u64), UserError> {
+    let mut client = PG_POOL.get().await?;
+    let trans = client.transaction().await?;
+
+    let mut banned = 0;
+    for ban in &lists.banned {
+        banned += trans
+            .execute(
+                "INSERT INTO banned_links (kind, value) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+                &[&ban.kind(), &ban.value()],
+            )
+            .await?;
+    }
+
+    let mut no_blacklist = 0;
+    for host_end in &lists.no_blacklist {
+        no_blacklist += trans
+            .execute(
+                "INSERT INTO no_blacklist (host_end) VALUES ($1) ON CONFLICT DO NOTHING",
+                &[host_end],
+            )
+            .await?;
+    }
+
+    trans.commit().await?;
+    forget_lists();
+
+    Ok((banned, no_blacklist))
+}
+
+/// Both lists as they're stored, oldest first
+pub async fn stored_lists() -> Result<BanLists, UserError> {
+    let client = PG_POOL.get().await?;
+
+    let banned = client
+        .query(
+            "SELECT kind, value FROM banned_links ORDER BY added_at, id",
+            &[],
+        )
+        .await?
+        .into_iter()
+        .filter_map(|row| Banned::from_parts(row.get("kind"), row.get("value")).ok())
+        .collect();
+
+    let no_blacklist = client
+        .query(
+            "SELECT host_end FROM no_blacklist ORDER BY added_at, host_end",
+            &[],
+        )
+        .await?
+        .into_iter()
+        .map(|row| row.get("host_end"))
+        .collect();
+
+    Ok(BanLists {
+        banned,
+        no_blacklist,
+    })
+}
+
+/// The lists, read again if they're older than `BANNED_REFRESH`; if they can't be read, the
+/// last copy of them is used
+async fn lists() -> Arc<BanLists> {
+    {
+        let cached = LISTS.lock().unwrap();
+        if let Some(checked) = cached.checked {
+            if checked.elapsed() < BANNED_REFRESH {
+                return cached.lists.clone();
+            }
+        }
+    }
+
+    match stored_lists().await {
+        Ok(lists) => {
+            let lists = Arc::new(lists);
+            *LISTS.lock().unwrap() = Cached {
+                checked: Some(Instant::now()),
+                lists: lists.clone(),
+            };
+            lists
+        }
+        Err(e) => {
+            warn!("Couldn't read the ban lists: {}", e);
+            LISTS.lock().unwrap().lists.clone()
+        }
+    }
+}
+
+/// The first ban that matches `url`, if any does
+pub async fn banned_by(url: &str) -> Option<Banned> {
+    lists()
+        .await
+        .banned
+        .iter()
+        .find(|banned| banned.matches(url))
+        .cloned()
+}
+
+/// Whether `host` is in no_blacklist, so its circuit is ignored
+pub async fn no_blacklisted(host: &str) -> bool {
+    lists()
+        .await
+        .no_blacklist
+        .iter()
+        .any(|end| host.ends_with(end.as_str()))
 }
 
 #[cfg(test)]
@@ -41,6 +329,30 @@ mod tests {
             .matches("http://site.com/x=http://asdf.com"));
     }
 
+    #[test]
+    fn parts() {
+        let banned = Banned::HostEnd("bad.com".to_string());
+        let parsed = Banned::from_parts(banned.kind(), banned.value().to_string()).unwrap();
+        assert!(parsed.matches("https://very.bad.com/asdf"));
+
+        assert!(Banned::from_parts("Nowhere", "bad.com".to_string()).is_err());
+        assert!(Banned::from_parts("Host", String::new()).is_err());
+    }
+
+    #[test]
+    fn lists_from_config() {
+        let lists: BanLists = ron::from_str(
+            r#"Config(author_stats: true, banned: [HostEnd("bad.com")], no_blacklist: ["imgur.com"])"#,
+        )
+        .unwrap();
+        assert_eq!(lists.banned[0].to_string(), r#"HostEnd("bad.com")"#);
+        assert_eq!(lists.no_blacklist, vec!["imgur.com"]);
+
+        let exported: BanLists = ron::from_str(&ron::to_string(&lists).unwrap()).unwrap();
+        assert!(exported.banned[0].matches("https://very.bad.com/asdf"));
+        assert_eq!(exported.no_blacklist, lists.no_blacklist);
+    }
+
     #[test]
     fn host() {
         assert!(Banned::Host("bad.com".to_string()).matches("https://bad.com/asdf"));
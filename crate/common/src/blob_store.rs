@@ -0,0 +1,294 @@
+use super::*;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// How long the store's size from Postgres is trusted before it's summed again
+const USAGE_REFRESH: Duration = Duration::from_secs(300);
+
+/// Bytes stored, as of `USAGE_CHECKED`, plus what this process has stored since
+static USAGE: AtomicI64 = AtomicI64::new(0);
+static USAGE_CHECKED: Lazy<Mutex<Option<Instant>>> = Lazy::new(|| Mutex::new(None));
+
+/// Numbers this process's temporary files, so no two writers share one
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Where a blob with these contents lives, sharded by the first two bytes of its SHA-256
+pub fn blob_key(bytes: &[u8]) -> String {
+    let digest = format!("{:x}", Sha256::digest(bytes));
+    format!("{}/{}/{}", &digest[0..2], &digest[2..4], digest)
+}
+
+async fn usage() -> Result<i64, UserError> {
+    let stale = USAGE_CHECKED
+        .lock()
+        .unwrap()
+        .map(|checked| checked.elapsed() >= USAGE_REFRESH)
+        .unwrap_or(true);
+
+    if stale {
+        // Rows sharing a blob are only counted once
+        let used: i64 = PG_POOL
+            .get()
+            .await?
+            .query_one(
+                "SELECT COALESCE(SUM(stored_size), 0)::bigint AS used FROM \
+                 (SELECT DISTINCT ON (stored_path) stored_size FROM images \
+                 WHERE stored_path IS NOT NULL) AS blobs",
+                &[],
+            )
+            .await?
+            .get("used");
+
+        USAGE.store(used, Ordering::SeqCst);
+        *USAGE_CHECKED.lock().unwrap() = Some(Instant::now());
+    }
+
+    Ok(USAGE.load(Ordering::SeqCst))
+}
+
+async fn put_file(root: &str, key: &str, bytes: &[u8]) -> Result<bool, UserError> {
+    let path = Path::new(root).join(key);
+    if tokio::fs::metadata(&path).await.is_ok() {
+        return Ok(false);
+    }
+
+    tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+    // Renamed into place so a crash never leaves a truncated blob under a real key
+    let temp_path = path.with_file_name(format!(
+        "{}.{}-{}.tmp",
+        path.file_name().unwrap().to_string_lossy(),
+        std::process::id(),
+        TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    if let Err(e) = tokio::fs::write(&temp_path, bytes).await {
+        let _ = tokio::fs::remove_file(&temp_path).await;
+        return Err(e.into());
+    }
+
+    if let Err(e) = tokio::fs::rename(&temp_path, &path).await {
+        let _ = tokio::fs::remove_file(&temp_path).await;
+        // Keys are the contents' digest, so whoever stored it first stored the same bytes
+        if tokio::fs::metadata(&path).await.is_ok() {
+            return Ok(false);
+        }
+        return Err(e.into());
+    }
+
+    Ok(true)
+}
+
+#[cfg(feature = "s3")]
+fn bucket(bucket: &str, region: &str, endpoint: &Option<String>) -> Result<s3::Bucket, UserError> {
+    let region = match endpoint {
+        Some(endpoint) => s3::Region::Custom {
+            region: region.to_string(),
+            endpoint: endpoint.clone(),
+        },
+        None => region.parse()?,
+    };
+    let s3_secrets = SECRETS
+        .s3
+        .as_ref()
+        .ok_or_else(|| ue!("the blob store is S3, but there are no S3 secrets"))?;
+    let credentials = s3::creds::Credentials::new(
+        Some(&s3_secrets.access_key),
+        Some(&s3_secrets.secret_key),
+        None,
+        None,
+        None,
+    )?;
+
+    Ok(s3::Bucket::new(bucket, region, credentials)?)
+}
+
+#[cfg(feature = "s3")]
+async fn put_s3(
+    name: &str,
+    region: &str,
+    endpoint: &Option<String>,
+    key: &str,
+    bytes: &[u8],
+) -> Result<bool, UserError> {
+    let bucket = bucket(name, region, endpoint)?;
+    if let Ok((_, 200)) = bucket.head_object(key).await {
+        return Ok(false);
+    }
+
+    bucket.put_object(key, bytes).await?;
+
+    Ok(true)
+}
+
+#[cfg(not(feature = "s3"))]
+async fn put_s3(
+    _name: &str,
+    _region: &str,
+    _endpoint: &Option<String>,
+    _key: &str,
+    _bytes: &[u8],
+) -> Result<bool, UserError> {
+    Err(ue!("common was built without the s3 feature"))
+}
+
+/// Keeps the original bytes of image `id` if the blob store is enabled and under its quota
+pub async fn store_blob(id: i64, bytes: &[u8]) -> Result<(), UserError> {
+    let store = match &CONFIG.load().blob_store {
+        Some(store) => store.clone(),
+        None => return Ok(()),
+    };
+
+    if let Some(quota) = store.quota_bytes {
+        if usage().await? + bytes.len() as i64 > quota {
+            warn!("Blob store is over its quota; not storing image {}", id);
+            return Ok(());
+        }
+    }
+
+    let key = blob_key(bytes);
+
+    let written = match &store.backend {
+        config::BlobBackend::Filesystem { root } => put_file(root, &key, bytes).await?,
+        config::BlobBackend::S3 {
+            bucket,
+            region,
+            endpoint,
+        } => put_s3(bucket, region, endpoint, &key, bytes).await?,
+    };
+
+    if written {
+        USAGE.fetch_add(bytes.len() as i64, Ordering::SeqCst);
+    }
+
+    PG_POOL
+        .get()
+        .await?
+        .execute(
+            "UPDATE images SET stored_path = $1, stored_size = $2 WHERE id = $3",
+            &[&key, &(bytes.len() as i64), &id],
+        )
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(feature = "s3")]
+async fn get_s3(
+    name: &str,
+    region: &str,
+    endpoint: &Option<String>,
+    key: &str,
+) -> Result<Vec<u8>, UserError> {
+    let response = bucket(name, region, endpoint)?.get_object(key).await?;
+    if response.status_code() != 200 {
+        return Err(ue!(format!(
+            "S3 returned {} for blob {}",
+            response.status_code(),
+            key
+        )));
+    }
+
+    Ok(response.bytes().to_vec())
+}
+
+#[cfg(not(feature = "s3"))]
+async fn get_s3(
+    _name: &str,
+    _region: &str,
+    _endpoint: &Option<String>,
+    _key: &str,
+) -> Result<Vec<u8>, UserError> {
+    Err(ue!("common was built without the s3 feature"))
+}
+
+/// The original bytes stored under `key`
+pub async fn fetch_blob(key: &str) -> Result<Vec<u8>, UserError> {
+    let store = match &CONFIG.load().blob_store {
+        Some(store) => store.clone(),
+        None => return Err(ue!("there's no blob store configured")),
+    };
+
+    match &store.backend {
+        config::BlobBackend::Filesystem { root } => {
+            Ok(tokio::fs::read(Path::new(root).join(key)).await?)
+        }
+        config::BlobBackend::S3 {
+            bucket,
+            region,
+            endpoint,
+        } => get_s3(bucket, region, endpoint, key).await,
+    }
+}
+
+/// Deletes the blob at `key`; rows pointing at it should be cleared first
+pub async fn delete_blob(key: &str) -> Result<(), UserError> {
+    let store = match &CONFIG.load().blob_store {
+        Some(store) => store.clone(),
+        None => return Err(ue!("there's no blob store configured")),
+    };
+
+    match &store.backend {
+        config::BlobBackend::Filesystem { root } => {
+            match tokio::fs::remove_file(Path::new(root).join(key)).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        #[cfg(feature = "s3")]
+        config::BlobBackend::S3 {
+            bucket: name,
+            region,
+            endpoint,
+        } => {
+            bucket(name, region, endpoint)?.delete_object(key).await?;
+        }
+        #[cfg(not(feature = "s3"))]
+        config::BlobBackend::S3 { .. } => {
+            return Err(ue!("common was built without the s3 feature"))
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys() {
+        assert_eq!(
+            blob_key(b"abc"),
+            "ba/78/ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[tokio::test]
+    async fn concurrent_puts() {
+        let root = std::env::temp_dir().join(format!("blob_store_test_{}", std::process::id()));
+        let root = root.to_str().unwrap().to_string();
+        let key = blob_key(b"abc");
+
+        let puts: Vec<_> = (0..8)
+            .map(|_| {
+                let (root, key) = (root.clone(), key.clone());
+                tokio::spawn(async move { put_file(&root, &key, b"abc").await.is_ok() })
+            })
+            .collect();
+        for put in puts {
+            assert!(put.await.unwrap());
+        }
+
+        let path = Path::new(&root).join(&key);
+        assert_eq!(tokio::fs::read(&path).await.unwrap(), b"abc");
+        // No temporary files are left behind
+        assert_eq!(
+            std::fs::read_dir(path.parent().unwrap()).unwrap().count(),
+            1
+        );
+
+        tokio::fs::remove_dir_all(&root).await.unwrap();
+    }
+}
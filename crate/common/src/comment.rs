@@ -0,0 +1,160 @@
+use super::*;
+
+#[derive(Deserialize, Debug)]
+pub struct Comment {
+    #[serde(default)]
+    pub id_int: i64,
+    pub id: String,
+    pub author: String,
+    pub body: String,
+    #[serde(deserialize_with = "super::submission::de_sub::created_utc")]
+    pub created_utc: NaiveDateTime,
+    /// The fullname of the post it's on, like t3_abc
+    pub link_id: String,
+    #[serde(default)]
+    pub permalink: Option<String>,
+    pub score: i64,
+    #[serde(default)]
+    pub subreddit: String,
+}
+
+impl Comment {
+    pub fn finalize(mut self) -> Result<Self, UserError> {
+        self.body = Submission::unescape(&self.body);
+
+        self.id_int = self
+            .id
+            .parse::<RedditId>()
+            .map_err(|ue| UserError {
+                source: Source::Internal,
+                ..ue
+            })?
+            .0;
+
+        Ok(self)
+    }
+
+    /// Links in the body that `Submission::desirable` would take as a post's link
+    pub fn image_links(&self) -> Vec<String> {
+        static LINK_RE: Lazy<Regex> =
+            Lazy::new(|| Regex::new(r#"(?i)https?://[^\s()\[\]<>"|]+"#).unwrap());
+
+        let mut seen = std::collections::HashSet::new();
+
+        LINK_RE
+            .find_iter(&self.body)
+            .map(|m| m.as_str().trim_end_matches(&['.', ',', '!', '?', '*'][..]))
+            .filter(|link| {
+                (EXT_RE.is_match(link) && URL_RE.is_match(link)) || is_link_special(link)
+            })
+            .filter(|link| seen.insert(*link))
+            .map(String::from)
+            .collect()
+    }
+
+    pub fn post_id_int(&self) -> Result<i64, UserError> {
+        self.link_id
+            .trim_start_matches("t3_")
+            .parse::<RedditId>()
+            .map(|id| id.0)
+            .map_err(|ue| UserError {
+                source: Source::Internal,
+                ..ue
+            })
+    }
+
+    pub fn permalink(&self) -> String {
+        self.permalink.clone().unwrap_or_else(|| {
+            format!(
+                "/r/{}/comments/{}/_/{}/",
+                self.subreddit,
+                self.link_id.trim_start_matches("t3_"),
+                self.id
+            )
+        })
+    }
+
+    /// Returns whether this link of the comment was already saved
+    pub async fn save(
+        &self,
+        link: &str,
+        image_id: Result<i64, Option<SaveError>>,
+    ) -> Result<bool, UserError> {
+        let (image_id, save_error) = match image_id {
+            Ok(image_id) => (Some(image_id), None),
+            Err(save_error) => (None, save_error),
+        };
+
+        let rows = PG_POOL
+            .get()
+            .await?
+            .query(
+                "INSERT INTO comment_images \
+                 (reddit_id, reddit_id_int, post_id_int, link, permalink, author, \
+                 created_utc, score, subreddit, image_id, save_error) \
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11) \
+                 ON CONFLICT DO NOTHING RETURNING reddit_id_int",
+                &[
+                    &self.id,
+                    &self.id_int,
+                    &self.post_id_int()?,
+                    &link,
+                    &self.permalink(),
+                    &self.author,
+                    &self.created_utc,
+                    &self.score,
+                    &self.subreddit,
+                    &image_id,
+                    &save_error,
+                ],
+            )
+            .await?;
+
+        Ok(rows.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn comment(body: &str) -> Comment {
+        serde_json::from_value::<Comment>(serde_json::json!({
+            "id": "def",
+            "author": "a",
+            "body": body,
+            "created_utc": 0,
+            "link_id": "t3_abc",
+            "score": 1,
+            "subreddit": "pics",
+        }))
+        .unwrap()
+        .finalize()
+        .unwrap()
+    }
+
+    #[test]
+    fn image_links() {
+        let c = comment(
+            "Original: https://i.imgur.com/abc.jpg, also [here](https://i.redd.it/x.png). \
+             Not https://example.com/page or https://i.imgur.com/abc.jpg again",
+        );
+        assert_eq!(
+            c.image_links(),
+            vec!["https://i.imgur.com/abc.jpg", "https://i.redd.it/x.png"]
+        );
+
+        assert_eq!(
+            comment("&lt;https://i.imgur.com/abc.gif&gt;").image_links(),
+            vec!["https://i.imgur.com/abc.gif"]
+        );
+    }
+
+    #[test]
+    fn ids() {
+        let c = comment("");
+        assert_eq!(c.id_int, 17_367);
+        assert_eq!(c.post_id_int().unwrap(), 13_368);
+        assert_eq!(c.permalink(), "/r/pics/comments/abc/_/def/");
+    }
+}
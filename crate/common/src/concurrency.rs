@@ -1,4 +1,5 @@
-use common::CONFIG;
+use super::CONFIG;
+use chrono::NaiveTime;
 use core::fmt;
 use core::pin::Pin;
 use futures::future::Future;
@@ -7,14 +8,34 @@ use futures::stream::{FusedStream, Stream};
 use futures::task::{Context, Poll};
 use pin_project_lite::pin_project;
 
+/// A window whose start is after its end wraps past midnight
+fn in_window(now: NaiveTime, start: NaiveTime, end: NaiveTime) -> bool {
+    if start <= end {
+        now > start && now < end
+    } else {
+        now > start || now < end
+    }
+}
+
+/// Whether it's currently within `time_limits`, local time
 pub fn is_limited() -> bool {
-    let now = chrono::Local::now().time();
-    now > CONFIG.time_limits.start && now < CONFIG.time_limits.end
+    let limits = &CONFIG.load().time_limits;
+    in_window(chrono::Local::now().time(), limits.start, limits.end)
+}
+
+/// How many workers may run right now
+pub fn worker_limit() -> usize {
+    let config = CONFIG.load();
+    if is_limited() {
+        config.time_limits.count
+    } else {
+        config.worker_count
+    }
 }
 
 pin_project! {
-    /// Stream for the [`buffer_unordered`](super::StreamExt::buffer_unordered)
-    /// method.
+    /// Like `futures`' `BufferUnordered`, but rechecks `worker_limit` on every poll,
+    /// so the limit follows the time of day and config reloads
     #[must_use = "streams do nothing unless polled"]
     pub struct BufferUnordered<St>
     where
@@ -43,7 +64,7 @@ where
     St: Stream,
     St::Item: Future,
 {
-    pub(super) fn new(stream: St) -> Self
+    pub fn new(stream: St) -> Self
     where
         St: Stream,
         St::Item: Future,
@@ -65,11 +86,7 @@ where
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         let mut this = self.project();
 
-        let max = if is_limited() {
-            CONFIG.time_limits.count
-        } else {
-            CONFIG.worker_count
-        };
+        let max = worker_limit();
 
         // First up, try to spawn off as many futures as possible by filling up
         // our queue of futures.
@@ -115,3 +132,37 @@ where
         self.in_progress_queue.is_terminated() && self.stream.is_terminated()
     }
 }
+
+pub trait BufferLimitedExt: Stream + Sized
+where
+    Self::Item: Future,
+{
+    /// Runs the stream's futures concurrently, up to `worker_limit` at a time
+    fn buffer_limited(self) -> BufferUnordered<Self> {
+        BufferUnordered::new(self)
+    }
+}
+
+impl<St> BufferLimitedExt for St
+where
+    St: Stream,
+    St::Item: Future,
+{
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window() {
+        let t = |h| NaiveTime::from_hms(h, 0, 0);
+
+        assert!(in_window(t(12), t(8), t(23)));
+        assert!(!in_window(t(2), t(8), t(23)));
+
+        assert!(in_window(t(23), t(22), t(6)));
+        assert!(in_window(t(2), t(22), t(6)));
+        assert!(!in_window(t(12), t(22), t(6)));
+    }
+}
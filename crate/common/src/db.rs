@@ -0,0 +1,460 @@
+use super::*;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+use tokio_postgres::binary_copy::BinaryCopyInWriter;
+use tokio_postgres::types::{ToSql, Type};
+use tokio_postgres_rustls::MakeRustlsConnect;
+
+/// Postgres takes at most this many parameters in one statement
+const MAX_PARAMS: usize = u16::MAX as usize;
+/// The most posts one multi-row insert can hold
+pub const MAX_BATCH_SIZE: usize = MAX_PARAMS / POST_COLUMN_COUNT;
+/// How long to wait for a connection to the read replica before using the primary instead
+const REPLICA_WAIT: Duration = Duration::from_secs(2);
+/// How long a replica that couldn't be reached is passed over
+const REPLICA_RETRY: Duration = Duration::from_secs(30);
+
+static REPLICA_POOL: Lazy<Option<Pool>> = Lazy::new(|| {
+    SECRETS
+        .postgres_replica
+        .as_ref()
+        .map(|replica| create_pool(replica).unwrap())
+});
+/// When the replica last couldn't be reached
+static REPLICA_DOWN: Lazy<Mutex<Option<Instant>>> = Lazy::new(|| Mutex::new(None));
+
+/// Accepts whatever certificate the server has, for `verify = "none"`
+struct NoVerifier;
+
+impl rustls::client::ServerCertVerifier for NoVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: std::time::SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::ServerCertVerified::assertion())
+    }
+}
+
+/// What Postgres connections are made with if `postgres_tls` is set
+pub fn tls() -> Result<Option<MakeRustlsConnect>, UserError> {
+    let settings = match &SECRETS.postgres_tls {
+        Some(settings) => settings,
+        None => return Ok(None),
+    };
+
+    let mut roots = rustls::RootCertStore::empty();
+    match &settings.root_cert_path {
+        Some(path) => {
+            let mut reader = std::io::BufReader::new(
+                std::fs::File::open(path).map_err(map_ue!("couldn't open root_cert_path"))?,
+            );
+            for cert in rustls_pemfile::certs(&mut reader)? {
+                roots
+                    .add(&rustls::Certificate(cert))
+                    .map_err(map_ue!("bad certificate in root_cert_path"))?;
+            }
+        }
+        None => {
+            roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|anchor| {
+                rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+                    anchor.subject,
+                    anchor.spki,
+                    anchor.name_constraints,
+                )
+            }));
+        }
+    }
+
+    let mut config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    if settings.verify == secrets::TlsVerify::None {
+        config
+            .dangerous()
+            .set_certificate_verifier(std::sync::Arc::new(NoVerifier));
+    }
+
+    Ok(Some(MakeRustlsConnect::new(config)))
+}
+
+/// A pool connecting with TLS if `postgres_tls` is set
+pub fn create_pool(config: &deadpool_postgres::Config) -> Result<Pool, UserError> {
+    match tls()? {
+        Some(tls) => config.create_pool(Some(Runtime::Tokio1), tls),
+        None => config.create_pool(Some(Runtime::Tokio1), tokio_postgres::NoTls),
+    }
+    .map_err(map_ue!("couldn't create the Postgres pool"))
+}
+
+/// A connection for reads that may lag a little behind writes: from `postgres_replica` if
+/// there is one and it can be reached, and otherwise from the primary
+pub async fn read_client() -> Result<deadpool_postgres::Object, UserError> {
+    if let Some(replica) = &*REPLICA_POOL {
+        let down = REPLICA_DOWN
+            .lock()
+            .unwrap()
+            .map(|at| at.elapsed() < REPLICA_RETRY)
+            .unwrap_or(false);
+
+        if !down {
+            match tokio::time::timeout(REPLICA_WAIT, replica.get()).await {
+                Ok(Ok(client)) => return Ok(client),
+                Ok(Err(e)) => warn!("Couldn't connect to the read replica: {}", e),
+                Err(_) => warn!("Timed out connecting to the read replica"),
+            }
+            warn!("Reading from the primary for the next {:?}", REPLICA_RETRY);
+            *REPLICA_DOWN.lock().unwrap() = Some(Instant::now());
+        }
+    }
+
+    Ok(PG_POOL.get().await?)
+}
+
+/// `($1, $2), ($3, $4)` for two rows of two columns
+pub fn values_sql(rows: usize, columns: usize) -> String {
+    (0..rows)
+        .map(|row| {
+            let params = (1..=columns)
+                .map(|column| format!("${}", row * columns + column))
+                .collect::<Vec<_>>()
+                .join(", ");
+            format!("({})", params)
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// How `PostWriter` gets a batch into posts
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WriteMode {
+    /// Multi-row upserts of up to `ingest_batch.size` posts
+    Insert,
+    /// Binary COPY into a temporary table, merged with one upsert per
+    /// `ingest_batch.copy_size` posts; quicker for backfills
+    Copy,
+}
+
+enum Message {
+    Post(Submission, i64),
+    Flush(oneshot::Sender<()>),
+}
+
+/// Saves posts that hashed successfully in batches, from a task of its own
+#[derive(Clone)]
+pub struct PostWriter {
+    sender: mpsc::Sender<Message>,
+}
+
+impl PostWriter {
+    /// Batches posts until there's a full batch for `mode`, or until `max_delay_ms` passes
+    pub fn spawn(mode: WriteMode) -> Self {
+        let (size, max_delay) = {
+            let config = CONFIG.load();
+            (
+                match mode {
+                    WriteMode::Insert => config.ingest_batch.size,
+                    WriteMode::Copy => config.ingest_batch.copy_size,
+                },
+                Duration::from_millis(config.ingest_batch.max_delay_ms),
+            )
+        };
+
+        // Bounded so that callers wait while a full batch is being written
+        let (sender, receiver) = mpsc::channel(size);
+
+        tokio::spawn(async move {
+            if let Err(e) = write_batches(receiver, mode, size, max_delay).await {
+                error!("Couldn't save a batch of posts: {:?}", e);
+            }
+        });
+
+        Self { sender }
+    }
+
+    pub async fn save(&self, post: Submission, image_id: i64) -> Result<(), UserError> {
+        record_event(post.id_int, IngestDecision::Hashed, None, None);
+        if let Err(ue) = save_album_images(post.id_int, &post.url).await {
+            warn!("Couldn't save the album images of {}: {}", post.id, ue);
+        }
+        self.sender
+            .send(Message::Post(post, image_id))
+            .await
+            .map_err(|_| ue!("the post writer has stopped"))
+    }
+
+    /// Waits until every post sent so far is saved
+    pub async fn flush(&self) -> Result<(), UserError> {
+        let (done, flushed) = oneshot::channel();
+        self.sender
+            .send(Message::Flush(done))
+            .await
+            .map_err(|_| ue!("the post writer has stopped"))?;
+        flushed
+            .await
+            .map_err(|_| ue!("the post writer stopped before flushing"))
+    }
+}
+
+async fn write_batches(
+    mut receiver: mpsc::Receiver<Message>,
+    mode: WriteMode,
+    size: usize,
+    max_delay: Duration,
+) -> Result<(), UserError> {
+    let mut batch = Vec::with_capacity(size);
+    let mut deadline = None;
+
+    loop {
+        let message = match deadline {
+            Some(due) => match tokio::time::timeout_at(due, receiver.recv()).await {
+                Ok(message) => message,
+                Err(_) => {
+                    write_posts(&mut batch, mode).await?;
+                    deadline = None;
+                    continue;
+                }
+            },
+            None => receiver.recv().await,
+        };
+
+        match message {
+            Some(Message::Post(post, image_id)) => {
+                if batch.is_empty() {
+                    deadline = Some(Instant::now() + max_delay);
+                }
+                batch.push((post, image_id));
+
+                if batch.len() >= size {
+                    write_posts(&mut batch, mode).await?;
+                    deadline = None;
+                }
+            }
+            Some(Message::Flush(done)) => {
+                write_posts(&mut batch, mode).await?;
+                deadline = None;
+                let _ = done.send(());
+            }
+            None => return write_posts(&mut batch, mode).await,
+        }
+    }
+}
+
+/// Keeps only the most recently fetched copy of each post, since one upsert can't touch
+/// a row twice
+fn dedupe(batch: Vec<(Submission, i64)>) -> Vec<(Submission, i64)> {
+    let mut newest: HashMap<i64, (Submission, i64)> = HashMap::with_capacity(batch.len());
+
+    for (post, image_id) in batch {
+        match newest.get(&post.id_int) {
+            Some((had, _)) if had.updated >= post.updated => {}
+            _ => {
+                newest.insert(post.id_int, (post, image_id));
+            }
+        }
+    }
+
+    newest.into_iter().map(|(_, entry)| entry).collect()
+}
+
+async fn write_posts(batch: &mut Vec<(Submission, i64)>, mode: WriteMode) -> Result<(), UserError> {
+    if batch.is_empty() {
+        return Ok(());
+    }
+
+    let batch = dedupe(std::mem::take(batch));
+
+    let rows = batch
+        .iter()
+        .map(|(post, image_id)| PostRow::new(post, Ok(*image_id)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    ensure_partitions(batch.iter().map(|(post, _)| post.created_utc)).await?;
+
+    let saved = match mode {
+        WriteMode::Insert => insert_posts(&rows).await?,
+        WriteMode::Copy => copy_posts(&rows).await?,
+    };
+
+    let returned: HashMap<i64, bool> = saved
+        .iter()
+        .map(|row| (row.get("reddit_id_int"), row.get("inserted")))
+        .collect();
+    for (post, _) in &batch {
+        let decision = match returned.get(&post.id_int) {
+            Some(true) => IngestDecision::Inserted,
+            Some(false) => IngestDecision::Updated,
+            None => IngestDecision::Unchanged,
+        };
+        record_event(post.id_int, decision, None, None);
+    }
+
+    let inserted_ids: Vec<i64> = returned
+        .iter()
+        .filter(|(_, inserted)| **inserted)
+        .map(|(id, _)| *id)
+        .collect();
+    if let Err(ue) = record_first_posts(&inserted_ids).await {
+        warn!("Couldn't record first posts: {}", ue);
+    }
+
+    let inserted = inserted_ids.len();
+    info!(
+        "Saved {} posts: {} new, {} updated",
+        rows.len(),
+        inserted,
+        saved.len() - inserted
+    );
+
+    Ok(())
+}
+
+async fn insert_posts(rows: &[PostRow<'_>]) -> Result<Vec<tokio_postgres::Row>, UserError> {
+    let params: Vec<&(dyn ToSql + Sync)> = rows.iter().flat_map(|row| row.params()).collect();
+
+    Ok(PG_POOL
+        .get()
+        .await?
+        .query(
+            format!(
+                "INSERT INTO posts ({}) VALUES {} {}",
+                POST_COLUMNS,
+                values_sql(rows.len(), POST_COLUMN_COUNT),
+                POST_UPSERT
+            )
+            .as_str(),
+            &params,
+        )
+        .await?)
+}
+
+/// Moves staged posts into posts, dropping links to images that went away after hashing
+fn merge_sql() -> String {
+    let columns = POST_COLUMNS
+        .split(',')
+        .map(|column| match column.trim() {
+            "image_id" => "images.id".to_string(),
+            "save_error" => "CASE WHEN staged.image_id IS NOT NULL AND images.id IS NULL \
+                 THEN 'image_missing' ELSE staged.save_error END"
+                .to_string(),
+            column => format!("staged.{}", column),
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    format!(
+        "INSERT INTO posts ({}) SELECT {} FROM post_staging AS staged \
+         LEFT JOIN images ON images.id = staged.image_id {}",
+        POST_COLUMNS, columns, POST_UPSERT
+    )
+}
+
+async fn copy_posts(rows: &[PostRow<'_>]) -> Result<Vec<tokio_postgres::Row>, UserError> {
+    let mut client = PG_POOL.get().await?;
+    let trans = client.transaction().await?;
+
+    trans
+        .batch_execute(&format!(
+            "CREATE TEMPORARY TABLE post_staging ON COMMIT DROP AS \
+             SELECT {} FROM posts WITH NO DATA",
+            POST_COLUMNS
+        ))
+        .await?;
+
+    let types: Vec<Type> = trans
+        .prepare(&format!("SELECT {} FROM post_staging", POST_COLUMNS))
+        .await?
+        .columns()
+        .iter()
+        .map(|column| column.type_().clone())
+        .collect();
+
+    let sink = trans
+        .copy_in(
+            format!(
+                "COPY post_staging ({}) FROM STDIN (FORMAT binary)",
+                POST_COLUMNS
+            )
+            .as_str(),
+        )
+        .await?;
+    let writer = BinaryCopyInWriter::new(sink, &types);
+    futures::pin_mut!(writer);
+    for row in rows {
+        writer.as_mut().write(&row.params()).await?;
+    }
+    writer.finish().await?;
+
+    let saved = trans.query(merge_sql().as_str(), &[]).await?;
+
+    trans.commit().await?;
+
+    Ok(saved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn post(id: &str, updated: Option<i64>) -> Submission {
+        let mut post = serde_json::from_value::<Submission>(serde_json::json!({
+            "id": id,
+            "author": "a",
+            "created_utc": 0,
+            "is_self": false,
+            "over_18": false,
+            "permalink": format!("/r/pics/comments/{}/_/", id),
+            "score": 1,
+            "title": "t",
+            "url": "https://i.imgur.com/a.jpg",
+        }))
+        .unwrap()
+        .finalize()
+        .unwrap();
+        post.updated = updated.map(|secs| NaiveDateTime::from_timestamp(secs, 0));
+        post
+    }
+
+    #[test]
+    fn values() {
+        assert_eq!(values_sql(1, 3), "($1, $2, $3)");
+        assert_eq!(values_sql(2, 2), "($1, $2), ($3, $4)");
+    }
+
+    #[test]
+    fn merge() {
+        let sql = merge_sql();
+        assert!(sql.starts_with(
+            "INSERT INTO posts (reddit_id, link, permalink, author, created_utc, score, "
+        ));
+        assert!(sql.contains("SELECT staged.reddit_id, staged.link, staged.permalink, "));
+        assert!(sql.contains(", images.id, staged.is_video, "));
+        assert!(sql.contains("ELSE staged.save_error END, staged.updated FROM post_staging"));
+    }
+
+    #[test]
+    fn dedupes() {
+        let mut deduped = dedupe(vec![
+            (post("abc", Some(10)), 1),
+            (post("abc", Some(20)), 2),
+            (post("abc", None), 3),
+            (post("def", None), 4),
+        ]);
+        deduped.sort_by_key(|(_, image_id)| *image_id);
+
+        assert_eq!(
+            deduped
+                .iter()
+                .map(|(_, image_id)| *image_id)
+                .collect::<Vec<_>>(),
+            vec![2, 4]
+        );
+    }
+}
@@ -0,0 +1,166 @@
+//! Events sent from the ingesters to anything listening, over Postgres LISTEN/NOTIFY
+
+use super::*;
+use serde::de::DeserializeOwned;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc;
+
+/// Carries a `NewImage` as JSON
+pub const NEW_IMAGE_CHANNEL: &str = "new_image";
+
+/// How long a subscriber waits before listening again after losing its connection
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+/// How many events can wait for a slow subscriber before listening stalls
+const SUBSCRIBER_CAPACITY: usize = 1024;
+
+/// Sent when an image is first saved to images, and again whenever a post is saved with it
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct NewImage {
+    pub image_id: i64,
+    pub hash: u64,
+    /// The post just saved with the image, if it was a post that was saved
+    pub post_id: Option<i64>,
+}
+
+async fn notify<T: Serialize>(channel: &str, payload: &T) -> Result<(), UserError> {
+    PG_POOL
+        .get()
+        .await?
+        .execute(
+            "SELECT pg_notify($1, $2)",
+            &[&channel, &serde_json::to_string(payload)?],
+        )
+        .await?;
+
+    Ok(())
+}
+
+/// Announces an image that was just inserted into images
+pub async fn notify_new_image(image_id: i64, hash: Hash) -> Result<(), UserError> {
+    if !CONFIG.load().new_image_events {
+        return Ok(());
+    }
+
+    notify(
+        NEW_IMAGE_CHANNEL,
+        &NewImage {
+            image_id,
+            hash: hash.0,
+            post_id: None,
+        },
+    )
+    .await
+}
+
+/// Announces a post that was just inserted with image `image_id`
+pub async fn notify_new_post(image_id: i64, post_id: i64) -> Result<(), UserError> {
+    if !CONFIG.load().new_image_events {
+        return Ok(());
+    }
+
+    let hash: Hash = PG_POOL
+        .get()
+        .await?
+        .query_one("SELECT hash FROM images WHERE id = $1", &[&image_id])
+        .await?
+        .get("hash");
+
+    notify(
+        NEW_IMAGE_CHANNEL,
+        &NewImage {
+            image_id,
+            hash: hash.0,
+            post_id: Some(post_id),
+        },
+    )
+    .await
+}
+
+/// Passes each payload on `channel` to `events` until the connection is lost or nothing's
+/// receiving any more
+async fn listen<T: DeserializeOwned>(
+    channel: &str,
+    events: &mpsc::Sender<T>,
+) -> Result<(), UserError> {
+    let pg_config = SECRETS.postgres.get_pg_config()?;
+
+    match db::tls()? {
+        Some(tls) => {
+            let (client, connection) = pg_config.connect(tls).await?;
+            listen_on(client, connection, channel, events).await
+        }
+        None => {
+            let (client, connection) = pg_config.connect(tokio_postgres::NoTls).await?;
+            listen_on(client, connection, channel, events).await
+        }
+    }
+}
+
+async fn listen_on<T, S, U>(
+    client: tokio_postgres::Client,
+    mut connection: tokio_postgres::Connection<S, U>,
+    channel: &str,
+    events: &mpsc::Sender<T>,
+) -> Result<(), UserError>
+where
+    T: DeserializeOwned,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    U: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // Notifications only arrive by polling the connection itself
+    let (messages_tx, mut messages) = mpsc::unbounded_channel();
+    let driver = tokio::spawn(async move {
+        let mut polled = stream::poll_fn(move |cx| connection.poll_message(cx));
+        while let Some(message) = polled.next().await {
+            if messages_tx.send(message?).is_err() {
+                break;
+            }
+        }
+        Ok::<_, tokio_postgres::Error>(())
+    });
+
+    client.batch_execute(&format!("LISTEN {}", channel)).await?;
+    info!("Listening on {}", channel);
+
+    while let Some(message) = messages.recv().await {
+        let notification = match message {
+            tokio_postgres::AsyncMessage::Notification(notification) => notification,
+            _ => continue,
+        };
+
+        match serde_json::from_str(notification.payload()) {
+            Ok(event) => {
+                if events.send(event).await.is_err() {
+                    return Ok(());
+                }
+            }
+            Err(e) => warn!("Bad payload on {}: {}", channel, e),
+        }
+    }
+
+    driver.await??;
+    Err(ue!("connection closed"))
+}
+
+/// Receives every event sent on `channel` from now on, reconnecting whenever the connection
+/// is lost; events sent while it's reconnecting are missed
+pub fn subscribe<T>(channel: &'static str) -> mpsc::Receiver<T>
+where
+    T: DeserializeOwned + Send + 'static,
+{
+    let (events_tx, events) = mpsc::channel(SUBSCRIBER_CAPACITY);
+
+    tokio::spawn(async move {
+        while !events_tx.is_closed() {
+            if let Err(ue) = listen(channel, &events_tx).await {
+                warn!(
+                    "Stopped listening on {}: {}; trying again in {:?}",
+                    channel, ue, RECONNECT_DELAY
+                );
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        }
+    });
+
+    events
+}
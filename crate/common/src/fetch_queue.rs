@@ -0,0 +1,221 @@
+use super::*;
+use chrono::NaiveDate;
+use std::collections::HashMap;
+
+/// How long a claimed link may go unfinished before another fetcher takes it over
+const CLAIM_TIMEOUT: &str = "1 hour";
+/// Links claimed this many times without being finished are left for someone to look at
+const MAX_ATTEMPTS: i32 = 5;
+
+/// A post saved by `ingest --defer-hashing` whose link hasn't been hashed yet
+#[derive(Debug)]
+pub struct Queued {
+    pub reddit_id_int: i64,
+    pub created_utc: NaiveDateTime,
+    pub link: String,
+}
+
+/// How soon a post's link should be hashed by `fetch_priority`; higher goes first
+fn priority(
+    score: i64,
+    created_utc: NaiveDateTime,
+    subreddit: &str,
+    weights: &config::FetchPriority,
+) -> f64 {
+    let reddit_launch = NaiveDate::from_ymd(2005, 6, 23).and_hms(0, 0, 0);
+    let days = (created_utc - reddit_launch).num_seconds() as f64 / 86400.0;
+
+    weights.score_weight * (score.max(0) as f64).ln_1p()
+        + weights.recency_weight * days
+        + weights
+            .subreddit_weights
+            .get(&subreddit.to_lowercase())
+            .copied()
+            .unwrap_or(0.0)
+}
+
+/// Queues `link` to be hashed for `post`, which should already be saved as deferred
+pub async fn enqueue(post: &Submission, link: &str) -> Result<(), UserError> {
+    let priority = priority(
+        post.score,
+        post.created_utc,
+        &post.subreddit,
+        &CONFIG.load().fetch_priority,
+    );
+
+    PG_POOL
+        .get()
+        .await?
+        .execute(
+            "INSERT INTO fetch_queue (reddit_id_int, created_utc, link, priority) \
+             VALUES ($1, $2, $3, $4) ON CONFLICT DO NOTHING",
+            &[&post.id_int, &post.created_utc, &link, &priority],
+        )
+        .await?;
+
+    Ok(())
+}
+
+/// Takes up to `limit` queued links that no other fetcher is working on, highest priority
+/// first, then takes turns between their hosts so that one busy host doesn't hold up the rest
+pub async fn claim(limit: i64) -> Result<Vec<Queued>, UserError> {
+    let rows = PG_POOL
+        .get()
+        .await?
+        .query(
+            format!(
+                "UPDATE fetch_queue SET claimed_at = now() AT TIME ZONE 'utc', \
+                 attempts = attempts + 1 \
+                 WHERE reddit_id_int IN (SELECT reddit_id_int FROM fetch_queue \
+                 WHERE (claimed_at IS NULL \
+                 OR claimed_at < now() AT TIME ZONE 'utc' - interval '{}') \
+                 AND attempts < $2 \
+                 ORDER BY priority DESC, queued_at LIMIT $1 FOR UPDATE SKIP LOCKED) \
+                 RETURNING reddit_id_int, created_utc, link, priority",
+                CLAIM_TIMEOUT
+            )
+            .as_str(),
+            &[&limit, &MAX_ATTEMPTS],
+        )
+        .await?;
+
+    // UPDATE ... RETURNING doesn't keep the subquery's order
+    let mut claimed: Vec<(f64, Queued)> = rows
+        .into_iter()
+        .map(|row| {
+            (
+                row.get("priority"),
+                Queued {
+                    reddit_id_int: row.get("reddit_id_int"),
+                    created_utc: row.get("created_utc"),
+                    link: row.get("link"),
+                },
+            )
+        })
+        .collect();
+    claimed.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+
+    Ok(take_turns(claimed.into_iter().map(|(_, queued)| queued)))
+}
+
+/// Reorders links so each host gets one in turn, keeping each host's own order and
+/// starting with the host of the first link
+fn take_turns(queued: impl IntoIterator<Item = Queued>) -> Vec<Queued> {
+    let mut hosts: Vec<Vec<Queued>> = Vec::new();
+    let mut host_index: HashMap<Option<String>, usize> = HashMap::new();
+
+    for queued in queued {
+        let index = *host_index.entry(get_host(&queued.link)).or_insert_with(|| {
+            hosts.push(Vec::new());
+            hosts.len() - 1
+        });
+        hosts[index].push(queued);
+    }
+
+    let mut hosts: Vec<_> = hosts.into_iter().map(Vec::into_iter).collect();
+    let mut turns = Vec::new();
+    loop {
+        let before = turns.len();
+        turns.extend(hosts.iter_mut().filter_map(Iterator::next));
+        if turns.len() == before {
+            return turns;
+        }
+    }
+}
+
+impl Queued {
+    /// Saves how hashing went to the post and takes it off the queue
+    pub async fn finish(&self, image_id: Result<i64, Option<SaveError>>) -> Result<(), UserError> {
+        let mut client = PG_POOL.get().await?;
+        let trans = client.transaction().await?;
+
+        trans
+            .execute(
+                "UPDATE posts SET image_id = $1, save_error = $2 \
+                 WHERE reddit_id_int = $3 AND created_utc = $4",
+                &[
+                    &image_id.ok(),
+                    &image_id.err().flatten(),
+                    &self.reddit_id_int,
+                    &self.created_utc,
+                ],
+            )
+            .await?;
+        trans
+            .execute(
+                "DELETE FROM fetch_queue WHERE reddit_id_int = $1",
+                &[&self.reddit_id_int],
+            )
+            .await?;
+
+        trans.commit().await?;
+
+        record_hashed(self.reddit_id_int, &image_id);
+
+        if let Ok(image_id) = image_id {
+            if let Err(ue) = record_first_posts(&[self.reddit_id_int]).await {
+                warn!(
+                    "Couldn't record {} as a first post: {}",
+                    self.reddit_id_int, ue
+                );
+            }
+            if let Err(ue) = save_album_images(self.reddit_id_int, &self.link).await {
+                warn!(
+                    "Couldn't save the album images of {}: {}",
+                    self.reddit_id_int, ue
+                );
+            }
+            if let Err(ue) = events::notify_new_post(image_id, self.reddit_id_int).await {
+                warn!("Couldn't announce {}: {}", self.reddit_id_int, ue);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queued(id: i64, link: &str) -> Queued {
+        Queued {
+            reddit_id_int: id,
+            created_utc: NaiveDate::from_ymd(2020, 1, 1).and_hms(0, 0, 0),
+            link: link.to_string(),
+        }
+    }
+
+    #[test]
+    fn takes_turns() {
+        let ids: Vec<i64> = take_turns(vec![
+            queued(1, "https://i.imgur.com/a.jpg"),
+            queued(2, "https://i.imgur.com/b.jpg"),
+            queued(3, "https://i.imgur.com/c.jpg"),
+            queued(4, "https://i.redd.it/d.jpg"),
+            queued(5, "https://example.com/e.png"),
+            queued(6, "https://i.redd.it/f.jpg"),
+        ])
+        .into_iter()
+        .map(|queued| queued.reddit_id_int)
+        .collect();
+
+        assert_eq!(ids, vec![1, 4, 5, 2, 6, 3]);
+    }
+
+    #[test]
+    fn priorities() {
+        let weights = config::FetchPriority {
+            score_weight: 1.0,
+            recency_weight: 0.01,
+            subreddit_weights: vec![("pics".to_string(), 5.0)].into_iter().collect(),
+        };
+        let at = NaiveDate::from_ymd(2020, 1, 1).and_hms(0, 0, 0);
+
+        let base = priority(0, at, "aww", &weights);
+        assert!(priority(100, at, "aww", &weights) > base);
+        assert_eq!(priority(-50, at, "aww", &weights), base);
+        assert!(priority(0, at + chrono::Duration::days(30), "aww", &weights) > base);
+        assert_eq!(priority(0, at, "Pics", &weights), base + 5.0);
+    }
+}
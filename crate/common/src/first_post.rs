@@ -0,0 +1,103 @@
+use super::*;
+use std::collections::HashMap;
+
+/// The post an image was first seen in, from image_first_post
+#[derive(Clone, Debug, Serialize)]
+pub struct FirstPost {
+    pub permalink: String,
+    pub subreddit: String,
+    pub created_utc: NaiveDateTime,
+}
+
+/// Selects the earliest of the posts matching `cond` for each of their images; ties go to
+/// the lowest ID, so rebuilding agrees with what was recorded on insert
+fn earliest_sql(cond: &str) -> String {
+    format!(
+        "SELECT DISTINCT ON (image_id) image_id, reddit_id_int, created_utc, subreddit \
+         FROM posts WHERE image_id IS NOT NULL AND {} \
+         ORDER BY image_id, created_utc ASC, reddit_id_int ASC",
+        cond
+    )
+}
+
+/// Records the posts as their images' first where they're earlier than what's recorded; called
+/// whenever posts get an image, so image_first_post stays current without a rebuild
+pub async fn record_first_posts(reddit_id_ints: &[i64]) -> Result<(), UserError> {
+    if reddit_id_ints.is_empty() {
+        return Ok(());
+    }
+
+    PG_POOL
+        .get()
+        .await?
+        .execute(
+            format!(
+                "INSERT INTO image_first_post (image_id, reddit_id_int, created_utc, subreddit) \
+                 {} \
+                 ON CONFLICT (image_id) DO UPDATE SET \
+                 reddit_id_int = EXCLUDED.reddit_id_int, created_utc = EXCLUDED.created_utc, \
+                 subreddit = EXCLUDED.subreddit \
+                 WHERE (EXCLUDED.created_utc, EXCLUDED.reddit_id_int) \
+                 < (image_first_post.created_utc, image_first_post.reddit_id_int)",
+                earliest_sql("reddit_id_int = ANY($1)")
+            )
+            .as_str(),
+            &[&reddit_id_ints],
+        )
+        .await?;
+
+    Ok(())
+}
+
+/// Replaces image_first_post with the earliest post of every image; returns how many images
+/// have one
+pub async fn rebuild_first_posts() -> Result<u64, UserError> {
+    let mut client = PG_POOL.get().await?;
+    let trans = client.transaction().await?;
+
+    trans.execute("DELETE FROM image_first_post", &[]).await?;
+    let rebuilt = trans
+        .execute(
+            format!(
+                "INSERT INTO image_first_post (image_id, reddit_id_int, created_utc, subreddit) {}",
+                earliest_sql("true")
+            )
+            .as_str(),
+            &[],
+        )
+        .await?;
+
+    trans.commit().await?;
+
+    Ok(rebuilt)
+}
+
+/// The first posts of the images that have one recorded, by image ID
+pub async fn first_posts(
+    client: &tokio_postgres::Client,
+    image_ids: &[i64],
+) -> Result<HashMap<i64, FirstPost>, UserError> {
+    Ok(client
+        .query(
+            "SELECT image_first_post.image_id, posts.permalink, image_first_post.subreddit, \
+             image_first_post.created_utc \
+             FROM image_first_post INNER JOIN posts \
+             ON posts.reddit_id_int = image_first_post.reddit_id_int \
+             AND posts.created_utc = image_first_post.created_utc \
+             WHERE image_first_post.image_id = ANY($1)",
+            &[&image_ids],
+        )
+        .await?
+        .into_iter()
+        .map(|row| {
+            (
+                row.get("image_id"),
+                FirstPost {
+                    permalink: format!("https://reddit.com{}", row.get::<_, &str>("permalink")),
+                    subreddit: row.get("subreddit"),
+                    created_utc: row.get("created_utc"),
+                },
+            )
+        })
+        .collect())
+}
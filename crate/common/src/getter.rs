@@ -1,12 +1,52 @@
 use super::*;
 
+use bytes::Bytes;
 use percent_encoding::{percent_decode, utf8_percent_encode, AsciiSet, CONTROLS};
 use reqwest::StatusCode;
 use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::RwLock;
 use url::Url;
 
 const FRAGMENT: &AsciiSet = &CONTROLS.add(b' ').add(b'"').add(b'<').add(b'>').add(b'`');
 
+/// Third-party APIs that links are resolved through
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Api {
+    Gfycat,
+    Imgur,
+}
+
+impl Api {
+    fn default_base(self) -> &'static str {
+        match self {
+            Api::Gfycat => "https://api.gfycat.com",
+            Api::Imgur => "https://imgur-apiv3.p.rapidapi.com",
+        }
+    }
+}
+
+static API_BASES: Lazy<RwLock<HashMap<Api, String>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// Sends `api`'s requests to `base` instead, e.g. a mock server in tests
+pub fn set_api_base(api: Api, base: &str) {
+    API_BASES
+        .write()
+        .unwrap()
+        .insert(api, base.trim_end_matches('/').to_string());
+}
+
+fn api_base(api: Api) -> String {
+    API_BASES
+        .read()
+        .unwrap()
+        .get(&api)
+        .cloned()
+        .unwrap_or_else(|| api.default_base().to_string())
+}
+
 pub fn new_domain_with_path_re(domain: &str) -> Result<Regex, regex::Error> {
     Regex::new(&format!(
         r"(?i)^https?://(?:[a-z0-9-.]+\.)?{}(?::\d+)?[/?#].+",
@@ -95,7 +135,7 @@ fn follow_gifsound(url: Url) -> Result<String, UserError> {
             } else {
                 return Err(ue_save!(
                     "Unsupported GifSound file",
-                    "gifsound_unsupported",
+                    SaveError::GifsoundUnsupported,
                     Source::User
                 ));
             }
@@ -103,7 +143,7 @@ fn follow_gifsound(url: Url) -> Result<String, UserError> {
     }
     Err(ue_save!(
         "GifSound URL without GIF",
-        "gifsound_no_gif",
+        SaveError::GifsoundNoGif,
         Source::User
     ))
 }
@@ -123,34 +163,115 @@ async fn follow_gfycat(url: Url) -> Result<String, UserError> {
         gfy_item: GfyItem,
     }
 
-    let resp = REQW_CLIENT
-        .get(&format!(
-            "https://api.gfycat.com/v1/gfycats/{}",
-            GFY_ID_SEL
-                .captures(url.path())
-                .and_then(|c| c.get(1))
-                .map(|m| m.as_str())
-                .ok_or_else(|| ue_save!(
-                    "couldn't find Gfycat ID in link",
-                    "gfycat_no_id",
-                    Source::User
-                ))?
-        ))
-        .send()
-        .await
-        .map_err(map_ue!("couldn't connect to GfyCat API"))?
-        .error_for_status()
-        .map_err(error_for_status_ue)?;
+    cached_resolution(url.as_str(), || async {
+        let resp = REQW_CLIENT
+            .get(&format!(
+                "{}/v1/gfycats/{}",
+                api_base(Api::Gfycat),
+                GFY_ID_SEL
+                    .captures(url.path())
+                    .and_then(|c| c.get(1))
+                    .map(|m| m.as_str())
+                    .ok_or_else(|| ue_save!(
+                        "couldn't find Gfycat ID in link",
+                        SaveError::GfycatNoId,
+                        Source::User
+                    ))?
+            ))
+            .send()
+            .await
+            .map_err(map_ue!("couldn't connect to GfyCat API"))?
+            .error_for_status()
+            .map_err(error_for_status_ue)?;
 
-    Ok(resp
-        .json::<Gfycats>()
-        .map_err(map_ue_save!(
-            "problematic JSON from Gfycat API",
-            "gfycat_json_bad"
-        ))
-        .await?
-        .gfy_item
-        .mobile_poster_url)
+        Ok(resp
+            .json::<Gfycats>()
+            .map_err(map_ue_save!(
+                "problematic JSON from Gfycat API",
+                SaveError::GfycatJsonBad
+            ))
+            .await?
+            .gfy_item
+            .mobile_poster_url)
+    })
+    .await
+}
+
+/// `ue`'s save error, if asking again won't help
+fn permanent_failure(ue: &UserError) -> Option<SaveError> {
+    ue.save_error
+        .or_else(|| {
+            ue.error
+                .downcast_ref::<reqwest::Error>()
+                .and_then(|e| e.status())
+                .map(|status| SaveError::Http(status.as_u16()))
+        })
+        .filter(|save_error| save_error.is_permanent())
+}
+
+/// Consults the link_resolutions table before calling out to an API to resolve `link`,
+/// and records what the API said afterwards
+async fn cached_resolution<F, Fut>(link: &str, resolve: F) -> Result<String, UserError>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<String, UserError>>,
+{
+    let (ttl_days, negative_ttl_days) = {
+        let config = CONFIG.load();
+        (
+            config.resolution_ttl_days,
+            config.negative_resolution_ttl_days,
+        )
+    };
+
+    let client = PG_POOL.get().await?;
+
+    let row = client
+        .query_opt(
+            "SELECT resolved, status FROM link_resolutions \
+             WHERE link = $1 AND resolved_at > NOW() AT TIME ZONE 'utc' - \
+             CASE WHEN status = 'ok' THEN make_interval(days => $2) \
+             ELSE make_interval(days => $3) END",
+            &[&link, &ttl_days, &negative_ttl_days],
+        )
+        .await?;
+
+    if let Some(row) = row {
+        let status: String = row.get("status");
+        return match row.get::<_, Option<String>>("resolved") {
+            Some(resolved) if status == "ok" => Ok(resolved),
+            _ => {
+                let msg = format!("previously failed to resolve ({})", status);
+                Err(match status.parse::<SaveError>() {
+                    Ok(save_error) => ue_save!(msg, save_error),
+                    Err(_) => ue!(msg),
+                })
+            }
+        };
+    }
+
+    let res = resolve().await;
+
+    let (resolved, status) = match &res {
+        Ok(resolved) => (Some(resolved.as_str()), Cow::Borrowed("ok")),
+        Err(ue) => match permanent_failure(ue) {
+            Some(save_error) => (None, Cow::Owned(save_error.to_string())),
+            None => return res,
+        },
+    };
+
+    client
+        .execute(
+            "INSERT INTO link_resolutions (link, resolved, status, resolved_at) \
+             VALUES ($1, $2, $3, NOW() AT TIME ZONE 'utc') \
+             ON CONFLICT (link) DO UPDATE \
+             SET resolved = EXCLUDED.resolved, status = EXCLUDED.status, \
+             resolved_at = EXCLUDED.resolved_at",
+            &[&link, &resolved, &status.as_ref()],
+        )
+        .await?;
+
+    res
 }
 
 async fn make_imgur_api_request(api_link: String) -> Result<Value, UserError> {
@@ -198,7 +319,7 @@ async fn make_imgur_api_request(api_link: String) -> Result<Value, UserError> {
                 resp.json::<Value>().await?
             )
         };
-        return Err(ue_save!(msg, format!("http_{}", status.as_str())));
+        return Err(ue_save!(msg, SaveError::Http(status.as_u16())));
     }
 
     if resp
@@ -217,7 +338,7 @@ async fn make_imgur_api_request(api_link: String) -> Result<Value, UserError> {
         resp.json::<Value>()
             .map_err(map_ue_save!(
                 "Imgur API returned invalid JSON",
-                "imgur_json_bad"
+                SaveError::ImgurJsonBad
             ))
             .await
     }
@@ -237,7 +358,10 @@ fn id_segment<'a>(segments: &'a [&str], loc: usize) -> Result<&'a str, UserError
     segments
         .get(loc)
         .and_then(|&seg| get_id(seg))
-        .ok_or(ue_save!("couldn't find Imgur ID in URL", "imgur_no_id"))
+        .ok_or(ue_save!(
+            "couldn't find Imgur ID in URL",
+            SaveError::ImgurNoId
+        ))
 }
 
 fn last_id<'a, 'b: 'a, D>(
@@ -251,11 +375,16 @@ where
         .into_iter()
         .rev()
         .find_map(|&id| get_id(id))
-        .ok_or(ue_save!("couldn't find Imgur ID in URL", "imgur_no_id"))
+        .ok_or(ue_save!(
+            "couldn't find Imgur ID in URL",
+            SaveError::ImgurNoId
+        ))
 }
 
+/// Imgur's videos, which have a GIF of their first frame at the same path
+static GIFV_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\.(?:gifv|webm|mp4)($|[?#])").unwrap());
+
 async fn follow_imgur(mut url: Url) -> Result<String, UserError> {
-    static GIFV_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\.(?:gifv|webm|mp4)($|[?#])").unwrap());
     static EXT_RE: Lazy<Regex> =
         Lazy::new(|| Regex::new(r"(?i)[[:alnum:]]\.(?:jpg|png)[[:alnum:]]+").unwrap());
     static HOST_LIMIT_RE: Lazy<Regex> =
@@ -287,53 +416,61 @@ async fn follow_imgur(mut url: Url) -> Result<String, UserError> {
     } else if EXT_RE.is_match(path) || path_start == "download" {
         Ok(url.into())
     } else if path_start == "a" {
-        if !CONFIG.enable_imgur_api {
+        if !CONFIG.load().enable_imgur_api {
             return Err(ue_save!(
                 "Albums are disabled",
-                "imgur_albums_disabled",
+                SaveError::ImgurAlbumsDisabled,
                 Source::External
             ));
         }
         let id = id_segment(&segments, 1)?;
-        let api_link = format!("https://imgur-apiv3.p.rapidapi.com/3/album/{}/images", id);
-        let json = make_imgur_api_request(api_link).await?;
-        Ok(GIFV_RE
-            .replace(
-                json["data"]
-                    .get(0)
-                    .ok_or(ue_save!("Imgur album is empty", "imgur_album_empty"))?["link"]
-                    .as_str()
-                    .ok_or(ue_save!(
-                        "Imgur API returned unexpectedly-structured JSON",
-                        "imgur_json_bad"
-                    ))?,
-                ".gif$1",
-            )
-            .to_string())
+        cached_resolution(url.as_str(), || async {
+            let api_link = format!("{}/3/album/{}/images", api_base(Api::Imgur), id);
+            let json = make_imgur_api_request(api_link).await?;
+            Ok(GIFV_RE
+                .replace(
+                    json["data"]
+                        .get(0)
+                        .ok_or(ue_save!("Imgur album is empty", SaveError::ImgurAlbumEmpty))?
+                        ["link"]
+                        .as_str()
+                        .ok_or(ue_save!(
+                            "Imgur API returned unexpectedly-structured JSON",
+                            SaveError::ImgurJsonBad
+                        ))?,
+                    ".gif$1",
+                )
+                .to_string())
+        })
+        .await
     } else if path_start == "gallery" {
-        if !CONFIG.enable_imgur_api {
+        if !CONFIG.load().enable_imgur_api {
             return Err(ue_save!(
                 "Albums are disabled",
-                "imgur_albums_disabled",
+                SaveError::ImgurAlbumsDisabled,
                 Source::External
             ));
         }
         let id = id_segment(&segments, 1)?;
-        let api_link = format!("https://imgur-apiv3.p.rapidapi.com/3/gallery/album/{}", id);
-        let json = make_imgur_api_request(api_link).await?;
-        Ok(GIFV_RE
-            .replace(
-                json["data"]["images"]
-                    .get(0)
-                    .ok_or(ue_save!("Imgur album is empty", "imgur_album_empty"))?["link"]
-                    .as_str()
-                    .ok_or(ue_save!(
-                        "Imgur API returned unexpectedly-structured JSON",
-                        "imgur_json_bad"
-                    ))?,
-                ".gif$1",
-            )
-            .to_string())
+        cached_resolution(url.as_str(), || async {
+            let api_link = format!("{}/3/gallery/album/{}", api_base(Api::Imgur), id);
+            let json = make_imgur_api_request(api_link).await?;
+            Ok(GIFV_RE
+                .replace(
+                    json["data"]["images"]
+                        .get(0)
+                        .ok_or(ue_save!("Imgur album is empty", SaveError::ImgurAlbumEmpty))?
+                        ["link"]
+                        .as_str()
+                        .ok_or(ue_save!(
+                            "Imgur API returned unexpectedly-structured JSON",
+                            SaveError::ImgurJsonBad
+                        ))?,
+                    ".gif$1",
+                )
+                .to_string())
+        })
+        .await
     } else {
         let id = last_id(&segments)?;
 
@@ -341,6 +478,78 @@ async fn follow_imgur(mut url: Url) -> Result<String, UserError> {
     }
 }
 
+/// The links of every image in the Imgur album or gallery at `link`, up to
+/// `imgur_album_images`; the first is the one `follow_imgur` gives. Anything else, or an
+/// album while the Imgur API is disabled, has none.
+pub async fn imgur_album_links(link: &str) -> Result<Vec<String>, UserError> {
+    let (max_images, enabled) = {
+        let config = CONFIG.load();
+        (config.imgur_album_images, config.enable_imgur_api)
+    };
+
+    let url = match Url::parse(link) {
+        Ok(url) => url,
+        Err(_) => return Ok(Vec::new()),
+    };
+    let is_imgur = url
+        .host_str()
+        .map(|host| host == "imgur.com" || host.ends_with(".imgur.com"))
+        .unwrap_or(false);
+    if !enabled || !is_imgur {
+        return Ok(Vec::new());
+    }
+
+    let segments = match url.path_segments() {
+        Some(segments) => segments.collect::<Vec<_>>(),
+        None => return Ok(Vec::new()),
+    };
+    let (api_link, gallery) = match segments.first() {
+        Some(&"a") => (
+            format!(
+                "{}/3/album/{}/images",
+                api_base(Api::Imgur),
+                id_segment(&segments, 1)?
+            ),
+            false,
+        ),
+        Some(&"gallery") => (
+            format!(
+                "{}/3/gallery/album/{}",
+                api_base(Api::Imgur),
+                id_segment(&segments, 1)?
+            ),
+            true,
+        ),
+        _ => return Ok(Vec::new()),
+    };
+
+    let json = make_imgur_api_request(api_link).await?;
+    let images = if gallery {
+        &json["data"]["images"]
+    } else {
+        &json["data"]
+    };
+
+    images
+        .as_array()
+        .ok_or(ue_save!(
+            "Imgur API returned unexpectedly-structured JSON",
+            SaveError::ImgurJsonBad
+        ))?
+        .iter()
+        .take(max_images)
+        .map(|image| {
+            image["link"]
+                .as_str()
+                .map(|link| GIFV_RE.replace(link, ".gif$1").to_string())
+                .ok_or(ue_save!(
+                    "Imgur API returned unexpectedly-structured JSON",
+                    SaveError::ImgurJsonBad
+                ))
+        })
+        .collect()
+}
+
 async fn follow_wikipedia(url: Url) -> Result<String, UserError> {
     #[derive(Debug, Deserialize)]
     struct ImageInfo {
@@ -429,77 +638,418 @@ pub fn host_ends_with(url: &str, end: &str) -> bool {
     get_host(url).map(|h| h.ends_with(end)).unwrap_or(false)
 }
 
+pub fn is_global_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_unspecified()
+                // 100.64.0.0/10, carrier-grade NAT
+                || (ip.octets()[0] == 100 && ip.octets()[1] & 0b1100_0000 == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_global_ip(IpAddr::V4(ip)),
+            None => {
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    // fc00::/7, unique local
+                    || ip.segments()[0] & 0xfe00 == 0xfc00
+                    // fe80::/10, link local
+                    || ip.segments()[0] & 0xffc0 == 0xfe80)
+            }
+        },
+    }
+}
+
+pub async fn check_public_host(url: &Url) -> Result<(), UserError> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| ue!("no host in URL", Source::User))?;
+    let port = url.port_or_known_default().unwrap_or(80);
+
+    let addrs = tokio::net::lookup_host((host.trim_start_matches('[').trim_end_matches(']'), port))
+        .await
+        .map_err(map_ue_save!(
+            "couldn't resolve host",
+            SaveError::HostUnresolvable
+        ))?;
+
+    for addr in addrs {
+        if !is_global_ip(addr.ip()) {
+            return Err(ue_save!(
+                "host resolves to a non-public address",
+                SaveError::HostNotPublic,
+                Source::User
+            ));
+        }
+    }
+
+    Ok(())
+}
+
 pub enum GetKind {
     Cache(HashDest, i64),
-    Request(HeaderMap),
+    Request(HeaderMap, Bytes, Resolution),
+}
+
+/// Where a link led when its image was downloaded, kept with the image for debugging
+#[derive(Clone, Debug)]
+pub struct Resolution {
+    /// The link as it was given, before `follow_link`
+    pub submitted_url: String,
+    /// Each URL the image's host redirected to, in order
+    pub redirect_chain: Vec<String>,
+    /// Where the image was downloaded from in the end
+    pub resolved_url: String,
 }
 
 pub struct HashGotten {
     pub hash: Hash,
+    pub hash128: Option<Hash128>,
     pub end_link: String,
     pub get_kind: GetKind,
 }
 
-pub async fn get_hash(orig_link: &str) -> Result<HashGotten, UserError> {
-    static EXT_REPLACE_RE: Lazy<Regex> =
-        Lazy::new(|| Regex::new(r"^(.+?)\.[[:alnum:]]+$").unwrap());
+/// How much of a download `sniff_image` looks at
+const SNIFF_BYTES: usize = 512;
+
+/// Checks an image whose Content-Type, `declared`, is missing or unsupported, since hosts
+/// often send images as text/html or application/octet-stream; HTML pages are always
+/// rejected, and with a declared type so is anything that isn't a known image format
+fn sniff_image(declared: Option<&str>, bytes: &[u8]) -> Result<(), UserError> {
+    let start =
+        String::from_utf8_lossy(&bytes[..bytes.len().min(SNIFF_BYTES)]).to_ascii_lowercase();
+    let start = start.trim_start();
+    if start.starts_with("<!doctype html") || start.starts_with("<html") {
+        return Err(ue_save!(
+            format!(
+                "got an HTML page instead of an image (Content-Type: {})",
+                declared.unwrap_or("none")
+            ),
+            SaveError::ContentTypeUnsupported
+        ));
+    }
+
+    match declared {
+        Some(ct) if image::guess_format(bytes).is_err() => Err(ue_save!(
+            format!("unsupported Content-Type: {}", ct),
+            SaveError::ContentTypeUnsupported
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Reads an image from `resp`, recording the bytes read against `host`; anything over
+/// `decode_limits.max_alloc_bytes` is refused as it's read, since it couldn't be decoded
+/// anyway, and unless `declared_image` the start goes through `sniff_image` before the rest
+/// is downloaded
+async fn read_image(
+    mut resp: reqwest::Response,
+    host: &str,
+    content_type: Option<&str>,
+    declared_image: bool,
+) -> Result<Bytes, UserError> {
+    let max_bytes = CONFIG.load().decode_limits.max_alloc_bytes;
+    let too_large = || {
+        ue_save!(
+            format!("image is larger than {} bytes", max_bytes),
+            SaveError::ImageTooLarge,
+            Source::User
+        )
+    };
+
+    if resp
+        .content_length()
+        .map(|len| len > max_bytes)
+        .unwrap_or(false)
+    {
+        return Err(too_large());
+    }
+
+    let mut image = Vec::with_capacity(resp.content_length().unwrap_or(0) as usize);
+    let mut sniffed = declared_image;
+    let read = async {
+        while let Some(chunk) = resp.chunk().await.map_err(map_ue_save!(
+            "couldn't download image",
+            SaveError::DownloadImage
+        ))? {
+            if (image.len() + chunk.len()) as u64 > max_bytes {
+                return Err(too_large());
+            }
+            image.extend_from_slice(&chunk);
+
+            if !sniffed && image.len() >= SNIFF_BYTES {
+                sniff_image(content_type, &image)?;
+                sniffed = true;
+            }
+        }
+
+        if !sniffed {
+            sniff_image(content_type, &image)?;
+        }
+
+        Ok::<_, UserError>(())
+    }
+    .await;
+
+    record_bandwidth(host, image.len()).await;
+    read?;
+
+    Ok(image.into())
+}
+
+/// A response reached by following redirects by hand
+pub struct Followed {
+    pub resp: reqwest::Response,
+    /// Each URL redirected to, in order
+    pub redirect_chain: Vec<String>,
+    /// The last URL reached only through permanent redirects, if the first one was
+    pub moved_to: Option<String>,
+}
+
+/// Sends the request `request` makes with the given client for `link`, and then for each URL
+/// it's redirected to, so the redirects can be recorded; 304 Not Modified is handed back like
+/// any other response. With `guard_ips`, every hop has to be on a public host, and is only
+/// connected to at the addresses that were checked; `link` itself is the caller's to check.
+pub async fn send_following<F>(
+    link: &str,
+    guard_ips: bool,
+    request: F,
+) -> Result<Followed, UserError>
+where
+    F: Fn(&reqwest::Client, &str) -> reqwest::RequestBuilder,
+{
+    let client: &reqwest::Client = if guard_ips {
+        &GUARDED_CLIENT
+    } else {
+        &NO_REDIRECT_CLIENT
+    };
+
+    let mut redirect_chain = Vec::new();
+    let mut moved_to = None;
+    let mut permanent = true;
+    let mut requested = link.to_string();
+
+    loop {
+        let resp = send_tracked(
+            &get_host(&requested).unwrap_or_default(),
+            request(client, &requested),
+        )
+        .map_err(map_ue!("couldn't connect to image host"))
+        .await?;
+
+        let status = resp.status();
+        if !status.is_redirection() || status == StatusCode::NOT_MODIFIED {
+            return Ok(Followed {
+                resp,
+                redirect_chain,
+                moved_to,
+            });
+        }
+
+        let next = resp
+            .headers()
+            .get(header::LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .and_then(|location| resp.url().join(location).ok())
+            .ok_or(ue!("redirected without a valid Location"))?;
+        if let Some(refusal) = redirect_refusal(&next, redirect_chain.len()) {
+            return Err(ue!(refusal));
+        }
+        // A public host can redirect to a name that resolves anywhere
+        if guard_ips {
+            check_public_host(&next).await?;
+        }
+
+        requested = next.to_string();
+        redirect_chain.push(requested.clone());
+
+        permanent = permanent
+            && (status == StatusCode::MOVED_PERMANENTLY
+                || status == StatusCode::PERMANENT_REDIRECT);
+        if permanent {
+            moved_to = Some(requested.clone());
+        }
+    }
+}
+
+/// IPFS gateways whose links are fetched through `gateways.ipfs` instead, besides those
+const KNOWN_IPFS_GATEWAYS: [&str; 7] = [
+    "ipfs.io",
+    "dweb.link",
+    "cloudflare-ipfs.com",
+    "gateway.pinata.cloud",
+    "nftstorage.link",
+    "w3s.link",
+    "4everland.io",
+];
+/// Arweave gateways whose links are fetched through `gateways.arweave` instead, besides those
+const KNOWN_ARWEAVE_GATEWAYS: [&str; 2] = ["arweave.net", "ar-io.net"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Network {
+    Ipfs,
+    Arweave,
+}
+
+/// Whether `host` is one of `known` or the host of one of `bases`
+fn is_gateway(host: &str, known: &[&str], bases: &[String]) -> bool {
+    known.contains(&host)
+        || bases.iter().any(|base| {
+            Url::parse(base)
+                .map(|base| base.host_str() == Some(host))
+                .unwrap_or(false)
+        })
+}
+
+/// The network `url`'s content is on and its path on a gateway of that network, if it's an
+/// ipfs://, ipns:// or ar:// link or a link to a gateway
+fn gateway_path(url: &Url, gateways: &config::Gateways) -> Option<(Network, String)> {
+    let host = url.host_str()?;
+    let rest = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+
+    match url.scheme() {
+        scheme @ "ipfs" | scheme @ "ipns" => {
+            Some((Network::Ipfs, format!("/{}/{}{}", scheme, host, rest)))
+        }
+        "ar" => Some((Network::Arweave, format!("/{}{}", host, rest))),
+        "http" | "https" => {
+            if is_gateway(host, &KNOWN_IPFS_GATEWAYS, &gateways.ipfs)
+                && (rest.starts_with("/ipfs/") || rest.starts_with("/ipns/"))
+            {
+                return Some((Network::Ipfs, rest));
+            }
+
+            // Subdomain gateways, like https://<cid>.ipfs.dweb.link/
+            for namespace in &["ipfs", "ipns"] {
+                if let Some((name, gateway)) = host.split_once(&format!(".{}.", namespace)) {
+                    if is_gateway(gateway, &KNOWN_IPFS_GATEWAYS, &gateways.ipfs) {
+                        return Some((Network::Ipfs, format!("/{}/{}{}", namespace, name, rest)));
+                    }
+                }
+            }
+
+            if is_gateway(host, &KNOWN_ARWEAVE_GATEWAYS, &gateways.arweave) && rest.len() > 1 {
+                return Some((Network::Arweave, rest));
+            }
 
+            None
+        }
+        _ => None,
+    }
+}
+
+pub async fn get_hash(orig_link: &str, guard_ips: bool) -> Result<HashGotten, UserError> {
     if orig_link.len() > 2000 {
         return Err(ue!("URL too long", Source::User));
     }
 
     let url = Url::parse(orig_link).map_err(map_ue!("invalid URL", Source::User))?;
 
+    let through_gateways = {
+        let gateways = &CONFIG.load().gateways;
+        if gateways.enabled {
+            gateway_path(&url, gateways).map(|(network, path)| {
+                let bases = match network {
+                    Network::Ipfs => &gateways.ipfs,
+                    Network::Arweave => &gateways.arweave,
+                };
+                bases
+                    .iter()
+                    .map(|base| format!("{}{}", base.trim_end_matches('/'), path))
+                    .collect::<Vec<_>>()
+            })
+        } else {
+            None
+        }
+    };
+
+    let links = match through_gateways {
+        Some(links) => links,
+        None => return fetch_hash(orig_link, orig_link, guard_ips).await,
+    };
+
+    // Gateways often time out or haven't found the content yet, so the next may have it
+    let mut last_error = None;
+    for link in &links {
+        match fetch_hash(orig_link, link, guard_ips).await {
+            Err(ue) if ue.error.downcast_ref::<reqwest::Error>().is_some() => {
+                warn!("Couldn't get {} through {}: {}", orig_link, link, ue);
+                last_error = Some(ue);
+            }
+            result => return result,
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| ue!("no gateways configured")))
+}
+
+/// Hashes the image at `link`, which is `orig_link` or where a gateway serves it
+async fn fetch_hash(orig_link: &str, link: &str, guard_ips: bool) -> Result<HashGotten, UserError> {
+    static EXT_REPLACE_RE: Lazy<Regex> =
+        Lazy::new(|| Regex::new(r"^(.+?)\.[[:alnum:]]+$").unwrap());
+
+    let url = Url::parse(link).map_err(map_ue!("invalid URL", Source::User))?;
+
     let scheme = url.scheme();
     if scheme != "http" && scheme != "https" {
         return Err(ue!("unsupported scheme in URL", Source::User));
     }
 
-    let is_photobucket = get_host(url.as_str())
-        .map(|h| h.ends_with("photobucket.com"))
+    let is_pixiv = get_host(url.as_str())
+        .map(|h| h.ends_with("photobucket.com") || h.ends_with("i.pximg.net"))
         .unwrap_or(false);
 
-    let is_pixiv = is_photobucket
-        || get_host(url.as_str())
-            .map(|h| h.ends_with("i.pximg.net"))
-            .unwrap_or(false);
-
     let mut link = follow_link(url).await?;
 
+    if guard_ips {
+        check_public_host(&Url::parse(&link).map_err(map_ue!("invalid URL", Source::User))?)
+            .await?;
+    }
+
     let found = get_existing(&link).await?;
 
-    if let Some((hash, hash_dest, id)) = found {
+    if let Some((hash, hash128, hash_dest, id)) = found {
         return Ok(HashGotten {
             hash,
+            hash128,
             end_link: link,
             get_kind: GetKind::Cache(hash_dest, id),
         });
     }
 
-    let resp = REQW_CLIENT
-        .get(&link)
-        .header(header::ACCEPT, {
-            if is_photobucket {
-                &IMAGE_MIMES_NO_WEBP as &[&str]
-            } else {
-                &IMAGE_MIMES as &[&str]
-            }
-            .join(",")
-        })
-        .header(header::USER_AGENT, USER_AGENT);
+    let Followed {
+        resp,
+        redirect_chain,
+        ..
+    } = send_following(&link, guard_ips, |client, requested| {
+        let req = client
+            .get(requested)
+            .header(header::ACCEPT, {
+                let mut accept = IMAGE_MIMES.join(",");
+                if svg_enabled() {
+                    accept.push(',');
+                    accept.push_str(SVG_MIME);
+                }
+                accept
+            })
+            .header(header::USER_AGENT, USER_AGENT);
 
-    let resp = if is_pixiv {
-        resp.header(header::REFERER, "https://www.pixiv.net")
-    } else {
-        resp
-    };
+        if is_pixiv {
+            req.header(header::REFERER, "https://www.pixiv.net")
+        } else {
+            req
+        }
+    })
+    .await?;
 
-    let resp = resp
-        .send()
-        .map_err(map_ue!("couldn't connect to image host"))
-        .await?
-        .error_for_status()
-        .map_err(error_for_status_ue)?;
+    let resp = resp.error_for_status().map_err(error_for_status_ue)?;
 
     let url = resp.url();
     if url
@@ -508,21 +1058,19 @@ pub async fn get_hash(orig_link: &str) -> Result<HashGotten, UserError> {
         .unwrap_or(false)
         && url.path() == "/removed.png"
     {
-        return Err(ue_save!("removed from Imgur", "imgur_removed"));
+        return Err(ue_save!("removed from Imgur", SaveError::ImgurRemoved));
     }
 
-    if let Some(ct) = resp.headers().get(header::CONTENT_TYPE) {
-        let ct = ct
-            .to_str()
-            .map_err(map_ue!("non-ASCII Content-Type header"))?;
-
-        if !IMAGE_MIMES.contains(&ct) {
-            return Err(ue_save!(
-                format!("unsupported Content-Type: {}", ct),
-                "content_type_unsupported"
-            ));
-        }
-
+    let content_type = resp
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .map(|ct| ct.to_str().map(str::to_string))
+        .transpose()
+        .map_err(map_ue!("non-ASCII Content-Type header"))?;
+    // Otherwise the bytes are checked as they're downloaded
+    let declared_image = content_type.as_deref().map(is_image_mime).unwrap_or(false);
+
+    if let Some(ct) = content_type.as_deref().filter(|_| declared_image) {
         if url
             .host_str()
             .map(|host| host == "i.imgur.com")
@@ -537,9 +1085,10 @@ pub async fn get_hash(orig_link: &str) -> Result<HashGotten, UserError> {
 
             let found = get_existing(&link).await?;
 
-            if let Some((hash, hash_dest, id)) = found {
+            if let Some((hash, hash128, hash_dest, id)) = found {
                 return Ok(HashGotten {
                     hash,
+                    hash128,
                     end_link: link,
                     get_kind: GetKind::Cache(hash_dest, id),
                 });
@@ -549,29 +1098,35 @@ pub async fn get_hash(orig_link: &str) -> Result<HashGotten, UserError> {
 
     let headers = resp.headers().to_owned();
 
-    let image = &resp
-        .bytes()
-        .map_err(map_ue_save!("couldn't download image", "download_image"))
-        .await?;
+    let host = resp.url().host_str().unwrap_or("").to_string();
+    let resolution = Resolution {
+        submitted_url: orig_link.to_string(),
+        redirect_chain,
+        resolved_url: resp.url().to_string(),
+    };
 
-    let hash = std::panic::catch_unwind(|| hash_from_memory(image))
-        .map_err(|_e| ue_save!("image panicked!", "image_panic", Source::User))??;
+    let image = read_image(resp, &host, content_type.as_deref(), declared_image).await?;
+
+    let (hash, hash128) = hash_in_pool(image.clone(), CONFIG.load().hash128).await?;
 
     Ok(HashGotten {
         hash,
+        hash128,
         end_link: link,
-        get_kind: GetKind::Request(headers),
+        get_kind: GetKind::Request(headers, image, resolution),
     })
 }
 
 pub struct HashSaved {
     pub hash: Hash,
+    pub hash128: Option<Hash128>,
     pub hash_dest: HashDest,
     pub id: i64,
 }
 
 async fn poss_move_row(
     hash: Hash,
+    hash128: Option<Hash128>,
     hash_dest: HashDest,
     found_hash_dest: HashDest,
     id: i64,
@@ -579,6 +1134,7 @@ async fn poss_move_row(
     if hash_dest == found_hash_dest || hash_dest == HashDest::ImageCache {
         Ok(HashSaved {
             hash,
+            hash128,
             hash_dest,
             id,
         })
@@ -588,16 +1144,27 @@ async fn poss_move_row(
         let stmt = trans
             .prepare(
                 "INSERT INTO images \
-                 (link, hash, no_store, no_cache, expires, etag, \
-                 must_revalidate, retrieved_on) \
-                 SELECT link, hash, no_store, no_cache, expires, etag, \
-                 must_revalidate, retrieved_on FROM image_cache WHERE id = $1 \
+                 (link, hash, hash128_hi, hash128_lo, hash_version, no_store, no_cache, expires, \
+                 etag, must_revalidate, retrieved_on, submitted_url, redirect_chain, \
+                 resolved_url, content_length) \
+                 SELECT link, hash, hash128_hi, hash128_lo, hash_version, no_store, no_cache, \
+                 expires, etag, must_revalidate, retrieved_on, submitted_url, redirect_chain, \
+                 resolved_url, content_length FROM image_cache WHERE id = $1 \
                  RETURNING id",
             )
             .await?;
 
         let new_id = trans.query_one(&stmt, &[&id]).await?.get::<_, i64>("id");
 
+        trans
+            .execute(
+                "INSERT INTO image_links (link, image_id) \
+                 SELECT link, $1 FROM images WHERE id = $1 \
+                 ON CONFLICT DO NOTHING",
+                &[&new_id],
+            )
+            .await?;
+
         let stmt = trans
             .prepare("DELETE FROM image_cache WHERE id = $1")
             .await?;
@@ -607,90 +1174,501 @@ async fn poss_move_row(
 
         Ok(HashSaved {
             hash,
+            hash128,
             hash_dest: HashDest::Images,
             id: new_id,
         })
     }
 }
 
+/// Moves rows of image_cache with the same hashes as image `id`, which was just saved, into
+/// image_links, so their links find it instead of keeping a second copy of its hash
+async fn absorb_cached(id: i64, hash: Hash, hash128: Option<Hash128>) -> Result<u64, UserError> {
+    Ok(PG_POOL
+        .get()
+        .await?
+        .execute(
+            "WITH absorbed AS (DELETE FROM image_cache WHERE hash <@ ($2, 0) \
+             AND hash_version = $3 \
+             AND (hash128_hi IS NULL OR $4::bigint IS NULL \
+             OR (hash128_hi = $4 AND hash128_lo = $5)) \
+             RETURNING link) \
+             INSERT INTO image_links (link, image_id) SELECT link, $1 FROM absorbed \
+             ON CONFLICT DO NOTHING",
+            &[
+                &id,
+                &hash,
+                &HASH_VERSION,
+                &hash128.map(Hash128::hi),
+                &hash128.map(Hash128::lo),
+            ],
+        )
+        .await?)
+}
+
+/// An image with the same hashes that was `content_length` bytes when downloaded, which is as
+/// sure as it can be that it's the same image at another link
+async fn find_duplicate(
+    hash: Hash,
+    hash128: Option<Hash128>,
+    content_length: usize,
+) -> Result<Option<i64>, UserError> {
+    Ok(PG_POOL
+        .get()
+        .await?
+        .query_opt(
+            "SELECT id FROM images WHERE hash <@ ($1, 0) AND hash_version = $2 \
+             AND hash128_hi IS NOT DISTINCT FROM $3 AND hash128_lo IS NOT DISTINCT FROM $4 \
+             AND content_length = $5 \
+             ORDER BY id LIMIT 1",
+            &[
+                &hash,
+                &HASH_VERSION,
+                &hash128.map(Hash128::hi),
+                &hash128.map(Hash128::lo),
+                &(content_length as i64),
+            ],
+        )
+        .await?
+        .map(|row| row.get("id")))
+}
+
+async fn insert_hash(
+    link: &str,
+    hash: Hash,
+    hash128: Option<Hash128>,
+    hash_dest: HashDest,
+    headers: &HeaderMap,
+    bytes: &[u8],
+    resolution: Option<&Resolution>,
+) -> Result<HashSaved, UserError> {
+    if is_taken_down(Some(link), Some(hash)).await? {
+        return Err(ue_save!(
+            "image was taken down",
+            SaveError::TakenDown,
+            Source::User
+        ));
+    }
+
+    if hash_dest == HashDest::Images {
+        if let Some(id) = find_duplicate(hash, hash128, bytes.len()).await? {
+            PG_POOL
+                .get()
+                .await?
+                .execute(
+                    "INSERT INTO image_links (link, image_id) VALUES ($1, $2) \
+                     ON CONFLICT DO NOTHING",
+                    &[&link, &id],
+                )
+                .await?;
+
+            return Ok(HashSaved {
+                hash,
+                hash128,
+                hash_dest,
+                id,
+            });
+        }
+    }
+
+    let now = chrono::offset::Utc::now().naive_utc();
+    let cc: Option<CacheControl> = headers
+        .get(header::CACHE_CONTROL)
+        .and_then(|hv| hv.to_str().ok())
+        .and_then(|s| cache_control::with_str(s).ok());
+    let cc = cc.as_ref();
+
+    let mut client = PG_POOL.get().await?;
+    let trans = client.transaction().await?;
+    let stmt = trans
+        .prepare(
+            format!(
+                "INSERT INTO {} (link, hash, hash128_hi, hash128_lo, hash_version, no_store, \
+                 no_cache, expires, etag, must_revalidate, retrieved_on, submitted_url, \
+                 redirect_chain, resolved_url, content_length) \
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15) \
+                 ON CONFLICT DO NOTHING \
+                 RETURNING id",
+                hash_dest.table_name()
+            )
+            .as_str(),
+        )
+        .await?;
+
+    let rows = trans
+        .query(
+            &stmt,
+            &[
+                &link,
+                &hash,
+                &hash128.map(Hash128::hi),
+                &hash128.map(Hash128::lo),
+                &HASH_VERSION,
+                &cc.map(|cc| cc.no_store),
+                &cc.map(|cc| cc.no_cache),
+                &cc.and_then(|cc| cc.max_age)
+                    .map(|n| NaiveDateTime::from_timestamp(n as i64, 0))
+                    .or_else(|| {
+                        headers
+                            .get(header::EXPIRES)
+                            .and_then(|hv| hv.to_str().ok())
+                            .and_then(|s| DateTime::parse_from_rfc2822(s).ok())
+                            .map(|dt| dt.naive_utc())
+                    }),
+                &headers.get(header::ETAG).and_then(|hv| hv.to_str().ok()),
+                &cc.map(|cc| cc.must_revalidate),
+                &now,
+                &resolution.map(|r| r.submitted_url.as_str()),
+                &resolution.map(|r| &r.redirect_chain),
+                &resolution.map(|r| r.resolved_url.as_str()),
+                &(bytes.len() as i64),
+            ],
+        )
+        .await?;
+
+    if let (HashDest::Images, Some(row)) = (hash_dest, rows.first()) {
+        trans
+            .execute(
+                "INSERT INTO image_links (link, image_id) VALUES ($1, $2) \
+                 ON CONFLICT DO NOTHING",
+                &[&link, &row.get::<_, i64>("id")],
+            )
+            .await?;
+    }
+
+    trans.commit().await?;
+
+    // Postgres will return no rows on a conflict, and a row with the new id on success
+    match rows.first() {
+        Some(row) => {
+            let id = row.get("id");
+
+            // indexd catches up from the images table on its own, so this is only to be prompt
+            if hash_dest == HashDest::Images && CONFIG.load().indexd.enabled {
+                if let Err(ue) = indexd::insert(hash, id).await {
+                    warn!("Couldn't insert into indexd: {}", ue);
+                }
+            }
+
+            if hash_dest == HashDest::Images {
+                match absorb_cached(id, hash, hash128).await {
+                    Ok(0) => {}
+                    Ok(absorbed) => info!("Image {} absorbed {} from image_cache", id, absorbed),
+                    Err(ue) => warn!("Couldn't absorb image_cache into image {}: {}", id, ue),
+                }
+                if let Err(ue) = store_blob(id, bytes).await {
+                    warn!("Couldn't store the original of image {}: {}", id, ue);
+                }
+                if let Err(ue) = events::notify_new_image(id, hash).await {
+                    warn!("Couldn't announce image {}: {}", id, ue);
+                }
+            }
+
+            Ok(HashSaved {
+                hash,
+                hash128,
+                hash_dest,
+                id,
+            })
+        }
+        None => {
+            let found = get_existing(link).await?;
+            match found {
+                Some((hash, hash128, found_hash_dest, id)) => {
+                    poss_move_row(hash, hash128, hash_dest, found_hash_dest, id).await
+                }
+                None => Err(ue!("conflict but no existing match")),
+            }
+        }
+    }
+}
+
 pub async fn save_hash(link: &str, hash_dest: HashDest) -> Result<HashSaved, UserError> {
+    if link.starts_with("data:") {
+        let bytes = decode_data_url(link)?;
+        return save_hash_bytes(&bytes, &content_label(&bytes), hash_dest, false).await;
+    }
+
+    // Checked again once it's hashed, but this saves downloading it
+    if is_taken_down(Some(link), None).await? {
+        return Err(ue_save!(
+            "image was taken down",
+            SaveError::TakenDown,
+            Source::User
+        ));
+    }
+
+    // Only the site caches into image_cache, and its links come from users
+    let guard_ips = hash_dest == HashDest::ImageCache && CONFIG.load().guard_private_ips;
+
     let HashGotten {
         hash,
+        hash128,
         end_link: link,
         get_kind,
-    } = get_hash(link).await?;
+    } = get_hash(link, guard_ips).await?;
     match get_kind {
         GetKind::Cache(found_hash_dest, id) => {
-            poss_move_row(hash, hash_dest, found_hash_dest, id).await
+            poss_move_row(hash, hash128, hash_dest, found_hash_dest, id).await
         }
-        GetKind::Request(headers) => {
-            let now = chrono::offset::Utc::now().naive_utc();
-            let cc: Option<CacheControl> = headers
-                .get(header::CACHE_CONTROL)
-                .and_then(|hv| hv.to_str().ok())
-                .and_then(|s| cache_control::with_str(s).ok());
-            let cc = cc.as_ref();
-
-            let mut client = PG_POOL.get().await?;
-            let trans = client.transaction().await?;
-            let stmt = trans
-                .prepare(
-                    format!(
-                        "INSERT INTO {} (link, hash, no_store, no_cache, expires, \
-                         etag, must_revalidate, retrieved_on) \
-                         VALUES ($1, $2, $3, $4, $5, $6, $7, $8) \
-                         ON CONFLICT DO NOTHING \
-                         RETURNING id",
-                        hash_dest.table_name()
-                    )
-                    .as_str(),
-                )
-                .await?;
+        GetKind::Request(headers, bytes, resolution) => {
+            insert_hash(
+                &link,
+                hash,
+                hash128,
+                hash_dest,
+                &headers,
+                &bytes,
+                Some(&resolution),
+            )
+            .await
+        }
+    }
+}
 
-            let rows = trans
-                .query(
-                    &stmt,
+/// What fetching an image again found
+#[derive(Debug)]
+pub struct Refetched {
+    /// `None` when the host answered 304 Not Modified to the stored ETag
+    pub bytes: Option<Bytes>,
+    pub etag: Option<String>,
+    /// Where the link permanently redirects to now, if it does
+    pub moved_to: Option<String>,
+}
+
+/// Downloads `link` again, without caching it; with `etag`, the host may answer that it
+/// hasn't changed instead of sending it
+pub async fn refetch_image(link: &str, etag: Option<&str>) -> Result<Refetched, UserError> {
+    let url = Url::parse(link).map_err(map_ue!("invalid URL", Source::User))?;
+    let link = follow_link(url).await?;
+
+    let Followed { resp, moved_to, .. } = send_following(&link, false, |client, requested| {
+        let req = client.get(requested).header(header::USER_AGENT, USER_AGENT);
+
+        match etag {
+            Some(etag) => req.header(header::IF_NONE_MATCH, etag),
+            None => req,
+        }
+    })
+    .await?;
+
+    if resp.status() == StatusCode::NOT_MODIFIED {
+        return Ok(Refetched {
+            bytes: None,
+            etag: etag.map(str::to_string),
+            moved_to,
+        });
+    }
+
+    let resp = resp.error_for_status().map_err(error_for_status_ue)?;
+
+    let host = resp.url().host_str().unwrap_or("").to_string();
+    let etag = resp
+        .headers()
+        .get(header::ETAG)
+        .and_then(|etag| etag.to_str().ok())
+        .map(str::to_string);
+    let bytes = read_image(resp, &host, None, true).await?;
+
+    Ok(Refetched {
+        bytes: Some(bytes),
+        etag,
+        moved_to,
+    })
+}
+
+/// Downloads `link` as-is, without looking up its image through an API or caching it
+pub async fn download_image(link: &str) -> Result<Vec<u8>, UserError> {
+    refetch_image(link, None)
+        .await?
+        .bytes
+        .map(|bytes| bytes.to_vec())
+        .ok_or_else(|| ue!("image host answered Not Modified without being asked"))
+}
+
+/// What `rehash_link` did
+#[derive(Debug)]
+pub struct Rehashed {
+    /// How many images rows were updated
+    pub updated: u64,
+    /// Whether the host said the image hadn't changed, so it wasn't hashed again
+    pub not_modified: bool,
+    /// Where the link permanently redirects to now, which it's stored under from here on
+    pub moved_to: Option<String>,
+}
+
+/// Downloads and hashes `link` again, replacing its hashes in images and dropping it from
+/// image_cache; if the host says it hasn't changed since its stored ETag, only its
+/// retrieved_on is updated
+pub async fn rehash_link(link: &str) -> Result<Rehashed, UserError> {
+    if is_taken_down(Some(link), None).await? {
+        return Err(ue_save!(
+            "image was taken down",
+            SaveError::TakenDown,
+            Source::User
+        ));
+    }
+
+    let mut client = PG_POOL.get().await?;
+    let stored = client
+        .query_opt(
+            "SELECT id, hash128_hi, etag FROM images WHERE link = $1",
+            &[&link],
+        )
+        .await?;
+    let etag = stored
+        .as_ref()
+        .and_then(|row| row.get::<_, Option<String>>("etag"));
+
+    let Refetched {
+        bytes,
+        etag,
+        moved_to,
+    } = refetch_image(link, etag.as_deref())
+        .await
+        .with_context(|| format!("downloading {} to rehash it", link))?;
+
+    let hashes = match bytes {
+        Some(bytes) => {
+            let wide = stored
+                .as_ref()
+                .map(|row| row.get::<_, Option<i64>>("hash128_hi").is_some())
+                .unwrap_or_else(|| CONFIG.load().hash128);
+            Some(hash_in_pool(bytes, wide).await?)
+        }
+        None => None,
+    };
+
+    let now = chrono::offset::Utc::now().naive_utc();
+    let trans = client.transaction().await?;
+    let updated = match hashes {
+        Some((hash, hash128)) => {
+            trans
+                .execute(
+                    "UPDATE images SET hash = $2, hash128_hi = $3, hash128_lo = $4, \
+                     hash_version = $5, next_hash = NULL, next_hash128_hi = NULL, \
+                     next_hash128_lo = NULL, next_hash_version = NULL, etag = $6, \
+                     retrieved_on = $7 WHERE link = $1",
                     &[
                         &link,
                         &hash,
-                        &cc.map(|cc| cc.no_store),
-                        &cc.map(|cc| cc.no_cache),
-                        &cc.and_then(|cc| cc.max_age)
-                            .map(|n| NaiveDateTime::from_timestamp(n as i64, 0))
-                            .or_else(|| {
-                                headers
-                                    .get(header::EXPIRES)
-                                    .and_then(|hv| hv.to_str().ok())
-                                    .and_then(|s| DateTime::parse_from_rfc2822(s).ok())
-                                    .map(|dt| dt.naive_utc())
-                            }),
-                        &headers.get(header::ETAG).and_then(|hv| hv.to_str().ok()),
-                        &cc.map(|cc| cc.must_revalidate),
+                        &hash128.map(Hash128::hi),
+                        &hash128.map(Hash128::lo),
+                        &HASH_VERSION,
+                        &etag,
                         &now,
                     ],
                 )
-                .await?;
+                .await?
+        }
+        None => {
+            trans
+                .execute(
+                    "UPDATE images SET retrieved_on = $2 WHERE link = $1",
+                    &[&link, &now],
+                )
+                .await?
+        }
+    };
+    trans
+        .execute("DELETE FROM image_cache WHERE link = $1", &[&link])
+        .await?;
 
-            trans.commit().await?;
+    // The old link stays in image_links, so posts of it still find the image
+    if let (Some(moved_to), Some(row)) = (&moved_to, &stored) {
+        trans
+            .execute(
+                "INSERT INTO image_links (link, image_id) VALUES ($1, $2) \
+                 ON CONFLICT DO NOTHING",
+                &[moved_to, &row.get::<_, i64>("id")],
+            )
+            .await?;
+        trans
+            .execute(
+                "UPDATE images SET link = $2 WHERE id = $1 \
+                 AND NOT EXISTS (SELECT 1 FROM images WHERE link = $2)",
+                &[&row.get::<_, i64>("id"), moved_to],
+            )
+            .await?;
+    }
 
-            // Postgres will return no rows on a conflict, and a row with the new id on success
-            match rows.first() {
-                Some(row) => Ok(HashSaved {
-                    hash,
-                    hash_dest,
-                    id: row.get("id"),
-                }),
-                None => {
-                    let found = get_existing(&link).await?;
-                    match found {
-                        Some((hash, found_hash_dest, id)) => {
-                            poss_move_row(hash, hash_dest, found_hash_dest, id).await
-                        }
-                        None => Err(ue!("conflict but no existing match")),
-                    }
-                }
-            }
-        }
+    trans.commit().await?;
+
+    Ok(Rehashed {
+        updated,
+        not_modified: hashes.is_none(),
+        moved_to,
+    })
+}
+
+/// Hashes and stores an image we already have the bytes of; `origin_label` stands in for
+/// the link, so it should be unique to the image. Its 128-bit hash is stored too if `hash128`
+/// or the config's `hash128` asks for it
+pub async fn save_hash_bytes(
+    bytes: &[u8],
+    origin_label: &str,
+    hash_dest: HashDest,
+    hash128: bool,
+) -> Result<HashSaved, UserError> {
+    if let Some((hash, hash128, found_hash_dest, id)) = get_existing(origin_label).await? {
+        return poss_move_row(hash, hash128, hash_dest, found_hash_dest, id).await;
+    }
+
+    let (hash, hash128) = hash_in_pool(
+        Bytes::copy_from_slice(bytes),
+        hash128 || CONFIG.load().hash128,
+    )
+    .await?;
+
+    insert_hash(
+        origin_label,
+        hash,
+        hash128,
+        hash_dest,
+        &HeaderMap::new(),
+        bytes,
+        None,
+    )
+    .await
+}
+
+/// A stable label for images that don't have a link of their own, from the SHA-256 of their
+/// bytes like `blob_key`, so two different images can't share one
+pub fn content_label(bytes: &[u8]) -> String {
+    format!("bytes:{:x}", Sha256::digest(bytes))
+}
+
+pub fn decode_data_url(link: &str) -> Result<Vec<u8>, UserError> {
+    let rest = link
+        .strip_prefix("data:")
+        .ok_or_else(|| ue!("not a data: URL", Source::User))?;
+    let comma = rest.find(',').ok_or_else(|| {
+        ue_save!(
+            "data: URL without data",
+            SaveError::DataUrlBad,
+            Source::User
+        )
+    })?;
+    let (meta, data) = (&rest[..comma], &rest[comma + 1..]);
+
+    let mut meta = meta.split(';');
+    let mime = meta.next().unwrap_or("");
+    if !mime.is_empty() && !is_image_mime(mime) {
+        return Err(ue_save!(
+            format!("unsupported data: URL type: {}", mime),
+            SaveError::ContentTypeUnsupported,
+            Source::User
+        ));
+    }
+
+    if meta.any(|param| param == "base64") {
+        base64::decode(percent_decode(data.as_bytes()).collect::<Vec<u8>>()).map_err(map_ue_save!(
+            "invalid base64 in data: URL",
+            SaveError::DataUrlBad,
+            Source::User
+        ))
+    } else {
+        Ok(percent_decode(data.as_bytes()).collect())
     }
 }
 
@@ -764,6 +1742,87 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn gateway_links() {
+        let gateways = config::Gateways {
+            enabled: true,
+            ipfs: vec!["https://ipfs.example.com/".to_string()],
+            arweave: vec!["https://arweave.net".to_string()],
+        };
+        let path = |link: &str| gateway_path(&Url::parse(link).unwrap(), &gateways);
+
+        let cid = "bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi";
+        let ipfs = Some((Network::Ipfs, format!("/ipfs/{}/cat.png", cid)));
+        assert_eq!(path(&format!("ipfs://{}/cat.png", cid)), ipfs);
+        assert_eq!(path(&format!("https://ipfs.io/ipfs/{}/cat.png", cid)), ipfs);
+        assert_eq!(
+            path(&format!("https://{}.ipfs.dweb.link/cat.png", cid)),
+            ipfs
+        );
+        assert_eq!(
+            path(&format!("https://ipfs.example.com/ipfs/{}/cat.png", cid)),
+            ipfs
+        );
+        assert_eq!(
+            path("ipns://example.eth/cat.png"),
+            Some((Network::Ipfs, "/ipns/example.eth/cat.png".to_string()))
+        );
+
+        let arweave = Some((Network::Arweave, "/abc123?ext=png".to_string()));
+        assert_eq!(path("ar://abc123?ext=png"), arweave);
+        assert_eq!(path("https://arweave.net/abc123?ext=png"), arweave);
+
+        assert_eq!(path("https://arweave.net/"), None);
+        assert_eq!(path("https://ipfs.io/about"), None);
+        assert_eq!(path("https://i.imgur.com/ipfs/cat.png"), None);
+    }
+
+    #[test]
+    fn sniffs_mislabeled_images() {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        let page = b"\n  <!DOCTYPE html><html><body>Not found</body></html>";
+
+        assert!(sniff_image(Some("application/octet-stream"), png).is_ok());
+        assert!(sniff_image(Some("text/html"), png).is_ok());
+        assert!(sniff_image(None, png).is_ok());
+
+        for declared in &[Some("text/html"), None] {
+            assert_eq!(
+                sniff_image(*declared, page).unwrap_err().save_error,
+                Some(SaveError::ContentTypeUnsupported)
+            );
+        }
+        assert!(sniff_image(Some("application/octet-stream"), b"plain text").is_err());
+        // Without a declared type, the image's decoder gets the final say
+        assert!(sniff_image(None, b"<svg></svg>").is_ok());
+    }
+
+    #[test]
+    fn global_ips() {
+        assert!(is_global_ip("151.101.1.140".parse().unwrap()));
+        assert!(is_global_ip("2a04:4e42::396".parse().unwrap()));
+        assert!(!is_global_ip("127.0.0.1".parse().unwrap()));
+        assert!(!is_global_ip("10.1.2.3".parse().unwrap()));
+        assert!(!is_global_ip("192.168.0.1".parse().unwrap()));
+        assert!(!is_global_ip("169.254.169.254".parse().unwrap()));
+        assert!(!is_global_ip("100.100.0.1".parse().unwrap()));
+        assert!(!is_global_ip("::1".parse().unwrap()));
+        assert!(!is_global_ip("fd00::1".parse().unwrap()));
+        assert!(!is_global_ip("fe80::1".parse().unwrap()));
+        assert!(!is_global_ip("::ffff:127.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn data_urls() {
+        assert_eq!(
+            decode_data_url("data:image/png;base64,aGVsbG8=").unwrap(),
+            b"hello"
+        );
+        assert_eq!(decode_data_url("data:,hi%20there").unwrap(), b"hi there");
+        assert!(decode_data_url("data:text/html,<p>").is_err());
+        assert!(decode_data_url("data:image/png;base64").is_err());
+    }
+
     #[test]
     fn gifsound_links() {
         assert!(is_link_gifsound(
@@ -1,12 +1,28 @@
-use super::{map_ue_save, ue_save, Source, UserError};
-use bytes::BytesMut;
-use image::{imageops, load_from_memory, DynamicImage, GrayImage};
+use super::{map_ue, map_ue_save, ue_save, SaveError, Source, UserError, CONFIG};
+use bytes::{Bytes, BytesMut};
+use image::io::{Limits, Reader};
+use image::{imageops, DynamicImage, GrayImage, ImageError};
+use once_cell::sync::Lazy;
 use std::fmt::{self, Display, Formatter};
+use std::io::Cursor;
+use tokio::sync::{oneshot, Semaphore};
 use tokio_postgres::types;
 
-#[derive(Debug, Copy, Clone)]
+/// Stored with every hashed image, so a database hashed by more than one version can be found.
+/// Bump it, and the schema's default, whenever a change to `dhash` or `grayscale` makes the
+/// golden tests below fail; old and new hashes of the same image can't be compared.
+pub const HASH_VERSION: i16 = 1;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct Hash(pub u64);
 
+impl Hash {
+    /// A hash as it's stored in a bigint column, which holds its bits as they are
+    pub fn from_db(stored: i64) -> Self {
+        Hash(stored as u64)
+    }
+}
+
 impl Display for Hash {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         Display::fmt(&self.0, f)
@@ -29,6 +45,19 @@ impl types::ToSql for Hash {
     types::to_sql_checked!();
 }
 
+impl<'a> types::FromSql<'a> for Hash {
+    fn from_sql(
+        t: &types::Type,
+        raw: &'a [u8],
+    ) -> Result<Self, Box<dyn std::error::Error + Sync + Send>> {
+        <i64 as types::FromSql>::from_sql(t, raw).map(Hash::from_db)
+    }
+
+    fn accepts(t: &types::Type) -> bool {
+        <i64 as types::FromSql>::accepts(t)
+    }
+}
+
 pub fn dhash(img: DynamicImage) -> Result<Hash, UserError> {
     let small_img = imageops::thumbnail(&grayscale(&img)?, 9, 8);
 
@@ -46,11 +75,414 @@ pub fn dhash(img: DynamicImage) -> Result<Hash, UserError> {
 }
 
 pub fn distance(a: Hash, b: Hash) -> u32 {
-    (a.0 ^ b.0).count_ones()
+    a.distance(b)
+}
+
+/// A difference hash of some fixed width
+pub trait PerceptualHash: Copy {
+    const BITS: u32;
+
+    fn from_image(img: &DynamicImage) -> Result<Self, UserError>;
+    fn distance(self, other: Self) -> u32;
+}
+
+impl PerceptualHash for Hash {
+    const BITS: u32 = 64;
+
+    fn from_image(img: &DynamicImage) -> Result<Self, UserError> {
+        dhash(img.clone())
+    }
+
+    fn distance(self, other: Self) -> u32 {
+        (self.0 ^ other.0).count_ones()
+    }
+}
+
+/// A 128-bit dhash, from a 9x16 thumbnail; stored in two bigint columns so each half can use
+/// the same index as the 64-bit hash
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Hash128(pub u128);
+
+impl Hash128 {
+    pub fn from_halves(hi: i64, lo: i64) -> Self {
+        Self(u128::from(hi as u64) << 64 | u128::from(lo as u64))
+    }
+
+    pub fn hi(self) -> i64 {
+        (self.0 >> 64) as u64 as i64
+    }
+
+    pub fn lo(self) -> i64 {
+        self.0 as u64 as i64
+    }
+}
+
+impl Display for Hash128 {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        Display::fmt(&self.0, f)
+    }
+}
+
+impl PerceptualHash for Hash128 {
+    const BITS: u32 = 128;
+
+    fn from_image(img: &DynamicImage) -> Result<Self, UserError> {
+        dhash128(img)
+    }
+
+    fn distance(self, other: Self) -> u32 {
+        (self.0 ^ other.0).count_ones()
+    }
+}
+
+pub fn dhash128(img: &DynamicImage) -> Result<Hash128, UserError> {
+    let small_img = imageops::thumbnail(&grayscale(img)?, 9, 16);
+
+    let mut hash: u128 = 0;
+
+    for y in 0..16 {
+        for x in 0..8 {
+            let bit = ((small_img.get_pixel(x, y)[0] > small_img.get_pixel(x + 1, y)[0]) as u128)
+                << (x + y * 8);
+            hash |= bit;
+        }
+    }
+
+    Ok(Hash128(hash))
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum ExtraFormat {
+    AnimatedWebp,
+    Heic,
+    Jxl,
+    Svg,
+}
+
+// The image crate already recognizes AVIF and still WebP, so only HEIC, JPEG XL and animated
+// WebP need sniffing
+fn sniff_extra_format(image: &[u8]) -> Option<ExtraFormat> {
+    const JXL_CODESTREAM: &[u8] = &[0xff, 0x0a];
+    const JXL_CONTAINER: &[u8] = b"\0\0\0\x0cJXL \r\n\x87\n";
+    const HEIC_BRANDS: [&[u8]; 6] = [b"heic", b"heix", b"hevc", b"hevx", b"heim", b"heis"];
+
+    if image.starts_with(JXL_CODESTREAM) || image.starts_with(JXL_CONTAINER) {
+        Some(ExtraFormat::Jxl)
+    } else if image.len() >= 12 && &image[4..8] == b"ftyp" && HEIC_BRANDS.contains(&&image[8..12]) {
+        Some(ExtraFormat::Heic)
+    } else if animated_webp_size(image).is_some() {
+        Some(ExtraFormat::AnimatedWebp)
+    } else if is_svg(image) {
+        Some(ExtraFormat::Svg)
+    } else {
+        None
+    }
+}
+
+/// The canvas size of an extended WebP with the animation flag set
+fn animated_webp_size(image: &[u8]) -> Option<(u32, u32)> {
+    const ANIMATION_FLAG: u8 = 0x02;
+
+    if image.len() < 30
+        || !image.starts_with(b"RIFF")
+        || &image[8..16] != b"WEBPVP8X"
+        || image[20] & ANIMATION_FLAG == 0
+    {
+        return None;
+    }
+
+    let u24 = |b: &[u8]| u32::from(b[0]) | u32::from(b[1]) << 8 | u32::from(b[2]) << 16;
+    Some((u24(&image[24..27]) + 1, u24(&image[27..30]) + 1))
+}
+
+/// Decodes only the first frame, the same as a GIF's
+fn load_animated_webp(image: &[u8]) -> Result<DynamicImage, UserError> {
+    use image::codecs::webp::WebPDecoder;
+    use image::AnimationDecoder;
+
+    let (width, height) = animated_webp_size(image)
+        .ok_or_else(|| ue_save!("not an animated WebP", SaveError::ImageInvalid))?;
+    check_dimensions(width, height)?;
+
+    let frame = WebPDecoder::new(Cursor::new(image))
+        .map_err(decode_error)?
+        .into_frames()
+        .next()
+        .ok_or_else(|| ue_save!("animated WebP has no frames", SaveError::ImageInvalid))?
+        .map_err(decode_error)?;
+
+    Ok(DynamicImage::ImageRgba8(frame.into_buffer()))
+}
+
+fn is_svg(image: &[u8]) -> bool {
+    // Skip past any XML declaration, doctype, or comments to the root element
+    let start = &image[..image.len().min(4096)];
+    let start = String::from_utf8_lossy(start);
+    let start = start.trim_start_matches('\u{feff}').trim_start();
+
+    (start.starts_with("<svg") || start.starts_with("<?xml") || start.starts_with("<!"))
+        && start.contains("<svg")
+}
+
+// SVGs have no inherent size, so they're all drawn onto the same canvas
+#[cfg(feature = "svg")]
+const SVG_CANVAS: u32 = 512;
+
+#[cfg(feature = "svg")]
+fn load_svg(image: &[u8]) -> Result<DynamicImage, UserError> {
+    use image::RgbaImage;
+
+    if !super::CONFIG.load().enable_svg {
+        return Err(ue_save!(
+            "SVG support is disabled",
+            SaveError::ImageFormatDisabled,
+            Source::Internal
+        ));
+    }
+
+    let opt = usvg::Options::default();
+    let tree = usvg::Tree::from_data(image, &opt.to_ref()).map_err(map_ue_save!(
+        "invalid SVG image",
+        SaveError::ImageSvgInvalid
+    ))?;
+
+    let mut pixmap = tiny_skia::Pixmap::new(SVG_CANVAS, SVG_CANVAS)
+        .ok_or_else(|| ue_save!("couldn't allocate SVG canvas", SaveError::ImageSvgInvalid))?;
+    // Transparent areas would otherwise hash as black
+    pixmap.fill(tiny_skia::Color::WHITE);
+
+    resvg::render(
+        &tree,
+        usvg::FitTo::Size(SVG_CANVAS, SVG_CANVAS),
+        tiny_skia::Transform::default(),
+        pixmap.as_mut(),
+    )
+    .ok_or_else(|| ue_save!("couldn't render SVG image", SaveError::ImageSvgInvalid))?;
+
+    RgbaImage::from_raw(SVG_CANVAS, SVG_CANVAS, pixmap.take())
+        .map(DynamicImage::ImageRgba8)
+        .ok_or_else(|| ue_save!("SVG canvas has the wrong size", SaveError::ImageSvgInvalid))
+}
+
+#[cfg(not(feature = "svg"))]
+fn load_svg(_image: &[u8]) -> Result<DynamicImage, UserError> {
+    Err(ue_save!(
+        "SVG support isn't enabled",
+        SaveError::ImageFormatDisabled,
+        Source::Internal
+    ))
+}
+
+#[cfg(feature = "heic")]
+fn load_heic(image: &[u8]) -> Result<DynamicImage, UserError> {
+    use image::RgbImage;
+    use libheif_rs::{ColorSpace, HeifContext, RgbChroma};
+
+    let ctx = HeifContext::read_from_bytes(image).map_err(map_ue_save!(
+        "invalid HEIC image",
+        SaveError::ImageHeicInvalid
+    ))?;
+    let handle = ctx.primary_image_handle().map_err(map_ue_save!(
+        "HEIC image has no primary image",
+        SaveError::ImageHeicInvalid
+    ))?;
+    check_dimensions(handle.width(), handle.height())?;
+    let decoded = handle
+        .decode(ColorSpace::Rgb(RgbChroma::Rgb), false)
+        .map_err(map_ue_save!(
+            "couldn't decode HEIC image",
+            SaveError::ImageHeicInvalid
+        ))?;
+
+    let planes = decoded.planes();
+    let interleaved = planes
+        .interleaved
+        .ok_or_else(|| ue_save!("HEIC image isn't interleaved", SaveError::ImageHeicInvalid))?;
+
+    // Rows can be padded past width * 3
+    let row_len = interleaved.width as usize * 3;
+    let data = interleaved
+        .data
+        .chunks(interleaved.stride)
+        .take(interleaved.height as usize)
+        .flat_map(|row| &row[..row_len])
+        .copied()
+        .collect();
+
+    RgbImage::from_raw(interleaved.width, interleaved.height, data)
+        .map(DynamicImage::ImageRgb8)
+        .ok_or_else(|| ue_save!("HEIC image has the wrong size", SaveError::ImageHeicInvalid))
+}
+
+#[cfg(not(feature = "heic"))]
+fn load_heic(_image: &[u8]) -> Result<DynamicImage, UserError> {
+    Err(ue_save!(
+        "HEIC support isn't enabled",
+        SaveError::ImageFormatDisabled,
+        Source::Internal
+    ))
+}
+
+#[cfg(feature = "jxl")]
+fn load_jxl(image: &[u8]) -> Result<DynamicImage, UserError> {
+    use image::{GrayAlphaImage, RgbImage, RgbaImage};
+    use jxl_oxide::JxlImage;
+
+    let jxl = JxlImage::builder().read(image).map_err(map_ue_save!(
+        "invalid JPEG XL image",
+        SaveError::ImageJxlInvalid
+    ))?;
+    let size = &jxl.image_header().size;
+    check_dimensions(size.width, size.height)?;
+    let render = jxl.render_frame(0).map_err(map_ue_save!(
+        "couldn't decode JPEG XL image",
+        SaveError::ImageJxlInvalid
+    ))?;
+    let frame = render.image();
+
+    let (width, height) = (frame.width() as u32, frame.height() as u32);
+    let data: Vec<u8> = frame
+        .buf()
+        .iter()
+        .map(|&v| (v.max(0.).min(1.) * 255.).round() as u8)
+        .collect();
+
+    match frame.channels() {
+        1 => GrayImage::from_raw(width, height, data).map(DynamicImage::ImageLuma8),
+        2 => GrayAlphaImage::from_raw(width, height, data).map(DynamicImage::ImageLumaA8),
+        3 => RgbImage::from_raw(width, height, data).map(DynamicImage::ImageRgb8),
+        4 => RgbaImage::from_raw(width, height, data).map(DynamicImage::ImageRgba8),
+        _ => None,
+    }
+    .ok_or_else(|| ue_save!("unsupported JPEG XL channels", SaveError::ImageJxlInvalid))
+}
+
+#[cfg(not(feature = "jxl"))]
+fn load_jxl(_image: &[u8]) -> Result<DynamicImage, UserError> {
+    Err(ue_save!(
+        "JPEG XL support isn't enabled",
+        SaveError::ImageFormatDisabled,
+        Source::Internal
+    ))
+}
+
+/// Fails images whose header claims more pixels than `decode_limits` allows, before any of
+/// them are allocated
+fn check_dimensions(width: u32, height: u32) -> Result<(), UserError> {
+    if u64::from(width) * u64::from(height) > CONFIG.load().decode_limits.max_pixels {
+        return Err(ue_save!(
+            format!("image is too large ({}x{})", width, height),
+            SaveError::ImageTooLarge,
+            Source::User
+        ));
+    }
+
+    Ok(())
+}
+
+fn decode_error(e: ImageError) -> UserError {
+    match e {
+        ImageError::Unsupported(_) => UserError {
+            file: Some(file!()),
+            line: Some(line!()),
+            save_error: Some(SaveError::ImageUnsupported),
+            ..UserError::new("unsupported image format", e)
+        },
+        ImageError::Limits(_) => UserError {
+            file: Some(file!()),
+            line: Some(line!()),
+            save_error: Some(SaveError::ImageTooLarge),
+            source: Source::User,
+            ..UserError::new("image is too large to decode", e)
+        },
+        e => UserError {
+            file: Some(file!()),
+            line: Some(line!()),
+            save_error: Some(SaveError::ImageInvalid),
+            ..UserError::new("invalid image", e)
+        },
+    }
+}
+
+/// Decodes any format the image crate knows, checking its size from the header first
+fn load_standard(image: &[u8]) -> Result<DynamicImage, UserError> {
+    let reader = || {
+        Reader::new(Cursor::new(image))
+            .with_guessed_format()
+            .map_err(map_ue_save!("invalid image", SaveError::ImageInvalid))
+    };
+
+    let (width, height) = reader()?.into_dimensions().map_err(decode_error)?;
+    check_dimensions(width, height)?;
+
+    // The header can lie, or a format can need more than its pixels while decoding
+    let mut limits = Limits::default();
+    limits.max_alloc = Some(CONFIG.load().decode_limits.max_alloc_bytes);
+
+    let mut reader = reader()?;
+    reader.limits(limits);
+    reader.decode().map_err(decode_error)
+}
+
+pub fn load_image(image: &[u8]) -> Result<DynamicImage, UserError> {
+    match sniff_extra_format(image) {
+        Some(ExtraFormat::AnimatedWebp) => load_animated_webp(image),
+        Some(ExtraFormat::Heic) => load_heic(image),
+        Some(ExtraFormat::Jxl) => load_jxl(image),
+        Some(ExtraFormat::Svg) => load_svg(image),
+        None => load_standard(image),
+    }
 }
 
 pub fn hash_from_memory(image: &[u8]) -> Result<Hash, UserError> {
-    dhash(load_from_memory(&image).map_err(map_ue_save!("invalid image", "image_invalid"))?)
+    dhash(load_image(image)?)
+}
+
+/// Like `hash_from_memory`, but decodes once to also get the 128-bit hash if `wide` is set
+pub fn hashes_from_memory(image: &[u8], wide: bool) -> Result<(Hash, Option<Hash128>), UserError> {
+    let img = load_image(image)?;
+
+    let hash128 = if wide { Some(dhash128(&img)?) } else { None };
+
+    Ok((dhash(img)?, hash128))
+}
+
+/// Decodes and hashes images off the async runtime's threads, which would otherwise stall
+/// every request and download while a large image is decoded
+static HASH_POOL: Lazy<rayon::ThreadPool> = Lazy::new(|| {
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(CONFIG.load().hash_pool.threads)
+        .thread_name(|i| format!("hash-{}", i))
+        .build()
+        .unwrap()
+});
+/// Images being hashed or waiting in `HASH_POOL`; past this, hashing waits to be queued
+static HASH_QUEUE: Lazy<Semaphore> = Lazy::new(|| Semaphore::new(CONFIG.load().hash_pool.queue));
+
+/// `hashes_from_memory` on the hashing pool; an image that panics while being decoded fails
+/// with `SaveError::ImagePanic` rather than taking a thread with it
+pub async fn hash_in_pool(image: Bytes, wide: bool) -> Result<(Hash, Option<Hash128>), UserError> {
+    let _queued = HASH_QUEUE
+        .acquire()
+        .await
+        .map_err(map_ue!("hashing queue closed"))?;
+
+    let (hashed_tx, hashed) = oneshot::channel();
+    HASH_POOL.spawn(move || {
+        let result =
+            std::panic::catch_unwind(|| hashes_from_memory(&image, wide)).unwrap_or_else(|_e| {
+                Err(ue_save!(
+                    "image panicked!",
+                    SaveError::ImagePanic,
+                    Source::User
+                ))
+            });
+        let _ = hashed_tx.send(result);
+    });
+
+    hashed
+        .await
+        .map_err(map_ue!("hashing pool dropped the image"))?
 }
 
 fn rgb_to_luma(r: u8, g: u8, b: u8) -> u8 {
@@ -89,9 +521,112 @@ pub fn grayscale(img: &DynamicImage) -> Result<DynamicImage, UserError> {
         _ => {
             return Err(ue_save!(
                 "unsupported image color space",
-                "image_color_space",
+                SaveError::ImageColorSpace,
                 Source::User
             ))
         }
     }))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Small images under fixtures/hash made of 2x4 blocks, so the 9x8 and 9x16 thumbnails are
+    // exact averages; luma.png has greens BT.709 puts above red and BT.601 below it
+    const GOLDEN: &[(&str, u64, Option<u128>)] = &[
+        (
+            "blocks.png",
+            0xca5c_a546_9b7a_55a9,
+            Some(0xcaca_5c5c_a5a5_4646_9b9b_7a7a_5555_a9a9),
+        ),
+        (
+            "luma.png",
+            0x2294_85d3_a198_6a14,
+            Some(0x2222_9494_8585_d3d3_a1a1_9898_6a6a_1414),
+        ),
+        (
+            "alpha.png",
+            0xada9_95ae_a934_52ad,
+            Some(0xadad_a9a9_9595_aeae_a9a9_3434_5252_adad),
+        ),
+        // 9x8, so there's nothing to shrink
+        ("gray.png", 0xb446_4a45_5446_eae5, None),
+        // The same 9x8 black and white pattern, still and as the first of two frames
+        ("lossless.webp", 0xa995_522a_a554_4aa9, None),
+        ("animated.webp", 0xa995_522a_a554_4aa9, None),
+    ];
+
+    fn fixture(name: &str) -> Vec<u8> {
+        std::fs::read(format!(
+            "{}/fixtures/hash/{}",
+            env!("CARGO_MANIFEST_DIR"),
+            name
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn too_large() {
+        // A 50000x50000 header with no pixels behind it
+        let e = hash_from_memory(&fixture("bomb.png")).unwrap_err();
+        assert_eq!(e.save_error, Some(SaveError::ImageTooLarge));
+    }
+
+    #[test]
+    fn lossy_webp() {
+        // The same picture, as a plain VP8 file and as VP8X with an alpha channel
+        for name in &["lossy.webp", "alpha.webp"] {
+            hash_from_memory(&fixture(name)).unwrap();
+        }
+        assert_eq!(sniff_extra_format(&fixture("alpha.webp")), None);
+        assert_eq!(
+            sniff_extra_format(&fixture("animated.webp")),
+            Some(ExtraFormat::AnimatedWebp)
+        );
+    }
+
+    #[test]
+    fn golden_hashes() {
+        for &(name, hash, hash128) in GOLDEN {
+            let image = fixture(name);
+            assert_eq!(
+                hash_from_memory(&image).unwrap().0,
+                hash,
+                "{} hashed differently; bump HASH_VERSION",
+                name
+            );
+
+            if let Some(hash128) = hash128 {
+                let (same, wide) = hashes_from_memory(&image, true).unwrap();
+                assert_eq!(same.0, hash, "{}", name);
+                assert_eq!(
+                    wide,
+                    Some(Hash128(hash128)),
+                    "{} hashed differently; bump HASH_VERSION",
+                    name
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn db_round_trip() {
+        use types::{FromSql, ToSql, Type};
+
+        let hash = Hash(0xf000_0000_0000_0001);
+        let mut raw = BytesMut::new();
+        hash.to_sql(&Type::INT8, &mut raw).unwrap();
+
+        assert_eq!(i64::from_sql(&Type::INT8, &raw).unwrap(), hash.0 as i64);
+        assert_eq!(Hash::from_sql(&Type::INT8, &raw).unwrap(), hash);
+        assert_eq!(Hash::from_db(-1), Hash(u64::MAX));
+    }
+
+    #[test]
+    fn luma() {
+        assert_eq!(rgb_to_luma(0, 90, 0), 64);
+        assert_eq!(rgb_to_luma(255, 0, 0), 54);
+        assert_eq!(rgb_to_luma(255, 255, 255), 255);
+    }
+}
@@ -0,0 +1,217 @@
+use super::*;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicI64, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// How many of the latest posts the error rate is over
+const RECENT_POSTS: usize = 1000;
+
+/// What an ingester has been up to, for orchestrators to decide whether it's stuck
+pub struct Health {
+    started: i64,
+    /// Unix seconds, or 0 before the first post
+    last_post: AtomicI64,
+    in_flight: AtomicUsize,
+    processed: AtomicU64,
+    errors: AtomicU64,
+    /// Whether each of the latest posts failed
+    recent: Mutex<VecDeque<bool>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HealthReport {
+    pub healthy: bool,
+    pub last_post_at: Option<NaiveDateTime>,
+    pub idle_secs: i64,
+    pub in_flight: usize,
+    pub processed: u64,
+    pub errors: u64,
+    /// Over the latest posts rather than all time
+    pub error_rate: f64,
+    /// For binaries that ask Reddit for posts, once it's responded
+    pub reddit_budget: Option<RateBudget>,
+}
+
+/// Decrements the in-flight count when dropped
+pub struct InFlight<'a>(&'a Health);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Health {
+    fn new(now: i64) -> Self {
+        Self {
+            started: now,
+            last_post: AtomicI64::new(0),
+            in_flight: AtomicUsize::new(0),
+            processed: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            recent: Mutex::new(VecDeque::with_capacity(RECENT_POSTS)),
+        }
+    }
+
+    /// Counts a post as in flight until the guard is dropped
+    pub fn start(&self) -> InFlight<'_> {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlight(self)
+    }
+
+    pub fn record(&self, ok: bool) {
+        self.record_at(ok, chrono::Utc::now().timestamp());
+    }
+
+    fn record_at(&self, ok: bool, now: i64) {
+        self.last_post.store(now, Ordering::SeqCst);
+        self.processed.fetch_add(1, Ordering::SeqCst);
+        if !ok {
+            self.errors.fetch_add(1, Ordering::SeqCst);
+        }
+
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() == RECENT_POSTS {
+            recent.pop_front();
+        }
+        recent.push_back(!ok);
+    }
+
+    fn report_at(&self, now: i64, max_idle_secs: i64) -> HealthReport {
+        let last_post = self.last_post.load(Ordering::SeqCst);
+        // Before the first post, a fresh start isn't stuck yet
+        let idle_secs = now
+            - if last_post == 0 {
+                self.started
+            } else {
+                last_post
+            };
+
+        let recent = self.recent.lock().unwrap();
+        let error_rate = if recent.is_empty() {
+            0.0
+        } else {
+            recent.iter().filter(|&&failed| failed).count() as f64 / recent.len() as f64
+        };
+
+        HealthReport {
+            healthy: idle_secs <= max_idle_secs,
+            last_post_at: if last_post == 0 {
+                None
+            } else {
+                Some(NaiveDateTime::from_timestamp(last_post, 0))
+            },
+            idle_secs,
+            in_flight: self.in_flight.load(Ordering::SeqCst),
+            processed: self.processed.load(Ordering::SeqCst),
+            errors: self.errors.load(Ordering::SeqCst),
+            error_rate,
+            reddit_budget: REDDIT_PACE.budget(),
+        }
+    }
+
+    pub fn report(&self) -> HealthReport {
+        self.report_at(
+            chrono::Utc::now().timestamp(),
+            CONFIG.load().health.max_idle_secs,
+        )
+    }
+}
+
+pub static HEALTH: Lazy<Health> = Lazy::new(|| Health::new(chrono::Utc::now().timestamp()));
+
+async fn respond(mut stream: tokio::net::TcpStream) -> std::io::Result<()> {
+    let mut buf = [0; 1024];
+    let read = stream.read(&mut buf).await?;
+    let request = String::from_utf8_lossy(&buf[..read]);
+
+    let (status, body) = if request.starts_with("GET /health ") {
+        let report = HEALTH.report();
+        let status = if report.healthy {
+            "200 OK"
+        } else {
+            "503 Service Unavailable"
+        };
+        (status, serde_json::to_string(&report)?)
+    } else {
+        ("404 Not Found", String::new())
+    };
+
+    stream
+        .write_all(
+            format!(
+                "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            )
+            .as_bytes(),
+        )
+        .await
+}
+
+/// Serves `GET /health` on `health.addr` and the port configured for `binary`, if there is one
+pub async fn serve_health(binary: &str) -> std::io::Result<()> {
+    let (addr, port) = {
+        let health = &CONFIG.load().health;
+        match health.ports.get(binary) {
+            Some(&port) => (health.addr, port),
+            None => return Ok(()),
+        }
+    };
+
+    Lazy::force(&HEALTH);
+    let listener = TcpListener::bind((addr, port)).await?;
+    info!("Serving health on {}:{}", addr, port);
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(async move {
+                        if let Err(e) = respond(stream).await {
+                            warn!("Health check failed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => warn!("Couldn't accept a health check: {}", e),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report() {
+        let health = Health::new(1000);
+
+        let report = health.report_at(1100, 300);
+        assert!(report.healthy);
+        assert_eq!(report.last_post_at, None);
+        assert_eq!(report.error_rate, 0.0);
+
+        assert!(!health.report_at(1400, 300).healthy);
+
+        {
+            let _in_flight = health.start();
+            assert_eq!(health.report_at(1400, 300).in_flight, 1);
+        }
+
+        health.record_at(true, 1500);
+        health.record_at(false, 1600);
+        let report = health.report_at(1700, 300);
+        assert!(report.healthy);
+        assert_eq!(report.idle_secs, 100);
+        assert_eq!(report.in_flight, 0);
+        assert_eq!(report.processed, 2);
+        assert_eq!(report.errors, 1);
+        assert_eq!(report.error_rate, 0.5);
+    }
+}
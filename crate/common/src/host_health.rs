@@ -0,0 +1,391 @@
+use super::*;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// How often stats are written to Postgres and other processes' open circuits are reread
+const FLUSH_INTERVAL: Duration = Duration::from_secs(30);
+/// How much the newest request counts towards a host's failure rate and latency
+const WEIGHT: f64 = 0.05;
+/// A half-open host gets another probe if the last one never reported back
+const PROBE_TIMEOUT: Duration = Duration::from_secs(120);
+/// How often links wait on a half-open host's probe check it again
+const PROBE_POLL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Circuit {
+    Closed,
+    /// Requests wait until `until`
+    Open {
+        until: Instant,
+    },
+    /// One request went out to see if the host is back; the rest wait on it
+    HalfOpen {
+        probed: Instant,
+    },
+}
+
+/// What a link to a host should do
+#[derive(Debug, PartialEq)]
+pub enum HostPermit {
+    Go,
+    /// Go, as the request that decides whether the host's circuit closes
+    Probe,
+    Wait(Duration),
+}
+
+struct Host {
+    circuit: Circuit,
+    consecutive_failures: u32,
+    /// How long the circuit stays open the next time it opens
+    cooldown: Duration,
+    failure_rate: f64,
+    latency_ms: Option<f64>,
+    /// Counts not yet written to Postgres
+    successes: i64,
+    failures: i64,
+    last_success: Option<NaiveDateTime>,
+    last_failure: Option<NaiveDateTime>,
+    dirty: bool,
+}
+
+impl Host {
+    fn new(breaker: &config::HostBreaker) -> Self {
+        Self {
+            circuit: Circuit::Closed,
+            consecutive_failures: 0,
+            cooldown: Duration::from_secs(breaker.cooldown_secs),
+            failure_rate: 0.,
+            latency_ms: None,
+            successes: 0,
+            failures: 0,
+            last_success: None,
+            last_failure: None,
+            dirty: false,
+        }
+    }
+
+    fn permit(&mut self, now: Instant) -> HostPermit {
+        match self.circuit {
+            Circuit::Closed => HostPermit::Go,
+            Circuit::Open { until } if now < until => HostPermit::Wait(until - now),
+            Circuit::HalfOpen { probed } if now.duration_since(probed) < PROBE_TIMEOUT => {
+                HostPermit::Wait(PROBE_POLL)
+            }
+            _ => {
+                self.circuit = Circuit::HalfOpen { probed: now };
+                HostPermit::Probe
+            }
+        }
+    }
+
+    fn record(&mut self, ok: bool, latency: Duration, now: Instant, breaker: &config::HostBreaker) {
+        let at = chrono::Utc::now().naive_utc();
+        let latency_ms = latency.as_secs_f64() * 1000.;
+
+        self.failure_rate += WEIGHT * (if ok { 0. } else { 1. } - self.failure_rate);
+        self.latency_ms = Some(match self.latency_ms {
+            Some(average) => average + WEIGHT * (latency_ms - average),
+            None => latency_ms,
+        });
+        self.dirty = true;
+
+        if ok {
+            self.successes += 1;
+            self.last_success = Some(at);
+            self.consecutive_failures = 0;
+            self.cooldown = Duration::from_secs(breaker.cooldown_secs);
+            self.circuit = Circuit::Closed;
+        } else {
+            self.failures += 1;
+            self.last_failure = Some(at);
+            self.consecutive_failures += 1;
+
+            let trips = match self.circuit {
+                Circuit::HalfOpen { .. } => true,
+                Circuit::Closed => self.consecutive_failures >= breaker.failures,
+                Circuit::Open { .. } => false,
+            };
+            if trips {
+                self.circuit = Circuit::Open {
+                    until: now + self.cooldown,
+                };
+                self.cooldown =
+                    (self.cooldown * 2).min(Duration::from_secs(breaker.max_cooldown_secs));
+            }
+        }
+    }
+
+    fn open_for(&self, now: Instant) -> Option<Duration> {
+        match self.circuit {
+            Circuit::Open { until } if now < until => Some(until - now),
+            _ => None,
+        }
+    }
+}
+
+static HOSTS: Lazy<Mutex<HashMap<String, Host>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+static FLUSHED: Lazy<Mutex<Instant>> = Lazy::new(|| Mutex::new(Instant::now()));
+
+/// Whether a link to `host` may be requested now, and if not, how long until it's worth
+/// asking again; hosts in no_blacklist are tracked, but their circuits are ignored
+pub async fn host_permit(host: &str) -> HostPermit {
+    if no_blacklisted(host).await {
+        return HostPermit::Go;
+    }
+
+    match HOSTS.lock().unwrap().get_mut(host) {
+        Some(state) => state.permit(Instant::now()),
+        None => HostPermit::Go,
+    }
+}
+
+/// Counts a request to `host` towards its health, opening or closing its circuit
+pub async fn record_host(host: &str, ok: bool, latency: Duration) {
+    {
+        let config = CONFIG.load();
+        HOSTS
+            .lock()
+            .unwrap()
+            .entry(host.to_string())
+            .or_insert_with(|| Host::new(&config.host_breaker))
+            .record(ok, latency, Instant::now(), &config.host_breaker);
+    }
+
+    let due = {
+        let mut flushed = FLUSHED.lock().unwrap();
+        if flushed.elapsed() >= FLUSH_INTERVAL {
+            *flushed = Instant::now();
+            true
+        } else {
+            false
+        }
+    };
+
+    if due {
+        if let Err(e) = flush_host_health().await {
+            warn!("Couldn't save host health: {}", e);
+        }
+    }
+}
+
+/// Sends `request` to `host`, counting server errors and failures to connect against it
+pub async fn send_tracked(
+    host: &str,
+    request: reqwest::RequestBuilder,
+) -> Result<reqwest::Response, reqwest::Error> {
+    let started = Instant::now();
+    let sent = request.send().await;
+
+    if !host.is_empty() {
+        // A 404 is about the link, not the host
+        let ok = match &sent {
+            Ok(resp) => {
+                !(resp.status().is_server_error()
+                    || resp.status() == reqwest::StatusCode::TOO_MANY_REQUESTS)
+            }
+            Err(_) => false,
+        };
+        record_host(host, ok, started.elapsed()).await;
+    }
+
+    sent
+}
+
+/// Waits for `host`'s circuit to let a link through, failing with `HostFailing` if that
+/// would take longer than `max_delay_secs`; other links carry on meanwhile, so a failing
+/// host's links end up behind everyone else's
+pub async fn wait_for_host(host: &str) -> Result<(), UserError> {
+    let deadline = Instant::now() + Duration::from_secs(CONFIG.load().host_breaker.max_delay_secs);
+
+    loop {
+        match host_permit(host).await {
+            HostPermit::Go | HostPermit::Probe => return Ok(()),
+            HostPermit::Wait(wait) if Instant::now() + wait <= deadline => {
+                tokio::time::sleep(wait).await
+            }
+            HostPermit::Wait(_) => {
+                return Err(ue_save!(
+                    format!("{} is failing", host),
+                    SaveError::HostFailing
+                ))
+            }
+        }
+    }
+}
+
+/// Writes changed hosts to host_health and opens circuits other processes opened
+pub async fn flush_host_health() -> Result<(), UserError> {
+    let now = Instant::now();
+    let changed: Vec<_> = {
+        let mut hosts = HOSTS.lock().unwrap();
+        hosts
+            .iter_mut()
+            .filter(|(_host, state)| state.dirty)
+            .map(|(host, state)| {
+                let row = (
+                    host.clone(),
+                    std::mem::take(&mut state.successes),
+                    std::mem::take(&mut state.failures),
+                    state.consecutive_failures as i32,
+                    state.failure_rate,
+                    state.latency_ms,
+                    state.last_success,
+                    state.last_failure,
+                    state.open_for(now).and_then(|open_for| {
+                        chrono::Duration::from_std(open_for)
+                            .ok()
+                            .map(|open_for| chrono::Utc::now().naive_utc() + open_for)
+                    }),
+                );
+                state.dirty = false;
+                row
+            })
+            .collect()
+    };
+
+    let mut client = PG_POOL.get().await?;
+    let trans = client.transaction().await?;
+
+    for (
+        host,
+        successes,
+        failures,
+        consecutive_failures,
+        failure_rate,
+        latency_ms,
+        last_success,
+        last_failure,
+        open_until,
+    ) in &changed
+    {
+        trans
+            .execute(
+                "INSERT INTO host_health (host, successes, failures, consecutive_failures, \
+                 failure_rate, latency_ms, last_success_at, last_failure_at, open_until) \
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9) \
+                 ON CONFLICT (host) DO UPDATE SET \
+                 successes = host_health.successes + $2, \
+                 failures = host_health.failures + $3, \
+                 consecutive_failures = $4, failure_rate = $5, latency_ms = $6, \
+                 last_success_at = COALESCE($7, host_health.last_success_at), \
+                 last_failure_at = COALESCE($8, host_health.last_failure_at), \
+                 open_until = $9, updated_at = now() AT TIME ZONE 'utc'",
+                &[
+                    host,
+                    successes,
+                    failures,
+                    consecutive_failures,
+                    failure_rate,
+                    latency_ms,
+                    last_success,
+                    last_failure,
+                    open_until,
+                ],
+            )
+            .await?;
+    }
+
+    let open = trans
+        .query(
+            "SELECT host, \
+             EXTRACT(EPOCH FROM open_until - now() AT TIME ZONE 'utc')::float8 AS open_secs \
+             FROM host_health WHERE open_until > now() AT TIME ZONE 'utc'",
+            &[],
+        )
+        .await?;
+
+    trans.commit().await?;
+
+    let config = CONFIG.load();
+    let mut hosts = HOSTS.lock().unwrap();
+    for row in open {
+        let until = now + Duration::from_secs_f64(row.get("open_secs"));
+
+        let state = hosts
+            .entry(row.get("host"))
+            .or_insert_with(|| Host::new(&config.host_breaker));
+        if state.circuit == Circuit::Closed {
+            state.circuit = Circuit::Open { until };
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BREAKER: config::HostBreaker = config::HostBreaker {
+        failures: 3,
+        cooldown_secs: 10,
+        max_cooldown_secs: 30,
+        max_delay_secs: 5,
+    };
+
+    #[test]
+    fn opens_after_failures() {
+        let now = Instant::now();
+        let mut host = Host::new(&BREAKER);
+
+        host.record(false, Duration::from_millis(100), now, &BREAKER);
+        host.record(false, Duration::from_millis(100), now, &BREAKER);
+        assert_eq!(host.permit(now), HostPermit::Go);
+
+        host.record(false, Duration::from_millis(100), now, &BREAKER);
+        assert_eq!(host.permit(now), HostPermit::Wait(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn half_open() {
+        let now = Instant::now();
+        let mut host = Host::new(&BREAKER);
+        for _ in 0..3 {
+            host.record(false, Duration::from_millis(100), now, &BREAKER);
+        }
+
+        // Only one probe goes out at a time
+        let later = now + Duration::from_secs(10);
+        assert_eq!(host.permit(later), HostPermit::Probe);
+        assert_eq!(host.permit(later), HostPermit::Wait(PROBE_POLL));
+
+        // A failed probe opens it for twice as long
+        host.record(false, Duration::from_millis(100), later, &BREAKER);
+        assert_eq!(
+            host.permit(later),
+            HostPermit::Wait(Duration::from_secs(20))
+        );
+
+        let later = later + Duration::from_secs(20);
+        assert_eq!(host.permit(later), HostPermit::Probe);
+        host.record(true, Duration::from_millis(100), later, &BREAKER);
+        assert_eq!(host.permit(later), HostPermit::Go);
+        assert_eq!(host.cooldown, Duration::from_secs(10));
+    }
+
+    #[test]
+    fn cooldown_capped() {
+        let mut now = Instant::now();
+        let mut host = Host::new(&BREAKER);
+        for _ in 0..3 {
+            host.record(false, Duration::from_millis(100), now, &BREAKER);
+        }
+
+        for _ in 0..5 {
+            now += host.open_for(now).unwrap();
+            assert_eq!(host.permit(now), HostPermit::Probe);
+            host.record(false, Duration::from_millis(100), now, &BREAKER);
+        }
+        assert_eq!(host.open_for(now), Some(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn stale_probe() {
+        let now = Instant::now();
+        let mut host = Host::new(&BREAKER);
+        host.circuit = Circuit::HalfOpen { probed: now };
+
+        assert_eq!(host.permit(now + PROBE_TIMEOUT), HostPermit::Probe);
+    }
+}
@@ -0,0 +1,105 @@
+use super::*;
+use roaring::RoaringTreemap;
+
+/// Rows per query while prefetching
+const PAGE_SIZE: i64 = 50_000;
+/// How many days of the archive are fetched at once
+const PARALLEL_QUERIES: usize = 8;
+
+/// Reddit IDs as integers; a compressed bitmap is far smaller than a BTreeSet of a month
+#[derive(Default)]
+pub struct IdSet(RoaringTreemap);
+
+impl IdSet {
+    pub fn insert(&mut self, id: i64) -> bool {
+        self.0.insert(id as u64)
+    }
+
+    pub fn contains(&self, id: i64) -> bool {
+        self.0.contains(id as u64)
+    }
+
+    /// Returns whether it was there
+    pub fn remove(&mut self, id: i64) -> bool {
+        self.0.remove(id as u64)
+    }
+
+    pub fn len(&self) -> u64 {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Runs `sql` once per day in `from..to` at a time, each paging through its IDs in order
+    /// by the last one it saw; `sql` takes the day as $1 and $2, the last ID as $3 and the
+    /// page size as $4
+    pub async fn fetch(
+        sql: &'static str,
+        from: NaiveDateTime,
+        to: NaiveDateTime,
+    ) -> Result<Self, UserError> {
+        let days = std::iter::successors(Some(from), |day| {
+            Some(*day + chrono::Duration::days(1)).filter(|day| *day < to)
+        })
+        .map(|day| (day, (day + chrono::Duration::days(1)).min(to)));
+
+        stream::iter(days)
+            .map(|(start, end)| fetch_range(sql, start, end))
+            .buffer_unordered(PARALLEL_QUERIES)
+            .try_fold(Self::default(), |mut ids, day_ids| async move {
+                ids.0 |= day_ids;
+                Ok(ids)
+            })
+            .await
+    }
+}
+
+async fn fetch_range(
+    sql: &'static str,
+    start: NaiveDateTime,
+    end: NaiveDateTime,
+) -> Result<RoaringTreemap, UserError> {
+    let client = PG_POOL.get().await?;
+    let statement = client.prepare(sql).await?;
+
+    let mut ids = RoaringTreemap::new();
+    let mut last = -1_i64;
+    loop {
+        let rows = client
+            .query(&statement, &[&start, &end, &last, &PAGE_SIZE])
+            .await?;
+
+        for row in &rows {
+            let id: i64 = row.get(0);
+            ids.insert(id as u64);
+            last = id;
+        }
+
+        if (rows.len() as i64) < PAGE_SIZE {
+            return Ok(ids);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn contains_remove() {
+        let mut ids = IdSet::default();
+        assert!(ids.is_empty());
+
+        ids.insert(5);
+        ids.insert(36_i64.pow(7));
+        assert_eq!(ids.len(), 2);
+        assert!(ids.contains(36_i64.pow(7)));
+        assert!(!ids.contains(6));
+
+        assert!(ids.remove(5));
+        assert!(!ids.remove(5));
+        assert_eq!(ids.len(), 1);
+    }
+}
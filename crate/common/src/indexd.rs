@@ -0,0 +1,86 @@
+//! A thin client for indexd, the daemon that answers similarity queries from a hash trie
+
+use super::*;
+
+#[derive(Serialize, Deserialize)]
+pub struct Insert {
+    pub hash: u64,
+    pub id: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Inserted {
+    /// Whether the hash was already in the trie
+    pub existed: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Query {
+    pub hash: u64,
+    pub distance: u8,
+    pub limit: usize,
+}
+
+/// Ids are from the images table
+#[derive(Serialize, Deserialize)]
+pub struct Match {
+    pub hash: u64,
+    pub distance: u8,
+    pub ids: Vec<i64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Similar {
+    /// How long since indexd last caught up with the images table
+    pub stale_secs: u64,
+    /// Nearest first
+    pub matches: Vec<Match>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Snapshotted {
+    pub hashes: usize,
+    pub last_id: i64,
+}
+
+fn url(path: &str) -> String {
+    format!("http://{}/{}", CONFIG.load().indexd.addr, path)
+}
+
+pub async fn insert(hash: Hash, id: i64) -> Result<bool, UserError> {
+    let inserted: Inserted = REQW_CLIENT
+        .post(&url("insert"))
+        .json(&Insert { hash: hash.0, id })
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    Ok(inserted.existed)
+}
+
+pub async fn similar(hash: Hash, distance: u8, limit: usize) -> Result<Similar, UserError> {
+    Ok(REQW_CLIENT
+        .get(&url("similar"))
+        .query(&Query {
+            hash: hash.0,
+            distance,
+            limit,
+        })
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?)
+}
+
+pub async fn snapshot() -> Result<Snapshotted, UserError> {
+    Ok(REQW_CLIENT
+        .post(&url("snapshot"))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?)
+}
@@ -0,0 +1,236 @@
+use super::*;
+use once_cell::sync::OnceCell;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// How often recorded events are written to Postgres
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+/// Events past this many waiting are dropped, so a stalled database can't eat memory
+const MAX_PENDING: usize = 100_000;
+/// How often events older than `retention_days` are deleted
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// What happened to a post at one step of ingesting it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IngestDecision {
+    /// Not an image post, or turned away by ingest_rules; the detail says which
+    Undesirable,
+    /// Turned away by the binary's subreddit lists
+    SubredditFiltered,
+    /// Skipped while fast forwarding through an archive, as it was already saved
+    AlreadyHad,
+    /// Its link matched a ban; the detail says which
+    Banned,
+    Hashed,
+    /// Its save error says why
+    HashFailed,
+    Inserted,
+    Updated,
+    /// Already saved, and this copy was no newer
+    Unchanged,
+    /// Skipped because another ingester had claimed it
+    ClaimedElsewhere,
+}
+
+impl IngestDecision {
+    pub fn as_str(self) -> &'static str {
+        use IngestDecision::*;
+        match self {
+            Undesirable => "undesirable",
+            SubredditFiltered => "subreddit_filtered",
+            AlreadyHad => "already_had",
+            Banned => "banned",
+            Hashed => "hashed",
+            HashFailed => "hash_failed",
+            Inserted => "inserted",
+            Updated => "updated",
+            Unchanged => "unchanged",
+            ClaimedElsewhere => "claimed_elsewhere",
+        }
+    }
+
+    /// Whether it's one of the decisions made about every post in an archive, which are only
+    /// recorded with `record_rejected`
+    fn is_rejection(self) -> bool {
+        matches!(
+            self,
+            IngestDecision::Undesirable
+                | IngestDecision::SubredditFiltered
+                | IngestDecision::AlreadyHad
+        )
+    }
+}
+
+/// A row of ingest_events
+#[derive(Debug)]
+pub struct IngestEvent {
+    pub reddit_id_int: i64,
+    pub source: String,
+    pub decision: String,
+    pub save_error: Option<String>,
+    pub detail: Option<String>,
+    pub at: NaiveDateTime,
+}
+
+struct Pending {
+    events: Vec<IngestEvent>,
+    pruned: Option<Instant>,
+    dropped: u64,
+}
+
+static PENDING: Lazy<Mutex<Pending>> = Lazy::new(|| {
+    Mutex::new(Pending {
+        events: Vec::new(),
+        pruned: None,
+        dropped: 0,
+    })
+});
+
+static SOURCE: OnceCell<&'static str> = OnceCell::new();
+
+/// Starts recording events under `binary`'s name, flushing them every `FLUSH_INTERVAL`;
+/// events recorded before this are dropped
+pub fn start_ingest_events(binary: &'static str) {
+    if SOURCE.set(binary).is_err() {
+        return;
+    }
+
+    tokio::spawn(async {
+        let mut flushes = tokio::time::interval(FLUSH_INTERVAL);
+        loop {
+            flushes.tick().await;
+            if let Err(e) = flush_ingest_events().await {
+                warn!("Couldn't save ingest events: {}", e);
+            }
+        }
+    });
+}
+
+/// Notes what happened to post `reddit_id_int`, to be written with the next flush
+pub fn record_event(
+    reddit_id_int: i64,
+    decision: IngestDecision,
+    save_error: Option<SaveError>,
+    detail: Option<&str>,
+) {
+    let source = match SOURCE.get() {
+        Some(source) => source,
+        None => return,
+    };
+
+    {
+        let config = CONFIG.load();
+        if !config.ingest_events.enabled
+            || (decision.is_rejection() && !config.ingest_events.record_rejected)
+        {
+            return;
+        }
+    }
+
+    let mut pending = PENDING.lock().unwrap();
+    if pending.events.len() >= MAX_PENDING {
+        pending.dropped += 1;
+        return;
+    }
+
+    pending.events.push(IngestEvent {
+        reddit_id_int,
+        source: source.to_string(),
+        decision: decision.as_str().to_string(),
+        save_error: save_error.map(|save_error| save_error.to_string()),
+        detail: detail.map(str::to_string),
+        at: chrono::Utc::now().naive_utc(),
+    });
+}
+
+/// Writes waiting events to ingest_events, and deletes ones past `retention_days` every so often
+pub async fn flush_ingest_events() -> Result<(), UserError> {
+    let (events, dropped, prune) = {
+        let mut pending = PENDING.lock().unwrap();
+
+        let prune = pending
+            .pruned
+            .map(|pruned| pruned.elapsed() >= PRUNE_INTERVAL)
+            .unwrap_or(true);
+        if prune {
+            pending.pruned = Some(Instant::now());
+        }
+
+        (
+            std::mem::take(&mut pending.events),
+            std::mem::take(&mut pending.dropped),
+            prune,
+        )
+    };
+
+    if dropped > 0 {
+        warn!(
+            "Dropped {} ingest events while Postgres was behind",
+            dropped
+        );
+    }
+
+    let client = PG_POOL.get().await?;
+
+    if !events.is_empty() {
+        let column = |f: fn(&IngestEvent) -> Option<String>| -> Vec<Option<String>> {
+            events.iter().map(f).collect()
+        };
+
+        client
+            .execute(
+                "INSERT INTO ingest_events \
+                 (reddit_id_int, source, decision, save_error, detail, at) \
+                 SELECT * FROM unnest($1::bigint[], $2::varchar[], $3::varchar[], \
+                 $4::varchar[], $5::varchar[], $6::timestamp[])",
+                &[
+                    &events.iter().map(|e| e.reddit_id_int).collect::<Vec<_>>(),
+                    &column(|e| Some(e.source.clone())),
+                    &column(|e| Some(e.decision.clone())),
+                    &column(|e| e.save_error.clone()),
+                    &column(|e| e.detail.clone()),
+                    &events.iter().map(|e| e.at).collect::<Vec<_>>(),
+                ],
+            )
+            .await?;
+    }
+
+    if prune {
+        let retention_days = CONFIG.load().ingest_events.retention_days;
+        let pruned = client
+            .execute(
+                "DELETE FROM ingest_events \
+                 WHERE at < now() AT TIME ZONE 'utc' - make_interval(days => $1)",
+                &[&retention_days],
+            )
+            .await?;
+        if pruned > 0 {
+            info!("Pruned {} ingest events", pruned);
+        }
+    }
+
+    Ok(())
+}
+
+/// Every event recorded for a post, oldest first
+pub async fn ingest_events(reddit_id_int: i64) -> Result<Vec<IngestEvent>, UserError> {
+    Ok(PG_POOL
+        .get()
+        .await?
+        .query(
+            "SELECT reddit_id_int, source, decision, save_error, detail, at FROM ingest_events \
+             WHERE reddit_id_int = $1 ORDER BY at, id",
+            &[&reddit_id_int],
+        )
+        .await?
+        .into_iter()
+        .map(|row| IngestEvent {
+            reddit_id_int: row.get("reddit_id_int"),
+            source: row.get("source"),
+            decision: row.get("decision"),
+            save_error: row.get("save_error"),
+            detail: row.get("detail"),
+            at: row.get("at"),
+        })
+        .collect())
+}
@@ -1,7 +1,8 @@
+pub use anyhow::{self, format_err, Error};
+use arc_swap::ArcSwap;
 use cache_control::CacheControl;
 use chrono::{DateTime, NaiveDateTime};
 use deadpool_postgres::{Pool, Runtime};
-pub use failure::{self, format_err, Error};
 use futures::prelude::*;
 use log::LevelFilter;
 use once_cell::sync::Lazy;
@@ -13,49 +14,189 @@ use std::fmt;
 use std::string::ToString;
 use std::time::Duration;
 
+mod bandwidth;
+pub use bandwidth::*;
+
 mod banned;
 pub use banned::*;
 
+mod blob_store;
+pub use blob_store::*;
+
+mod comment;
+pub use comment::*;
+
+pub mod concurrency;
+
+pub mod db;
+
+pub mod events;
+
+mod first_post;
+pub use first_post::*;
+
+mod fetch_queue;
+pub use fetch_queue::*;
+
 mod getter;
 pub use getter::*;
 
 mod hash;
 pub use hash::*;
 
+mod health;
+pub use health::*;
+
+mod host_health;
+pub use host_health::*;
+
+mod id_set;
+pub use id_set::*;
+
+mod ingest_events;
+pub use ingest_events::*;
+
+pub mod indexd;
+
+mod link_check;
+pub use link_check::*;
+
+mod partitions;
+pub use partitions::*;
+
+mod post_claims;
+pub use post_claims::*;
+
+mod post_images;
+pub use post_images::*;
+
+mod progress;
+pub use progress::*;
+
+mod query_log;
+pub use query_log::*;
+
+mod reddit_id;
+pub use reddit_id::*;
+
+mod reddit_pace;
+pub use reddit_pace::*;
+
+pub mod rules;
+
+mod save_error;
+pub use save_error::*;
+
+mod subreddit_lists;
+pub use subreddit_lists::*;
+
 mod submission;
 pub use submission::*;
 
+mod takedown;
+pub use takedown::*;
+
 pub use tracing::{debug, error, info, info_span, warn};
 
 pub const USER_AGENT: &str = concat!("Tidder ", env!("CARGO_PKG_VERSION"));
 
-pub static EXT_RE: Lazy<Regex> =
-    Lazy::new(|| Regex::new(r"(?i)\W(?:png|jpe?g|gif|webp|p[bgpn]m|tiff?|bmp|ico|hdr)\b").unwrap());
-pub static URL_RE: Lazy<Regex> =
-    Lazy::new(|| Regex::new(r"^(?i)https?://(?:[a-z0-9.-]+|\[[0-9a-f:]+\])(?:$|[:/?#])").unwrap());
-pub static PG_POOL: Lazy<Pool> = Lazy::new(|| {
-    SECRETS
-        .postgres
-        .create_pool(Some(Runtime::Tokio1), tokio_postgres::NoTls)
+pub static EXT_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)\W(?:png|jpe?g|gif|webp|p[bgpn]m|tiff?|bmp|ico|hdr|avif|hei[cf]|jxl)\b")
         .unwrap()
 });
+pub static URL_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^(?i)https?://(?:[a-z0-9.-]+|\[[0-9a-f:]+\])(?:$|[:/?#])").unwrap());
+pub static PG_POOL: Lazy<Pool> = Lazy::new(|| db::create_pool(&SECRETS.postgres).unwrap());
 pub static COMMON_HEADERS: Lazy<HeaderMap<HeaderValue>> = Lazy::new(|| {
     let mut headers = HeaderMap::new();
     headers.insert(header::USER_AGENT, HeaderValue::from_static(USER_AGENT));
     headers
 });
+
+/// The most redirects one request follows
+pub const MAX_REDIRECTS: usize = 10;
+
+/// Why a redirect to `url` after `hops` others shouldn't be followed, if it shouldn't
+pub fn redirect_refusal(url: &url::Url, hops: usize) -> Option<&'static str> {
+    // Names can't be resolved here, but at least don't follow redirects to private IPs
+    let private = match url.host() {
+        Some(url::Host::Ipv4(ip)) => !is_global_ip(ip.into()),
+        Some(url::Host::Ipv6(ip)) => !is_global_ip(ip.into()),
+        _ => false,
+    };
+
+    if private && CONFIG.load().guard_private_ips {
+        Some("redirected to a non-public address")
+    } else if hops >= MAX_REDIRECTS {
+        Some("too many redirects")
+    } else {
+        None
+    }
+}
+
 pub static REQW_CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
     reqwest::Client::builder()
         .timeout(Duration::from_secs(30))
+        .redirect(reqwest::redirect::Policy::custom(
+            |attempt| match redirect_refusal(attempt.url(), attempt.previous().len()) {
+                Some(refusal) => attempt.error(refusal),
+                None => attempt.follow(),
+            },
+        ))
+        .default_headers(COMMON_HEADERS.clone())
+        .build()
+        .unwrap()
+});
+
+/// Like `REQW_CLIENT`, but hands redirects back so `get_hash` can record where they went
+pub static NO_REDIRECT_CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(30))
+        .redirect(reqwest::redirect::Policy::none())
+        .default_headers(COMMON_HEADERS.clone())
+        .build()
+        .unwrap()
+});
+
+/// Resolves names like the system does, but fails on any name with a non-public address, so
+/// the addresses connected to are the ones that were checked; a host can't pass
+/// `check_public_host` and then resolve somewhere else for the connection
+struct PublicResolver;
+
+impl reqwest::dns::Resolve for PublicResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        Box::pin(async move {
+            // The connector fills in the port
+            let addrs: Vec<std::net::SocketAddr> =
+                tokio::net::lookup_host((name.as_str(), 0)).await?.collect();
+
+            if addrs.iter().any(|addr| !is_global_ip(addr.ip())) {
+                return Err(format!("{} resolves to a non-public address", name.as_str()).into());
+            }
+
+            Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+/// Like `NO_REDIRECT_CLIENT`, but only connects to public addresses; IP literals skip
+/// resolving, so they're checked by `redirect_refusal` and `check_public_host` instead
+pub static GUARDED_CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(30))
+        .redirect(reqwest::redirect::Policy::none())
+        .dns_resolver(std::sync::Arc::new(PublicResolver))
         .default_headers(COMMON_HEADERS.clone())
         .build()
         .unwrap()
 });
 
 pub mod user_error {
-    use failure::Error;
+    use super::SaveError;
+    use anyhow::Error;
     use reqwest::StatusCode;
     use serde::Serialize;
+    use std::backtrace::BacktraceStatus;
     use std::borrow::Cow;
     use std::fmt::{self, Display, Formatter};
 
@@ -77,7 +218,7 @@ pub mod user_error {
         #[serde(skip)]
         pub line: Option<u32>,
         #[serde(skip)]
-        pub save_error: Option<Cow<'static, str>>,
+        pub save_error: Option<SaveError>,
     }
 
     impl UserError {
@@ -110,7 +251,7 @@ pub mod user_error {
         }
         pub fn new_msg<M: Into<Cow<'static, str>> + Sync + Send>(user_msg: M) -> Self {
             let user_msg = user_msg.into();
-            let error = failure::err_msg(user_msg.clone());
+            let error = Error::msg(user_msg.clone());
             Self {
                 source: Source::External,
                 user_msg,
@@ -125,7 +266,7 @@ pub mod user_error {
             source: Source,
         ) -> Self {
             let user_msg = user_msg.into();
-            let error = failure::err_msg(user_msg.clone());
+            let error = Error::msg(user_msg.clone());
             Self {
                 source,
                 user_msg,
@@ -153,6 +294,53 @@ pub mod user_error {
                 Source::User => StatusCode::BAD_REQUEST,
             }
         }
+
+        /// Says what was being done when the error happened; it goes in front of the chain
+        pub fn context<C: Display + Send + Sync + 'static>(self, context: C) -> Self {
+            Self {
+                error: self.error.context(context),
+                ..self
+            }
+        }
+
+        /// The error's message and those of the errors that caused it, outermost first
+        pub fn chain(&self) -> Vec<String> {
+            self.error.chain().map(ToString::to_string).collect()
+        }
+
+        /// Where the error was made, if RUST_BACKTRACE or RUST_LIB_BACKTRACE asked for
+        /// backtraces
+        pub fn backtrace(&self) -> Option<String> {
+            let backtrace = self.error.backtrace();
+            if backtrace.status() == BacktraceStatus::Captured {
+                Some(backtrace.to_string())
+            } else {
+                None
+            }
+        }
+    }
+
+    /// `UserError::context` for results
+    pub trait UeContext<T> {
+        fn context<C: Display + Send + Sync + 'static>(self, context: C) -> Result<T, UserError>;
+
+        fn with_context<C: Display + Send + Sync + 'static, F: FnOnce() -> C>(
+            self,
+            context: F,
+        ) -> Result<T, UserError>;
+    }
+
+    impl<T> UeContext<T> for Result<T, UserError> {
+        fn context<C: Display + Send + Sync + 'static>(self, context: C) -> Result<T, UserError> {
+            self.map_err(|ue| ue.context(context))
+        }
+
+        fn with_context<C: Display + Send + Sync + 'static, F: FnOnce() -> C>(
+            self,
+            context: F,
+        ) -> Result<T, UserError> {
+            self.map_err(|ue| ue.context(context()))
+        }
     }
 
     impl<E> From<E> for UserError
@@ -164,9 +352,10 @@ pub mod user_error {
         }
     }
 
+    /// The whole chain, separated by colons
     impl Display for UserError {
         fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-            Display::fmt(&self.error, f)
+            write!(f, "{:#}", self.error)
         }
     }
 
@@ -230,14 +419,14 @@ pub mod user_error {
             |e| UserError {
                 file: Some(file!()),
                 line: Some(line!()),
-                ..UserError::new($msg, failure::Error::from(e))
+                ..UserError::new($msg, anyhow::Error::from(e))
             }
         };
         ($msg:expr, $source:expr) => {
             |e| UserError {
                 file: Some(file!()),
                 line: Some(line!()),
-                ..UserError::new_source($msg, $source, failure::Error::from(e))
+                ..UserError::new_source($msg, $source, anyhow::Error::from(e))
             }
         };
     }
@@ -257,7 +446,7 @@ pub mod user_error {
                 file: Some(file!()),
                 line: Some(line!()),
                 save_error: Some($save_error.into()),
-                ..UserError::new($msg, failure::Error::from(e))
+                ..UserError::new($msg, anyhow::Error::from(e))
             }
         };
         ($msg:expr, $save_error:expr, $source:expr) => {
@@ -265,7 +454,7 @@ pub mod user_error {
                 file: Some(file!()),
                 line: Some(line!()),
                 save_error: Some($save_error.into()),
-                ..UserError::new_source($msg, $source, failure::Error::from(e))
+                ..UserError::new_source($msg, $source, anyhow::Error::from(e))
             }
         };
     }
@@ -273,55 +462,8 @@ pub mod user_error {
 
 pub use user_error::*;
 
-pub struct Base36 {
-    x: i64,
-}
-
-impl Base36 {
-    pub fn new(x: i64) -> Self {
-        Self { x }
-    }
-}
-
-impl fmt::Display for Base36 {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let mut x = self.x;
-        // Good for binary formatting of `u128`s
-        let mut result = ['\0'; 20];
-        let mut used = 0;
-        let negative = x < 0;
-        if negative {
-            x *= -1;
-        }
-        let mut x = x as u32;
-        loop {
-            let m = x % 36;
-            x /= 36;
-
-            result[used] = std::char::from_digit(m, 36).unwrap();
-            used += 1;
-
-            if x == 0 {
-                break;
-            }
-        }
-
-        if negative {
-            write!(f, "-")?;
-        }
-
-        for c in result[..used].iter().rev() {
-            write!(f, "{}", c)?;
-        }
-
-        Ok(())
-    }
-}
-
-pub const DEFAULT_DISTANCE: i64 = 1;
-
 // We need image/* because i.reddituploads.com sends it sometimes
-pub const IMAGE_MIMES: [&str; 13] = [
+pub const IMAGE_MIMES: [&str; 17] = [
     "image/*",
     "image/png",
     "image/jpeg",
@@ -335,26 +477,59 @@ pub const IMAGE_MIMES: [&str; 13] = [
     "image/bmp",
     "image/vnd.microsoft.icon",
     "image/vnd.radiance",
+    "image/avif",
+    "image/heic",
+    "image/heif",
+    "image/jxl",
 ];
 
-pub const IMAGE_MIMES_NO_WEBP: [&str; 12] = [
-    "image/*",
-    "image/png",
-    "image/jpeg",
-    "image/jpg",
-    "image/gif",
-    "image/x-portable-anymap",
-    "image/tiff",
-    "image/x-targa",
-    "image/x-tga",
-    "image/bmp",
-    "image/vnd.microsoft.icon",
-    "image/vnd.radiance",
-];
+pub const SVG_MIME: &str = "image/svg+xml";
+
+pub fn svg_enabled() -> bool {
+    cfg!(feature = "svg") && CONFIG.load().enable_svg
+}
+
+pub fn is_image_mime(mime: &str) -> bool {
+    IMAGE_MIMES.contains(&mime) || (mime == SVG_MIME && svg_enabled())
+}
+
+/// A post found by a search, as the site and op show it
+#[derive(Clone, Debug, Serialize)]
+pub struct Match {
+    pub author: Option<String>,
+    pub created_utc: chrono::NaiveDateTime,
+    pub distance: i64,
+    /// Its image has the same hash as the one searched for, rather than a similar one
+    pub exact: bool,
+    pub image_id: i64,
+    pub link: String,
+    pub preview: String,
+    pub permalink: String,
+    pub post_id: i64,
+    pub score: i64,
+    pub subreddit: String,
+    pub title: String,
+    /// Old enough and with a link the link checker found gone, so it may not load
+    pub possibly_dead: bool,
+    /// Crossposts of it that the same search found, shown under it rather than on their own
+    pub crossposts: Vec<Crosspost>,
+}
+
+/// A crosspost collapsed under the post it crossposted
+#[derive(Clone, Debug, Serialize)]
+pub struct Crosspost {
+    pub post_id: i64,
+    pub permalink: String,
+    pub subreddit: String,
+    pub created_utc: chrono::NaiveDateTime,
+}
 
 #[derive(Deserialize, Serialize)]
 pub struct CommonImages {
     pub as_of: chrono::DateTime<chrono::Utc>,
+    /// What was left out when they were ranked; absent from files written before filtering
+    #[serde(default)]
+    pub filters: Option<config::RankFilters>,
     pub common_images: Vec<CommonImage>,
 }
 
@@ -379,15 +554,18 @@ impl HashDest {
     }
 }
 
-async fn get_existing(link: &str) -> Result<Option<(Hash, HashDest, i64)>, UserError> {
+async fn get_existing(
+    link: &str,
+) -> Result<Option<(Hash, Option<Hash128>, HashDest, i64)>, UserError> {
     let client = PG_POOL.get().await?;
 
     let stmt = client
         .prepare(
-            "SELECT hash, id, 'images' as table_name \
-             FROM images WHERE link = $1 \
+            "SELECT hash, hash128_hi, hash128_lo, images.id, 'images' as table_name \
+             FROM image_links INNER JOIN images ON images.id = image_links.image_id \
+             WHERE image_links.link = $1 \
              UNION \
-             SELECT hash, id, 'image_cache' as table_name \
+             SELECT hash, hash128_hi, hash128_lo, id, 'image_cache' as table_name \
              FROM image_cache WHERE link = $1",
         )
         .await?;
@@ -396,7 +574,10 @@ async fn get_existing(link: &str) -> Result<Option<(Hash, HashDest, i64)>, UserE
 
     Ok(rows.first().map(|row| {
         (
-            Hash(row.get::<_, i64>("hash") as u64),
+            row.get::<_, Hash>("hash"),
+            row.get::<_, Option<i64>>("hash128_hi")
+                .zip(row.get::<_, Option<i64>>("hash128_lo"))
+                .map(|(hi, lo)| Hash128::from_halves(hi, lo)),
             match row.get("table_name") {
                 "images" => HashDest::Images,
                 "image_cache" => HashDest::ImageCache,
@@ -466,12 +647,19 @@ pub struct IngestState {
     pub year: i32,
     pub posts_per_minute: u64,
     pub limited: bool,
+    #[serde(default)]
+    pub bandwidth_paused: bool,
 }
 
 pub mod secrets {
-    use failure::Error;
+    use anyhow::{Context, Error};
+    use once_cell::sync::OnceCell;
     use serde::Deserialize;
     use std::io::Read;
+    use std::path::PathBuf;
+    use toml::value::{Table, Value};
+
+    static PATH: OnceCell<PathBuf> = OnceCell::new();
 
     #[derive(Debug, Deserialize)]
     pub struct Imgur {
@@ -487,56 +675,767 @@ pub mod secrets {
         pub password: String,
     }
     #[derive(Debug, Deserialize)]
+    pub struct S3 {
+        pub access_key: String,
+        pub secret_key: String,
+    }
+    #[derive(Debug, Default, Deserialize)]
+    pub struct Site {
+        pub admin_token: Option<String>,
+        /// Keeps a hash of each searcher's IP, salted with this, in the query log
+        pub query_log_salt: Option<String>,
+    }
+    #[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+    #[serde(rename_all = "lowercase")]
+    pub enum TlsVerify {
+        /// The server's certificate must chain to a trusted root and name the host
+        Full,
+        /// Anything is accepted, which only keeps the connection private from eavesdroppers
+        None,
+    }
+    impl Default for TlsVerify {
+        fn default() -> Self {
+            TlsVerify::Full
+        }
+    }
+    #[derive(Debug, Deserialize)]
+    pub struct PostgresTls {
+        /// PEM certificates to trust, like a managed provider's CA, instead of the web's roots
+        #[serde(default)]
+        pub root_cert_path: Option<String>,
+        #[serde(default)]
+        pub verify: TlsVerify,
+    }
+    #[derive(Debug, Deserialize)]
     pub struct Secrets {
         pub imgur: Imgur,
         pub postgres: deadpool_postgres::Config,
+        /// A read replica for the site's searches, which fall back to `postgres` without it
+        #[serde(default)]
+        pub postgres_replica: Option<deadpool_postgres::Config>,
+        /// Connects to `postgres` and `postgres_replica` over TLS; without it they're unencrypted
+        #[serde(default)]
+        pub postgres_tls: Option<PostgresTls>,
         pub reddit: Reddit,
+        #[serde(default)]
+        pub s3: Option<S3>,
+        #[serde(default)]
+        pub site: Site,
     }
 
-    pub fn load() -> Result<Secrets, Error> {
+    /// Variables like TIDDER_SECRET__REDDIT__CLIENT_ID set `client_id` in `[reddit]`
+    const VAR_PREFIX: &str = "TIDDER_SECRET__";
+    /// Added to a variable's name to read its value from the file it names, like Docker secrets
+    const FILE_SUFFIX: &str = "_FILE";
+
+    /// Takes precedence over TIDDER_SECRETS; does nothing once SECRETS has been loaded
+    pub fn set_path<P: Into<PathBuf>>(path: P) {
+        let _ = PATH.set(path.into());
+    }
+
+    fn explicit_path() -> Option<PathBuf> {
+        PATH.get()
+            .cloned()
+            .or_else(|| std::env::var_os("TIDDER_SECRETS").map(PathBuf::from))
+    }
+
+    fn default_path() -> PathBuf {
+        concat!(env!("CARGO_MANIFEST_DIR"), "/../../secrets/secrets.toml").into()
+    }
+
+    pub fn path() -> PathBuf {
+        explicit_path().unwrap_or_else(default_path)
+    }
+
+    /// The value of `name`, or of the file named by `name` with `_FILE` added, without the
+    /// newline files tend to end with
+    fn var(name: &str) -> Result<Option<String>, Error> {
+        if let Ok(value) = std::env::var(name) {
+            return Ok(Some(value));
+        }
+
+        match std::env::var_os(format!("{}{}", name, FILE_SUFFIX)) {
+            Some(path) => {
+                let value = std::fs::read_to_string(&path)
+                    .with_context(|| format!("reading {} from {:?}", name, path))?;
+                Ok(Some(value.trim_end_matches(&['\r', '\n'][..]).to_string()))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// The explicit path's file, which must exist, else the default file if there is one, else
+    /// nothing, for when every secret comes from the environment
+    fn file_table() -> Result<Table, Error> {
+        let path = match explicit_path() {
+            Some(path) => path,
+            None if default_path().exists() => default_path(),
+            None => return Ok(Table::new()),
+        };
+
         let mut s = String::new();
-        std::fs::File::open(concat!(
-            env!("CARGO_MANIFEST_DIR"),
-            "/../../secrets/secrets.toml"
-        ))?
-        .read_to_string(&mut s)?;
-        toml::from_str::<Secrets>(&s).map_err(Error::from)
+        std::fs::File::open(&path)
+            .with_context(|| format!("opening secrets file {:?}", path))?
+            .read_to_string(&mut s)?;
+
+        Ok(toml::from_str(&s)?)
+    }
+
+    /// A variable's value as the TOML integer, float, boolean or quoted string it reads as,
+    /// or else as a string, so numeric secrets like ports deserialize; a secret that only
+    /// looks like a number, such as a password, can be quoted to keep it a string
+    fn scalar(value: String) -> Value {
+        match toml::from_str::<Table>(&format!("value = {}", value))
+            .ok()
+            .and_then(|mut table| table.remove("value"))
+        {
+            Some(parsed) if !parsed.is_table() && !parsed.is_array() && !parsed.is_datetime() => {
+                parsed
+            }
+            _ => Value::String(value),
+        }
+    }
+
+    /// Sets the secrets named by `TIDDER_SECRET__` variables in `table`, parsed by `scalar`
+    fn overlay(table: &mut Table, vars: impl IntoIterator<Item = (String, String)>) {
+        for (name, value) in vars {
+            let path = match name.strip_prefix(VAR_PREFIX) {
+                Some(path) if !path.is_empty() => path.to_lowercase(),
+                _ => continue,
+            };
+
+            let mut keys: Vec<&str> = path.split("__").collect();
+            let last = keys.pop().unwrap();
+
+            let mut current = &mut *table;
+            for key in keys {
+                let entry = current
+                    .entry(key.to_string())
+                    .or_insert_with(|| Value::Table(Table::new()));
+                if !entry.is_table() {
+                    *entry = Value::Table(Table::new());
+                }
+                current = entry.as_table_mut().unwrap();
+            }
+            current.insert(last.to_string(), scalar(value));
+        }
+    }
+
+    /// The `TIDDER_SECRET__` variables, with those ending in `_FILE` read from their files
+    fn secret_vars() -> Result<Vec<(String, String)>, Error> {
+        let mut vars = Vec::new();
+
+        for (name, _value) in std::env::vars() {
+            if !name.starts_with(VAR_PREFIX) {
+                continue;
+            }
+            let name = name.strip_suffix(FILE_SUFFIX).unwrap_or(&name).to_string();
+            if vars.iter().any(|(seen, _)| *seen == name) {
+                continue;
+            }
+            if let Some(value) = var(&name)? {
+                vars.push((name, value));
+            }
+        }
+
+        Ok(vars)
+    }
+
+    /// Reads secrets.toml, if there is one, then overrides it with the environment
+    pub fn load() -> Result<Secrets, Error> {
+        let mut table = file_table()?;
+        overlay(&mut table, secret_vars()?);
+        let mut secrets = Value::Table(table).try_into::<Secrets>()?;
+
+        // Replaces the file's connection settings entirely, but keeps its pool settings
+        if let Some(url) = var("DATABASE_URL")? {
+            secrets.postgres = deadpool_postgres::Config {
+                url: Some(url),
+                manager: secrets.postgres.manager.take(),
+                pool: secrets.postgres.pool.take(),
+                ..Default::default()
+            };
+        }
+        if let Some(url) = var("DATABASE_REPLICA_URL")? {
+            let replica = secrets.postgres_replica.take().unwrap_or_default();
+            secrets.postgres_replica = Some(deadpool_postgres::Config {
+                url: Some(url),
+                manager: replica.manager,
+                pool: replica.pool,
+                ..Default::default()
+            });
+        }
+
+        Ok(secrets)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn overlays_vars() {
+            let mut table: Table = toml::from_str(
+                "[reddit]\nclient_id = \"from file\"\nusername = \"kept\"\n[site]\n",
+            )
+            .unwrap();
+
+            overlay(
+                &mut table,
+                vec![
+                    (
+                        "TIDDER_SECRET__REDDIT__CLIENT_ID".to_string(),
+                        "from env".to_string(),
+                    ),
+                    (
+                        "TIDDER_SECRET__SITE__ADMIN_TOKEN".to_string(),
+                        "token".to_string(),
+                    ),
+                    (
+                        "TIDDER_SECRET__S3__ACCESS_KEY".to_string(),
+                        "key".to_string(),
+                    ),
+                    ("TIDDER_SECRET__".to_string(), "ignored".to_string()),
+                    ("OTHER".to_string(), "ignored".to_string()),
+                ],
+            );
+
+            assert_eq!(table["reddit"]["client_id"].as_str(), Some("from env"));
+            assert_eq!(table["reddit"]["username"].as_str(), Some("kept"));
+            assert_eq!(table["site"]["admin_token"].as_str(), Some("token"));
+            assert_eq!(table["s3"]["access_key"].as_str(), Some("key"));
+            assert_eq!(table.len(), 3);
+        }
+
+        #[test]
+        fn overlays_scalars() {
+            let mut table = Table::new();
+
+            overlay(
+                &mut table,
+                vec![
+                    (
+                        "TIDDER_SECRET__POSTGRES__PORT".to_string(),
+                        "5432".to_string(),
+                    ),
+                    (
+                        "TIDDER_SECRET__POSTGRES__PASSWORD".to_string(),
+                        "\"12345\"".to_string(),
+                    ),
+                    (
+                        "TIDDER_SECRET__POSTGRES__USER".to_string(),
+                        "tidder user".to_string(),
+                    ),
+                ],
+            );
+
+            assert_eq!(table["postgres"]["port"].as_integer(), Some(5432));
+            assert_eq!(table["postgres"]["password"].as_str(), Some("12345"));
+            assert_eq!(table["postgres"]["user"].as_str(), Some("tidder user"));
+
+            let postgres: deadpool_postgres::Config = table["postgres"].clone().try_into().unwrap();
+            assert_eq!(postgres.port, Some(5432));
+        }
     }
 }
 
 pub mod config {
-    use failure::Error;
-    use serde::Deserialize;
+    use anyhow::{format_err, Error};
+    use once_cell::sync::OnceCell;
+    use serde::{Deserialize, Serialize};
+    use std::path::PathBuf;
 
+    static PATH: OnceCell<PathBuf> = OnceCell::new();
+
+    /// A daily window in local time when fewer workers run, e.g. to stay under a bandwidth cap
     #[derive(Deserialize)]
     pub struct TimeLimits {
         pub start: chrono::NaiveTime,
+        /// May be before `start` for a window that wraps past midnight
         pub end: chrono::NaiveTime,
+        /// Used instead of `worker_count` within the window
         pub count: usize,
     }
 
+    #[derive(Deserialize)]
+    pub struct RateLimit {
+        pub burst: u32,
+        pub per_minute: u32,
+        pub keyed_burst: u32,
+        pub keyed_per_minute: u32,
+        pub behind_proxy: bool,
+    }
+
+    #[derive(Clone, Deserialize)]
+    pub enum BlobBackend {
+        /// Blobs go under `root`, sharded by hash prefix
+        Filesystem { root: String },
+        /// Needs common's s3 feature and S3 secrets
+        S3 {
+            bucket: String,
+            region: String,
+            #[serde(default)]
+            endpoint: Option<String>,
+        },
+    }
+
+    #[derive(Clone, Deserialize)]
+    pub struct BlobStore {
+        pub backend: BlobBackend,
+        /// New images stop being stored once this many bytes are
+        pub quota_bytes: Option<i64>,
+    }
+
+    fn localhost() -> std::net::IpAddr {
+        std::net::Ipv4Addr::LOCALHOST.into()
+    }
+
+    #[derive(Deserialize)]
+    pub struct Health {
+        /// The address health listeners bind to; only this machine can reach them by default
+        #[serde(default = "localhost")]
+        pub addr: std::net::IpAddr,
+        /// Health listener ports, keyed by the binary that serves them
+        pub ports: std::collections::HashMap<String, u16>,
+        /// Instances that haven't finished a post for longer than this report unhealthy
+        pub max_idle_secs: i64,
+    }
+
+    /// Keeps crafted images from using up memory while they're decoded
+    #[derive(Deserialize)]
+    pub struct DecodeLimits {
+        /// Images whose headers claim more are refused before decoding
+        pub max_pixels: u64,
+        /// The most a decoder may allocate for one image
+        pub max_alloc_bytes: u64,
+    }
+
+    /// Keeps the site's recent findings so the same search isn't run again right away
+    #[derive(Deserialize)]
+    pub struct FindingsCache {
+        /// How many searches are kept, the least recently used going first; 0 turns it off
+        pub capacity: usize,
+        pub ttl_secs: u64,
+    }
+
+    /// The threads images are decoded and hashed on; read at startup
+    #[derive(Deserialize)]
+    pub struct HashPool {
+        /// 0 for one per core
+        pub threads: usize,
+        /// How many images may be hashing or waiting to at once, across the binary
+        pub queue: usize,
+    }
+
+    /// When ingesters stop requesting from a failing host, and for how long
+    #[derive(Deserialize)]
+    pub struct HostBreaker {
+        /// Failures in a row that open a host's circuit
+        pub failures: u32,
+        /// How long a circuit first stays open; it doubles each time a probe fails
+        pub cooldown_secs: u64,
+        pub max_cooldown_secs: u64,
+        /// Links wait this long at most for a host's circuit before failing with host_failing
+        pub max_delay_secs: u64,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Indexd {
+        pub enabled: bool,
+        pub addr: std::net::SocketAddr,
+        pub trie_path: String,
+        pub snapshot_path: String,
+        pub snapshot_interval_secs: u64,
+        /// Searches go to Postgres when indexd hasn't caught up for longer than this
+        pub max_staleness_secs: u64,
+    }
+
+    /// How the fetcher orders links queued by `ingest --defer-hashing`; read as they're queued
+    #[derive(Deserialize)]
+    pub struct FetchPriority {
+        /// Added per natural log of a post's score plus one
+        pub score_weight: f64,
+        /// Added per day a post was made after Reddit launched
+        pub recency_weight: f64,
+        /// Added for posts in these subreddits, keyed in lower case
+        pub subreddit_weights: std::collections::HashMap<String, f64>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct IngestBatch {
+        pub size: usize,
+        /// Posts per merge with ingest --copy
+        pub copy_size: usize,
+        pub max_delay_ms: u64,
+    }
+
+    /// What ingesters record in ingest_events, for `op why`
+    #[derive(Deserialize)]
+    pub struct IngestEvents {
+        pub enabled: bool,
+        /// Events older than this are deleted
+        pub retention_days: i32,
+        /// Also records posts turned away or skipped, which is most of every archive
+        pub record_rejected: bool,
+    }
+
+    /// Searches for several images in one request
+    #[derive(Deserialize)]
+    pub struct MultiSearch {
+        /// The most links or files one search may have
+        pub max_images: usize,
+        /// How many of them are hashed and searched for at once
+        pub concurrency: usize,
+    }
+
+    /// How a site client authenticated, which sets how far and how much it may search
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize)]
+    pub enum Tier {
+        Anonymous,
+        /// Sent a valid API key
+        Keyed,
+        /// Has an admin session or token
+        Admin,
+    }
+
+    #[derive(Clone, Copy, Debug, Deserialize)]
+    pub struct SearchLimits {
+        pub max_distance: u8,
+        pub max_results: i64,
+    }
+
+    /// Lets site visitors watch for new posts of an image over a WebSocket
+    #[derive(Deserialize)]
+    pub struct Live {
+        /// Whether the site serves /live, which needs `new_image_events`
+        pub enabled: bool,
+        /// The most sockets the site keeps open at once
+        pub max_clients: usize,
+    }
+
+    /// Serves previews through the site when browsers couldn't load them from their hosts
+    #[derive(Deserialize)]
+    pub struct PreviewProxy {
+        pub enabled: bool,
+        /// Hosts that refuse requests from other sites, matched by suffix; plain http
+        /// previews are always proxied
+        pub hotlink_blocked: Vec<String>,
+        /// Larger previews are cut off
+        pub max_bytes: u64,
+        /// How long browsers and caches may keep a proxied preview
+        pub cache_secs: u64,
+    }
+
+    /// What the site records of each search in query_log, for `op query_report`; IPs are
+    /// only kept, hashed, when `query_log_salt` is in the site's secrets
+    #[derive(Deserialize)]
+    pub struct QueryLog {
+        pub enabled: bool,
+        /// Searches older than this are deleted
+        pub retention_days: i32,
+    }
+
+    /// Lists every image page in /sitemap.xml for search engines
+    #[derive(Deserialize)]
+    pub struct Sitemap {
+        pub enabled: bool,
+        /// How many images each file of the sitemap covers, by ID; search engines take at
+        /// most 50,000 URLs per file
+        pub images_per_file: i64,
+    }
+
+    /// Chooses between indexd and the database for each search, from hash_popularity and
+    /// subreddit_stats; without it, indexd is asked whenever it's enabled
+    #[derive(Deserialize)]
+    pub struct SearchPlanner {
+        pub enabled: bool,
+        /// Closer searches go to the database, whose index is quick for them
+        pub index_min_distance: u8,
+        /// Filtered searches expected to keep less than this share of posts go to the
+        /// database, since indexd's capped results might not include any that pass
+        pub min_selectivity: f64,
+    }
+
+    /// Fetches ipfs://, ipns:// and ar:// links, and links to known gateways, through the
+    /// gateways here, trying the next when one fails
+    #[derive(Deserialize)]
+    pub struct Gateways {
+        pub enabled: bool,
+        /// Base URLs, like `https://ipfs.io`; ipfs:// links are fetched from the first
+        pub ipfs: Vec<String>,
+        /// Base URLs, like `https://arweave.net`; ar:// links are fetched from the first
+        pub arweave: Vec<String>,
+    }
+
+    /// Samples images and checks whether their links still answer, so old results whose
+    /// links are gone can be marked
+    #[derive(Deserialize)]
+    pub struct LinkCheck {
+        pub enabled: bool,
+        /// The percentage of images each round samples from, for TABLESAMPLE BERNOULLI
+        pub sample_percent: f32,
+        /// The most links checked each round
+        pub batch_size: i64,
+        pub interval_secs: u64,
+        pub requests_per_second: f64,
+        /// Links are checked again once their last check is this old
+        pub recheck_days: i32,
+        /// Results this old whose links were found gone are shown as possibly dead
+        pub dead_after_years: i32,
+    }
+
+    /// What op rank leaves out of the rankings, so placeholders and bot-posted templates
+    /// don't crowd out images people actually share; saved with the rankings for the site
+    /// to show
+    #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+    pub struct RankFilters {
+        /// 64-bit hashes, in decimal as searches show them, that are never ranked
+        pub hash_denylist: Vec<u64>,
+        /// The fewest different subreddits an image has to be posted in
+        pub min_subreddits: u32,
+        /// The fewest different authors an image has to be posted by; deleted ones don't count
+        pub min_authors: u32,
+    }
+
     #[derive(Deserialize)]
     pub struct Config {
-        pub banned: Vec<super::Banned>,
+        /// Whether /stats/author pages are served at all; authors can also opt out singly
+        pub author_stats: bool,
+        /// Keeps the original bytes of ingested images when set
+        pub blob_store: Option<BlobStore>,
         pub custom_limits: std::collections::HashMap<String, Option<u32>>,
+        /// Bytes all ingesters together may download per UTC day before pausing
+        pub daily_bandwidth_cap: Option<i64>,
+        pub decode_limits: DecodeLimits,
+        /// The distance searches use when none is given; must be within every tier's
+        /// `max_distance`
+        pub default_distance: u8,
         pub enable_imgur_api: bool,
+        pub enable_svg: bool,
+        pub fetch_priority: FetchPriority,
+        pub findings_cache: FindingsCache,
+        pub gateways: Gateways,
+        pub guard_private_ips: bool,
+        pub hash128: bool,
+        pub hash_pool: HashPool,
+        pub health: Health,
+        pub host_breaker: HostBreaker,
+        /// How many images of an Imgur album are hashed for a post that links to it; past
+        /// the first, they're kept in post_images. Needs `enable_imgur_api`.
+        pub imgur_album_images: usize,
+        pub indexd: Indexd,
+        pub ingest_batch: IngestBatch,
+        pub ingest_events: IngestEvents,
+        /// Checked in order by `Submission::desirable`, so every ingester obeys them
+        pub ingest_rules: Vec<super::rules::Rule>,
         pub domains_in_flight_limit: u32,
+        pub link_check: LinkCheck,
+        pub live: Live,
         pub max_distance: u8,
         pub max_results: i64,
-        pub no_blacklist: Vec<String>,
+        pub multi_search: MultiSearch,
+        pub negative_resolution_ttl_days: i32,
+        /// Whether images and posts saved are announced on `events::NEW_IMAGE_CHANNEL`
+        pub new_image_events: bool,
+        pub preview_proxy: PreviewProxy,
+        pub query_log: QueryLog,
+        /// Where the site is served from, for links that have to be absolute
+        pub public_url: String,
+        pub rank_filters: RankFilters,
+        pub rate_limit: RateLimit,
+        pub resolution_ttl_days: i32,
+        pub search_host_allow: Vec<String>,
+        pub search_host_deny: Vec<String>,
+        pub search_planner: SearchPlanner,
+        pub sitemap: Sitemap,
         pub worker_count: usize,
         pub state_file: String,
+        /// Paths of allowlist and denylist files, keyed by the binary that uses them
+        pub subreddit_lists: std::collections::HashMap<String, String>,
+        /// Caps for searches by tier; a tier that isn't here gets `max_distance` and
+        /// `max_results`
+        pub tier_limits: std::collections::HashMap<Tier, SearchLimits>,
         pub time_limits: TimeLimits,
     }
 
+    impl Config {
+        /// Catches values that would stall or break the binaries before they're swapped in
+        pub fn validate(&self) -> Result<(), Error> {
+            if self.worker_count == 0 {
+                return Err(format_err!("worker_count must be above 0"));
+            }
+            if self.domains_in_flight_limit == 0 {
+                return Err(format_err!("domains_in_flight_limit must be above 0"));
+            }
+            if self.max_results <= 0 {
+                return Err(format_err!("max_results must be above 0"));
+            }
+            if self
+                .tier_limits
+                .values()
+                .any(|limits| limits.max_results <= 0)
+            {
+                return Err(format_err!("tier_limits' max_results must be above 0"));
+            }
+            if self.default_distance > self.max_distance
+                || self
+                    .tier_limits
+                    .values()
+                    .any(|limits| self.default_distance > limits.max_distance)
+            {
+                return Err(format_err!(
+                    "default_distance must be within max_distance and tier_limits' max_distance"
+                ));
+            }
+            if self.time_limits.count == 0 {
+                return Err(format_err!("time_limits.count must be above 0"));
+            }
+            if self.ingest_batch.size == 0 || self.ingest_batch.size > super::db::MAX_BATCH_SIZE {
+                return Err(format_err!(
+                    "ingest_batch.size must be from 1 to {}",
+                    super::db::MAX_BATCH_SIZE
+                ));
+            }
+            if self.ingest_batch.copy_size == 0 {
+                return Err(format_err!("ingest_batch.copy_size must be above 0"));
+            }
+            if self.decode_limits.max_pixels == 0 || self.decode_limits.max_alloc_bytes == 0 {
+                return Err(format_err!(
+                    "decode_limits.max_pixels and decode_limits.max_alloc_bytes must be above 0"
+                ));
+            }
+            if self.hash_pool.queue == 0 {
+                return Err(format_err!("hash_pool.queue must be above 0"));
+            }
+            if self.host_breaker.failures == 0 {
+                return Err(format_err!("host_breaker.failures must be above 0"));
+            }
+            if self.multi_search.max_images == 0 || self.multi_search.concurrency == 0 {
+                return Err(format_err!(
+                    "multi_search.max_images and multi_search.concurrency must be above 0"
+                ));
+            }
+            if self.ingest_events.retention_days <= 0 {
+                return Err(format_err!("ingest_events.retention_days must be above 0"));
+            }
+            if self.gateways.enabled
+                && (self.gateways.ipfs.is_empty() || self.gateways.arweave.is_empty())
+            {
+                return Err(format_err!(
+                    "gateways.ipfs and gateways.arweave need a gateway each when enabled"
+                ));
+            }
+            if self
+                .gateways
+                .ipfs
+                .iter()
+                .chain(&self.gateways.arweave)
+                .any(|base| url::Url::parse(base).is_err())
+            {
+                return Err(format_err!("gateways must be absolute URLs"));
+            }
+            if self.imgur_album_images == 0 {
+                return Err(format_err!("imgur_album_images must be above 0"));
+            }
+            if !(0.0..=100.0).contains(&self.link_check.sample_percent)
+                || self.link_check.batch_size <= 0
+                || self.link_check.requests_per_second <= 0.0
+            {
+                return Err(format_err!(
+                    "link_check.sample_percent must be from 0 to 100, and link_check.batch_size \
+                     and link_check.requests_per_second above 0"
+                ));
+            }
+            if self.query_log.retention_days <= 0 {
+                return Err(format_err!("query_log.retention_days must be above 0"));
+            }
+            if !(0.0..=1.0).contains(&self.search_planner.min_selectivity) {
+                return Err(format_err!(
+                    "search_planner.min_selectivity must be from 0 to 1"
+                ));
+            }
+            if self.sitemap.images_per_file <= 0 || self.sitemap.images_per_file > 50_000 {
+                return Err(format_err!(
+                    "sitemap.images_per_file must be from 1 to 50000"
+                ));
+            }
+
+            Ok(())
+        }
+
+        pub fn search_limits(&self, tier: Tier) -> SearchLimits {
+            self.tier_limits
+                .get(&tier)
+                .copied()
+                .unwrap_or(SearchLimits {
+                    max_distance: self.max_distance,
+                    max_results: self.max_results,
+                })
+        }
+    }
+
+    /// Takes precedence over TIDDER_CONFIG; does nothing once CONFIG has been loaded
+    pub fn set_path<P: Into<PathBuf>>(path: P) {
+        let _ = PATH.set(path.into());
+    }
+
+    pub fn path() -> PathBuf {
+        PATH.get()
+            .cloned()
+            .or_else(|| std::env::var_os("TIDDER_CONFIG").map(PathBuf::from))
+            .unwrap_or_else(|| concat!(env!("CARGO_MANIFEST_DIR"), "/../tidder.ron").into())
+    }
+
     pub fn load() -> Result<Config, Error> {
-        ron::de::from_reader(std::fs::File::open(concat!(
-            env!("CARGO_MANIFEST_DIR"),
-            "/../tidder.ron"
-        ))?)
-        .map_err(Error::from)
+        let config: Config = ron::de::from_reader(std::fs::File::open(path())?)?;
+        config.validate()?;
+
+        Ok(config)
     }
 }
 
 pub static SECRETS: Lazy<secrets::Secrets> = Lazy::new(|| secrets::load().unwrap());
-pub static CONFIG: Lazy<config::Config> = Lazy::new(|| config::load().unwrap());
+/// Swapped out whole on reload, so `CONFIG.load()` always sees one consistent config
+pub static CONFIG: Lazy<ArcSwap<config::Config>> =
+    Lazy::new(|| ArcSwap::from_pointee(config::load().unwrap()));
+
+/// Sets the config and secrets paths from `--config PATH` and `--secrets PATH`,
+/// returning the rest of the arguments for the binary to parse
+pub fn take_path_args() -> Vec<String> {
+    let mut rest = Vec::new();
+    let mut args = std::env::args();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => config::set_path(args.next().unwrap_or_default()),
+            "--secrets" => secrets::set_path(args.next().unwrap_or_default()),
+            _ => rest.push(arg),
+        }
+    }
+
+    rest
+}
+
+/// Rereads the config file; on any error the running config is kept
+pub fn reload_config() -> Result<(), Error> {
+    CONFIG.store(std::sync::Arc::new(config::load()?));
+    Ok(())
+}
+
+/// Reloads the config whenever the process gets a SIGHUP
+pub fn reload_config_on_sighup() -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = signal(SignalKind::hangup())?;
+
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            match reload_config() {
+                Ok(()) => info!("Reloaded {}", config::path().display()),
+                Err(e) => error!("Couldn't reload the config, keeping the old one: {}", e),
+            }
+        }
+    });
+
+    Ok(())
+}
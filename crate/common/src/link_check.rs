@@ -0,0 +1,121 @@
+use super::*;
+use once_cell::sync::OnceCell;
+use reqwest::StatusCode;
+
+/// True for `images` rows whose link was found gone the last time it was checked
+pub const DEAD_LINK_SQL: &str = "COALESCE(images.link_status IN (404, 410), false)";
+
+static STARTED: OnceCell<()> = OnceCell::new();
+
+/// The status `link` answers with, or `None` if its host couldn't be reached
+async fn check_link(link: &str) -> Option<i16> {
+    let head = REQW_CLIENT
+        .head(link)
+        .header(header::USER_AGENT, USER_AGENT)
+        .send()
+        .await;
+
+    // Some hosts only answer GET
+    let resp = match head {
+        Ok(resp)
+            if resp.status() == StatusCode::METHOD_NOT_ALLOWED
+                || resp.status() == StatusCode::NOT_IMPLEMENTED =>
+        {
+            REQW_CLIENT
+                .get(link)
+                .header(header::USER_AGENT, USER_AGENT)
+                .send()
+                .await
+        }
+        head => head,
+    };
+
+    match resp {
+        // Imgur redirects removed images to a placeholder instead of answering 404
+        Ok(resp)
+            if resp.url().host_str() == Some("i.imgur.com")
+                && resp.url().path() == "/removed.png" =>
+        {
+            Some(404)
+        }
+        Ok(resp) => Some(resp.status().as_u16() as i16),
+        Err(e) => {
+            debug!("Couldn't check {}: {}", link, e);
+            None
+        }
+    }
+}
+
+/// Checks a sample of the images not checked in `recheck_days`, one request at a time;
+/// returns how many were checked
+async fn check_sample() -> Result<usize, UserError> {
+    let (sample_percent, batch_size, recheck_days, delay) = {
+        let link_check = &CONFIG.load().link_check;
+        (
+            link_check.sample_percent,
+            link_check.batch_size,
+            link_check.recheck_days,
+            Duration::from_secs_f64(1.0 / link_check.requests_per_second),
+        )
+    };
+
+    let client = PG_POOL.get().await?;
+    let rows = client
+        .query(
+            "SELECT id, link FROM images TABLESAMPLE BERNOULLI ($1) \
+             WHERE link ~ '^https?://' AND (link_checked_at IS NULL \
+             OR link_checked_at < now() AT TIME ZONE 'utc' - make_interval(days => $2)) \
+             LIMIT $3",
+            &[&sample_percent, &recheck_days, &batch_size],
+        )
+        .await?;
+
+    for row in &rows {
+        tokio::time::sleep(delay).await;
+
+        let link: &str = row.get("link");
+        let status = check_link(link).await;
+
+        client
+            .execute(
+                "UPDATE images SET link_checked_at = $2, link_status = $3 WHERE id = $1",
+                &[
+                    &row.get::<_, i64>("id"),
+                    &chrono::offset::Utc::now().naive_utc(),
+                    &status,
+                ],
+            )
+            .await?;
+    }
+
+    Ok(rows.len())
+}
+
+/// Checks a sample of images' links every `interval_secs` while `link_check.enabled` is set,
+/// recording what they answered in link_checked_at and link_status
+pub fn start_link_checker() {
+    if STARTED.set(()).is_err() {
+        return;
+    }
+
+    tokio::spawn(async {
+        loop {
+            let (enabled, interval) = {
+                let link_check = &CONFIG.load().link_check;
+                (
+                    link_check.enabled,
+                    Duration::from_secs(link_check.interval_secs),
+                )
+            };
+
+            if enabled {
+                match check_sample().await {
+                    Ok(checked) => info!("Checked {} image links", checked),
+                    Err(e) => warn!("Couldn't check image links: {}", e),
+                }
+            }
+
+            tokio::time::sleep(interval).await;
+        }
+    });
+}
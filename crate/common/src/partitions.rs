@@ -0,0 +1,152 @@
+use super::*;
+use chrono::{Datelike, NaiveDate};
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+/// Months whose partition of posts this process has made sure of
+static KNOWN_MONTHS: Lazy<Mutex<HashSet<NaiveDate>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+/// The first day of the month `at` is in
+pub fn month_of(at: NaiveDateTime) -> NaiveDate {
+    NaiveDate::from_ymd(at.year(), at.month(), 1)
+}
+
+pub fn next_month(month: NaiveDate) -> NaiveDate {
+    if month.month() == 12 {
+        NaiveDate::from_ymd(month.year() + 1, 1, 1)
+    } else {
+        NaiveDate::from_ymd(month.year(), month.month() + 1, 1)
+    }
+}
+
+/// Like `posts_y2019m03`
+pub fn partition_name(month: NaiveDate) -> String {
+    format!("posts_y{:04}m{:02}", month.year(), month.month())
+}
+
+/// The month a partition named by `partition_name` holds
+pub fn partition_month(name: &str) -> Option<NaiveDate> {
+    static NAME_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"^posts_y(\d{4})m(\d{2})$").unwrap());
+
+    let caps = NAME_RE.captures(name)?;
+    NaiveDate::from_ymd_opt(caps[1].parse().ok()?, caps[2].parse().ok()?, 1)
+}
+
+/// Creates the partitions of posts that posts created at `created` go in, if they're missing.
+/// One that can't be created, usually because posts_default already has posts from its month,
+/// is warned about and left to posts_default.
+pub async fn ensure_partitions(
+    created: impl IntoIterator<Item = NaiveDateTime>,
+) -> Result<(), UserError> {
+    let missing: HashSet<NaiveDate> = {
+        let known = KNOWN_MONTHS.lock().unwrap();
+        created
+            .into_iter()
+            .map(month_of)
+            .filter(|month| !known.contains(month))
+            .collect()
+    };
+
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    let client = PG_POOL.get().await?;
+    for month in missing {
+        let name = partition_name(month);
+        let created = client
+            .batch_execute(&format!(
+                "CREATE TABLE IF NOT EXISTS public.{} PARTITION OF public.posts \
+                 FOR VALUES FROM ('{}') TO ('{}')",
+                name,
+                month,
+                next_month(month)
+            ))
+            .await;
+
+        if let Err(e) = created {
+            warn!(
+                "Couldn't create {}, so posts_default keeps its posts: {}",
+                name, e
+            );
+        }
+
+        KNOWN_MONTHS.lock().unwrap().insert(month);
+    }
+
+    Ok(())
+}
+
+/// A table of posts for one month, attached to posts or not
+#[derive(Debug)]
+pub struct Partition {
+    pub name: String,
+    pub month: NaiveDate,
+    pub attached: bool,
+    /// None for the database's default
+    pub tablespace: Option<String>,
+    /// From the planner's statistics, so it can be off
+    pub estimated_rows: i64,
+}
+
+/// Every month's partition, including ones detached from posts, oldest first
+pub async fn partitions() -> Result<Vec<Partition>, UserError> {
+    let rows = PG_POOL
+        .get()
+        .await?
+        .query(
+            "SELECT pg_class.relname, pg_tablespace.spcname, \
+             pg_class.reltuples::bigint AS estimated_rows, \
+             EXISTS (SELECT 1 FROM pg_inherits WHERE pg_inherits.inhrelid = pg_class.oid \
+             AND pg_inherits.inhparent = 'public.posts'::regclass) AS attached \
+             FROM pg_class \
+             INNER JOIN pg_namespace ON pg_namespace.oid = pg_class.relnamespace \
+             LEFT JOIN pg_tablespace ON pg_tablespace.oid = pg_class.reltablespace \
+             WHERE pg_namespace.nspname = 'public' AND pg_class.relkind = 'r' \
+             AND pg_class.relname LIKE 'posts\\_y%'",
+            &[],
+        )
+        .await?;
+
+    let mut partitions: Vec<Partition> = rows
+        .into_iter()
+        .filter_map(|row| {
+            let name: String = row.get("relname");
+            Some(Partition {
+                month: partition_month(&name)?,
+                name,
+                attached: row.get("attached"),
+                tablespace: row.get("spcname"),
+                estimated_rows: row.get("estimated_rows"),
+            })
+        })
+        .collect();
+    partitions.sort_by_key(|partition| partition.month);
+
+    Ok(partitions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names() {
+        let month = NaiveDate::from_ymd(2019, 3, 1);
+        assert_eq!(partition_name(month), "posts_y2019m03");
+        assert_eq!(partition_month("posts_y2019m03"), Some(month));
+        assert_eq!(partition_month("posts_y2019m13"), None);
+        assert_eq!(partition_month("posts_default"), None);
+    }
+
+    #[test]
+    fn months() {
+        let at = NaiveDate::from_ymd(2019, 12, 31).and_hms(23, 59, 59);
+        assert_eq!(month_of(at), NaiveDate::from_ymd(2019, 12, 1));
+        assert_eq!(next_month(month_of(at)), NaiveDate::from_ymd(2020, 1, 1));
+        assert_eq!(
+            next_month(NaiveDate::from_ymd(2019, 3, 1)),
+            NaiveDate::from_ymd(2019, 4, 1)
+        );
+    }
+}
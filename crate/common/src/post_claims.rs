@@ -0,0 +1,59 @@
+use super::*;
+
+/// Claims older than this were left by a process that died, and may be taken over
+const CLAIM_TTL: &str = "1 hour";
+
+/// This process's hold on a post, so that archive and live ingesters running at once don't
+/// both fetch its image; it can be released once the image is hashed, as the image is
+/// found by its link after that
+#[derive(Debug)]
+pub struct PostClaim {
+    reddit_id_int: i64,
+}
+
+/// Claims post `reddit_id_int`, or gives None if another ingester is already working on it
+pub async fn claim_post(reddit_id_int: i64) -> Result<Option<PostClaim>, UserError> {
+    let claimed = PG_POOL
+        .get()
+        .await?
+        .query_opt(
+            format!(
+                "INSERT INTO post_claims (reddit_id_int) VALUES ($1) \
+                 ON CONFLICT (reddit_id_int) DO UPDATE SET claimed_at = EXCLUDED.claimed_at \
+                 WHERE post_claims.claimed_at < now() AT TIME ZONE 'utc' - interval '{}' \
+                 RETURNING reddit_id_int",
+                CLAIM_TTL
+            )
+            .as_str(),
+            &[&reddit_id_int],
+        )
+        .await?;
+
+    if claimed.is_none() {
+        record_event(reddit_id_int, IngestDecision::ClaimedElsewhere, None, None);
+    }
+
+    Ok(claimed.map(|_| PostClaim { reddit_id_int }))
+}
+
+impl PostClaim {
+    /// Lets other ingesters have the post again; one that can't be released expires
+    pub async fn release(self) {
+        let released = async {
+            PG_POOL
+                .get()
+                .await?
+                .execute(
+                    "DELETE FROM post_claims WHERE reddit_id_int = $1",
+                    &[&self.reddit_id_int],
+                )
+                .await?;
+            Ok::<_, UserError>(())
+        }
+        .await;
+
+        if let Err(ue) = released {
+            warn!("Couldn't release claim on {}: {}", self.reddit_id_int, ue);
+        }
+    }
+}
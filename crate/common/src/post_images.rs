@@ -0,0 +1,34 @@
+use super::*;
+
+/// Hashes the rest of the images in the Imgur album a post links to, beyond the first that's
+/// its `image_id`, and saves them to post_images so searches match the post by any of them;
+/// an image that can't be hashed is skipped
+pub async fn save_album_images(reddit_id_int: i64, link: &str) -> Result<(), UserError> {
+    if CONFIG.load().imgur_album_images <= 1 {
+        return Ok(());
+    }
+
+    let links = imgur_album_links(link).await?;
+
+    for (position, link) in links.iter().enumerate().skip(1) {
+        let image_id = match save_hash(link, HashDest::Images).await {
+            Ok(saved) => saved.id,
+            Err(ue) => {
+                warn!("Couldn't hash album image {}: {}", link, ue);
+                continue;
+            }
+        };
+
+        PG_POOL
+            .get()
+            .await?
+            .execute(
+                "INSERT INTO post_images (reddit_id_int, image_id, position) \
+                 VALUES ($1, $2, $3) ON CONFLICT DO NOTHING",
+                &[&reddit_id_int, &image_id, &(position as i32)],
+            )
+            .await?;
+    }
+
+    Ok(())
+}
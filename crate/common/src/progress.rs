@@ -0,0 +1,145 @@
+use super::*;
+use chrono::Utc;
+
+/// How many minutes a running ingest can go without an update before it's taken to have died
+const STALE_MINUTES: i64 = 2;
+
+/// One archive's run of ingest, as kept in ingest_progress
+#[derive(Debug, Serialize)]
+pub struct IngestProgress {
+    pub archive: String,
+    /// running, done, or failed
+    pub status: String,
+    pub month: i32,
+    pub year: i32,
+    pub posts: i64,
+    pub posts_per_minute: i64,
+    pub limited: bool,
+    pub bandwidth_paused: bool,
+    pub started: NaiveDateTime,
+    pub finished: Option<NaiveDateTime>,
+    pub updated: NaiveDateTime,
+}
+
+impl From<&tokio_postgres::Row> for IngestProgress {
+    fn from(row: &tokio_postgres::Row) -> Self {
+        Self {
+            archive: row.get("archive"),
+            status: row.get("status"),
+            month: row.get("month"),
+            year: row.get("year"),
+            posts: row.get("posts"),
+            posts_per_minute: row.get("posts_per_minute"),
+            limited: row.get("limited"),
+            bandwidth_paused: row.get("bandwidth_paused"),
+            started: row.get("started"),
+            finished: row.get("finished"),
+            updated: row.get("updated"),
+        }
+    }
+}
+
+/// Marks `archive` as running, starting over any earlier run's counts
+pub async fn start_progress(archive: &str, month: u32, year: i32) -> Result<(), UserError> {
+    let now = Utc::now().naive_utc();
+
+    PG_POOL
+        .get()
+        .await?
+        .execute(
+            "INSERT INTO ingest_progress (archive, status, month, year, started, updated) \
+             VALUES ($1, 'running', $2, $3, $4, $4) \
+             ON CONFLICT (archive) DO UPDATE SET status = 'running', \
+             month = EXCLUDED.month, year = EXCLUDED.year, posts = 0, posts_per_minute = 0, \
+             limited = false, bandwidth_paused = false, \
+             started = EXCLUDED.started, finished = NULL, updated = EXCLUDED.updated",
+            &[&archive, &(month as i32), &year, &now],
+        )
+        .await?;
+
+    Ok(())
+}
+
+pub async fn update_progress(
+    archive: &str,
+    state: &IngestState,
+    posts: u64,
+) -> Result<(), UserError> {
+    PG_POOL
+        .get()
+        .await?
+        .execute(
+            "UPDATE ingest_progress SET posts = $2, posts_per_minute = $3, limited = $4, \
+             bandwidth_paused = $5, updated = $6 WHERE archive = $1",
+            &[
+                &archive,
+                &(posts as i64),
+                &(state.posts_per_minute as i64),
+                &state.limited,
+                &state.bandwidth_paused,
+                &state.as_of,
+            ],
+        )
+        .await?;
+
+    Ok(())
+}
+
+pub async fn finish_progress(archive: &str, ok: bool, posts: u64) -> Result<(), UserError> {
+    let now = Utc::now().naive_utc();
+    let status = if ok { "done" } else { "failed" };
+
+    PG_POOL
+        .get()
+        .await?
+        .execute(
+            "UPDATE ingest_progress SET status = $2, posts = $3, posts_per_minute = 0, \
+             finished = $4, updated = $4 WHERE archive = $1",
+            &[&archive, &status, &(posts as i64), &now],
+        )
+        .await?;
+
+    Ok(())
+}
+
+/// The latest runs, newest first
+pub async fn recent_progress(limit: i64) -> Result<Vec<IngestProgress>, UserError> {
+    Ok(PG_POOL
+        .get()
+        .await?
+        .query(
+            "SELECT * FROM ingest_progress ORDER BY started DESC LIMIT $1",
+            &[&limit],
+        )
+        .await?
+        .iter()
+        .map(IngestProgress::from)
+        .collect())
+}
+
+/// The running ingest that updated most recently, if it's still updating
+pub async fn current_ingest() -> Result<Option<IngestState>, UserError> {
+    let cutoff = Utc::now().naive_utc() - chrono::Duration::minutes(STALE_MINUTES);
+
+    let row = PG_POOL
+        .get()
+        .await?
+        .query_opt(
+            "SELECT * FROM ingest_progress WHERE status = 'running' AND updated >= $1 \
+             ORDER BY updated DESC LIMIT 1",
+            &[&cutoff],
+        )
+        .await?;
+
+    Ok(row.map(|row| {
+        let progress = IngestProgress::from(&row);
+        IngestState {
+            as_of: progress.updated,
+            month: progress.month as u32,
+            year: progress.year,
+            posts_per_minute: progress.posts_per_minute as u64,
+            limited: progress.limited,
+            bandwidth_paused: progress.bandwidth_paused,
+        }
+    }))
+}
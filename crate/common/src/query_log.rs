@@ -0,0 +1,206 @@
+use super::*;
+use once_cell::sync::OnceCell;
+use sha2::{Digest, Sha256};
+use std::convert::TryInto;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// How often logged searches are written to Postgres
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+/// Searches past this many waiting are dropped, so a stalled database can't eat memory
+const MAX_PENDING: usize = 100_000;
+/// How often searches older than `retention_days` are deleted
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// A row of query_log
+#[derive(Debug)]
+pub struct LoggedQuery {
+    pub at: NaiveDateTime,
+    pub hash: Hash,
+    pub distance: i64,
+    /// The search's other parameters, as JSON
+    pub params: String,
+    /// Only kept when `query_log_salt` is set in the site's secrets
+    pub ip_hash: Option<String>,
+    pub result_count: i32,
+    pub latency_ms: i32,
+    /// Whether it was answered from the findings cache
+    pub cached: bool,
+}
+
+struct Pending {
+    queries: Vec<LoggedQuery>,
+    pruned: Option<Instant>,
+    dropped: u64,
+}
+
+static PENDING: Lazy<Mutex<Pending>> = Lazy::new(|| {
+    Mutex::new(Pending {
+        queries: Vec::new(),
+        pruned: None,
+        dropped: 0,
+    })
+});
+
+static STARTED: OnceCell<()> = OnceCell::new();
+
+/// Starts logging searches, flushing them every `FLUSH_INTERVAL`; searches made before this
+/// aren't logged
+pub fn start_query_log() {
+    if STARTED.set(()).is_err() {
+        return;
+    }
+
+    tokio::spawn(async {
+        let mut flushes = tokio::time::interval(FLUSH_INTERVAL);
+        loop {
+            flushes.tick().await;
+            if let Err(e) = flush_query_log().await {
+                warn!("Couldn't save the query log: {}", e);
+            }
+        }
+    });
+}
+
+/// Hashes `ip` with `salt`, so one client's searches can be told apart without keeping
+/// where they came from
+fn ip_hash(salt: &str, ip: IpAddr) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(salt.as_bytes());
+    hasher.update(ip.to_string().as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+/// Notes a search that found `result_count` matches in `latency`, to be written with the
+/// next flush
+pub fn log_query(
+    hash: Hash,
+    distance: i64,
+    params: String,
+    ip: Option<IpAddr>,
+    result_count: usize,
+    latency: Duration,
+    cached: bool,
+) {
+    if STARTED.get().is_none() || !CONFIG.load().query_log.enabled {
+        return;
+    }
+
+    let ip_hash = SECRETS
+        .site
+        .query_log_salt
+        .as_ref()
+        .zip(ip)
+        .map(|(salt, ip)| ip_hash(salt, ip));
+
+    let mut pending = PENDING.lock().unwrap();
+    if pending.queries.len() >= MAX_PENDING {
+        pending.dropped += 1;
+        return;
+    }
+
+    pending.queries.push(LoggedQuery {
+        at: chrono::Utc::now().naive_utc(),
+        hash,
+        distance,
+        params,
+        ip_hash,
+        result_count: result_count.try_into().unwrap_or(i32::MAX),
+        latency_ms: latency.as_millis().try_into().unwrap_or(i32::MAX),
+        cached,
+    });
+}
+
+/// Writes waiting searches to query_log, and deletes ones past `retention_days` every so often
+pub async fn flush_query_log() -> Result<(), UserError> {
+    let (queries, dropped, prune) = {
+        let mut pending = PENDING.lock().unwrap();
+
+        let prune = pending
+            .pruned
+            .map(|pruned| pruned.elapsed() >= PRUNE_INTERVAL)
+            .unwrap_or(true);
+        if prune {
+            pending.pruned = Some(Instant::now());
+        }
+
+        (
+            std::mem::take(&mut pending.queries),
+            std::mem::take(&mut pending.dropped),
+            prune,
+        )
+    };
+
+    if dropped > 0 {
+        warn!(
+            "Dropped {} logged searches while Postgres was behind",
+            dropped
+        );
+    }
+
+    let client = PG_POOL.get().await?;
+
+    if !queries.is_empty() {
+        client
+            .execute(
+                "INSERT INTO query_log \
+                 (at, hash, distance, params, ip_hash, result_count, latency_ms, cached) \
+                 SELECT at, hash, distance, params::jsonb, ip_hash, result_count, latency_ms, \
+                 cached FROM unnest($1::timestamp[], $2::bigint[], $3::bigint[], \
+                 $4::varchar[], $5::varchar[], $6::integer[], $7::integer[], $8::boolean[]) \
+                 AS q(at, hash, distance, params, ip_hash, result_count, latency_ms, cached)",
+                &[
+                    &queries.iter().map(|q| q.at).collect::<Vec<_>>(),
+                    &queries.iter().map(|q| q.hash).collect::<Vec<_>>(),
+                    &queries.iter().map(|q| q.distance).collect::<Vec<_>>(),
+                    &queries
+                        .iter()
+                        .map(|q| q.params.as_str())
+                        .collect::<Vec<_>>(),
+                    &queries
+                        .iter()
+                        .map(|q| q.ip_hash.as_deref())
+                        .collect::<Vec<_>>(),
+                    &queries.iter().map(|q| q.result_count).collect::<Vec<_>>(),
+                    &queries.iter().map(|q| q.latency_ms).collect::<Vec<_>>(),
+                    &queries.iter().map(|q| q.cached).collect::<Vec<_>>(),
+                ],
+            )
+            .await?;
+    }
+
+    if prune {
+        let retention_days = CONFIG.load().query_log.retention_days;
+        let pruned = client
+            .execute(
+                "DELETE FROM query_log \
+                 WHERE at < now() AT TIME ZONE 'utc' - make_interval(days => $1)",
+                &[&retention_days],
+            )
+            .await?;
+        if pruned > 0 {
+            info!("Pruned {} logged searches", pruned);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn salted_ip_hashes() {
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+
+        assert_eq!(ip_hash("salt", ip), ip_hash("salt", ip));
+        assert_ne!(ip_hash("salt", ip), ip_hash("pepper", ip));
+        assert_ne!(
+            ip_hash("salt", ip),
+            ip_hash("salt", "203.0.113.8".parse().unwrap())
+        );
+        assert!(!ip_hash("salt", ip).contains("203.0.113.7"));
+    }
+}
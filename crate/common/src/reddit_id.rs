@@ -0,0 +1,167 @@
+use super::*;
+use bytes::BytesMut;
+use serde::de::{self, Deserializer, Unexpected, Visitor};
+use serde::Serializer;
+use std::convert::TryFrom;
+use std::str::FromStr;
+use tokio_postgres::types::{self, FromSql, ToSql};
+
+/// A post's or comment's ID, which Reddit writes in base 36 and which is stored as a bigint
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RedditId(pub i64);
+
+impl RedditId {
+    /// Parses an ID that may start with a kind prefix, like the `t3_` of a post's fullname
+    pub fn parse_fullname(s: &str) -> Result<Self, UserError> {
+        static PREFIX_RE: Lazy<Regex> = Lazy::new(|| Regex::new("^t[1-6]_").unwrap());
+
+        PREFIX_RE.replace(s, "").parse()
+    }
+}
+
+impl FromStr for RedditId {
+    type Err = UserError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ue!(format!("'{}' isn't a Reddit ID", s), Source::User);
+
+        // from_str_radix would also take a sign
+        if s.is_empty() || !s.bytes().all(|b| b.is_ascii_alphanumeric()) {
+            return Err(invalid());
+        }
+
+        i64::from_str_radix(s, 36)
+            .map(RedditId)
+            .map_err(|_| invalid())
+    }
+}
+
+impl fmt::Display for RedditId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 < 0 {
+            write!(f, "-")?;
+        }
+
+        let mut x = self.0.unsigned_abs();
+        let mut digits = Vec::new();
+        loop {
+            digits.push(std::char::from_digit((x % 36) as u32, 36).unwrap());
+            x /= 36;
+
+            if x == 0 {
+                break;
+            }
+        }
+
+        digits.iter().rev().try_for_each(|c| write!(f, "{}", c))
+    }
+}
+
+impl Serialize for RedditId {
+    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+        ser.collect_str(self)
+    }
+}
+
+/// Takes base 36, with or without a kind prefix, or the number itself
+impl<'de> Deserialize<'de> for RedditId {
+    fn deserialize<D: Deserializer<'de>>(des: D) -> Result<Self, D::Error> {
+        struct RedditIdVisitor;
+        impl<'de> Visitor<'de> for RedditIdVisitor {
+            type Value = RedditId;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                write!(formatter, "a base 36 Reddit ID, or its number")
+            }
+
+            fn visit_str<E: de::Error>(self, s: &str) -> Result<Self::Value, E> {
+                RedditId::parse_fullname(s).map_err(|_| E::invalid_value(Unexpected::Str(s), &self))
+            }
+
+            fn visit_i64<E: de::Error>(self, id: i64) -> Result<Self::Value, E> {
+                Ok(RedditId(id))
+            }
+
+            fn visit_u64<E: de::Error>(self, id: u64) -> Result<Self::Value, E> {
+                i64::try_from(id)
+                    .map(RedditId)
+                    .map_err(|_| E::invalid_value(Unexpected::Unsigned(id), &self))
+            }
+        }
+
+        des.deserialize_any(RedditIdVisitor)
+    }
+}
+
+impl ToSql for RedditId {
+    fn to_sql(
+        &self,
+        t: &types::Type,
+        w: &mut BytesMut,
+    ) -> Result<types::IsNull, Box<dyn std::error::Error + Sync + Send>> {
+        self.0.to_sql(t, w)
+    }
+
+    fn accepts(t: &types::Type) -> bool {
+        i64::accepts(t)
+    }
+
+    types::to_sql_checked!();
+}
+
+impl<'a> FromSql<'a> for RedditId {
+    fn from_sql(
+        t: &types::Type,
+        raw: &'a [u8],
+    ) -> Result<Self, Box<dyn std::error::Error + Sync + Send>> {
+        i64::from_sql(t, raw).map(RedditId)
+    }
+
+    fn accepts(t: &types::Type) -> bool {
+        <i64 as FromSql>::accepts(t)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        for &(s, id) in &[("0", 0), ("z", 35), ("10", 36), ("bx0lfl", 720_583_041)] {
+            assert_eq!(s.parse::<RedditId>().unwrap(), RedditId(id));
+            assert_eq!(RedditId(id).to_string(), s);
+        }
+
+        // Past what fits in 32 bits
+        let big = RedditId(1 << 40);
+        assert_eq!(big.to_string().parse::<RedditId>().unwrap(), big);
+    }
+
+    #[test]
+    fn invalid() {
+        for s in &["", "+abc", "-abc", "ab_c", "t3_abc", "zzzzzzzzzzzzzzzzzzzz"] {
+            assert!(s.parse::<RedditId>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn fullname() {
+        assert_eq!(
+            RedditId::parse_fullname("t3_10").unwrap(),
+            RedditId::parse_fullname("10").unwrap()
+        );
+        assert!(RedditId::parse_fullname("t9_10").is_err());
+    }
+
+    #[test]
+    fn serde() {
+        let id: RedditId = serde_json::from_str("\"t3_bx0lfl\"").unwrap();
+        assert_eq!(id, RedditId(720_583_041));
+        assert_eq!(
+            serde_json::from_str::<RedditId>("36").unwrap(),
+            RedditId(36)
+        );
+        assert_eq!(serde_json::to_string(&id).unwrap(), "\"bx0lfl\"");
+    }
+}
@@ -0,0 +1,157 @@
+//! Paces requests to Reddit's API by the rate limit headers it sends back
+
+use super::*;
+use reqwest::StatusCode;
+use std::str::FromStr;
+use std::sync::Mutex;
+use tokio::time::Instant;
+
+/// How long to wait after a 429 that didn't say when the window resets
+const DEFAULT_RESET: Duration = Duration::from_secs(60);
+
+/// What's left of Reddit's rate limit, as of its last response
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct RateBudget {
+    /// Requests left in the window; Reddit sends it with a fraction
+    pub remaining: Option<f64>,
+    pub used: Option<u64>,
+    /// Seconds until the window resets
+    pub reset_secs: Option<u64>,
+    /// How many 429s Reddit has sent since starting
+    pub throttled: u64,
+}
+
+impl RateBudget {
+    fn from_headers(headers: &HeaderMap, throttled: u64) -> RateBudget {
+        fn header<T: FromStr>(headers: &HeaderMap, name: &str) -> Option<T> {
+            headers.get(name)?.to_str().ok()?.trim().parse().ok()
+        }
+
+        RateBudget {
+            remaining: header(headers, "x-ratelimit-remaining"),
+            used: header(headers, "x-ratelimit-used"),
+            reset_secs: header(headers, "x-ratelimit-reset"),
+            throttled,
+        }
+    }
+}
+
+/// How long to wait before the next request: until the window resets after a 429 or once
+/// it's used up, and otherwise the rest of the window spread over the requests left in it
+fn pace_delay(budget: &RateBudget, too_many: bool) -> Duration {
+    let reset = budget.reset_secs.map(Duration::from_secs);
+
+    if too_many {
+        return reset.unwrap_or(DEFAULT_RESET);
+    }
+
+    match (budget.remaining, reset) {
+        (Some(remaining), Some(reset)) if remaining < 1.0 => reset,
+        (Some(remaining), Some(reset)) => reset.div_f64(remaining),
+        _ => Duration::from_secs(0),
+    }
+}
+
+struct PaceState {
+    /// None until Reddit has responded
+    budget: Option<RateBudget>,
+    next_request: Instant,
+}
+
+/// Spaces out one binary's requests to Reddit so they stay within its rate limit
+pub struct RedditPace {
+    state: Mutex<PaceState>,
+}
+
+impl RedditPace {
+    fn new() -> Self {
+        Self {
+            state: Mutex::new(PaceState {
+                budget: None,
+                next_request: Instant::now(),
+            }),
+        }
+    }
+
+    /// Waits until the next request to Reddit may be sent
+    pub async fn wait(&self) {
+        let next_request = self.state.lock().unwrap().next_request;
+        tokio::time::sleep_until(next_request).await;
+    }
+
+    /// Takes note of a response from Reddit to decide when the next request may be sent
+    pub fn update(&self, status: StatusCode, headers: &HeaderMap) {
+        let too_many = status == StatusCode::TOO_MANY_REQUESTS;
+
+        let mut state = self.state.lock().unwrap();
+        let throttled = state.budget.as_ref().map(|b| b.throttled).unwrap_or(0);
+        let budget = RateBudget::from_headers(headers, throttled + too_many as u64);
+        let delay = pace_delay(&budget, too_many);
+
+        if too_many {
+            warn!("Reddit said too many requests; waiting {:?}", delay);
+        }
+
+        state.next_request = Instant::now() + delay;
+        state.budget = Some(budget);
+    }
+
+    /// None until Reddit has responded
+    pub fn budget(&self) -> Option<RateBudget> {
+        self.state.lock().unwrap().budget.clone()
+    }
+}
+
+pub static REDDIT_PACE: Lazy<RedditPace> = Lazy::new(RedditPace::new);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn budget(remaining: Option<f64>, reset_secs: Option<u64>) -> RateBudget {
+        RateBudget {
+            remaining,
+            reset_secs,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn delays() {
+        assert_eq!(
+            pace_delay(&budget(Some(100.0), Some(200)), false),
+            Duration::from_secs(2)
+        );
+        assert_eq!(
+            pace_delay(&budget(Some(0.0), Some(30)), false),
+            Duration::from_secs(30)
+        );
+        assert_eq!(
+            pace_delay(&budget(Some(50.0), Some(30)), true),
+            Duration::from_secs(30)
+        );
+        assert_eq!(pace_delay(&budget(None, None), true), DEFAULT_RESET);
+        assert_eq!(
+            pace_delay(&budget(None, None), false),
+            Duration::from_secs(0)
+        );
+    }
+
+    #[test]
+    fn reads_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ratelimit-remaining", HeaderValue::from_static("596.0"));
+        headers.insert("x-ratelimit-used", HeaderValue::from_static("4"));
+        headers.insert("x-ratelimit-reset", HeaderValue::from_static("321"));
+
+        assert_eq!(
+            RateBudget::from_headers(&headers, 1),
+            RateBudget {
+                remaining: Some(596.0),
+                used: Some(4),
+                reset_secs: Some(321),
+                throttled: 1,
+            }
+        );
+    }
+}
@@ -0,0 +1,128 @@
+use super::*;
+use serde::{Deserialize, Deserializer};
+
+fn de_regex<'de, D>(des: D) -> Result<Regex, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let pattern = String::deserialize(des)?;
+    Regex::new(&pattern).map_err(serde::de::Error::custom)
+}
+
+#[derive(Debug, Deserialize)]
+pub enum Condition {
+    /// Case-insensitive
+    Subreddit(String),
+    /// Case-insensitive
+    Author(String),
+    /// The link's host is this or a subdomain of it
+    Domain(String),
+    Title(#[serde(deserialize_with = "de_regex")] Regex),
+    ScoreBelow(i64),
+    All(Vec<Condition>),
+    Any(Vec<Condition>),
+}
+
+impl Condition {
+    pub fn matches(&self, post: &Submission) -> bool {
+        use Condition::*;
+        match self {
+            Subreddit(subreddit) => post.subreddit.eq_ignore_ascii_case(subreddit),
+            Author(author) => post.author.eq_ignore_ascii_case(author),
+            Domain(domain) => get_host(&post.url)
+                .map(|host| {
+                    let domain = domain.to_lowercase();
+                    host == domain || host.ends_with(&format!(".{}", domain))
+                })
+                .unwrap_or(false),
+            Title(re) => re.is_match(&post.title),
+            ScoreBelow(score) => post.score < *score,
+            All(conditions) => conditions.iter().all(|c| c.matches(post)),
+            Any(conditions) => conditions.iter().any(|c| c.matches(post)),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub enum Rule {
+    Allow(Condition),
+    Deny(Condition),
+}
+
+/// The first rule whose condition matches decides; posts that match none are allowed
+pub fn allowed(rules: &[Rule], post: &Submission) -> bool {
+    rules
+        .iter()
+        .find_map(|rule| match rule {
+            Rule::Allow(condition) if condition.matches(post) => Some(true),
+            Rule::Deny(condition) if condition.matches(post) => Some(false),
+            _ => None,
+        })
+        .unwrap_or(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn post(subreddit: &str, author: &str, url: &str, title: &str, score: i64) -> Submission {
+        serde_json::from_value(serde_json::json!({
+            "id": "abc",
+            "author": author,
+            "created_utc": 0,
+            "is_self": false,
+            "over_18": false,
+            "permalink": "/r/x/comments/abc/x/",
+            "promoted": null,
+            "score": score,
+            "spoiler": null,
+            "subreddit": subreddit,
+            "title": title,
+            "thumbnail": null,
+            "thumbnail_width": null,
+            "thumbnail_height": null,
+            "url": url,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn first_match_decides() {
+        let rules: Vec<Rule> = ron::from_str(
+            r#"[
+                Allow(Subreddit("pics")),
+                Deny(Any([Domain("bad.com"), ScoreBelow(2)])),
+                Deny(Title("(?i)giveaway")),
+            ]"#,
+        )
+        .unwrap();
+
+        let p = |subreddit, url, title, score| {
+            allowed(&rules, &post(subreddit, "a", url, title, score))
+        };
+
+        assert!(p("PICS", "https://bad.com/a.jpg", "x", 0));
+        assert!(!p("funny", "https://i.bad.com/a.jpg", "x", 10));
+        assert!(p("funny", "https://notbad.com/a.jpg", "x", 10));
+        assert!(!p("funny", "https://good.com/a.jpg", "x", 1));
+        assert!(!p("funny", "https://good.com/a.jpg", "Big GIVEAWAY", 10));
+        assert!(p("funny", "https://good.com/a.jpg", "x", 10));
+    }
+
+    #[test]
+    fn author() {
+        let rules = vec![Rule::Deny(Condition::All(vec![
+            Condition::Author("Spammer".to_string()),
+            Condition::Subreddit("funny".to_string()),
+        ]))];
+
+        assert!(!allowed(
+            &rules,
+            &post("funny", "spammer", "https://a.com/a.jpg", "x", 1)
+        ));
+        assert!(allowed(
+            &rules,
+            &post("pics", "spammer", "https://a.com/a.jpg", "x", 1)
+        ));
+    }
+}
@@ -0,0 +1,255 @@
+use super::*;
+use bytes::BytesMut;
+use tokio_postgres::types;
+
+/// Why an image couldn't be saved, as it's stored in the save_error columns; a new variant
+/// needs adding to those columns' CHECK constraints in schema.sql too
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SaveError {
+    /// The host answered with this status
+    Http(u16),
+    Timeout,
+    /// The connection failed below HTTP
+    Hyper,
+    /// Written by ingesters before host_health's circuits replaced their blacklist
+    Blacklisted,
+    Banned,
+    TakenDown,
+    ContentTypeUnsupported,
+    DataUrlBad,
+    /// Saved by `ingest --defer-hashing`; the fetcher hashes it later from fetch_queue
+    Deferred,
+    DownloadImage,
+    GfycatJsonBad,
+    GfycatNoId,
+    GifsoundNoGif,
+    GifsoundUnsupported,
+    /// The host's circuit stayed open; see host_health
+    HostFailing,
+    HostNotPublic,
+    HostUnresolvable,
+    ImageColorSpace,
+    ImageFormatDisabled,
+    ImageHeicInvalid,
+    ImageInvalid,
+    ImageJxlInvalid,
+    /// The post's image row was deleted out from under it
+    ImageMissing,
+    ImagePanic,
+    ImageSvgInvalid,
+    /// Its header claimed more pixels than `decode_limits` allows, so it wasn't decoded
+    ImageTooLarge,
+    ImageUnsupported,
+    ImgurAlbumEmpty,
+    ImgurAlbumsDisabled,
+    ImgurJsonBad,
+    ImgurNoId,
+    ImgurRemoved,
+    UrlInvalid,
+    VideoNoPreview,
+    VReddItNoPreview,
+}
+
+/// What `op save-errors` groups save errors by
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum SaveErrorCategory {
+    /// The host couldn't be reached or wouldn't send the image
+    Network,
+    /// We chose not to fetch it
+    Refused,
+    /// The link doesn't point at an image we can find
+    Link,
+    /// A third-party API gave an unusable answer
+    Api,
+    /// We got something, but couldn't hash it
+    Image,
+}
+
+impl SaveErrorCategory {
+    pub fn as_str(self) -> &'static str {
+        use SaveErrorCategory::*;
+        match self {
+            Network => "network",
+            Refused => "refused",
+            Link => "link",
+            Api => "api",
+            Image => "image",
+        }
+    }
+}
+
+/// Every variant but `Http`, which is written with its status
+const NAMED: [(SaveError, &str); 34] = {
+    use SaveError::*;
+    [
+        (Timeout, "timeout"),
+        (Hyper, "hyper"),
+        (Blacklisted, "blacklisted"),
+        (Banned, "banned"),
+        (TakenDown, "taken_down"),
+        (ContentTypeUnsupported, "content_type_unsupported"),
+        (DataUrlBad, "data_url_bad"),
+        (Deferred, "deferred"),
+        (DownloadImage, "download_image"),
+        (GfycatJsonBad, "gfycat_json_bad"),
+        (GfycatNoId, "gfycat_no_id"),
+        (GifsoundNoGif, "gifsound_no_gif"),
+        (GifsoundUnsupported, "gifsound_unsupported"),
+        (HostFailing, "host_failing"),
+        (HostNotPublic, "host_not_public"),
+        (HostUnresolvable, "host_unresolvable"),
+        (ImageColorSpace, "image_color_space"),
+        (ImageFormatDisabled, "image_format_disabled"),
+        (ImageHeicInvalid, "image_heic_invalid"),
+        (ImageInvalid, "image_invalid"),
+        (ImageJxlInvalid, "image_jxl_invalid"),
+        (ImageMissing, "image_missing"),
+        (ImagePanic, "image_panic"),
+        (ImageSvgInvalid, "image_svg_invalid"),
+        (ImageTooLarge, "image_too_large"),
+        (ImageUnsupported, "image_unsupported"),
+        (ImgurAlbumEmpty, "imgur_album_empty"),
+        (ImgurAlbumsDisabled, "imgur_albums_disabled"),
+        (ImgurJsonBad, "imgur_json_bad"),
+        (ImgurNoId, "imgur_no_id"),
+        (ImgurRemoved, "imgur_removed"),
+        (UrlInvalid, "url_invalid"),
+        (VideoNoPreview, "video_no_preview"),
+        (VReddItNoPreview, "v_redd_it_no_preview"),
+    ]
+};
+
+impl SaveError {
+    pub fn category(self) -> SaveErrorCategory {
+        use SaveError::*;
+        use SaveErrorCategory as C;
+        match self {
+            Http(_) | Timeout | Hyper | DownloadImage | HostFailing | HostUnresolvable => {
+                C::Network
+            }
+            Blacklisted | Banned | Deferred | TakenDown | HostNotPublic | ImgurAlbumsDisabled
+            | ImageFormatDisabled | ImageTooLarge => C::Refused,
+            DataUrlBad | GfycatNoId | GifsoundNoGif | GifsoundUnsupported | ImgurNoId
+            | UrlInvalid | VideoNoPreview | VReddItNoPreview => C::Link,
+            GfycatJsonBad | ImgurAlbumEmpty | ImgurJsonBad | ImgurRemoved => C::Api,
+            ContentTypeUnsupported
+            | ImageColorSpace
+            | ImageHeicInvalid
+            | ImageInvalid
+            | ImageJxlInvalid
+            | ImageMissing
+            | ImagePanic
+            | ImageSvgInvalid
+            | ImageUnsupported => C::Image,
+        }
+    }
+
+    /// Failures that won't go away by asking again
+    pub fn is_permanent(self) -> bool {
+        use SaveError::*;
+        matches!(
+            self,
+            Http(404) | Http(410) | ImgurAlbumEmpty | ImgurNoId | GfycatNoId
+        )
+    }
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SaveError::Http(status) => write!(f, "http_{}", status),
+            other => f.write_str(
+                NAMED
+                    .iter()
+                    .find(|(save_error, _name)| save_error == other)
+                    .map(|(_save_error, name)| *name)
+                    .unwrap(),
+            ),
+        }
+    }
+}
+
+impl std::str::FromStr for SaveError {
+    type Err = UserError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(status) = s.strip_prefix("http_") {
+            return status
+                .parse()
+                .ok()
+                .filter(|status| (100..600).contains(status))
+                .map(SaveError::Http)
+                .ok_or_else(|| ue!(format!("invalid HTTP status in save error {}", s)));
+        }
+
+        NAMED
+            .iter()
+            .find(|(_save_error, name)| *name == s)
+            .map(|(save_error, _name)| *save_error)
+            .ok_or_else(|| ue!(format!("unknown save error {}", s)))
+    }
+}
+
+impl types::ToSql for SaveError {
+    fn to_sql(
+        &self,
+        t: &types::Type,
+        w: &mut BytesMut,
+    ) -> Result<types::IsNull, Box<dyn std::error::Error + Sync + Send>> {
+        types::ToSql::to_sql(&self.to_string(), t, w)
+    }
+
+    fn accepts(t: &types::Type) -> bool {
+        <String as types::ToSql>::accepts(t)
+    }
+
+    types::to_sql_checked!();
+}
+
+impl<'a> types::FromSql<'a> for SaveError {
+    fn from_sql(
+        t: &types::Type,
+        raw: &'a [u8],
+    ) -> Result<Self, Box<dyn std::error::Error + Sync + Send>> {
+        <&str as types::FromSql>::from_sql(t, raw)?
+            .parse()
+            .map_err(|ue: UserError| ue.to_string().into())
+    }
+
+    fn accepts(t: &types::Type) -> bool {
+        <&str as types::FromSql>::accepts(t)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        for (save_error, name) in NAMED.iter() {
+            assert_eq!(save_error.to_string(), *name);
+            assert_eq!(name.parse::<SaveError>().unwrap(), *save_error);
+        }
+
+        assert_eq!(SaveError::Http(404).to_string(), "http_404");
+        assert_eq!(
+            "http_503".parse::<SaveError>().unwrap(),
+            SaveError::Http(503)
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        assert!("http_abc".parse::<SaveError>().is_err());
+        assert!("http_42".parse::<SaveError>().is_err());
+        assert!("nope".parse::<SaveError>().is_err());
+    }
+
+    #[test]
+    fn permanent() {
+        assert!(SaveError::Http(404).is_permanent());
+        assert!(!SaveError::Http(503).is_permanent());
+        assert!(!SaveError::Timeout.is_permanent());
+    }
+}
@@ -1,4 +1,5 @@
 use super::*;
+use tokio_postgres::types::ToSql;
 use url::Url;
 
 #[derive(Deserialize, Debug)]
@@ -33,40 +34,127 @@ pub struct Submission {
 }
 
 impl Submission {
-    pub fn desirable(&self) -> bool {
-        !self.is_self
-            && self.promoted.map_or(true, |promoted| !promoted)
-            && !self.title.contains('\0')
-            && (self.is_video
+    /// Why the post shouldn't be ingested, if it shouldn't
+    fn rejection(&self) -> Option<&'static str> {
+        if self.is_self
+            || self.promoted.unwrap_or(false)
+            || self.title.contains('\0')
+            || !(self.is_video
                 || (EXT_RE.is_match(&self.url) && URL_RE.is_match(&self.url))
                 || is_link_special(&self.url))
+        {
+            Some("not an image post")
+        } else if !rules::allowed(&CONFIG.load().ingest_rules, self) {
+            Some("denied by ingest_rules")
+        } else {
+            None
+        }
+    }
+
+    pub fn desirable(&self) -> bool {
+        match self.rejection() {
+            Some(why) => {
+                record_event(self.id_int, IngestDecision::Undesirable, None, Some(why));
+                false
+            }
+            None => true,
+        }
+    }
+
+    /// Whether `filter` lets the post's subreddit through
+    pub fn allowed_by(&self, filter: &SubredditFilter) -> bool {
+        let allowed = filter.allows(&self.subreddit);
+        if !allowed {
+            record_event(
+                self.id_int,
+                IngestDecision::SubredditFiltered,
+                None,
+                Some(&self.subreddit),
+            );
+        }
+        allowed
     }
 
     pub fn choose_url(&self) -> Result<Url, UserError> {
         if self.is_video {
             return Url::parse(
-                &self
-                    .preview
-                    .as_ref()
-                    .ok_or_else(|| ue_save!("is_video but no preview", "video_no_preview"))?,
+                &self.preview.as_ref().ok_or_else(|| {
+                    ue_save!("is_video but no preview", SaveError::VideoNoPreview)
+                })?,
             )
-            .map_err(map_ue_save!("invalid URL", "url_invalid"));
+            .map_err(map_ue_save!("invalid URL", SaveError::UrlInvalid));
         }
 
-        let post_url = Url::parse(&self.url).map_err(map_ue_save!("invalid URL", "url_invalid"))?;
+        let post_url =
+            Url::parse(&self.url).map_err(map_ue_save!("invalid URL", SaveError::UrlInvalid))?;
 
         if let Some("v.redd.it") = post_url.host_str() {
             Url::parse(
-                self.preview
-                    .as_ref()
-                    .ok_or_else(|| ue_save!("v.redd.it but no preview", "v_redd_it_no_preview"))?,
+                self.preview.as_ref().ok_or_else(|| {
+                    ue_save!("v.redd.it but no preview", SaveError::VReddItNoPreview)
+                })?,
             )
-            .map_err(map_ue_save!("invalid URL", "url_invalid"))
+            .map_err(map_ue_save!("invalid URL", SaveError::UrlInvalid))
         } else {
             Ok(post_url)
         }
     }
 
+    /// Reddit's preview, unless `choose_url` already picked it
+    fn preview_url(&self) -> Option<Url> {
+        let preview = Url::parse(self.preview.as_ref()?).ok()?;
+        match self.choose_url() {
+            Ok(chosen) if chosen == preview => None,
+            _ => Some(preview),
+        }
+    }
+
+    /// The thumbnail, if it's an image rather than a placeholder like `self` or `default`
+    fn thumbnail_url(&self) -> Option<Url> {
+        Url::parse(self.thumbnail.as_ref()?)
+            .ok()
+            .filter(|url| url.scheme() == "http" || url.scheme() == "https")
+    }
+
+    /// Hashes the preview, or failing that the thumbnail, after the post's own image failed with
+    /// `failed`. `failed` is given back when neither hashes, unless trying them failed
+    /// internally. A hash of the thumbnail is too small to be trusted like the others, so its
+    /// image is flagged with `low_quality_hash`.
+    pub async fn save_fallback_hash(&self, failed: UserError) -> Result<HashSaved, UserError> {
+        if matches!(failed.source, Source::Internal) || failed.save_error == Some(SaveError::Banned)
+        {
+            return Err(failed);
+        }
+
+        if let Some(preview) = self.preview_url() {
+            match save_hash(preview.as_str(), HashDest::Images).await {
+                Ok(saved) => return Ok(saved),
+                Err(ue) if matches!(ue.source, Source::Internal) => return Err(ue),
+                Err(ue) => warn!("preview failed to save too: {}", ue),
+            }
+        }
+
+        if let Some(thumbnail) = self.thumbnail_url() {
+            match save_hash(thumbnail.as_str(), HashDest::Images).await {
+                Ok(saved) => {
+                    PG_POOL
+                        .get()
+                        .await?
+                        .execute(
+                            "UPDATE images SET low_quality_hash = true WHERE id = $1",
+                            &[&saved.id],
+                        )
+                        .await?;
+                    return Ok(saved);
+                }
+                Err(ue) if matches!(ue.source, Source::Internal) => return Err(ue),
+                Err(ue) => warn!("thumbnail failed to save too: {}", ue),
+            }
+        }
+
+        Err(failed)
+    }
+
     pub fn unescape(s: &str) -> String {
         s.replace("&lt;", "<")
             .replace("&gt;", ">")
@@ -78,121 +166,183 @@ impl Submission {
         self.title = Self::unescape(&self.title);
         self.preview = self.preview.map(|p| Self::unescape(&p));
 
-        self.id_int = i64::from_str_radix(&self.id, 36).map_err(|e| {
-            UserError::new_source(
-                format!("Couldn't parse number from ID '{}'", self.id),
-                Source::Internal,
-                e,
-            )
-        })?;
+        self.id_int = self
+            .id
+            .parse::<RedditId>()
+            .map_err(|ue| UserError {
+                source: Source::Internal,
+                ..ue
+            })?
+            .0;
 
         Ok(self)
     }
 
-    pub async fn save(
-        &self,
-        image_id: Result<i64, Option<Cow<'static, str>>>,
-    ) -> Result<bool, UserError> {
+    pub async fn save(&self, image_id: Result<i64, Option<SaveError>>) -> Result<Saved, UserError> {
+        let row = PostRow::new(self, image_id)?;
+        ensure_partitions(std::iter::once(self.created_utc)).await?;
+
+        let rows = PG_POOL
+            .get()
+            .await?
+            .query(
+                format!(
+                    "INSERT INTO posts ({}) VALUES {} {}",
+                    POST_COLUMNS,
+                    db::values_sql(1, POST_COLUMN_COUNT),
+                    POST_UPSERT
+                )
+                .as_str(),
+                &row.params(),
+            )
+            .await?;
+
+        let saved = match rows.first() {
+            None => Saved::Skipped,
+            Some(row) if row.get("inserted") => Saved::Inserted,
+            Some(_) => Saved::Updated,
+        };
+        record_hashed(self.id_int, &image_id);
+        record_event(self.id_int, saved.decision(), None, None);
+
+        if let (Saved::Inserted, Ok(image_id)) = (&saved, image_id) {
+            if let Err(ue) = record_first_posts(&[self.id_int]).await {
+                warn!("Couldn't record {} as a first post: {}", self.id, ue);
+            }
+            if let Err(ue) = save_album_images(self.id_int, &self.url).await {
+                warn!("Couldn't save the album images of {}: {}", self.id, ue);
+            }
+            if let Err(ue) = events::notify_new_post(image_id, self.id_int).await {
+                warn!("Couldn't announce {}: {}", self.id, ue);
+            }
+        }
+
+        Ok(saved)
+    }
+}
+
+/// The columns of posts that `PostRow::params` fills, in order
+pub(crate) const POST_COLUMNS: &str = "reddit_id, link, permalink, author, \
+     created_utc, score, subreddit, title, nsfw, \
+     spoiler, image_id, is_video, preview, reddit_id_int, \
+     thumbnail, thumbnail_width, thumbnail_height, \
+     crosspost_parent, save_error, updated";
+pub(crate) const POST_COLUMN_COUNT: usize = 20;
+
+/// Several ingesters can see the same post; only a more recently fetched copy may
+/// replace the metadata that changes over time. posts is partitioned by month, so its unique
+/// keys include created_utc, which a post never changes.
+pub(crate) const POST_UPSERT: &str = "ON CONFLICT (reddit_id_int, created_utc) DO UPDATE SET \
+     score = EXCLUDED.score, preview = EXCLUDED.preview, \
+     thumbnail = EXCLUDED.thumbnail, \
+     thumbnail_width = EXCLUDED.thumbnail_width, \
+     thumbnail_height = EXCLUDED.thumbnail_height, \
+     updated = EXCLUDED.updated \
+     WHERE EXCLUDED.updated IS NOT NULL \
+     AND (posts.updated IS NULL OR EXCLUDED.updated > posts.updated) \
+     RETURNING reddit_id_int, (xmax = 0) AS inserted";
+
+/// A post with the values it's saved with that aren't fields of `Submission`
+pub(crate) struct PostRow<'a> {
+    post: &'a Submission,
+    reddit_id: String,
+    reddit_id_int: i64,
+    spoiler: bool,
+    image_id: Option<i64>,
+    save_error: Option<SaveError>,
+}
+
+impl<'a> PostRow<'a> {
+    pub(crate) fn new(
+        post: &'a Submission,
+        image_id: Result<i64, Option<SaveError>>,
+    ) -> Result<Self, UserError> {
         static ID_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"/comments/([^/]+)/").unwrap());
 
         let reddit_id = String::from(
             ID_RE
-                .captures(&self.permalink)
+                .captures(&post.permalink)
                 .and_then(|cap| cap.get(1))
                 .ok_or_else(|| ue!("Couldn't find ID in permalink"))?
                 .as_str(),
         );
 
-        let client = PG_POOL.get().await?;
-
-        let rows = match image_id {
-            Ok(image_id) => {
-                let stmt = client
-                    .prepare(
-                        "INSERT INTO posts \
-                         (reddit_id, link, permalink, author, \
-                         created_utc, score, subreddit, title, nsfw, \
-                         spoiler, image_id, is_video, preview, reddit_id_int, \
-                         thumbnail, thumbnail_width, thumbnail_height, \
-                         crosspost_parent) \
-                         VALUES ($1, $2, $3, $4, $5, $6, $7, \
-                         $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18) \
-                         ON CONFLICT DO NOTHING RETURNING id",
-                    )
-                    .await?;
-                client
-                    .query(
-                        &stmt,
-                        &[
-                            &reddit_id,
-                            &self.url,
-                            &self.permalink,
-                            &self.author,
-                            &self.created_utc,
-                            &self.score,
-                            &self.subreddit,
-                            &self.title,
-                            &self.over_18,
-                            &self.spoiler.unwrap_or(false),
-                            &image_id,
-                            &self.is_video,
-                            &self.preview,
-                            &i64::from_str_radix(&reddit_id, 36).unwrap(),
-                            &self.thumbnail,
-                            &self.thumbnail_width,
-                            &self.thumbnail_height,
-                            &self.crosspost_parent,
-                        ],
-                    )
-                    .await?
-            }
-            Err(save_error) => {
-                let stmt = client
-                    .prepare(
-                        "INSERT INTO posts \
-                         (reddit_id, link, permalink, author, \
-                         created_utc, score, subreddit, title, nsfw, \
-                         spoiler, reddit_id_int, thumbnail, \
-                         thumbnail_width, thumbnail_height, save_error, \
-                         crosspost_parent, is_video, preview) \
-                         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, \
-                         $10, $11, $12, $13, $14, $15, $16, $17, $18) \
-                         ON CONFLICT DO NOTHING RETURNING id",
-                    )
-                    .await?;
-                client
-                    .query(
-                        &stmt,
-                        &[
-                            &reddit_id,
-                            &self.url,
-                            &self.permalink,
-                            &self.author,
-                            &self.created_utc,
-                            &self.score,
-                            &self.subreddit,
-                            &self.title,
-                            &self.over_18,
-                            &self.spoiler.unwrap_or(false),
-                            &i64::from_str_radix(&reddit_id, 36).unwrap(),
-                            &self.thumbnail,
-                            &self.thumbnail_width,
-                            &self.thumbnail_height,
-                            &save_error,
-                            &self.crosspost_parent,
-                            &self.is_video,
-                            &self.preview,
-                        ],
-                    )
-                    .await?
-            }
+        let (image_id, save_error) = match image_id {
+            Ok(image_id) => (Some(image_id), None),
+            Err(save_error) => (None, save_error),
         };
 
-        Ok(rows.is_empty())
+        Ok(Self {
+            post,
+            reddit_id_int: reddit_id.parse::<RedditId>()?.0,
+            reddit_id,
+            spoiler: post.spoiler.unwrap_or(false),
+            image_id,
+            save_error,
+        })
+    }
+
+    pub(crate) fn params(&self) -> [&(dyn ToSql + Sync); POST_COLUMN_COUNT] {
+        [
+            &self.reddit_id,
+            &self.post.url,
+            &self.post.permalink,
+            &self.post.author,
+            &self.post.created_utc,
+            &self.post.score,
+            &self.post.subreddit,
+            &self.post.title,
+            &self.post.over_18,
+            &self.spoiler,
+            &self.image_id,
+            &self.post.is_video,
+            &self.post.preview,
+            &self.reddit_id_int,
+            &self.post.thumbnail,
+            &self.post.thumbnail_width,
+            &self.post.thumbnail_height,
+            &self.post.crosspost_parent,
+            &self.save_error,
+            &self.post.updated,
+        ]
+    }
+}
+
+/// What `Submission::save` did with a post
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Saved {
+    Inserted,
+    /// It was already saved, but this copy was fetched more recently
+    Updated,
+    /// It was already saved from a copy at least as recent
+    Skipped,
+}
+
+impl Saved {
+    pub fn already_have(self) -> bool {
+        self != Saved::Inserted
+    }
+
+    pub fn decision(self) -> IngestDecision {
+        match self {
+            Saved::Inserted => IngestDecision::Inserted,
+            Saved::Updated => IngestDecision::Updated,
+            Saved::Skipped => IngestDecision::Unchanged,
+        }
     }
 }
 
-mod de_sub {
+/// Records whether hashing the post's image worked, and if not, why
+pub fn record_hashed(reddit_id_int: i64, image_id: &Result<i64, Option<SaveError>>) {
+    match image_id {
+        Ok(_) => record_event(reddit_id_int, IngestDecision::Hashed, None, None),
+        Err(save_error) => {
+            record_event(reddit_id_int, IngestDecision::HashFailed, *save_error, None)
+        }
+    }
+}
+
+pub(crate) mod de_sub {
     use super::*;
     use serde::de::{self, Deserializer, Unexpected, Visitor};
     use std::fmt::{self, Formatter};
@@ -267,13 +417,9 @@ mod de_sub {
             where
                 E: de::Error,
             {
-                static T3_RE: Lazy<Regex> =
-                    Lazy::new(|| Regex::new("^t3_([[:alnum:]]+)$").unwrap());
-
-                T3_RE
-                    .captures(name)
-                    .and_then(|cs| cs.get(1))
-                    .and_then(|id| i64::from_str_radix(id.as_str(), 36).ok())
+                name.strip_prefix("t3_")
+                    .and_then(|id| id.parse::<RedditId>().ok())
+                    .map(|id| id.0)
                     .ok_or_else(|| E::invalid_value(Unexpected::Str(name), &self))
                     .map(Some)
             }
@@ -0,0 +1,131 @@
+use super::*;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::{Instant, SystemTime};
+
+/// How often the list file's modification time is looked at
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The contents of a list file
+#[derive(Default, Deserialize)]
+struct SubredditLists {
+    /// If not empty, only these are ingested
+    #[serde(default)]
+    allow: HashSet<String>,
+    #[serde(default)]
+    deny: HashSet<String>,
+}
+
+impl SubredditLists {
+    fn load(path: &Path) -> Result<Self, Error> {
+        let lists: Self = ron::de::from_reader(std::fs::File::open(path)?)?;
+
+        Ok(Self {
+            allow: lists.allow.iter().map(|s| s.to_lowercase()).collect(),
+            deny: lists.deny.iter().map(|s| s.to_lowercase()).collect(),
+        })
+    }
+
+    fn allows(&self, subreddit: &str) -> bool {
+        let subreddit = subreddit.to_lowercase();
+        (self.allow.is_empty() || self.allow.contains(&subreddit))
+            && !self.deny.contains(&subreddit)
+    }
+}
+
+struct Loaded {
+    lists: SubredditLists,
+    modified: Option<SystemTime>,
+    checked: Instant,
+}
+
+/// A binary's subreddit allowlist and denylist, reloaded when its file changes
+pub struct SubredditFilter {
+    path: Option<PathBuf>,
+    loaded: RwLock<Loaded>,
+}
+
+impl SubredditFilter {
+    /// Uses the file named for `binary` in `subreddit_lists`, or allows everything if there's none
+    pub fn for_binary(binary: &str) -> Result<Self, Error> {
+        let path = CONFIG.load().subreddit_lists.get(binary).map(PathBuf::from);
+
+        let loaded = Loaded {
+            lists: match &path {
+                Some(path) => SubredditLists::load(path)?,
+                None => SubredditLists::default(),
+            },
+            modified: path.as_ref().and_then(|path| modified(path)),
+            checked: Instant::now(),
+        };
+
+        Ok(Self {
+            path,
+            loaded: RwLock::new(loaded),
+        })
+    }
+
+    fn reload_if_changed(&self, path: &Path) {
+        if self.loaded.read().unwrap().checked.elapsed() < RELOAD_CHECK_INTERVAL {
+            return;
+        }
+
+        let mut loaded = self.loaded.write().unwrap();
+        loaded.checked = Instant::now();
+
+        let now_modified = modified(path);
+        if now_modified == loaded.modified {
+            return;
+        }
+
+        // A broken edit keeps the old lists rather than letting everything through
+        match SubredditLists::load(path) {
+            Ok(lists) => {
+                info!("Reloaded subreddit lists from {}", path.display());
+                loaded.lists = lists;
+                loaded.modified = now_modified;
+            }
+            Err(e) => error!("Couldn't reload {}: {}", path.display(), e),
+        }
+    }
+
+    /// Case-insensitive
+    pub fn allows(&self, subreddit: &str) -> bool {
+        match &self.path {
+            None => true,
+            Some(path) => {
+                self.reload_if_changed(path);
+                self.loaded.read().unwrap().lists.allows(subreddit)
+            }
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists() {
+        let lists = SubredditLists {
+            allow: HashSet::new(),
+            deny: vec!["spam".to_string()].into_iter().collect(),
+        };
+        assert!(lists.allows("pics"));
+        assert!(!lists.allows("Spam"));
+
+        let lists = SubredditLists {
+            allow: vec!["pics".to_string()].into_iter().collect(),
+            deny: HashSet::new(),
+        };
+        assert!(lists.allows("PICS"));
+        assert!(!lists.allows("funny"));
+    }
+}
@@ -0,0 +1,233 @@
+use super::*;
+
+/// True for `images` rows under a takedown that hasn't been lifted, including one of any of
+/// their links
+pub const TAKEN_DOWN_SQL: &str = "EXISTS (SELECT 1 FROM takedowns WHERE lifted_at IS NULL \
+     AND (takedowns.hash = images.hash OR takedowns.link = images.link \
+     OR takedowns.link IN (SELECT link FROM image_links WHERE image_links.image_id = images.id)))";
+
+/// `TAKEN_DOWN_SQL` for `images` rows selected as `alias`
+pub fn taken_down_sql_as(alias: &str) -> String {
+    TAKEN_DOWN_SQL.replace("images.", &format!("{}.", alias))
+}
+
+/// What a takedown covers
+#[derive(Clone, Debug)]
+pub enum TakedownTarget {
+    /// Only images at exactly this link
+    Link(String),
+    /// Every image with this hash, wherever it was posted
+    Hash(Hash),
+}
+
+impl TakedownTarget {
+    fn parts(&self) -> (Option<Hash>, Option<&str>) {
+        match self {
+            TakedownTarget::Link(link) => (None, Some(link)),
+            TakedownTarget::Hash(hash) => (Some(*hash), None),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Takedown {
+    pub id: i64,
+    pub hash: Option<i64>,
+    pub link: Option<String>,
+    pub reason: String,
+    pub taken_down_at: NaiveDateTime,
+    pub lifted_at: Option<NaiveDateTime>,
+}
+
+/// A row of takedown_log, which records every takedown and lift
+#[derive(Debug, Serialize)]
+pub struct TakedownEvent {
+    pub takedown_id: i64,
+    pub action: String,
+    pub actor: String,
+    pub note: Option<String>,
+    pub at: NaiveDateTime,
+}
+
+pub struct TakenDown {
+    pub id: i64,
+    /// Posts whose thumbnails were cleared
+    pub posts: u64,
+    pub blobs_deleted: u64,
+}
+
+/// Takes down `target`, logging it as done by `actor`: its images leave search, their
+/// stored blobs and posts' thumbnails are deleted, and it can't be hashed again
+pub async fn take_down(
+    target: &TakedownTarget,
+    reason: &str,
+    actor: &str,
+) -> Result<TakenDown, UserError> {
+    let (hash, link) = target.parts();
+
+    let mut client = PG_POOL.get().await?;
+    let trans = client.transaction().await?;
+
+    let id: i64 = trans
+        .query_one(
+            "INSERT INTO takedowns (hash, link, reason) VALUES ($1, $2, $3) RETURNING id",
+            &[&hash, &link, &reason],
+        )
+        .await?
+        .get("id");
+
+    trans
+        .execute(
+            "INSERT INTO takedown_log (takedown_id, action, actor, note) \
+             VALUES ($1, 'take down', $2, $3)",
+            &[&id, &actor, &reason],
+        )
+        .await?;
+
+    let keys: Vec<String> = trans
+        .query(
+            "WITH cleared AS (UPDATE images SET stored_path = NULL, stored_size = NULL \
+             FROM (SELECT id, stored_path FROM images \
+             WHERE stored_path IS NOT NULL AND (hash = $1 OR link = $2 \
+             OR id IN (SELECT image_id FROM image_links WHERE link = $2))) AS taken \
+             WHERE images.id = taken.id RETURNING taken.stored_path) \
+             SELECT DISTINCT stored_path FROM cleared",
+            &[&hash, &link],
+        )
+        .await?
+        .iter()
+        .map(|row| row.get("stored_path"))
+        .collect();
+
+    let posts = trans
+        .execute(
+            "UPDATE posts SET thumbnail = NULL, thumbnail_width = NULL, \
+             thumbnail_height = NULL, preview = NULL FROM images \
+             WHERE posts.image_id = images.id AND (images.hash = $1 OR images.link = $2 \
+             OR images.id IN (SELECT image_id FROM image_links WHERE link = $2))",
+            &[&hash, &link],
+        )
+        .await?;
+
+    trans
+        .execute(
+            "DELETE FROM image_cache WHERE hash = $1 OR link = $2",
+            &[&hash, &link],
+        )
+        .await?;
+
+    trans.commit().await?;
+
+    let mut blobs_deleted = 0;
+    for key in &keys {
+        // Identical bytes at another link share the blob
+        let shared = client
+            .query_opt(
+                "SELECT 1 FROM images WHERE stored_path = $1 LIMIT 1",
+                &[key],
+            )
+            .await?
+            .is_some();
+
+        if !shared {
+            delete_blob(key)
+                .await
+                .with_context(|| format!("deleting blob {} of takedown {}", key, id))?;
+            blobs_deleted += 1;
+        }
+    }
+
+    Ok(TakenDown {
+        id,
+        posts,
+        blobs_deleted,
+    })
+}
+
+/// Lets takedown `id`'s images back into search and ingest; what was deleted stays deleted.
+/// Returns false if there was no such takedown or it was already lifted
+pub async fn lift_takedown(id: i64, actor: &str) -> Result<bool, UserError> {
+    let mut client = PG_POOL.get().await?;
+    let trans = client.transaction().await?;
+
+    let lifted = trans
+        .execute(
+            "UPDATE takedowns SET lifted_at = now() AT TIME ZONE 'utc' \
+             WHERE id = $1 AND lifted_at IS NULL",
+            &[&id],
+        )
+        .await?;
+
+    if lifted > 0 {
+        trans
+            .execute(
+                "INSERT INTO takedown_log (takedown_id, action, actor) VALUES ($1, 'lift', $2)",
+                &[&id, &actor],
+            )
+            .await?;
+    }
+
+    trans.commit().await?;
+
+    Ok(lifted > 0)
+}
+
+pub async fn takedowns() -> Result<Vec<Takedown>, UserError> {
+    Ok(PG_POOL
+        .get()
+        .await?
+        .query(
+            "SELECT id, hash, link, reason, taken_down_at, lifted_at FROM takedowns \
+             ORDER BY taken_down_at DESC",
+            &[],
+        )
+        .await?
+        .into_iter()
+        .map(|row| Takedown {
+            id: row.get("id"),
+            hash: row.get("hash"),
+            link: row.get("link"),
+            reason: row.get("reason"),
+            taken_down_at: row.get("taken_down_at"),
+            lifted_at: row.get("lifted_at"),
+        })
+        .collect())
+}
+
+/// The newest `limit` entries of the audit log
+pub async fn takedown_log(limit: i64) -> Result<Vec<TakedownEvent>, UserError> {
+    Ok(PG_POOL
+        .get()
+        .await?
+        .query(
+            "SELECT takedown_id, action, actor, note, at FROM takedown_log \
+             ORDER BY at DESC, id DESC LIMIT $1",
+            &[&limit],
+        )
+        .await?
+        .into_iter()
+        .map(|row| TakedownEvent {
+            takedown_id: row.get("takedown_id"),
+            action: row.get("action"),
+            actor: row.get("actor"),
+            note: row.get("note"),
+            at: row.get("at"),
+        })
+        .collect())
+}
+
+/// Whether a live takedown covers `link` or `hash`, or another link of `link`'s image
+pub async fn is_taken_down(link: Option<&str>, hash: Option<Hash>) -> Result<bool, UserError> {
+    Ok(PG_POOL
+        .get()
+        .await?
+        .query_opt(
+            "SELECT 1 FROM takedowns WHERE lifted_at IS NULL AND (hash = $1 OR link = $2 \
+             OR link IN (SELECT link FROM image_links WHERE image_id IN \
+             (SELECT image_id FROM image_links WHERE link = $2))) \
+             LIMIT 1",
+            &[&hash, &link],
+        )
+        .await?
+        .is_some())
+}
@@ -0,0 +1,294 @@
+use super::get_100;
+use chrono::NaiveDateTime;
+use common::*;
+use std::collections::{BTreeSet, VecDeque};
+use tokio::time::{sleep_until, Duration, Instant};
+
+const BATCH_SIZE: i64 = 100;
+const ERROR_WAIT: Duration = Duration::from_secs(5);
+/// How long missing IDs wait before they're asked for again
+const RETRY_DELAY: Duration = Duration::from_secs(5 * 60);
+/// Empty batches in a row past the cursor before it's taken to be at the newest post
+const MAX_EMPTY_AHEAD: i64 = 10;
+/// Empty batches after posts older than this are a deleted range, not the newest post
+const HEAD_AGE_MINUTES: i64 = 60;
+/// How long to wait for new posts once at the newest one
+const HEAD_WAIT: Duration = Duration::from_secs(30);
+
+/// How far direct has got, kept in direct_cursors under `name` so a restart carries on
+/// from it; IDs it skipped over are kept in direct_missing
+pub struct Cursor {
+    name: String,
+    /// The lowest ID that hasn't come back yet, apart from those waiting to be retried
+    next_id: i64,
+    /// The last ID to ask for
+    end_id: Option<i64>,
+    /// Batches after `next_id` that came back empty while looking for the next post
+    empty_ahead: i64,
+    /// When the newest post seen so far was posted
+    newest_at: Option<NaiveDateTime>,
+    /// IDs to ask for once more, and when
+    retries: VecDeque<(Instant, i64)>,
+    next_req: Option<Instant>,
+}
+
+impl Cursor {
+    /// Starts from `start_id`, or else where the last run under `name` stopped
+    pub async fn load(
+        name: String,
+        start_id: Option<i64>,
+        end_id: Option<i64>,
+    ) -> Result<Self, UserError> {
+        let client = PG_POOL.get().await?;
+
+        let saved = client
+            .query_opt(
+                "SELECT next_id, end_id FROM direct_cursors WHERE name = $1",
+                &[&name],
+            )
+            .await?
+            .map(|row| (row.get::<_, i64>("next_id"), row.get("end_id")));
+
+        let (next_id, end_id) = match (start_id, saved) {
+            (Some(start_id), _) => (start_id, end_id),
+            (None, Some((next_id, saved_end_id))) => (next_id, end_id.or(saved_end_id)),
+            (None, None) => {
+                return Err(ue!(
+                    format!("no starting ID provided, and no cursor named {}", name),
+                    Source::User
+                ))
+            }
+        };
+
+        // Ones a previous run didn't get to retry
+        let now = Instant::now();
+        let retries = client
+            .query(
+                "SELECT reddit_id_int FROM direct_missing WHERE NOT retried \
+                 ORDER BY reddit_id_int",
+                &[],
+            )
+            .await?
+            .into_iter()
+            .map(|row| (now, row.get("reddit_id_int")))
+            .collect();
+
+        let cursor = Self {
+            name,
+            next_id,
+            end_id,
+            empty_ahead: 0,
+            newest_at: None,
+            retries,
+            next_req: None,
+        };
+        cursor.save().await?;
+
+        Ok(cursor)
+    }
+
+    async fn save(&self) -> Result<(), UserError> {
+        PG_POOL
+            .get()
+            .await?
+            .execute(
+                "INSERT INTO direct_cursors (name, next_id, end_id, updated) \
+                 VALUES ($1, $2, $3, now() AT TIME ZONE 'utc') \
+                 ON CONFLICT (name) DO UPDATE SET next_id = EXCLUDED.next_id, \
+                 end_id = EXCLUDED.end_id, updated = EXCLUDED.updated",
+                &[&self.name, &self.next_id, &self.end_id],
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Records IDs that were asked for but didn't come back, to be retried later
+    async fn missed(&mut self, ids: Vec<i64>) -> Result<(), UserError> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+
+        PG_POOL
+            .get()
+            .await?
+            .execute(
+                "INSERT INTO direct_missing (reddit_id_int, missed_at) \
+                 SELECT id, now() AT TIME ZONE 'utc' FROM unnest($1::bigint[]) AS id \
+                 ON CONFLICT DO NOTHING",
+                &[&ids],
+            )
+            .await?;
+
+        let due = Instant::now() + RETRY_DELAY;
+        self.retries.extend(ids.into_iter().map(|id| (due, id)));
+
+        Ok(())
+    }
+
+    /// Asks for the retries that are due; ones still missing are given up on
+    async fn retry(&mut self) -> Result<Vec<Submission>, UserError> {
+        let now = Instant::now();
+        let mut ids = Vec::new();
+        while ids.len() < BATCH_SIZE as usize {
+            match self.retries.front() {
+                Some((due, _id)) if *due <= now => ids.push(self.retries.pop_front().unwrap().1),
+                _ => break,
+            }
+        }
+
+        let posts = match get_100(ids.iter().copied()).await {
+            Ok(got) => got,
+            Err(ue) => {
+                for id in ids.into_iter().rev() {
+                    self.retries.push_front((now, id));
+                }
+                return Err(ue);
+            }
+        };
+
+        let returned: BTreeSet<i64> = posts.iter().map(|post| post.id_int).collect();
+        let (found, gone): (Vec<i64>, Vec<i64>) =
+            ids.into_iter().partition(|id| returned.contains(id));
+
+        let client = PG_POOL.get().await?;
+        client
+            .execute(
+                "DELETE FROM direct_missing WHERE reddit_id_int = ANY($1)",
+                &[&found],
+            )
+            .await?;
+        client
+            .execute(
+                "UPDATE direct_missing SET retried = true WHERE reddit_id_int = ANY($1)",
+                &[&gone],
+            )
+            .await?;
+
+        info!(
+            "Retried {} missing posts; {} came back",
+            found.len() + gone.len(),
+            found.len()
+        );
+
+        Ok(posts)
+    }
+
+    /// Asks for the next batch of IDs after the cursor
+    async fn advance(&mut self) -> Result<Vec<Submission>, UserError> {
+        let start = self.next_id + self.empty_ahead * BATCH_SIZE;
+        let end = match self.end_id {
+            Some(end_id) => (start + BATCH_SIZE).min(end_id + 1),
+            None => start + BATCH_SIZE,
+        };
+
+        let posts = get_100(start..end).await?;
+
+        let max = match posts.iter().map(|post| post.id_int).max() {
+            Some(max) => max,
+            None => {
+                self.empty_ahead += 1;
+
+                let at_head = self.end_id.is_none()
+                    && self
+                        .newest_at
+                        .map(|at| {
+                            chrono::Utc::now().naive_utc() - at
+                                < chrono::Duration::minutes(HEAD_AGE_MINUTES)
+                        })
+                        .unwrap_or(true);
+                if at_head && self.empty_ahead >= MAX_EMPTY_AHEAD {
+                    info!(
+                        "No posts after {} ({}) yet; waiting",
+                        self.next_id,
+                        RedditId(self.next_id)
+                    );
+                    self.empty_ahead = 0;
+                    self.next_req = Some(Instant::now() + HEAD_WAIT);
+                }
+                return Ok(posts);
+            }
+        };
+
+        // Everything below the newest post should exist already; anything above it may
+        // just not have been posted yet, so it's asked for again with the next batch
+        let returned: BTreeSet<i64> = posts.iter().map(|post| post.id_int).collect();
+        let missing = (self.next_id..max)
+            .filter(|id| !returned.contains(id))
+            .collect::<Vec<_>>();
+
+        info!(
+            "Got {} posts within {} ({}) and {} ({}); {} missing",
+            posts.len(),
+            self.next_id,
+            RedditId(self.next_id),
+            end - 1,
+            RedditId(end - 1),
+            missing.len()
+        );
+
+        self.missed(missing).await?;
+        self.next_id = max + 1;
+        self.empty_ahead = 0;
+        self.newest_at = posts.iter().map(|post| post.created_utc).max();
+        self.save().await?;
+
+        Ok(posts)
+    }
+
+    /// The next posts, or None once past `end_id` with nothing left to retry
+    pub async fn next_batch(&mut self) -> Option<Vec<Submission>> {
+        loop {
+            if let Some(next_req) = self.next_req.take() {
+                sleep_until(next_req).await;
+            }
+
+            let retry_due = self
+                .retries
+                .front()
+                .map(|(due, _id)| *due <= Instant::now())
+                .unwrap_or(false);
+            let past_end = self
+                .end_id
+                .map(|end_id| self.next_id + self.empty_ahead * BATCH_SIZE > end_id)
+                .unwrap_or(false);
+
+            let res = if retry_due {
+                self.retry().await
+            } else if past_end && self.empty_ahead > 0 {
+                // Nothing came back between the cursor and the end
+                let missing = (self.next_id..=self.end_id.unwrap()).collect();
+                self.empty_ahead = 0;
+                self.next_id = self.end_id.unwrap() + 1;
+                match self.missed(missing).await {
+                    Ok(()) => self.save().await.map(|()| Vec::new()),
+                    Err(ue) => Err(ue),
+                }
+            } else if past_end {
+                match self.retries.front() {
+                    Some((due, _id)) => {
+                        self.next_req = Some(*due);
+                        continue;
+                    }
+                    None => return None,
+                }
+            } else {
+                self.advance().await
+            };
+
+            match res {
+                Ok(posts) if posts.is_empty() => continue,
+                Ok(posts) => return Some(posts),
+                Err(ue) => {
+                    error!(
+                        "Error getting posts after {} ({}): {}",
+                        self.next_id,
+                        RedditId(self.next_id),
+                        ue
+                    );
+                    self.next_req = Some(Instant::now() + ERROR_WAIT);
+                }
+            }
+        }
+    }
+}
@@ -1,33 +1,56 @@
+use clap::Parser;
+use common::concurrency::BufferLimitedExt;
 use common::*;
 
 use futures::prelude::*;
-use futures::stream::poll_fn;
-use futures::task::Poll;
+use once_cell::sync::Lazy;
 use std::borrow::Cow;
 use std::error::Error;
-use tokio::time::{delay_until, Duration, Instant};
 use tracing_futures::Instrument;
 
+mod cursor;
 mod info;
 
 const BASE_GET_URL: &str = "https://api.reddit.com/api/info/?id=";
 
-const ERROR_WAIT: Duration = Duration::from_secs(5);
+static SUBREDDITS: Lazy<SubredditFilter> =
+    Lazy::new(|| SubredditFilter::for_binary("direct").unwrap());
 
 async fn ingest_post(post: Submission) -> bool {
+    wait_for_bandwidth().await;
+
+    let _in_flight = HEALTH.start();
+
+    let claim = match claim_post(post.id_int).await {
+        Ok(Some(claim)) => claim,
+        Ok(None) => {
+            info!("another ingester has it");
+            return false;
+        }
+        Err(e) => {
+            eprintln!("failed to claim: {:?}", e);
+            std::process::exit(1);
+        }
+    };
+
     let post_url_res = post.choose_url();
 
     let save_res = match post_url_res {
         Ok(post_url) => save_hash(post_url.as_str(), HashDest::Images).await,
         Err(e) => Err(e),
     };
+    let save_res = match save_res {
+        Err(ue) => post.save_fallback_hash(ue).await,
+        res => res,
+    };
+    claim.release().await;
 
     let image_id = match save_res {
         Ok(hash_gotten) => Ok(hash_gotten.id),
         Err(ue) => match ue.source {
             Source::Internal => {
                 eprintln!(
-                    "{}{}{}\n{:#?}\n{:#?}",
+                    "{}{}{}\n{:?}\n{:#?}",
                     ue.file.unwrap_or(""),
                     ue.line
                         .map(|line| Cow::Owned(format!("#{}", line)))
@@ -49,15 +72,15 @@ async fn ingest_post(post: Submission) -> bool {
                             e.source().and_then(|he| he.downcast_ref::<hyper::Error>());
 
                         e.status()
-                            .map(|status| format!("http_{}", status.as_str()).into())
+                            .map(|status| SaveError::Http(status.as_u16()))
                             .or_else(|| {
                                 if e.is_timeout() {
-                                    Some("timeout".into())
+                                    Some(SaveError::Timeout)
                                 } else {
                                     None
                                 }
                             })
-                            .or_else(|| hyper_error.map(|_| "hyper".into()))
+                            .or_else(|| hyper_error.map(|_| SaveError::Hyper))
                     }
                     None => None,
                 };
@@ -70,7 +93,7 @@ async fn ingest_post(post: Submission) -> bool {
                         .as_ref()
                         .map(|se| Cow::Owned(format!(" ({})", se)))
                         .unwrap_or_else(|| Cow::Borrowed("")),
-                    ue.error
+                    ue
                 );
 
                 Err(save_error)
@@ -79,17 +102,18 @@ async fn ingest_post(post: Submission) -> bool {
     };
 
     let good = image_id.is_ok();
+    HEALTH.record(good);
 
     match post.save(image_id).await {
-        Ok(already_have) => {
+        Ok(saved) => {
             if good {
-                if already_have {
-                    info!("already have");
-                } else {
-                    info!("successfully saved");
+                match saved {
+                    Saved::Inserted => info!("successfully saved"),
+                    Saved::Updated => info!("already have; updated"),
+                    Saved::Skipped => info!("already have"),
                 }
             }
-            already_have
+            saved.already_have()
         }
         Err(e) => {
             eprintln!("failed to save: {:?}", e);
@@ -98,124 +122,77 @@ async fn ingest_post(post: Submission) -> bool {
     }
 }
 
-async fn get_100(
-    next_req: Option<Instant>,
-    range: impl Iterator<Item = i64>,
-) -> Result<(Option<u64>, Vec<Submission>), UserError> {
-    if let Some(next_req) = next_req {
-        delay_until(next_req).await;
-    }
-
+/// Asks Reddit for up to 100 posts by ID, once its rate limit allows
+async fn get_100(range: impl Iterator<Item = i64>) -> Result<Vec<Submission>, UserError> {
     let client = reqwest::Client::builder().user_agent(USER_AGENT).build()?;
 
     let mut url = BASE_GET_URL.to_string();
 
     for id in range {
-        url += &format!("t3_{},", Base36::new(id));
+        url += &format!("t3_{},", RedditId(id));
     }
 
+    REDDIT_PACE.wait().await;
     let res = client.get(&url).send().await?;
-
-    let wait = if res.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
-        let wait = res
-            .headers()
-            .get("x-ratelimit-reset")
-            .expect("No ratelimit reset header")
-            .to_str()
-            .expect("Non-unicode reset header value")
-            .parse()
-            .expect("Non-integer reset header value");
-        error!("Too many requests; waiting for {} seconds", wait);
-        Some(wait)
-    } else {
-        None
-    };
+    REDDIT_PACE.update(res.status(), res.headers());
 
     let info = res.error_for_status()?.json::<info::Info>().await?;
 
-    Ok((
-        wait,
-        info.data
-            .children
-            .into_iter()
-            .map(|c| c.data.finalize().unwrap())
-            .collect(),
-    ))
+    Ok(info
+        .data
+        .children
+        .into_iter()
+        .map(|c| c.data.finalize().unwrap())
+        .collect())
+}
+
+#[derive(Parser)]
+#[command(about = "Ingests new posts by asking Reddit for them in order of ID")]
+struct Cli {
+    /// Base 36 ID to start from; without one, carries on from where the last run with the
+    /// same --name stopped
+    start_id: Option<String>,
+    /// Base 36 ID to stop after
+    #[arg(long)]
+    end_id: Option<String>,
+    /// Which saved cursor to carry on from and update
+    #[arg(long, default_value = "direct")]
+    name: String,
+}
+
+fn parse_id(id: &str) -> Result<i64, UserError> {
+    Ok(RedditId::parse_fullname(id)?.0)
 }
 
 #[tokio::main]
 async fn main() -> Result<(), UserError> {
     tracing_subscriber::fmt::init();
 
-    let start_id = i64::from_str_radix(&std::env::args().nth(1).unwrap(), 36)?;
-
-    let mut getter_fut = Box::pin(tokio::spawn(get_100(None, start_id..start_id + 100)));
-    let mut this_id = start_id;
-    let get_stream = poll_fn(|ctx| match Future::poll(getter_fut.as_mut(), ctx) {
-        Poll::Pending => Poll::Pending,
-        Poll::Ready(Err(e)) => panic!("tokio error: {}", e),
-        Poll::Ready(Ok(Err(e))) => {
-            error!(
-                "Error getting posts starting at {} ({}): {}",
-                this_id,
-                Base36::new(this_id),
-                e
-            );
-            getter_fut = Box::pin(tokio::spawn(get_100(
-                Some(Instant::now() + ERROR_WAIT),
-                this_id..this_id + 100,
-            )));
-
-            ctx.waker().wake_by_ref();
-
-            Poll::Pending
-        }
-        Poll::Ready(Ok(Ok((wait, this_100)))) => {
-            if let Some(next_id) = this_100.iter().map(|p| p.id_int).max() {
-                this_id = next_id + 1;
-
-                getter_fut = Box::pin(tokio::spawn(get_100(
-                    wait.map(|wait| Instant::now() + Duration::from_secs(wait)),
-                    this_id..this_id + 100,
-                )));
-
-                info!(
-                    "Ingesting {} posts within {} ({}) and {} ({})",
-                    this_100.len(),
-                    this_id,
-                    Base36::new(this_id),
-                    this_id + 99,
-                    Base36::new(this_id + 99)
-                );
-
-                Poll::Ready(Some(futures::stream::iter(this_100)))
-            } else {
-                info!(
-                    "Got no posts within {} ({}) and {} ({})",
-                    this_id,
-                    Base36::new(this_id),
-                    this_id + 99,
-                    Base36::new(this_id + 99)
-                );
-
-                this_id += 100;
-
-                getter_fut = Box::pin(tokio::spawn(get_100(
-                    wait.map(|wait| Instant::now() + Duration::from_secs(wait)),
-                    this_id..this_id + 100,
-                )));
-
-                ctx.waker().wake_by_ref();
-
-                Poll::Pending
-            }
-        }
+    let args = Cli::parse_from(take_path_args());
+
+    Lazy::force(&SUBREDDITS);
+    reload_config_on_sighup()?;
+    serve_health("direct").await?;
+    start_ingest_events("direct");
+
+    let cursor = cursor::Cursor::load(
+        args.name,
+        args.start_id.as_deref().map(parse_id).transpose()?,
+        args.end_id.as_deref().map(parse_id).transpose()?,
+    )
+    .await?;
+
+    let get_stream = stream::unfold(cursor, |mut cursor| async move {
+        cursor
+            .next_batch()
+            .await
+            .map(|posts| (stream::iter(posts), cursor))
     });
 
     get_stream
         .flatten()
         .filter_map(|post| async move {
-            if post.desirable() {
+            if post.desirable() && post.allowed_by(&SUBREDDITS) {
                 Some(tokio::spawn(async move {
                     let span = info_span!(
                         "ingest_post",
@@ -229,7 +206,7 @@ async fn main() -> Result<(), UserError> {
                 None
             }
         })
-        .buffer_unordered(CONFIG.worker_count)
+        .buffer_limited()
         .try_collect::<()>()
         .await
         .map_err(From::from)
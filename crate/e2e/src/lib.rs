@@ -0,0 +1,138 @@
+use common::*;
+use image::{DynamicImage, ImageOutputFormat, Rgb, RgbImage};
+use once_cell::sync::{Lazy, OnceCell};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::runtime::Runtime;
+use warp::http::{header, Response, StatusCode, Uri};
+use warp::{Filter, Rejection, Reply};
+
+/// A Postgres URL with schema.sql loaded; run.sh starts one in Docker.
+/// The tests are skipped when it isn't set.
+pub const DATABASE_URL_VAR: &str = "TIDDER_TEST_DATABASE_URL";
+
+fn encode(format: ImageOutputFormat) -> Vec<u8> {
+    let image = RgbImage::from_fn(64, 64, |x, y| {
+        Rgb([(x * 4) as u8, (y * 4) as u8, ((x + y) * 2) as u8])
+    });
+    let mut bytes = std::io::Cursor::new(Vec::new());
+    DynamicImage::ImageRgb8(image)
+        .write_to(&mut bytes, format)
+        .unwrap();
+    bytes.into_inner()
+}
+
+pub static PNG: Lazy<Vec<u8>> = Lazy::new(|| encode(ImageOutputFormat::Png));
+pub static JPEG: Lazy<Vec<u8>> = Lazy::new(|| encode(ImageOutputFormat::Jpeg(90)));
+pub static GIF: Lazy<Vec<u8>> = Lazy::new(|| encode(ImageOutputFormat::Gif));
+
+fn image_response(
+    bytes: &[u8],
+    content_type: &str,
+    headers: &[(header::HeaderName, &str)],
+) -> Response<Vec<u8>> {
+    let mut response = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, content_type);
+    for (name, value) in headers {
+        response = response.header(name.clone(), *value);
+    }
+    response.body(bytes.to_vec()).unwrap()
+}
+
+/// Everything else is a 404
+fn routes() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let png = warp::path!("image.png").map(|| {
+        image_response(
+            &PNG,
+            "image/png",
+            &[
+                (header::CACHE_CONTROL, "max-age=3600"),
+                (header::ETAG, "\"png\""),
+            ],
+        )
+    });
+    let jpeg = warp::path!("image.jpg")
+        .map(|| image_response(&JPEG, "image/jpeg", &[(header::CACHE_CONTROL, "no-store")]));
+    let gif = warp::path!("image.gif").map(|| {
+        image_response(
+            &GIF,
+            "image/gif",
+            &[(header::EXPIRES, "Wed, 21 Oct 2037 07:28:00 GMT")],
+        )
+    });
+    let redirect =
+        warp::path!("redirect").map(|| warp::redirect::found(Uri::from_static("/image.jpg")));
+    let page = warp::path!("page.html").map(|| warp::reply::html("<html></html>"));
+
+    warp::get().and(png.or(jpeg).or(gif).or(redirect).or(page))
+}
+
+pub struct Mock {
+    pub addr: SocketAddr,
+    /// Milliseconds since the epoch when the tests started, so that reruns against the
+    /// same database don't collide
+    pub run: u64,
+}
+
+impl Mock {
+    /// A link to `path` on the mock image host
+    pub fn link(&self, path: &str) -> String {
+        format!("http://{}/{}?run={}", self.addr, path, self.run)
+    }
+
+    /// A Reddit ID that's unique to this run and `n`
+    pub fn reddit_id(&self, n: u64) -> String {
+        let mut id = self.run * 100 + n;
+        let mut digits = Vec::new();
+        while id > 0 {
+            digits.push(std::char::from_digit((id % 36) as u32, 36).unwrap());
+            id /= 36;
+        }
+        digits.iter().rev().collect()
+    }
+}
+
+/// One runtime for every test, since the pool's connections and the mock host live on it
+static RUNTIME: Lazy<Runtime> = Lazy::new(|| Runtime::new().unwrap());
+static MOCK: OnceCell<Option<Mock>> = OnceCell::new();
+
+fn setup() -> Option<Mock> {
+    let database_url = std::env::var(DATABASE_URL_VAR).ok()?;
+    std::env::set_var("DATABASE_URL", database_url);
+    secrets::set_path(concat!(env!("CARGO_MANIFEST_DIR"), "/secrets.toml"));
+
+    // The mock host is on loopback, and nothing should be stored, paused, or sent to indexd
+    let mut test_config = config::load().unwrap();
+    test_config.guard_private_ips = false;
+    test_config.blob_store = None;
+    test_config.daily_bandwidth_cap = None;
+    test_config.indexd.enabled = false;
+    CONFIG.store(Arc::new(test_config));
+
+    let _entered = RUNTIME.enter();
+    let (addr, server) = warp::serve(routes()).bind_ephemeral(([127, 0, 0, 1], 0));
+    RUNTIME.spawn(server);
+
+    // Nothing should reach the real APIs, even by accident
+    set_api_base(Api::Gfycat, &format!("http://{}/gfycat", addr));
+    set_api_base(Api::Imgur, &format!("http://{}/imgur", addr));
+
+    Some(Mock {
+        addr,
+        run: chrono::Utc::now().timestamp_millis() as u64,
+    })
+}
+
+/// Runs `test` against the mock host and test database, or skips it without a database
+pub fn run<F, Fut>(test: F)
+where
+    F: FnOnce(&'static Mock) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    match MOCK.get_or_init(setup) {
+        Some(mock) => RUNTIME.block_on(test(mock)),
+        None => eprintln!("{} isn't set; skipping", DATABASE_URL_VAR),
+    }
+}
@@ -0,0 +1,189 @@
+use chrono::NaiveDateTime;
+use common::*;
+use e2e::{run, GIF, JPEG, PNG};
+
+async fn image_row(link: &str) -> tokio_postgres::Row {
+    PG_POOL
+        .get()
+        .await
+        .unwrap()
+        .query_one("SELECT * FROM images WHERE link = $1", &[&link])
+        .await
+        .unwrap()
+}
+
+async fn post_row(reddit_id: &str) -> tokio_postgres::Row {
+    PG_POOL
+        .get()
+        .await
+        .unwrap()
+        .query_one("SELECT * FROM posts WHERE reddit_id = $1", &[&reddit_id])
+        .await
+        .unwrap()
+}
+
+fn submission(reddit_id: &str, link: &str, score: i64, updated: i64) -> Submission {
+    let mut post = serde_json::from_value::<Submission>(serde_json::json!({
+        "id": reddit_id,
+        "author": "e2e",
+        "created_utc": 1_500_000_000,
+        "is_self": false,
+        "over_18": false,
+        "permalink": format!("/r/pics/comments/{}/e2e/", reddit_id),
+        "score": score,
+        "subreddit": "pics",
+        "title": "e2e",
+        "url": link,
+    }))
+    .unwrap()
+    .finalize()
+    .unwrap();
+    post.updated = Some(NaiveDateTime::from_timestamp(updated, 0));
+    post
+}
+
+#[test]
+fn saves_png_with_cache_headers() {
+    run(|mock| async move {
+        let link = mock.link("image.png");
+        let saved = save_hash(&link, HashDest::Images).await.unwrap();
+        assert_eq!(saved.hash.0, hash_from_memory(&PNG).unwrap().0);
+
+        let row = image_row(&link).await;
+        assert_eq!(row.get::<_, i64>("id"), saved.id);
+        assert_eq!(row.get::<_, Hash>("hash"), saved.hash);
+        assert_eq!(row.get::<_, Option<&str>>("etag"), Some("\"png\""));
+        assert_eq!(row.get::<_, Option<bool>>("no_store"), Some(false));
+        assert!(row.get::<_, Option<NaiveDateTime>>("expires").is_some());
+
+        // The second time comes from the database
+        let again = save_hash(&link, HashDest::Images).await.unwrap();
+        assert_eq!(again.id, saved.id);
+    });
+}
+
+#[test]
+fn saves_jpeg_and_gif() {
+    run(|mock| async move {
+        let jpeg_link = mock.link("image.jpg");
+        let jpeg = save_hash(&jpeg_link, HashDest::Images).await.unwrap();
+        assert_eq!(jpeg.hash.0, hash_from_memory(&JPEG).unwrap().0);
+        assert_eq!(
+            image_row(&jpeg_link)
+                .await
+                .get::<_, Option<bool>>("no_store"),
+            Some(true)
+        );
+
+        let gif_link = mock.link("image.gif");
+        let gif = save_hash(&gif_link, HashDest::Images).await.unwrap();
+        assert_eq!(gif.hash.0, hash_from_memory(&GIF).unwrap().0);
+        assert!(image_row(&gif_link)
+            .await
+            .get::<_, Option<NaiveDateTime>>("expires")
+            .is_some());
+    });
+}
+
+#[test]
+fn follows_redirects() {
+    run(|mock| async move {
+        let link = mock.link("redirect");
+        let saved = save_hash(&link, HashDest::Images).await.unwrap();
+        assert_eq!(saved.hash.0, hash_from_memory(&JPEG).unwrap().0);
+
+        // Saved under the link it was asked for, not where it ended up
+        assert_eq!(image_row(&link).await.get::<_, i64>("id"), saved.id);
+    });
+}
+
+#[test]
+fn caches_into_image_cache() {
+    run(|mock| async move {
+        let link = mock.link("image.png") + "&cache";
+        let saved = save_hash(&link, HashDest::ImageCache).await.unwrap();
+        assert_eq!(saved.hash_dest, HashDest::ImageCache);
+
+        let cached = PG_POOL
+            .get()
+            .await
+            .unwrap()
+            .query_one("SELECT id FROM image_cache WHERE link = $1", &[&link])
+            .await
+            .unwrap();
+        assert_eq!(cached.get::<_, i64>("id"), saved.id);
+
+        // Saving it for a post moves it out of the cache
+        let moved = save_hash(&link, HashDest::Images).await.unwrap();
+        assert_eq!(moved.hash_dest, HashDest::Images);
+        assert_eq!(image_row(&link).await.get::<_, i64>("id"), moved.id);
+    });
+}
+
+#[test]
+fn rejects_non_images() {
+    run(|mock| async move {
+        let ue = save_hash(&mock.link("page.html"), HashDest::Images)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(ue.save_error, Some(SaveError::ContentTypeUnsupported));
+
+        let ue = save_hash(&mock.link("missing.png"), HashDest::Images)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            ue.error
+                .downcast_ref::<reqwest::Error>()
+                .and_then(|e| e.status()),
+            Some(reqwest::StatusCode::NOT_FOUND)
+        );
+    });
+}
+
+#[test]
+fn saves_posts() {
+    run(|mock| async move {
+        let reddit_id = mock.reddit_id(1);
+        let link = mock.link("image.png") + "&post";
+        let saved = save_hash(&link, HashDest::Images).await.unwrap();
+
+        let post = submission(&reddit_id, &link, 10, 1_600_000_000);
+        assert_eq!(post.save(Ok(saved.id)).await.unwrap(), Saved::Inserted);
+
+        let row = post_row(&reddit_id).await;
+        assert_eq!(row.get::<_, Option<i64>>("image_id"), Some(saved.id));
+        assert_eq!(row.get::<_, &str>("link"), link);
+        assert_eq!(row.get::<_, i64>("score"), 10);
+
+        // Only a more recently fetched copy replaces the score
+        let newer = submission(&reddit_id, &link, 20, 1_600_000_100);
+        assert_eq!(newer.save(Ok(saved.id)).await.unwrap(), Saved::Updated);
+        let older = submission(&reddit_id, &link, 5, 1_600_000_050);
+        assert_eq!(older.save(Ok(saved.id)).await.unwrap(), Saved::Skipped);
+        assert_eq!(post_row(&reddit_id).await.get::<_, i64>("score"), 20);
+    });
+}
+
+#[test]
+fn saves_posts_that_failed() {
+    run(|mock| async move {
+        let reddit_id = mock.reddit_id(2);
+        let link = mock.link("page.html");
+        let ue = save_hash(&link, HashDest::Images).await.err().unwrap();
+
+        let post = submission(&reddit_id, &link, 1, 1_600_000_000);
+        assert_eq!(
+            post.save(Err(ue.save_error)).await.unwrap(),
+            Saved::Inserted
+        );
+
+        let row = post_row(&reddit_id).await;
+        assert_eq!(row.get::<_, Option<i64>>("image_id"), None);
+        assert_eq!(
+            row.get::<_, Option<SaveError>>("save_error"),
+            Some(SaveError::ContentTypeUnsupported)
+        );
+    });
+}
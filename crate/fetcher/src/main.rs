@@ -0,0 +1,151 @@
+use clap::Parser;
+use common::concurrency::BufferLimitedExt;
+use common::*;
+
+use futures::prelude::*;
+use std::borrow::Cow;
+use std::error::Error;
+use std::time::Duration;
+use tracing_futures::Instrument;
+
+/// How long to wait before looking again when the queue is empty
+const IDLE_WAIT: Duration = Duration::from_secs(30);
+
+/// Hashes a queued post's link, with the same limits as the ingesters
+async fn fetch(queued: Queued) {
+    wait_for_bandwidth().await;
+
+    let _in_flight = HEALTH.start();
+
+    let save_res = match banned_by(&queued.link).await {
+        Some(banned) => Err(ue_save!(format!("banned by {}", banned), SaveError::Banned)),
+        None => match get_host(&queued.link) {
+            Some(host) => wait_for_host(&host).await,
+            None => Ok(()),
+        },
+    };
+
+    let save_res = match save_res {
+        Ok(()) => save_hash(&queued.link, HashDest::Images).await,
+        Err(e) => Err(e),
+    };
+
+    let image_id = match save_res {
+        Ok(hash_gotten) => Ok(hash_gotten.id),
+        Err(ue) => match ue.source {
+            Source::Internal => {
+                eprintln!(
+                    "{}{}{}\n{:?}\n{:#?}",
+                    ue.file.unwrap_or(""),
+                    ue.line
+                        .map(|line| Cow::Owned(format!("#{}", line)))
+                        .unwrap_or(Cow::Borrowed("")),
+                    if ue.file.is_some() || ue.line.is_some() {
+                        ": "
+                    } else {
+                        ""
+                    },
+                    ue.error,
+                    queued
+                );
+                std::process::exit(1)
+            }
+            _ => {
+                let reqwest_save_error = match ue.error.downcast_ref::<reqwest::Error>() {
+                    Some(e) => {
+                        let hyper_error =
+                            e.source().and_then(|he| he.downcast_ref::<hyper::Error>());
+
+                        e.status()
+                            .map(|status| SaveError::Http(status.as_u16()))
+                            .or_else(|| {
+                                if e.is_timeout() {
+                                    Some(SaveError::Timeout)
+                                } else {
+                                    None
+                                }
+                            })
+                            .or_else(|| hyper_error.map(|_| SaveError::Hyper))
+                    }
+                    None => None,
+                };
+
+                let save_error = ue.save_error.or(reqwest_save_error);
+
+                warn!(
+                    "failed to hash{}: {}",
+                    save_error
+                        .as_ref()
+                        .map(|se| Cow::Owned(format!(" ({})", se)))
+                        .unwrap_or_else(|| Cow::Borrowed("")),
+                    ue
+                );
+
+                Err(save_error)
+            }
+        },
+    };
+
+    HEALTH.record(image_id.is_ok());
+
+    match queued.finish(image_id).await {
+        Ok(()) => info!("finished"),
+        Err(e) => {
+            eprintln!("failed to save: {:?}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[derive(Parser)]
+#[command(about = "Hashes the links of posts saved by ingest --defer-hashing")]
+struct Cli {
+    /// How many queued links to claim at a time; each claim takes turns between its hosts
+    #[arg(long, default_value_t = 100)]
+    batch_size: i64,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), UserError> {
+    tracing_subscriber::fmt::init();
+
+    let args = Cli::parse_from(take_path_args());
+
+    reload_config_on_sighup()?;
+    serve_health("fetcher").await?;
+    flush_bandwidth().await?;
+    flush_host_health().await?;
+    start_ingest_events("fetcher");
+    start_link_checker();
+
+    let batch_size = args.batch_size;
+    let claim_stream = stream::unfold((), |()| async move {
+        loop {
+            match claim(batch_size).await {
+                Ok(batch) if batch.is_empty() => tokio::time::sleep(IDLE_WAIT).await,
+                Ok(batch) => return Some((stream::iter(batch), ())),
+                Err(e) => {
+                    error!("Couldn't claim queued links: {:?}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+    });
+
+    claim_stream
+        .flatten()
+        .map(|queued| {
+            tokio::spawn(async move {
+                let span = info_span!(
+                    "fetch",
+                    id = queued.reddit_id_int,
+                    url = queued.link.as_str(),
+                );
+                fetch(queued).instrument(span).await;
+            })
+        })
+        .buffer_limited()
+        .try_collect::<()>()
+        .await
+        .map_err(From::from)
+}
@@ -0,0 +1,162 @@
+//! Compares the trie's backends. Each is filled with 1M hashes unless HASH_TRIE_BENCH_SIZES
+//! says otherwise, like `1000000,10000000,100000000`; the largest sets take a while to build
+//! and several GB of memory and disk.
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use hash_trie::{FileMap, HashTrie, Node, ReadOnlyFileMap};
+use rand::prelude::*;
+use std::path::PathBuf;
+
+/// The same hashes every run, so runs can be compared
+const SEED: u64 = 0x7469_6464_6572;
+/// How many hashes share a base in a clustered set, like reposts of one image
+const CLUSTER_SIZE: usize = 20;
+/// How many searches each similar benchmark cycles through
+const NEEDLES: usize = 1000;
+/// `FileMap` remaps its file for every node it adds, so inserting whole sets into it would
+/// take hours; it's timed on this many of each set's hashes instead
+const FILE_MAP_INSERTS: usize = 10_000;
+
+fn sizes() -> Vec<usize> {
+    std::env::var("HASH_TRIE_BENCH_SIZES")
+        .map(|sizes| {
+            sizes
+                .split(',')
+                .map(|size| size.trim().parse().expect("invalid HASH_TRIE_BENCH_SIZES"))
+                .collect()
+        })
+        .unwrap_or_else(|_| vec![1_000_000])
+}
+
+/// Flips up to `max_bits` random bits of `hash`
+fn flip_bits(rng: &mut StdRng, hash: u64, max_bits: u32) -> u64 {
+    (0..rng.gen_range(0..=max_bits)).fold(hash, |hash, _| hash ^ (1 << rng.gen_range(0..64)))
+}
+
+fn random(size: usize) -> Vec<u64> {
+    let mut rng = StdRng::seed_from_u64(SEED);
+    std::iter::repeat_with(|| rng.gen()).take(size).collect()
+}
+
+/// Groups of near-duplicates, which is closer to what gets posted than uniform hashes
+fn clustered(size: usize) -> Vec<u64> {
+    let mut rng = StdRng::seed_from_u64(SEED);
+    let mut hashes = Vec::with_capacity(size);
+
+    while hashes.len() < size {
+        let base = rng.gen();
+        for _ in 0..CLUSTER_SIZE.min(size - hashes.len()) {
+            hashes.push(flip_bits(&mut rng, base, 4));
+        }
+    }
+
+    hashes
+}
+
+fn sets() -> Vec<(&'static str, usize, Vec<u64>)> {
+    sizes()
+        .into_iter()
+        .flat_map(|size| {
+            vec![
+                ("random", size, random(size)),
+                ("clustered", size, clustered(size)),
+            ]
+        })
+        .collect()
+}
+
+fn trie_path(name: &str, size: usize) -> PathBuf {
+    std::env::temp_dir().join(format!("hash_trie_bench_{}_{}.trie", name, size))
+}
+
+fn insert(c: &mut Criterion) {
+    let mut group = c.benchmark_group("insert");
+    group.sample_size(10);
+
+    for (name, size, hashes) in sets() {
+        group.throughput(Throughput::Elements(size as u64));
+        let id = format!("{}/{}", name, size);
+
+        group.bench_with_input(BenchmarkId::new("vec", &id), &hashes, |b, hashes| {
+            b.iter(|| hashes.iter().copied().collect::<HashTrie<Vec<Node>>>())
+        });
+
+        let hashes = &hashes[..FILE_MAP_INSERTS.min(size)];
+        group.throughput(Throughput::Elements(hashes.len() as u64));
+        let path = trie_path(name, size);
+        group.bench_with_input(BenchmarkId::new("file_map", &id), hashes, |b, hashes| {
+            b.iter_batched(
+                || {
+                    let _ = std::fs::remove_file(&path);
+                    HashTrie::<FileMap>::open(&path).unwrap()
+                },
+                |mut trie| {
+                    for &hash in hashes {
+                        trie.insert(hash);
+                    }
+                    trie
+                },
+                BatchSize::PerIteration,
+            )
+        });
+        let _ = std::fs::remove_file(&path);
+    }
+
+    group.finish();
+}
+
+fn similar(c: &mut Criterion) {
+    let mut group = c.benchmark_group("similar");
+
+    for (name, size, hashes) in sets() {
+        let vec: HashTrie<Vec<Node>> = hashes.iter().copied().collect();
+
+        let path = trie_path(name, size);
+        vec.write_out(&path).unwrap();
+        let file_map = HashTrie::<FileMap>::open(&path).unwrap();
+        let readonly = HashTrie::<ReadOnlyFileMap>::open_readonly(&path).unwrap();
+
+        // Reported here since criterion only measures time
+        println!(
+            "{}/{}: {} nodes, {} bytes in memory, {} bytes on disk",
+            name,
+            size,
+            vec.node_count(),
+            vec.node_count() as usize * std::mem::size_of::<Node>(),
+            std::fs::metadata(&path).unwrap().len()
+        );
+
+        // Near stored hashes, so searches find something like real ones do
+        let mut rng = StdRng::seed_from_u64(SEED);
+        let needles: Vec<u64> = hashes
+            .choose_multiple(&mut rng, NEEDLES)
+            .map(|&hash| flip_bits(&mut rng, hash, 4))
+            .collect();
+
+        for distance in 0..=8 {
+            let id = format!("{}/{}/{}", name, size, distance);
+
+            group.bench_function(BenchmarkId::new("vec", &id), |b| {
+                let mut needles = needles.iter().cycle();
+                b.iter(|| vec.similar(*needles.next().unwrap(), distance).count())
+            });
+            group.bench_function(BenchmarkId::new("file_map", &id), |b| {
+                let mut needles = needles.iter().cycle();
+                b.iter(|| file_map.similar(*needles.next().unwrap(), distance).count())
+            });
+            group.bench_function(BenchmarkId::new("readonly", &id), |b| {
+                let mut needles = needles.iter().cycle();
+                b.iter(|| readonly.similar(*needles.next().unwrap(), distance).count())
+            });
+        }
+
+        drop(file_map);
+        drop(readonly);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, insert, similar);
+criterion_main!(benches);
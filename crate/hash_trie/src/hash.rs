@@ -11,10 +11,6 @@ impl HashBits {
     pub fn new(hash: u64) -> Self {
         Self { hash, pos: 0 }
     }
-
-    pub fn new_at(hash: u64, pos: u8) -> Self {
-        Self { hash, pos }
-    }
 }
 
 impl Iterator for HashBits {
@@ -0,0 +1,104 @@
+use std::convert::TryInto;
+use std::io;
+
+pub const MAGIC: &[u8; 4] = b"HTRI";
+pub const VERSION: u16 = 1;
+pub const HASH_WIDTH: u16 = 64;
+/// A multiple of the node size, so nodes stay aligned after it
+pub const HEADER_SIZE: usize = 32;
+
+/// Sits at the start of every trie file:
+///
+/// | bytes  | contents                              |
+/// |--------|---------------------------------------|
+/// | 0..4   | `HTRI`                                |
+/// | 4..6   | format version                        |
+/// | 6..8   | hash width in bits                    |
+/// | 8..16  | node count                            |
+/// | 16..24 | FNV-1a of the nodes, or 0 if unknown  |
+/// | 24..32 | reserved                              |
+///
+/// Everything is little-endian. Files from before the header existed are only nodes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Header {
+    pub version: u16,
+    pub hash_width: u16,
+    pub node_count: u64,
+    pub checksum: u64,
+}
+
+impl Header {
+    pub fn new(node_count: u64, checksum: u64) -> Self {
+        Self {
+            version: VERSION,
+            hash_width: HASH_WIDTH,
+            node_count,
+            checksum,
+        }
+    }
+
+    /// Returns `None` for legacy files without a header
+    pub fn parse(bytes: &[u8]) -> io::Result<Option<Self>> {
+        if bytes.len() < HEADER_SIZE || &bytes[0..4] != MAGIC {
+            return Ok(None);
+        }
+
+        let header = Self {
+            version: u16::from_le_bytes(bytes[4..6].try_into().unwrap()),
+            hash_width: u16::from_le_bytes(bytes[6..8].try_into().unwrap()),
+            node_count: u64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+            checksum: u64::from_le_bytes(bytes[16..24].try_into().unwrap()),
+        };
+
+        if header.version > VERSION {
+            return Err(invalid(format!(
+                "trie file is version {}, but only up to {} is supported",
+                header.version, VERSION
+            )));
+        }
+
+        if header.hash_width != HASH_WIDTH {
+            return Err(invalid(format!(
+                "trie file holds {}-bit hashes, not {}-bit",
+                header.hash_width, HASH_WIDTH
+            )));
+        }
+
+        Ok(Some(header))
+    }
+
+    pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut out = [0; HEADER_SIZE];
+        out[0..4].copy_from_slice(MAGIC);
+        out[4..6].copy_from_slice(&self.version.to_le_bytes());
+        out[6..8].copy_from_slice(&self.hash_width.to_le_bytes());
+        out[8..16].copy_from_slice(&self.node_count.to_le_bytes());
+        out[16..24].copy_from_slice(&self.checksum.to_le_bytes());
+        out
+    }
+
+    pub fn verify(&self, nodes: &[u8]) -> io::Result<()> {
+        if self.checksum != 0 && self.checksum != checksum(nodes) {
+            Err(invalid("trie file checksum doesn't match".to_string()))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+pub fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// FNV-1a, continuing from `hash`; start with `CHECKSUM_START`
+pub fn checksum_continue(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+pub const CHECKSUM_START: u64 = 0xcbf2_9ce4_8422_2325;
+
+pub fn checksum(bytes: &[u8]) -> u64 {
+    checksum_continue(CHECKSUM_START, bytes)
+}
@@ -1,15 +1,25 @@
-use memmap::MmapMut;
+use memmap::{Mmap, MmapMut, MmapOptions};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
 use std::convert::TryInto;
 use std::fs::{File, OpenOptions};
-use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::os::unix::fs::FileExt;
 use std::path::Path;
 
 mod hash;
 use hash::*;
 
+mod header;
+pub use header::Header;
+use header::*;
+
 const NODE_SIZE: usize = 8;
 
+fn node_offset(index: u32) -> usize {
+    HEADER_SIZE + index as usize * NODE_SIZE
+}
+
 fn u32ize<T>(n: T) -> u32
 where
     T: TryInto<u32>,
@@ -18,14 +28,9 @@ where
     n.try_into().unwrap()
 }
 
-pub trait HashTreeStorage {
-    type Data;
-    fn new(data: Self::Data) -> Self;
+pub trait HashTreeRead {
     fn get_zero(&self, index: u32) -> u32;
     fn get_one(&self, index: u32) -> u32;
-    fn set_zero(&mut self, index: u32, val: u32);
-    fn set_one(&mut self, index: u32, val: u32);
-    fn push(&mut self, zero: u32, one: u32);
     fn len(&self) -> u32;
     fn is_empty(&self) -> bool {
         self.len() == 0
@@ -35,17 +40,31 @@ pub trait HashTreeStorage {
     }
 }
 
-impl HashTreeStorage for Vec<Node> {
-    type Data = ();
-    fn new(_data: Self::Data) -> Self {
-        vec![Node::default()]
-    }
+pub trait HashTreeStorage: HashTreeRead {
+    type Data;
+    fn new(data: Self::Data) -> Self;
+    fn set_zero(&mut self, index: u32, val: u32);
+    fn set_one(&mut self, index: u32, val: u32);
+    fn push(&mut self, zero: u32, one: u32);
+}
+
+impl HashTreeRead for Vec<Node> {
     fn get_zero(&self, index: u32) -> u32 {
         self[index as usize].zero
     }
     fn get_one(&self, index: u32) -> u32 {
         self[index as usize].one
     }
+    fn len(&self) -> u32 {
+        u32ize(self.len())
+    }
+}
+
+impl HashTreeStorage for Vec<Node> {
+    type Data = ();
+    fn new(_data: Self::Data) -> Self {
+        vec![Node::default()]
+    }
     fn set_zero(&mut self, index: u32, val: u32) {
         self[index as usize].zero = val
     }
@@ -55,9 +74,6 @@ impl HashTreeStorage for Vec<Node> {
     fn push(&mut self, zero: u32, one: u32) {
         self.push(Node { zero, one });
     }
-    fn len(&self) -> u32 {
-        u32ize(self.len())
-    }
 }
 
 pub struct FileMap {
@@ -65,39 +81,123 @@ pub struct FileMap {
     mmap: MmapMut,
 }
 
-impl HashTreeStorage for FileMap {
-    type Data = String;
+impl FileMap {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
 
-    fn new(path: Self::Data) -> Self {
         let mut file = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .open(path)
-            .unwrap();
+            .open(path)?;
+
+        let mut start = [0; HEADER_SIZE];
+        let read = file.read_at(&mut start, 0)?;
 
-        if file.metadata().unwrap().len() == 0 {
-            file.write_all(&[0, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+        if read == 0 {
+            file.write_all(&Header::new(1, 0).to_bytes())?;
+            file.write_all(&[0, 0, 0, 0, 0, 0, 0, 0])?;
+        } else if Header::parse(&start[..read])?.is_none() {
+            file = Self::migrate(path, file)?;
         }
 
-        let mmap = unsafe { MmapMut::map_mut(&file).unwrap() };
+        let mut mmap = unsafe { MmapMut::map_mut(&file)? };
 
-        Self { file, mmap }
+        let mut header = Header::parse(&mmap)?.unwrap();
+        let nodes_end = HEADER_SIZE + header.node_count as usize * NODE_SIZE;
+        if nodes_end > mmap.len() {
+            return Err(invalid(
+                "trie file is shorter than its header says".to_string(),
+            ));
+        }
+        header.verify(&mmap[HEADER_SIZE..nodes_end])?;
+
+        // Nodes get changed in place, so the checksum is only good again once we're done
+        header.checksum = 0;
+        mmap[..HEADER_SIZE].copy_from_slice(&header.to_bytes());
+
+        Ok(Self { file, mmap })
     }
+
+    /// Rewrites a file from before headers with one at the start
+    fn migrate(path: &Path, mut file: File) -> io::Result<File> {
+        let mut nodes = Vec::new();
+        file.seek(SeekFrom::Start(0))?;
+        file.read_to_end(&mut nodes)?;
+
+        if nodes.len() % NODE_SIZE != 0 {
+            return Err(invalid(
+                "legacy trie file isn't a whole number of nodes".to_string(),
+            ));
+        }
+
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".migrating");
+
+        let mut tmp = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        tmp.write_all(&Header::new((nodes.len() / NODE_SIZE) as u64, checksum(&nodes)).to_bytes())?;
+        tmp.write_all(&nodes)?;
+        tmp.sync_all()?;
+
+        std::fs::rename(&tmp_path, path)?;
+
+        Ok(tmp)
+    }
+
+    fn header(&self) -> Header {
+        Header::parse(&self.mmap).unwrap().unwrap()
+    }
+
+    /// Writes the checksum and syncs everything to disk
+    pub fn flush(&mut self) -> io::Result<()> {
+        let node_count = self.header().node_count;
+        let nodes_end = HEADER_SIZE + node_count as usize * NODE_SIZE;
+        let header = Header::new(node_count, checksum(&self.mmap[HEADER_SIZE..nodes_end]));
+
+        self.mmap[..HEADER_SIZE].copy_from_slice(&header.to_bytes());
+        self.mmap.flush()
+    }
+}
+
+impl Drop for FileMap {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            eprintln!("Couldn't flush trie file: {}", e);
+        }
+    }
+}
+
+impl HashTreeRead for FileMap {
     fn get_zero(&self, index: u32) -> u32 {
-        let index = index as usize * NODE_SIZE;
+        let index = node_offset(index);
         u32::from_le_bytes(self.mmap[index..index + 4].try_into().unwrap())
     }
     fn get_one(&self, index: u32) -> u32 {
-        let index = index as usize * NODE_SIZE;
+        let index = node_offset(index);
         u32::from_le_bytes(self.mmap[index + 4..index + 8].try_into().unwrap())
     }
+    fn len(&self) -> u32 {
+        u32ize(self.header().node_count)
+    }
+}
+
+impl HashTreeStorage for FileMap {
+    type Data = String;
+
+    fn new(path: Self::Data) -> Self {
+        Self::open(path).unwrap()
+    }
     fn set_zero(&mut self, index: u32, val: u32) {
-        let index = index as usize * NODE_SIZE;
+        let index = node_offset(index);
         self.mmap[index..index + 4].copy_from_slice(&val.to_le_bytes());
     }
     fn set_one(&mut self, index: u32, val: u32) {
-        let index = index as usize * NODE_SIZE;
+        let index = node_offset(index);
         self.mmap[index + 4..index + 8].copy_from_slice(&val.to_le_bytes());
     }
     fn push(&mut self, zero: u32, one: u32) {
@@ -105,17 +205,94 @@ impl HashTreeStorage for FileMap {
         out[0..4].copy_from_slice(&zero.to_le_bytes());
         out[4..8].copy_from_slice(&one.to_le_bytes());
 
+        let len = self.len();
+
         self.file
-            .write_all_at(&out, self.len() as u64 * NODE_SIZE as u64)
+            .write_all_at(&out, node_offset(len) as u64)
             .unwrap();
 
-        std::mem::replace(&mut self.mmap, unsafe {
-            MmapMut::map_mut(&self.file).unwrap()
-        });
+        self.mmap = unsafe { MmapMut::map_mut(&self.file).unwrap() };
+
+        // The count goes up only after the node is there, so readers never see a missing one
+        let mut header = self.header();
+        header.node_count = u64::from(len) + 1;
+        self.mmap[..HEADER_SIZE].copy_from_slice(&header.to_bytes());
+    }
+}
+
+/// Shares a trie file with a process that's still inserting into it. It only sees the nodes
+/// that were there when it was opened or last refreshed; links to anything newer look empty.
+pub struct ReadOnlyFileMap {
+    file: File,
+    mmap: Mmap,
+    len: u32,
+}
+
+impl ReadOnlyFileMap {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).open(path)?;
+
+        let mmap = unsafe { MmapOptions::new().map(&file)? };
+        let len = Self::visible_len(&mmap)?;
+
+        if let Some(header) = Header::parse(&mmap)? {
+            header.verify(&mmap[HEADER_SIZE..node_offset(len)])?;
+        }
+
+        Ok(Self { file, mmap, len })
+    }
+
+    fn visible_len(mmap: &[u8]) -> io::Result<u32> {
+        Ok(match Header::parse(mmap)? {
+            Some(header) => {
+                let mapped = (mmap.len() - HEADER_SIZE) / NODE_SIZE;
+                u32ize(header.node_count.min(mapped as u64))
+            }
+            None => u32ize(mmap.len() / NODE_SIZE),
+        })
+    }
+
+    fn node_offset(&self, index: u32) -> usize {
+        if self.mmap.starts_with(MAGIC) {
+            node_offset(index)
+        } else {
+            index as usize * NODE_SIZE
+        }
+    }
+
+    fn get(&self, offset: usize) -> u32 {
+        let index = u32::from_le_bytes(self.mmap[offset..offset + 4].try_into().unwrap());
+        if index < self.len {
+            index
+        } else {
+            0
+        }
     }
 
+    /// Picks up nodes added since it was opened; returns whether there were any
+    pub fn refresh(&mut self) -> io::Result<bool> {
+        let mmap = unsafe { MmapOptions::new().map(&self.file)? };
+        let len = Self::visible_len(&mmap)?;
+
+        if len > self.len {
+            self.mmap = mmap;
+            self.len = len;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+}
+
+impl HashTreeRead for ReadOnlyFileMap {
+    fn get_zero(&self, index: u32) -> u32 {
+        self.get(self.node_offset(index))
+    }
+    fn get_one(&self, index: u32) -> u32 {
+        self.get(self.node_offset(index) + 4)
+    }
     fn len(&self) -> u32 {
-        u32ize(self.mmap.len() / NODE_SIZE)
+        self.len
     }
 }
 
@@ -127,7 +304,7 @@ pub struct Node {
 }
 
 #[derive(Debug, Default)]
-pub struct HashTrie<S: HashTreeStorage> {
+pub struct HashTrie<S: HashTreeRead> {
     haystack: S,
 }
 
@@ -139,56 +316,247 @@ impl<S: HashTreeStorage> HashTrie<S> {
     }
 
     pub fn insert(&mut self, hash: u64) -> bool {
-        let (start_pos, mut prev_index) = self.search(hash);
+        let (start_pos, prev_index) = match self.search(hash) {
+            Some(_) => return true,
+            None => self.missing_from(hash),
+        };
 
-        if start_pos == 63 {
-            return true;
-        }
+        // The new branch is built from the leaf up and attached last, so anyone reading the
+        // file at the same time sees either all of it or none of it
+        let mut child_index = self.haystack.len();
+        self.haystack.push(0, 0);
 
-        for bit in HashBits::new_at(hash, start_pos) {
+        for pos in (start_pos + 1..64).rev() {
             let new_index = self.haystack.len();
-            self.haystack.push(0, 0);
 
-            if bit == 0 {
-                self.haystack.set_zero(prev_index, new_index);
-            } else if bit == 1 {
-                self.haystack.set_one(prev_index, new_index);
+            if get_bit(hash, pos) == 0 {
+                self.haystack.push(child_index, 0);
+            } else {
+                self.haystack.push(0, child_index);
             }
 
-            prev_index = new_index;
+            child_index = new_index;
+        }
+
+        if get_bit(hash, start_pos) == 0 {
+            self.haystack.set_zero(prev_index, child_index);
+        } else {
+            self.haystack.set_one(prev_index, child_index);
         }
 
         false
     }
+}
 
-    fn search(&self, needle: u64) -> (u8, u32) {
+impl<S: HashTreeRead> HashTrie<S> {
+    /// Returns the index of the leaf for `needle` if it's in the trie
+    fn search(&self, needle: u64) -> Option<u32> {
+        let haystack = &self.haystack;
+
+        let mut current_index = 0;
+
+        for bit in HashBits::new(needle) {
+            current_index = match bit {
+                0 => haystack.get_zero(current_index),
+                _ => haystack.get_one(current_index),
+            };
+
+            if current_index == 0 {
+                return None;
+            }
+        }
+
+        Some(current_index)
+    }
+
+    /// The position of the first bit of `needle` that isn't in the trie, and the node that's
+    /// missing it
+    fn missing_from(&self, needle: u64) -> (u8, u32) {
         let haystack = &self.haystack;
 
         let mut current_index = 0;
-        let mut next_index = 0;
 
         for (pos, bit) in HashBits::new(needle).enumerate() {
-            next_index = if bit == 0 && haystack.get_zero(current_index) != 0 {
-                haystack.get_zero(current_index)
-            } else if bit == 1 && haystack.get_one(current_index) != 0 {
-                haystack.get_one(current_index)
-            } else {
-                return (pos as u8, next_index);
+            let next_index = match bit {
+                0 => haystack.get_zero(current_index),
+                _ => haystack.get_one(current_index),
             };
 
+            if next_index == 0 {
+                return (pos as u8, current_index);
+            }
+
             current_index = next_index;
         }
 
-        (63, next_index)
+        unreachable!("missing_from called on a hash that's in the trie")
+    }
+
+    pub fn contains(&self, hash: u64) -> bool {
+        self.search(hash).is_some()
+    }
+
+    /// How many stored hashes have the same first `bits` bits as `hash`, going from the least
+    /// significant bit like the trie does
+    pub fn count_prefix(&self, hash: u64, bits: u8) -> u64 {
+        if !self.has_hashes() {
+            return 0;
+        }
+
+        let mut current_index = 0;
+
+        for bit in HashBits::new(hash).take(bits.min(64) as usize) {
+            current_index = match bit {
+                0 => self.haystack.get_zero(current_index),
+                _ => self.haystack.get_one(current_index),
+            };
+
+            if current_index == 0 {
+                return 0;
+            }
+        }
+
+        self.count_leaves(current_index, bits.min(64))
+    }
+
+    /// How many hashes are stored
+    pub fn len_hashes(&self) -> u64 {
+        self.count_prefix(0, 0)
+    }
+
+    pub fn node_count(&self) -> u32 {
+        self.haystack.len()
+    }
+
+    fn count_leaves(&self, index: u32, depth: u8) -> u64 {
+        let mut count = 0;
+        let mut branches = vec![(index, depth)];
+
+        while let Some((index, depth)) = branches.pop() {
+            if depth == 64 {
+                count += 1;
+                continue;
+            }
+
+            let (zero, one) = self.haystack.get_both(index);
+            if zero != 0 {
+                branches.push((zero, depth + 1));
+            }
+            if one != 0 {
+                branches.push((one, depth + 1));
+            }
+        }
+
+        count
+    }
+
+    fn has_hashes(&self) -> bool {
+        !self.haystack.is_empty() && self.haystack.get_both(0) != (0, 0)
     }
 
     pub fn similar(&self, needle: u64, max_distance: u8) -> Similar<S> {
         Similar::new(self, needle, max_distance)
     }
 
+    /// Like `similar`, but yields hashes nearest first, along with their distances
+    pub fn similar_ordered(&self, needle: u64, max_distance: u8) -> SimilarOrdered<S> {
+        SimilarOrdered::new(self, needle, max_distance)
+    }
+
+    /// The closest hash to `needle` and its distance, if there are any hashes
+    pub fn nearest(&self, needle: u64) -> Option<(u64, u8)> {
+        self.similar_ordered(needle, 64).next()
+    }
+
     pub fn hashes(&self) -> HashIter<S> {
         HashIter::new(self)
     }
+
+    /// Checks that the nodes form a proper trie: every link points at a node that exists and
+    /// nothing else links to, every branch ends in a leaf at depth 64, and every stored node
+    /// is reachable from the root. A `ReadOnlyFileMap` can fail the last while its writer is
+    /// partway through an insert.
+    pub fn check_invariants(&self) -> io::Result<()> {
+        let len = self.haystack.len();
+        if len == 0 {
+            return Err(invalid("trie has no root node".to_string()));
+        }
+
+        let mut seen = vec![false; len as usize];
+        seen[0] = true;
+        let mut reachable = 1;
+        let mut branches = vec![(0, 0_u8)];
+
+        while let Some((index, depth)) = branches.pop() {
+            let (zero, one) = self.haystack.get_both(index);
+
+            if depth == 64 {
+                if (zero, one) != (0, 0) {
+                    return Err(invalid(format!("leaf {} has children", index)));
+                }
+                continue;
+            }
+            if (zero, one) == (0, 0) && index != 0 {
+                return Err(invalid(format!(
+                    "node {} at depth {} has no children",
+                    index, depth
+                )));
+            }
+
+            for &child in [zero, one].iter().filter(|&&child| child != 0) {
+                if child >= len {
+                    return Err(invalid(format!(
+                        "node {} links to {}, past the last node",
+                        index, child
+                    )));
+                }
+                if seen[child as usize] {
+                    return Err(invalid(format!(
+                        "node {} is linked to more than once",
+                        child
+                    )));
+                }
+
+                seen[child as usize] = true;
+                reachable += 1;
+                branches.push((child, depth + 1));
+            }
+        }
+
+        if reachable != len {
+            return Err(invalid(format!(
+                "{} of {} nodes are reachable from the root",
+                reachable, len
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+impl HashTrie<FileMap> {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self {
+            haystack: FileMap::open(path)?,
+        })
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.haystack.flush()
+    }
+}
+
+impl HashTrie<ReadOnlyFileMap> {
+    pub fn open_readonly(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self {
+            haystack: ReadOnlyFileMap::open(path)?,
+        })
+    }
+
+    /// See `ReadOnlyFileMap::refresh`
+    pub fn refresh(&mut self) -> io::Result<bool> {
+        self.haystack.refresh()
+    }
 }
 
 impl HashTrie<Vec<Node>> {
@@ -199,23 +567,53 @@ impl HashTrie<Vec<Node>> {
 
         let mut file = BufReader::new(file);
 
+        let mut start = [0; HEADER_SIZE];
+        let header = if len >= HEADER_SIZE as u64 {
+            file.read_exact(&mut start)?;
+            Header::parse(&start)?
+        } else {
+            None
+        };
+
+        let node_count = match header {
+            Some(header) => {
+                if HEADER_SIZE as u64 + header.node_count * NODE_SIZE as u64 > len {
+                    return Err(invalid(
+                        "trie file is shorter than its header says".to_string(),
+                    ));
+                }
+                header.node_count
+            }
+            None => {
+                file.seek(SeekFrom::Start(0))?;
+                len / NODE_SIZE as u64
+            }
+        };
+
         let mut new = Self {
             haystack: Vec::new(),
         };
 
-        for _i in 0..len / (2 * std::mem::size_of::<u32>() as u64) {
-            let mut zero_bytes = [0, 0, 0, 0];
-            let mut one_bytes = [0, 0, 0, 0];
+        let mut sum = CHECKSUM_START;
 
-            file.read_exact(&mut zero_bytes)?;
-            file.read_exact(&mut one_bytes)?;
+        for _i in 0..node_count {
+            let mut node_bytes = [0; NODE_SIZE];
 
-            let zero = u32::from_le_bytes(zero_bytes);
-            let one = u32::from_le_bytes(one_bytes);
+            file.read_exact(&mut node_bytes)?;
+            sum = checksum_continue(sum, &node_bytes);
+
+            let zero = u32::from_le_bytes(node_bytes[0..4].try_into().unwrap());
+            let one = u32::from_le_bytes(node_bytes[4..8].try_into().unwrap());
 
             new.haystack.push(Node { zero, one });
         }
 
+        if let Some(header) = header {
+            if header.checksum != 0 && header.checksum != sum {
+                return Err(invalid("trie file checksum doesn't match".to_string()));
+            }
+        }
+
         Ok(new)
     }
 
@@ -228,6 +626,15 @@ impl HashTrie<Vec<Node>> {
                 .open(path)?,
         );
 
+        let sum = self.haystack.iter().fold(CHECKSUM_START, |sum, node| {
+            checksum_continue(
+                checksum_continue(sum, &node.zero.to_le_bytes()),
+                &node.one.to_le_bytes(),
+            )
+        });
+
+        file.write_all(&Header::new(self.haystack.len() as u64, sum).to_bytes())?;
+
         for node in self.haystack.iter() {
             file.write_all(&node.zero.to_le_bytes())?;
             file.write_all(&node.one.to_le_bytes())?;
@@ -259,30 +666,34 @@ struct SimilarBranch {
     index: u32,
 }
 
-pub struct Similar<'a, S: HashTreeStorage> {
+pub struct Similar<'a, S: HashTreeRead> {
     trie: &'a HashTrie<S>,
     needle: u64,
     max_distance: u8,
     branches: Vec<SimilarBranch>,
 }
 
-impl<'a, S: HashTreeStorage> Similar<'a, S> {
+impl<'a, S: HashTreeRead> Similar<'a, S> {
     fn new(trie: &'a HashTrie<S>, needle: u64, max_distance: u8) -> Self {
         Self {
             trie,
             needle,
             max_distance,
-            branches: vec![SimilarBranch {
-                hash: 0,
-                pos: 0,
-                distance: 0,
-                index: 0,
-            }],
+            branches: if trie.has_hashes() {
+                vec![SimilarBranch {
+                    hash: 0,
+                    pos: 0,
+                    distance: 0,
+                    index: 0,
+                }]
+            } else {
+                Vec::new()
+            },
         }
     }
 }
 
-impl<'a, S: HashTreeStorage> Iterator for Similar<'a, S> {
+impl<'a, S: HashTreeRead> Iterator for Similar<'a, S> {
     type Item = u64;
 
     fn next(&mut self) -> Option<Self::Item> {
@@ -366,21 +777,93 @@ impl<'a, S: HashTreeStorage> Iterator for Similar<'a, S> {
     }
 }
 
-pub struct HashIter<'a, S: HashTreeStorage> {
+/// Ordered by distance, then deepest first so leaves are reached sooner
+type OrderedBranch = (Reverse<u8>, u8, u64, u32);
+
+pub struct SimilarOrdered<'a, S: HashTreeRead> {
+    trie: &'a HashTrie<S>,
+    needle: u64,
+    max_distance: u8,
+    branches: BinaryHeap<OrderedBranch>,
+}
+
+impl<'a, S: HashTreeRead> SimilarOrdered<'a, S> {
+    fn new(trie: &'a HashTrie<S>, needle: u64, max_distance: u8) -> Self {
+        let mut branches = BinaryHeap::new();
+        if trie.has_hashes() {
+            branches.push((Reverse(0), 0, 0, 0));
+        }
+
+        Self {
+            trie,
+            needle,
+            max_distance,
+            branches,
+        }
+    }
+}
+
+impl<'a, S: HashTreeRead> Iterator for SimilarOrdered<'a, S> {
+    type Item = (u64, u8);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Every branch left in the heap is at least as far as the one popped,
+        // so following the needle's own bits can't skip past anything closer
+        'branches: while let Some((Reverse(distance), start_pos, mut hash, mut current_index)) =
+            self.branches.pop()
+        {
+            for pos in start_pos..64 {
+                let needle_bit = get_bit(self.needle, pos);
+                let (zero_index, one_index) = self.trie.haystack.get_both(current_index);
+                let (same_index, other_index, other_hash) = if needle_bit == 0 {
+                    (zero_index, one_index, hash | 1 << pos)
+                } else {
+                    (one_index, zero_index, hash & !(1 << pos))
+                };
+
+                if other_index != 0 && distance < self.max_distance {
+                    self.branches
+                        .push((Reverse(distance + 1), pos + 1, other_hash, other_index));
+                }
+
+                if same_index == 0 {
+                    continue 'branches;
+                }
+
+                hash = if needle_bit == 0 {
+                    hash & !(1 << pos)
+                } else {
+                    hash | 1 << pos
+                };
+                current_index = same_index;
+            }
+
+            return Some((hash, distance));
+        }
+
+        None
+    }
+}
+
+pub struct HashIter<'a, S: HashTreeRead> {
     trie: &'a HashTrie<S>,
     branches: Vec<(u64, u8, u32)>,
 }
 
-impl<'a, S: HashTreeStorage> HashIter<'a, S> {
+impl<'a, S: HashTreeRead> HashIter<'a, S> {
     fn new(trie: &'a HashTrie<S>) -> Self {
         Self {
             trie,
-            branches: vec![(0, 0, 0)],
+            branches: if trie.has_hashes() {
+                vec![(0, 0, 0)]
+            } else {
+                Vec::new()
+            },
         }
     }
 }
 
-impl<'a, S: HashTreeStorage> Iterator for HashIter<'a, S> {
+impl<'a, S: HashTreeRead> Iterator for HashIter<'a, S> {
     type Item = u64;
 
     fn next(&mut self) -> Option<Self::Item> {
@@ -460,6 +943,55 @@ mod test {
         assert_eq!(should_match, matches);
     }
 
+    #[test]
+    fn similar_ordered() {
+        let mut rng = thread_rng();
+
+        let input: Vec<u64> = std::iter::repeat_with(|| rng.gen()).take(1000).collect();
+        let trie: HashTrie<Vec<_>> = input.iter().copied().collect();
+
+        let needle = rng.gen();
+        let max_distance = 28;
+
+        let mut should_match: Vec<_> = trie.similar(needle, max_distance).collect();
+        should_match.sort();
+
+        let ordered: Vec<_> = trie.similar_ordered(needle, max_distance).collect();
+        assert!(ordered.windows(2).all(|pair| pair[0].1 <= pair[1].1));
+        assert!(ordered
+            .iter()
+            .all(|&(hash, distance)| u64::count_ones(hash ^ needle) as u8 == distance));
+
+        let mut matches: Vec<_> = ordered.into_iter().map(|(hash, _)| hash).collect();
+        matches.sort();
+
+        assert_eq!(should_match, matches);
+    }
+
+    #[test]
+    fn nearest() {
+        let mut rng = thread_rng();
+
+        let input: Vec<u64> = std::iter::repeat_with(|| rng.gen()).take(1000).collect();
+        let trie: HashTrie<Vec<_>> = input.iter().copied().collect();
+
+        for _ in 0..20 {
+            let needle: u64 = rng.gen();
+            let best = input
+                .iter()
+                .map(|hash| (hash ^ needle).count_ones() as u8)
+                .min()
+                .unwrap();
+
+            let (hash, distance) = trie.nearest(needle).unwrap();
+            assert_eq!(distance, best);
+            assert_eq!((hash ^ needle).count_ones() as u8, best);
+        }
+
+        assert_eq!(trie.nearest(input[0]), Some((input[0], 0)));
+        assert_eq!(HashTrie::<Vec<_>>::new(()).nearest(0), None);
+    }
+
     #[test]
     fn save() {
         let mut rng = thread_rng();
@@ -475,6 +1007,48 @@ mod test {
         assert_eq!(in_trie.haystack, out_trie.haystack);
     }
 
+    #[test]
+    fn save_legacy() {
+        let mut rng = thread_rng();
+
+        let input: Vec<u64> = std::iter::repeat_with(|| rng.gen()).take(100).collect();
+
+        let in_trie: HashTrie<Vec<_>> = input.iter().copied().collect();
+
+        in_trie.write_out("/tmp/test_legacy.hashtrie").unwrap();
+
+        // Strip the header off to get what older versions wrote
+        let bytes = std::fs::read("/tmp/test_legacy.hashtrie").unwrap();
+        std::fs::write("/tmp/test_legacy.hashtrie", &bytes[HEADER_SIZE..]).unwrap();
+
+        let out_trie = HashTrie::read_in("/tmp/test_legacy.hashtrie").unwrap();
+        assert_eq!(in_trie.haystack, out_trie.haystack);
+
+        let mut output = HashTrie::<FileMap>::new("/tmp/test_legacy.hashtrie".to_string())
+            .hashes()
+            .collect::<Vec<_>>();
+        let mut input = input;
+        input.sort();
+        output.sort();
+        assert_eq!(input, output);
+
+        let migrated = std::fs::read("/tmp/test_legacy.hashtrie").unwrap();
+        assert_eq!(&migrated[0..4], MAGIC);
+    }
+
+    #[test]
+    fn save_corrupt() {
+        let in_trie: HashTrie<Vec<_>> = vec![1, 2, 3].into_iter().collect();
+
+        in_trie.write_out("/tmp/test_corrupt.hashtrie").unwrap();
+
+        let mut bytes = std::fs::read("/tmp/test_corrupt.hashtrie").unwrap();
+        *bytes.last_mut().unwrap() ^= 1;
+        std::fs::write("/tmp/test_corrupt.hashtrie", &bytes).unwrap();
+
+        assert!(HashTrie::read_in("/tmp/test_corrupt.hashtrie").is_err());
+    }
+
     #[test]
     fn mmap() {
         if std::path::Path::exists("/tmp/test.mmaptrie".as_ref()) {
@@ -498,6 +1072,186 @@ mod test {
         assert_eq!(input, output);
     }
 
+    #[test]
+    fn last_bit() {
+        let mut trie = HashTrie::<Vec<_>>::new(());
+
+        assert!(!trie.insert(0));
+        assert!(!trie.insert(1 << 63));
+        assert!(trie.insert(1 << 63));
+
+        let mut output = trie.hashes().collect::<Vec<_>>();
+        output.sort();
+        assert_eq!(output, vec![0, 1 << 63]);
+    }
+
+    #[test]
+    fn empty() {
+        let trie = HashTrie::<Vec<_>>::new(());
+
+        assert_eq!(trie.hashes().count(), 0);
+        assert_eq!(trie.similar(0, 3).count(), 0);
+    }
+
+    #[test]
+    fn readonly() {
+        if std::path::Path::exists("/tmp/test.readonlytrie".as_ref()) {
+            std::fs::remove_file("/tmp/test.readonlytrie").unwrap();
+        }
+
+        let mut rng = thread_rng();
+
+        let mut input: Vec<u64> = std::iter::repeat_with(|| rng.gen()).take(100).collect();
+        input.sort();
+
+        let mut writer = HashTrie::<FileMap>::new("/tmp/test.readonlytrie".to_string());
+        for hash in input[..50].iter() {
+            writer.insert(*hash);
+        }
+
+        let mut reader = HashTrie::open_readonly("/tmp/test.readonlytrie").unwrap();
+
+        for hash in input[50..].iter() {
+            writer.insert(*hash);
+        }
+
+        // Only what was there when it was opened
+        let mut output = reader.hashes().collect::<Vec<_>>();
+        output.sort();
+        assert_eq!(&input[..50], output.as_slice());
+
+        assert!(reader.refresh().unwrap());
+
+        let mut output = reader.hashes().collect::<Vec<_>>();
+        output.sort();
+        assert_eq!(input, output);
+    }
+
+    #[test]
+    fn count_prefix() {
+        let input = [
+            0b1001, 0b0100, 0b0010, 0b0101, 0b0110, 0b0001, 0b0000, 0b1111,
+        ];
+
+        let trie: HashTrie<Vec<_>> = input.iter().copied().collect();
+
+        assert_eq!(trie.len_hashes(), 8);
+        assert_eq!(trie.count_prefix(0b01, 2), 3);
+        assert_eq!(trie.count_prefix(0b1, 1), 4);
+        assert_eq!(trie.count_prefix(0b1111, 64), 1);
+        assert_eq!(trie.count_prefix(0b1011, 64), 0);
+        assert_eq!(HashTrie::<Vec<_>>::new(()).len_hashes(), 0);
+    }
+
     #[test]
     fn both() {}
+
+    #[test]
+    fn invariants() {
+        let mut trie: HashTrie<Vec<_>> = vec![1, 2, 3].into_iter().collect();
+        trie.check_invariants().unwrap();
+        HashTrie::<Vec<_>>::new(()).check_invariants().unwrap();
+
+        trie.haystack.set_one(0, 0);
+        assert!(trie.check_invariants().is_err());
+
+        // Branches are built from the leaf up, so 2 is at depth 63 and 64 at depth 1
+        let mut cyclic: HashTrie<Vec<_>> = vec![0].into_iter().collect();
+        cyclic.haystack.set_one(2, 64);
+        assert!(cyclic.check_invariants().is_err());
+
+        let mut dangling: HashTrie<Vec<_>> = vec![0].into_iter().collect();
+        dangling.haystack.set_one(0, 100);
+        assert!(dangling.check_invariants().is_err());
+
+        let mut unreachable: HashTrie<Vec<_>> = vec![0].into_iter().collect();
+        unreachable.haystack.push(Node::default());
+        assert!(unreachable.check_invariants().is_err());
+    }
+}
+
+/// Checks the trie against a plain list of hashes, so changes to how nodes are stored can't
+/// quietly change what's found
+#[cfg(test)]
+mod properties {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// Hashes a few bits from a shared base, so that similar searches find something
+    fn clustered() -> impl Strategy<Value = (u64, Vec<u64>)> {
+        (
+            any::<u64>(),
+            prop::collection::vec((0..64_u32, 0..64_u32, 0..64_u32), 0..200),
+        )
+            .prop_map(|(base, flips)| {
+                let hashes = flips
+                    .into_iter()
+                    .map(|(a, b, c)| base ^ (1 << a) ^ (1 << b) ^ (1 << c))
+                    .collect();
+                (base, hashes)
+            })
+    }
+
+    fn distance(a: u64, b: u64) -> u8 {
+        (a ^ b).count_ones() as u8
+    }
+
+    proptest! {
+        #[test]
+        fn insert_matches_naive((_, hashes) in clustered()) {
+            let mut trie = HashTrie::<Vec<_>>::new(());
+            let mut naive: Vec<u64> = Vec::new();
+
+            for hash in hashes {
+                prop_assert_eq!(trie.insert(hash), naive.contains(&hash));
+                if !naive.contains(&hash) {
+                    naive.push(hash);
+                }
+            }
+            trie.check_invariants().unwrap();
+
+            naive.sort_unstable();
+            let mut stored: Vec<_> = trie.hashes().collect();
+            stored.sort_unstable();
+            prop_assert_eq!(&stored, &naive);
+            prop_assert_eq!(trie.len_hashes(), naive.len() as u64);
+            for hash in &naive {
+                prop_assert!(trie.contains(*hash));
+            }
+        }
+
+        #[test]
+        fn search_matches_naive(
+            (base, hashes) in clustered(),
+            flips in prop::collection::vec(0..64_u32, 0..6),
+            max_distance in 0..10_u8,
+        ) {
+            let needle = flips.into_iter().fold(base, |needle, bit| needle ^ (1 << bit));
+            let trie: HashTrie<Vec<_>> = hashes.iter().copied().collect();
+
+            let mut naive: Vec<_> = hashes
+                .iter()
+                .copied()
+                .filter(|&hash| distance(hash, needle) <= max_distance)
+                .collect();
+            naive.sort_unstable();
+            naive.dedup();
+
+            let mut similar: Vec<_> = trie.similar(needle, max_distance).collect();
+            similar.sort_unstable();
+            prop_assert_eq!(&similar, &naive);
+
+            let ordered: Vec<_> = trie.similar_ordered(needle, max_distance).collect();
+            prop_assert!(ordered.windows(2).all(|pair| pair[0].1 <= pair[1].1));
+            for &(hash, found) in &ordered {
+                prop_assert_eq!(found, distance(hash, needle));
+            }
+            let mut ordered: Vec<_> = ordered.into_iter().map(|(hash, _)| hash).collect();
+            ordered.sort_unstable();
+            prop_assert_eq!(&ordered, &naive);
+
+            let nearest = hashes.iter().map(|&hash| distance(hash, needle)).min();
+            prop_assert_eq!(trie.nearest(needle).map(|(_, found)| found), nearest);
+        }
+    }
 }
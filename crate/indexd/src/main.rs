@@ -0,0 +1,241 @@
+use common::indexd::{Insert, Inserted, Match, Query, Similar, Snapshotted};
+use common::*;
+use futures::prelude::*;
+use hash_trie::{FileMap, HashTrie};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+use tokio_postgres::types::ToSql;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+mod snapshot;
+
+const CATCH_UP_BATCH: usize = 10_000;
+
+struct Index {
+    trie: HashTrie<FileMap>,
+    ids: HashMap<u64, Vec<i64>>,
+    /// Only advanced by catching up, since inserts can arrive out of order
+    last_id: i64,
+    caught_up: Instant,
+}
+
+type SharedIndex = Arc<RwLock<Index>>;
+
+impl Index {
+    fn open() -> Result<Self, UserError> {
+        let (ids, last_id) = match snapshot::read(&CONFIG.load().indexd.snapshot_path)? {
+            Some(snapshot) => {
+                info!("Warm start from image {}", snapshot.last_id);
+                (snapshot.ids, snapshot.last_id)
+            }
+            None => {
+                // Hashes in a trie without a snapshot have no ids to go with them
+                match std::fs::remove_file(&CONFIG.load().indexd.trie_path) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => {}
+                }
+
+                info!("Cold start, rebuilding from the images table");
+                (HashMap::new(), 0)
+            }
+        };
+
+        Ok(Self {
+            trie: HashTrie::open(&CONFIG.load().indexd.trie_path)?,
+            ids,
+            last_id,
+            caught_up: Instant::now(),
+        })
+    }
+
+    fn insert(&mut self, hash: u64, id: i64) -> bool {
+        let existed = self.trie.insert(hash);
+
+        let ids = self.ids.entry(hash).or_default();
+        if !ids.contains(&id) {
+            ids.push(id);
+        }
+
+        existed
+    }
+
+    fn similar(&self, query: &Query) -> Similar {
+        Similar {
+            stale_secs: self.caught_up.elapsed().as_secs(),
+            matches: self
+                .trie
+                .similar_ordered(query.hash, query.distance)
+                .filter_map(|(hash, distance)| {
+                    self.ids.get(&hash).map(|ids| Match {
+                        hash,
+                        distance,
+                        ids: ids.clone(),
+                    })
+                })
+                .take(query.limit)
+                .collect(),
+        }
+    }
+
+    fn catch_up_with(&mut self, batch: &mut Vec<(u64, i64)>) {
+        for (hash, id) in batch.drain(..) {
+            self.insert(hash, id);
+            self.last_id = self.last_id.max(id);
+        }
+    }
+}
+
+/// Inserts everything in the images table newer than the last id seen
+async fn catch_up(index: &SharedIndex) -> Result<usize, UserError> {
+    let last_id = index.read().await.last_id;
+
+    let client = PG_POOL.get().await?;
+    let mut rows = Box::pin(
+        client
+            .query_raw(
+                "SELECT id, hash FROM images WHERE id > $1 ORDER BY id",
+                std::iter::once(&last_id as &dyn ToSql),
+            )
+            .await?,
+    );
+
+    let mut count = 0;
+    let mut batch = Vec::with_capacity(CATCH_UP_BATCH);
+
+    while let Some(row) = rows.next().await {
+        let row = row?;
+        batch.push((row.get::<_, Hash>("hash").0, row.get("id")));
+
+        // Don't keep queries waiting on the whole table
+        if batch.len() == CATCH_UP_BATCH {
+            count += batch.len();
+            index.write().await.catch_up_with(&mut batch);
+        }
+    }
+
+    count += batch.len();
+    let mut index = index.write().await;
+    index.catch_up_with(&mut batch);
+    index.caught_up = Instant::now();
+
+    Ok(count)
+}
+
+async fn snapshot(index: &SharedIndex) -> Result<Snapshotted, UserError> {
+    let mut index = index.write().await;
+
+    // The trie has to be on disk before the ids that point into it
+    index.trie.flush()?;
+    let hashes = snapshot::write(
+        &CONFIG.load().indexd.snapshot_path,
+        index.last_id,
+        &index.ids,
+    )?;
+
+    Ok(Snapshotted {
+        hashes,
+        last_id: index.last_id,
+    })
+}
+
+async fn maintain(index: SharedIndex) {
+    let mut interval = tokio::time::interval(Duration::from_secs(
+        CONFIG.load().indexd.snapshot_interval_secs,
+    ));
+    // The first tick is immediate, and startup has just caught up
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+
+        match catch_up(&index).await {
+            Ok(count) => info!("Caught up on {} images", count),
+            Err(ue) => error!("Couldn't catch up: {}", ue),
+        }
+
+        match snapshot(&index).await {
+            Ok(snapshotted) => info!("Snapshotted {} hashes", snapshotted.hashes),
+            Err(ue) => error!("Couldn't snapshot: {}", ue),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct IndexdError {
+    error: String,
+}
+
+fn reply<T: Serialize>(result: Result<T, UserError>) -> impl Reply {
+    match result {
+        Ok(body) => warp::reply::with_status(warp::reply::json(&body), StatusCode::OK),
+        Err(ue) => {
+            error!("{}", ue);
+            warp::reply::with_status(
+                warp::reply::json(&IndexdError {
+                    error: ue.user_msg.to_string(),
+                }),
+                ue.status_code(),
+            )
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), UserError> {
+    tracing_subscriber::fmt::init();
+
+    take_path_args();
+
+    let index: SharedIndex = Arc::new(RwLock::new(Index::open()?));
+
+    info!("Caught up on {} images", catch_up(&index).await?);
+
+    tokio::spawn(maintain(index.clone()));
+
+    let with_index = {
+        let index = index.clone();
+        warp::any().map(move || index.clone())
+    };
+
+    let insert = warp::path!("insert")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_index.clone())
+        .and_then(|insert: Insert, index: SharedIndex| async move {
+            let existed = index.write().await.insert(insert.hash, insert.id);
+            Ok::<_, Rejection>(reply(Ok(Inserted { existed })))
+        });
+
+    let similar = warp::path!("similar")
+        .and(warp::get())
+        .and(warp::query::<Query>())
+        .and(with_index.clone())
+        .and_then(|query: Query, index: SharedIndex| async move {
+            Ok::<_, Rejection>(reply(Ok(index.read().await.similar(&query))))
+        });
+
+    let snapshot_route = warp::path!("snapshot")
+        .and(warp::post())
+        .and(with_index)
+        .and_then(
+            |index: SharedIndex| async move { Ok::<_, Rejection>(reply(snapshot(&index).await)) },
+        );
+
+    let (addr, server) = warp::serve(insert.or(similar).or(snapshot_route))
+        .bind_with_graceful_shutdown(CONFIG.load().indexd.addr, async {
+            tokio::signal::ctrl_c().await.ok();
+        });
+
+    info!("Listening on {}", addr);
+    server.await;
+
+    // So the next start is warm
+    let snapshotted = snapshot(&index).await?;
+    info!("Snapshotted {} hashes", snapshotted.hashes);
+
+    Ok(())
+}
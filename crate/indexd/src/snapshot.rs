@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+
+const MAGIC: &[u8; 4] = b"IDXS";
+const VERSION: u16 = 1;
+
+/// The trie only holds hashes, so the ids they came from are kept here
+///
+/// | bytes  | contents                    |
+/// |--------|-----------------------------|
+/// | 0..4   | `IDXS`                      |
+/// | 4..6   | format version              |
+/// | 6..14  | last image id caught up to  |
+/// | 14..22 | pair count                  |
+/// | 22..   | pairs of hash and image id  |
+///
+/// Everything is little-endian.
+pub struct Snapshot {
+    pub last_id: i64,
+    pub ids: HashMap<u64, Vec<i64>>,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn read_8(reader: &mut impl Read) -> io::Result<[u8; 8]> {
+    let mut buf = [0; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Returns `None` if there's no snapshot yet
+pub fn read(path: &str) -> io::Result<Option<Snapshot>> {
+    let mut reader = match File::open(path) {
+        Ok(file) => BufReader::new(file),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let mut head = [0; 6];
+    reader.read_exact(&mut head)?;
+    if &head[0..4] != MAGIC {
+        return Err(invalid("not an indexd snapshot"));
+    }
+    if u16::from_le_bytes(head[4..6].try_into().unwrap()) != VERSION {
+        return Err(invalid("unsupported indexd snapshot version"));
+    }
+
+    let last_id = i64::from_le_bytes(read_8(&mut reader)?);
+    let count = u64::from_le_bytes(read_8(&mut reader)?);
+
+    let mut ids: HashMap<u64, Vec<i64>> = HashMap::new();
+    for _ in 0..count {
+        let hash = u64::from_le_bytes(read_8(&mut reader)?);
+        let id = i64::from_le_bytes(read_8(&mut reader)?);
+        ids.entry(hash).or_default().push(id);
+    }
+
+    Ok(Some(Snapshot { last_id, ids }))
+}
+
+/// Written beside the old snapshot and renamed over it, so a crash leaves one or the other
+pub fn write(path: &str, last_id: i64, ids: &HashMap<u64, Vec<i64>>) -> io::Result<usize> {
+    let temp_path = format!("{}.tmp", path);
+    let count: usize = ids.values().map(Vec::len).sum();
+
+    let mut writer = BufWriter::new(File::create(&temp_path)?);
+    writer.write_all(MAGIC)?;
+    writer.write_all(&VERSION.to_le_bytes())?;
+    writer.write_all(&last_id.to_le_bytes())?;
+    writer.write_all(&(count as u64).to_le_bytes())?;
+
+    for (hash, hash_ids) in ids {
+        for id in hash_ids {
+            writer.write_all(&hash.to_le_bytes())?;
+            writer.write_all(&id.to_le_bytes())?;
+        }
+    }
+
+    writer.into_inner()?.sync_all()?;
+    std::fs::rename(temp_path, path)?;
+
+    Ok(count)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let path = "/tmp/test.indexd_snapshot";
+
+        let mut ids = HashMap::new();
+        ids.insert(0, vec![1, 2]);
+        ids.insert(u64::MAX, vec![3]);
+
+        assert_eq!(write(path, 3, &ids).unwrap(), 3);
+
+        let snapshot = read(path).unwrap().unwrap();
+        assert_eq!(snapshot.last_id, 3);
+        assert_eq!(snapshot.ids, ids);
+
+        std::fs::remove_file(path).unwrap();
+        assert!(read(path).unwrap().is_none());
+    }
+}
@@ -1,18 +1,18 @@
 #![recursion_limit = "128"]
 
-mod worker_limit;
 use chrono::prelude::*;
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use common::*;
 use dashmap::DashMap;
 use future::poll_fn;
+use futures::future::BoxFuture;
 use futures::prelude::*;
 use futures::task::Poll;
 use once_cell::sync::Lazy;
 use regex::Regex;
+use serde::de::DeserializeOwned;
 use serde_json::Deserializer;
 use std::borrow::Cow;
-use std::collections::BTreeSet;
 use std::convert::TryInto;
 use std::error::Error as _;
 use std::fs::{remove_file, File};
@@ -23,7 +23,6 @@ use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use tokio::io::AsyncWriteExt;
 use tokio::time::{interval_at, Duration, Instant};
-use tokio_postgres::types::ToSql;
 use tracing_futures::Instrument;
 use url::Url;
 
@@ -31,53 +30,67 @@ static POST_COUNT: AtomicU64 = AtomicU64::new(0);
 static POSTS_PER_MINUTE: AtomicU64 = AtomicU64::new(0);
 
 struct IngestInfo {
+    /// The archive's file name, which its ingest_progress row is keyed by
+    archive: String,
     month: u32,
     year: i32,
-    already_have: Option<BTreeSet<i64>>,
+    already_have: Option<IdSet>,
 }
 
-async fn ingest_post(
-    post: Submission,
+/// Hashes the image at `post_url`; `link` is what it came from, and `source` is only
+/// for reporting internal errors. `post` is the post it's from, if it's from one; its preview and
+/// thumbnail are tried if `post_url` fails.
+async fn hash_link(
+    link: &str,
+    post_url: Result<Url, UserError>,
+    source: &(dyn std::fmt::Debug + Sync),
+    post: Option<&Submission>,
     verbose: bool,
-    blacklist: &DashMap<String, ()>,
     domains_in_flight: &DashMap<String, u32>,
-) {
-    if verbose {
-        info!("Starting to ingest {}", post.url);
-    }
+) -> Result<i64, Option<SaveError>> {
+    // The state file keeps being written while this waits, so a restart picks up here
+    wait_for_bandwidth().await;
 
-    let post_url_res = (|| async {
-        let post_url = post.choose_url()?;
+    let _in_flight = HEALTH.start();
 
-        if get_host(post_url.as_str())
-            .map(|host| blacklist.contains_key(&host))
-            .unwrap_or(false)
-        {
-            return Err(ue_save!("blacklisted", "blacklisted"));
-        }
-
-        if CONFIG
-            .banned
-            .iter()
-            .any(|banned| banned.matches(post_url.as_str()))
-        {
-            return Err(ue_save!("banned", "banned"));
-        }
+    if verbose {
+        info!("Starting to ingest {}", link);
+    }
 
-        Ok(post_url)
-    })()
-    .await;
+    let post_url_res = match post_url {
+        Ok(post_url) => match banned_by(post_url.as_str()).await {
+            Some(banned) => {
+                if let Some(post) = post {
+                    record_event(
+                        post.id_int,
+                        IngestDecision::Banned,
+                        None,
+                        Some(&banned.to_string()),
+                    );
+                }
+                Err(ue_save!(format!("banned by {}", banned), SaveError::Banned))
+            }
+            None => match get_host(post_url.as_str()) {
+                Some(host) => wait_for_host(&host).await.map(|()| post_url),
+                None => Ok(post_url),
+            },
+        },
+        res => res,
+    };
 
     let save_res = match post_url_res {
         Ok(post_url) => {
             let host = post_url.host_str().unwrap();
 
-            let custom_limit: Option<&Option<_>> = CONFIG.custom_limits.get(host);
+            let limit = {
+                let config = CONFIG.load();
+                let custom_limit: Option<&Option<_>> = config.custom_limits.get(host);
 
-            let limit = match custom_limit {
-                None => Some(CONFIG.domains_in_flight_limit),
-                Some(&Some(limit)) => Some(limit),
-                Some(&None) => None,
+                match custom_limit {
+                    None => Some(config.domains_in_flight_limit),
+                    Some(&Some(limit)) => Some(limit),
+                    Some(&None) => None,
+                }
             };
 
             poll_fn(|context| {
@@ -114,7 +127,12 @@ async fn ingest_post(
         Err(e) => Err(e),
     };
 
-    let image_id = match save_res {
+    let save_res = match (save_res, post) {
+        (Err(ue), Some(post)) => post.save_fallback_hash(ue).await,
+        (res, _) => res,
+    };
+
+    match save_res {
         Ok(hash_gotten) => {
             if verbose {
                 info!("successfully hashed");
@@ -125,7 +143,7 @@ async fn ingest_post(
         Err(ue) => match ue.source {
             Source::Internal => {
                 eprintln!(
-                    "{}{}{}\n{:#?}\n{:#?}",
+                    "{}{}{}\n{:?}\n{:#?}",
                     ue.file.unwrap_or(""),
                     ue.line
                         .map(|line| Cow::Owned(format!("#{}", line)))
@@ -136,7 +154,7 @@ async fn ingest_post(
                         ""
                     },
                     ue.error,
-                    post
+                    source
                 );
                 std::process::exit(1)
             }
@@ -146,26 +164,16 @@ async fn ingest_post(
                         let hyper_error =
                             e.source().and_then(|he| he.downcast_ref::<hyper::Error>());
 
-                        if e.is_timeout() || hyper_error.is_some() {
-                            if let Ok(url) = Url::parse(&post.url) {
-                                if let Some(host) = url.host_str() {
-                                    if !CONFIG.no_blacklist.iter().any(|n| host.ends_with(n)) {
-                                        blacklist.insert(host.to_string(), ());
-                                    }
-                                }
-                            }
-                        }
-
                         e.status()
-                            .map(|status| format!("http_{}", status.as_str()).into())
+                            .map(|status| SaveError::Http(status.as_u16()))
                             .or_else(|| {
                                 if e.is_timeout() {
-                                    Some("timeout".into())
+                                    Some(SaveError::Timeout)
                                 } else {
                                     None
                                 }
                             })
-                            .or_else(|| hyper_error.map(|_| "hyper".into()))
+                            .or_else(|| hyper_error.map(|_| SaveError::Hyper))
                     }
                     None => None,
                 };
@@ -178,18 +186,102 @@ async fn ingest_post(
                         .as_ref()
                         .map(|se| Cow::Owned(format!(" ({})", se)))
                         .unwrap_or_else(|| Cow::Borrowed("")),
-                    ue.error
+                    ue
                 );
 
                 Err(save_error)
             }
         },
+    }
+}
+
+/// Saves `post` as deferred and queues its link for the fetcher, unless there's no link to queue
+async fn defer_post(post: Submission) {
+    let link = post.choose_url();
+    let image_id = match &link {
+        Ok(_) => Err(Some(SaveError::Deferred)),
+        Err(ue) => Err(ue.save_error),
+    };
+
+    let queued = async {
+        let saved = post.save(image_id).await?;
+        if let (Saved::Inserted, Ok(link)) = (&saved, &link) {
+            enqueue(&post, link.as_str()).await?;
+        }
+        Ok::<_, UserError>(saved)
+    }
+    .await;
+
+    match queued {
+        Ok(saved) => {
+            POST_COUNT.fetch_add(1, Ordering::SeqCst);
+            match saved {
+                Saved::Inserted => info!("saved; hashing deferred"),
+                Saved::Updated => info!("already have; updated"),
+                Saved::Skipped => info!("already have"),
+            }
+        }
+        Err(e) => {
+            error!("post \n{:#?} \nfailed to save:\n{:?}", post, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+async fn ingest_post(
+    post: Submission,
+    verbose: bool,
+    defer_hashing: bool,
+    domains_in_flight: &DashMap<String, u32>,
+    writer: &db::PostWriter,
+) {
+    if defer_hashing {
+        return defer_post(post).await;
+    }
+
+    let claim = match claim_post(post.id_int).await {
+        Ok(Some(claim)) => claim,
+        Ok(None) => {
+            info!("another ingester has it");
+            return;
+        }
+        Err(e) => {
+            error!("post \n{:#?} \nfailed to be claimed:\n{:?}", post, e);
+            std::process::exit(1);
+        }
     };
 
+    let image_id = hash_link(
+        &post.url,
+        post.choose_url(),
+        &post,
+        Some(&post),
+        verbose,
+        domains_in_flight,
+    )
+    .await;
+    claim.release().await;
+
+    HEALTH.record(image_id.is_ok());
+
+    // Posts that hashed are saved in batches; the rest are rare enough to save one by one
+    if let Ok(image_id) = image_id {
+        if let Err(e) = writer.save(post, image_id).await {
+            error!("post failed to queue for saving:\n{:?}", e);
+            std::process::exit(1);
+        }
+        POST_COUNT.fetch_add(1, Ordering::SeqCst);
+        return;
+    }
+
     match post.save(image_id).await {
-        Ok(_) => {
+        Ok(saved) => {
             POST_COUNT.fetch_add(1, Ordering::SeqCst);
-            info!("successfully saved");
+            match saved {
+                Saved::Inserted => info!("successfully saved"),
+                Saved::Updated => info!("already have; updated"),
+                Saved::Skipped => info!("already have"),
+            }
         }
         Err(e) => {
             error!("post \n{:#?} \nfailed to save:\n{:?}", post, e);
@@ -198,16 +290,145 @@ async fn ingest_post(
     }
 }
 
-async fn ingest_json<R: Read + 'static>(
+async fn ingest_comment(comment: Comment, verbose: bool, domains_in_flight: &DashMap<String, u32>) {
+    for link in comment.image_links() {
+        let image_id = hash_link(
+            &link,
+            Url::parse(&link).map_err(map_ue_save!("invalid URL", SaveError::UrlInvalid)),
+            &comment,
+            None,
+            verbose,
+            domains_in_flight,
+        )
+        .await;
+
+        HEALTH.record(image_id.is_ok());
+
+        if let Err(e) = comment.save(&link, image_id).await {
+            error!("comment \n{:#?} \nfailed to save:\n{:?}", comment, e);
+            std::process::exit(1);
+        }
+    }
+
+    POST_COUNT.fetch_add(1, Ordering::SeqCst);
+}
+
+type DomainsInFlight = Arc<DashMap<String, u32>>;
+
+/// What an archive holds one of per line
+trait Item: DeserializeOwned + Send + Sized + 'static {
+    /// Selects the ids of items already saved from a range of `created_utc`, as $1 and $2,
+    /// a page at a time for `IdSet::fetch`
+    const ALREADY_HAVE_SQL: &'static str;
+
+    fn finalize(self) -> Result<Self, UserError>;
+    fn desirable(&self) -> bool;
+    /// Notes what happened to the item in ingest_events, if its kind is recorded there
+    fn record_event(&self, _decision: IngestDecision) {}
+    fn id_int(&self) -> i64;
+    fn created_utc(&self) -> NaiveDateTime;
+    fn span(&self) -> tracing::Span;
+    /// Saves the item, hashing its images unless `defer_hashing`
+    fn ingest(
+        self,
+        verbose: bool,
+        defer_hashing: bool,
+        domains_in_flight: DomainsInFlight,
+        writer: db::PostWriter,
+    ) -> BoxFuture<'static, ()>;
+}
+
+impl Item for Submission {
+    const ALREADY_HAVE_SQL: &'static str = "SELECT reddit_id_int FROM posts \
+         WHERE created_utc >= $1 and created_utc < $2 AND reddit_id_int > $3 \
+         ORDER BY reddit_id_int LIMIT $4";
+
+    fn finalize(self) -> Result<Self, UserError> {
+        Submission::finalize(self)
+    }
+    fn desirable(&self) -> bool {
+        Submission::desirable(self)
+    }
+    fn record_event(&self, decision: IngestDecision) {
+        record_event(self.id_int, decision, None, None);
+    }
+    fn id_int(&self) -> i64 {
+        self.id_int
+    }
+    fn created_utc(&self) -> NaiveDateTime {
+        self.created_utc
+    }
+    fn span(&self) -> tracing::Span {
+        info_span!(
+            "ingest_post",
+            id = self.id.as_str(),
+            date = self.created_utc.to_string().as_str(),
+            url = self.url.as_str()
+        )
+    }
+    fn ingest(
+        self,
+        verbose: bool,
+        defer_hashing: bool,
+        domains_in_flight: DomainsInFlight,
+        writer: db::PostWriter,
+    ) -> BoxFuture<'static, ()> {
+        Box::pin(async move {
+            ingest_post(self, verbose, defer_hashing, &domains_in_flight, &writer).await;
+        })
+    }
+}
+
+impl Item for Comment {
+    const ALREADY_HAVE_SQL: &'static str = "SELECT DISTINCT reddit_id_int FROM comment_images \
+         WHERE created_utc >= $1 and created_utc < $2 AND reddit_id_int > $3 \
+         ORDER BY reddit_id_int LIMIT $4";
+
+    fn finalize(self) -> Result<Self, UserError> {
+        Comment::finalize(self)
+    }
+    fn desirable(&self) -> bool {
+        !self.image_links().is_empty()
+    }
+    fn id_int(&self) -> i64 {
+        self.id_int
+    }
+    fn created_utc(&self) -> NaiveDateTime {
+        self.created_utc
+    }
+    fn span(&self) -> tracing::Span {
+        info_span!(
+            "ingest_comment",
+            id = self.id.as_str(),
+            date = self.created_utc.to_string().as_str(),
+        )
+    }
+    fn ingest(
+        self,
+        verbose: bool,
+        _defer_hashing: bool,
+        domains_in_flight: DomainsInFlight,
+        _writer: db::PostWriter,
+    ) -> BoxFuture<'static, ()> {
+        Box::pin(async move {
+            ingest_comment(self, verbose, &domains_in_flight).await;
+        })
+    }
+}
+
+async fn ingest_json<T: Item, R: Read + 'static>(
     verbose: bool,
+    defer_hashing: bool,
+    write_mode: db::WriteMode,
     IngestInfo {
+        archive,
         month,
         year,
         mut already_have,
     }: IngestInfo,
     json_stream: R,
-) {
-    let json_iter = Deserializer::from_reader(json_stream).into_iter::<Submission>();
+) -> Result<(), UserError> {
+    let json_iter = Deserializer::from_reader(json_stream).into_iter::<T>();
 
     let mut ff_day = None;
 
@@ -232,12 +453,15 @@ async fn ingest_json<R: Read + 'static>(
             && match already_have {
                 None => true,
                 Some(ref mut set) => {
-                    let day = post.created_utc.day();
+                    let day = post.created_utc().day();
                     if ff_day.map(|ff_day| day > ff_day).unwrap_or(true) {
-                        info!("Fast forwarding through {}", post.created_utc.date());
+                        info!("Fast forwarding through {}", post.created_utc().date());
                         ff_day = Some(day);
                     }
-                    let had = set.remove(&post.id_int);
+                    let had = set.remove(post.id_int());
+                    if had {
+                        post.record_event(IngestDecision::AlreadyHad);
+                    }
                     if set.is_empty() {
                         info!("Done fast forwarding!");
                         already_have = None;
@@ -252,6 +476,23 @@ async fn ingest_json<R: Read + 'static>(
         }
     });
 
+    let writer = db::PostWriter::spawn(write_mode);
+
+    let interrupt_writer = writer.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            info!("Saving queued posts before exiting");
+            if let Err(e) = interrupt_writer.flush().await {
+                error!("Couldn't save queued posts: {:?}", e);
+            }
+            if let Err(e) = flush_ingest_events().await {
+                error!("Couldn't save ingest events: {:?}", e);
+            }
+            std::process::exit(130);
+        }
+    });
+
+    let checkpoint_writer = writer.clone();
     tokio::spawn(async move {
         let minute = Duration::from_secs(60);
 
@@ -273,74 +514,102 @@ async fn ingest_json<R: Read + 'static>(
 
             POSTS_PER_MINUTE.store(current_speed, Ordering::SeqCst);
 
+            if let Err(e) = checkpoint_writer.flush().await {
+                error!("Couldn't save queued posts: {:?}", e);
+                std::process::exit(1);
+            }
+
+            let state = IngestState {
+                as_of: Utc::now().naive_utc(),
+                month,
+                year,
+                posts_per_minute: current_speed,
+                limited: concurrency::is_limited(),
+                bandwidth_paused: over_bandwidth_cap(),
+            };
+
             let pretty_config = ron::ser::PrettyConfig::new();
 
+            let state_path = CONFIG.load().state_file.clone();
             let mut state_file = tokio::fs::OpenOptions::new()
                 .write(true)
                 .truncate(true)
                 .create(true)
-                .open(&CONFIG.state_file)
+                .open(&state_path)
                 .await
                 .map_err(map_ue!())
                 .unwrap();
             state_file.set_len(0).await.map_err(map_ue!()).unwrap();
             state_file
                 .write_all(
-                    ron::ser::to_string_pretty(
-                        &IngestState {
-                            as_of: Utc::now().naive_utc(),
-                            month,
-                            year,
-                            posts_per_minute: current_speed,
-                            limited: worker_limit::is_limited(),
-                        },
-                        pretty_config,
-                    )
-                    .map_err(map_ue!())
-                    .unwrap()
-                    .as_bytes(),
+                    ron::ser::to_string_pretty(&state, pretty_config)
+                        .map_err(map_ue!())
+                        .unwrap()
+                        .as_bytes(),
                 )
                 .await
                 .map_err(map_ue!())
                 .unwrap();
+
+            if let Err(e) = update_progress(&archive, &state, current_count).await {
+                warn!("Couldn't update ingest progress: {}", e);
+            }
             previous_count = current_count;
             previous_time = current_time;
         }
     });
 
-    let blacklist = Arc::new(DashMap::<String, ()>::new());
-    let domains_in_flight = Arc::new(DashMap::<String, u32>::new());
+    let domains_in_flight: DomainsInFlight = Arc::new(DashMap::new());
 
     info!("Starting ingestion!");
 
-    worker_limit::BufferUnordered::new(futures::stream::iter(json_iter.map(|post| {
-        let blacklist = blacklist.clone();
+    concurrency::BufferUnordered::new(futures::stream::iter(json_iter.map(|post| {
         let domains_in_flight = domains_in_flight.clone();
+        let writer = writer.clone();
 
-        tokio::spawn(Box::pin(async move {
-            let span = info_span!(
-                "ingest_post",
-                id = post.id.as_str(),
-                date = post.created_utc.to_string().as_str(),
-                url = post.url.as_str()
-            );
-            ingest_post(post, verbose, &blacklist, &domains_in_flight)
-                .instrument(span)
-                .await;
-        }))
+        let span = post.span();
+        tokio::spawn(
+            post.ingest(verbose, defer_hashing, domains_in_flight, writer)
+                .instrument(span),
+        )
     })))
     .map(|t| t.unwrap())
     .collect::<()>()
-    .await
+    .await;
+
+    writer.flush().await?;
+    flush_ingest_events().await
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Kind {
+    Submissions,
+    /// Saves image links found in comment bodies to comment_images
+    Comments,
 }
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = "none")]
 struct Cli {
+    #[arg(long, value_enum, default_value_t = Kind::Submissions)]
+    kind: Kind,
     #[arg(long, short = 'D')]
     no_delete: bool,
     #[arg(long, short)]
     verbose: bool,
+    /// Saves posts by COPYing them into a staging table, for faster backfills
+    #[arg(long)]
+    copy: bool,
+    /// Saves posts as deferred without fetching their images, queueing their links in
+    /// fetch_queue for the fetcher to hash later. Submissions only.
+    #[arg(long)]
+    defer_hashing: bool,
+    /// Overrides TIDDER_CONFIG
+    #[arg(long)]
+    config: Option<std::path::PathBuf>,
+    /// Overrides TIDDER_SECRETS
+    #[arg(long)]
+    secrets: Option<std::path::PathBuf>,
     path: String,
 }
 
@@ -353,7 +622,32 @@ async fn main() -> Result<(), UserError> {
 
     let args = Cli::parse();
 
+    if let Some(config_path) = &args.config {
+        config::set_path(config_path);
+    }
+    if let Some(secrets_path) = &args.secrets {
+        secrets::set_path(secrets_path);
+    }
+
+    reload_config_on_sighup()?;
+    serve_health("ingest").await?;
+    flush_bandwidth().await?;
+    flush_host_health().await?;
+    start_ingest_events("ingest");
+
     let verbose = args.verbose;
+    let defer_hashing = args.defer_hashing;
+    if defer_hashing && matches!(args.kind, Kind::Comments) {
+        return Err(ue!(
+            "--defer-hashing only works with submissions",
+            Source::User
+        ));
+    }
+    let write_mode = if args.copy {
+        db::WriteMode::Copy
+    } else {
+        db::WriteMode::Insert
+    };
     let path = args.path;
 
     let (year, month, day): (i32, u32, Option<u32>) = DATE_RE
@@ -401,6 +695,12 @@ async fn main() -> Result<(), UserError> {
 
     info!("Ingesting {}", path);
 
+    let archive = Path::new(&path)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.clone());
+    start_progress(&archive, month, year).await?;
+
     let (input_file, arch_path): (File, _) =
         if path.starts_with("http://") || path.starts_with("https://") {
             let arch_path = std::env::var("HOME")?
@@ -448,23 +748,11 @@ async fn main() -> Result<(), UserError> {
 
     info!("Processing posts we already have");
 
-    let client = PG_POOL.get().await?;
-    let already_have = client
-        .query_raw(
-            "SELECT reddit_id_int FROM posts \
-             WHERE created_utc >= $1 and created_utc < $2",
-            [&date as &dyn ToSql, &next_date as &dyn ToSql]
-                .iter()
-                .copied(),
-        )
-        .await?
-        .try_fold(BTreeSet::new(), move |mut already_have, row| async move {
-            already_have.insert(row.get(0));
-            Ok(already_have)
-        })
-        .await?;
-
-    drop(client);
+    let already_have_sql = match args.kind {
+        Kind::Submissions => Submission::ALREADY_HAVE_SQL,
+        Kind::Comments => Comment::ALREADY_HAVE_SQL,
+    };
+    let already_have = IdSet::fetch(already_have_sql, date, next_date).await?;
 
     let already_have_len = already_have.len();
     info!(
@@ -482,25 +770,51 @@ async fn main() -> Result<(), UserError> {
     let input = BufReader::new(input_file);
 
     let ingest_info = IngestInfo {
+        archive: archive.clone(),
         month,
         year,
         already_have,
     };
 
-    if path.ends_with("bz2") {
-        ingest_json(verbose, ingest_info, bzip2::bufread::BzDecoder::new(input)).await;
+    let json_stream: Box<dyn Read> = if path.ends_with("bz2") {
+        Box::new(bzip2::bufread::BzDecoder::new(input))
     } else if path.ends_with("xz") {
-        ingest_json(verbose, ingest_info, xz2::bufread::XzDecoder::new(input)).await;
+        Box::new(xz2::bufread::XzDecoder::new(input))
     } else if path.ends_with("zst") {
         let mut zstd_decoder = zstd::Decoder::new(input)?;
         zstd_decoder.set_parameter(zstd::stream::raw::DParameter::WindowLogMax(31))?;
-        ingest_json(verbose, ingest_info, zstd_decoder).await;
+        Box::new(zstd_decoder)
     } else if path.ends_with("gz") {
-        ingest_json(verbose, ingest_info, flate2::bufread::GzDecoder::new(input)).await;
+        Box::new(flate2::bufread::GzDecoder::new(input))
     } else {
-        ingest_json(verbose, ingest_info, input).await;
+        Box::new(input)
+    };
+
+    let ingested = match args.kind {
+        Kind::Submissions => {
+            ingest_json::<Submission, _>(
+                verbose,
+                defer_hashing,
+                write_mode,
+                ingest_info,
+                json_stream,
+            )
+            .await
+        }
+        Kind::Comments => {
+            ingest_json::<Comment, _>(verbose, defer_hashing, write_mode, ingest_info, json_stream)
+                .await
+        }
     };
 
+    finish_progress(
+        &archive,
+        ingested.is_ok(),
+        POST_COUNT.load(Ordering::SeqCst),
+    )
+    .await?;
+    ingested?;
+
     if !args.no_delete {
         if let Some(arch_path) = arch_path {
             remove_file(arch_path)?;
@@ -0,0 +1,73 @@
+use common::*;
+
+/// Stands in for a ban kind to mean a host end in no_blacklist instead
+const NO_BLACKLIST: &str = "NoBlacklist";
+
+pub async fn add(kind: &str, value: &str) -> Result<(), UserError> {
+    let added = if kind == NO_BLACKLIST {
+        add_no_blacklist(value).await?
+    } else {
+        ban_link(&Banned::from_parts(kind, value.to_string())?).await?
+    };
+
+    println!("{}", if added { "Added" } else { "Already there" });
+
+    Ok(())
+}
+
+pub async fn remove(kind: &str, value: &str) -> Result<(), UserError> {
+    let removed = if kind == NO_BLACKLIST {
+        remove_no_blacklist(value).await?
+    } else {
+        unban(&Banned::from_parts(kind, value.to_string())?).await?
+    };
+
+    println!("{}", if removed { "Removed" } else { "Not there" });
+
+    Ok(())
+}
+
+/// Prints each ban and no_blacklist host, written as they'd be added
+pub async fn list() -> Result<(), UserError> {
+    let lists = stored_lists().await?;
+
+    for banned in &lists.banned {
+        println!("{}", banned);
+    }
+    for host_end in &lists.no_blacklist {
+        println!("{}({:?})", NO_BLACKLIST, host_end);
+    }
+
+    Ok(())
+}
+
+/// Adds the lists in the RON file at `path`, which can be a tidder.ron from before they were
+/// kept in the database
+pub async fn import(path: &str) -> Result<(), UserError> {
+    let lists: BanLists = ron::from_str(&std::fs::read_to_string(path)?)
+        .map_err(map_ue!("couldn't parse the lists", Source::User))?;
+
+    let (banned, no_blacklist) = import_lists(&lists).await?;
+
+    println!(
+        "Added {} of {} bans and {} of {} no_blacklist hosts",
+        banned,
+        lists.banned.len(),
+        no_blacklist,
+        lists.no_blacklist.len()
+    );
+
+    Ok(())
+}
+
+/// Writes the lists as RON that `import` reads, to `path` or else stdout
+pub async fn export(path: Option<&str>) -> Result<(), UserError> {
+    let out = ron::ser::to_string_pretty(&stored_lists().await?, Default::default())?;
+
+    match path {
+        Some(path) => std::fs::write(path, out)?,
+        None => println!("{}", out),
+    }
+
+    Ok(())
+}
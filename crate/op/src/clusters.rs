@@ -0,0 +1,229 @@
+use common::*;
+use hash_trie::HashTrie;
+use serde::Serialize;
+use std::io::{BufWriter, Write};
+
+const TOP_SUBREDDITS: usize = 5;
+
+#[derive(Clone, Copy)]
+pub enum Format {
+    Csv,
+    Ron,
+}
+
+impl std::str::FromStr for Format {
+    type Err = UserError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "ron" => Ok(Format::Ron),
+            _ => Err(ue!(format!("unknown format {}", s))),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct SubredditCount {
+    subreddit: String,
+    posts: i64,
+}
+
+#[derive(Serialize)]
+struct Cluster {
+    id: usize,
+    hashes: usize,
+    posts: i64,
+    subreddits: usize,
+    top_subreddits: Vec<SubredditCount>,
+    /// The link of the earliest post
+    representative: Option<String>,
+    first_post: Option<chrono::NaiveDateTime>,
+}
+
+/// Union-find over indices into the sorted hashes
+struct Components {
+    parents: Vec<u32>,
+}
+
+impl Components {
+    fn new(len: usize) -> Self {
+        Self {
+            parents: (0..len as u32).collect(),
+        }
+    }
+
+    fn root(&mut self, mut index: u32) -> u32 {
+        while self.parents[index as usize] != index {
+            let grandparent = self.parents[self.parents[index as usize] as usize];
+            self.parents[index as usize] = grandparent;
+            index = grandparent;
+        }
+        index
+    }
+
+    fn union(&mut self, a: u32, b: u32) {
+        let (a, b) = (self.root(a), self.root(b));
+        if a != b {
+            // Lower roots win so the result doesn't depend on the order of unions
+            self.parents[a.max(b) as usize] = a.min(b);
+        }
+    }
+}
+
+/// Splits `order` wherever the root changes
+fn runs<'a>(order: &'a [u32], roots: &'a [u32]) -> impl Iterator<Item = &'a [u32]> {
+    let mut rest = order;
+    std::iter::from_fn(move || {
+        let root = roots[*rest.first()? as usize];
+        let len = rest
+            .iter()
+            .position(|&i| roots[i as usize] != root)
+            .unwrap_or(rest.len());
+        let (run, tail) = rest.split_at(len);
+        rest = tail;
+        Some(run)
+    })
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains(&[',', '"', '\n'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+async fn describe(id: usize, hashes: &[i64]) -> Result<Cluster, UserError> {
+    let client = PG_POOL.get().await?;
+
+    let subreddit_rows = client
+        .query(
+            "SELECT subreddit, COUNT(*) AS posts \
+             FROM posts INNER JOIN images ON image_id = images.id \
+             WHERE hash = ANY($1) \
+             GROUP BY subreddit ORDER BY posts DESC",
+            &[&hashes],
+        )
+        .await?;
+
+    let first = client
+        .query_opt(
+            "SELECT images.link, created_utc \
+             FROM posts INNER JOIN images ON image_id = images.id \
+             WHERE hash = ANY($1) \
+             ORDER BY created_utc ASC LIMIT 1",
+            &[&hashes],
+        )
+        .await?;
+
+    Ok(Cluster {
+        id,
+        hashes: hashes.len(),
+        posts: subreddit_rows
+            .iter()
+            .map(|row| row.get::<_, i64>("posts"))
+            .sum(),
+        subreddits: subreddit_rows.len(),
+        top_subreddits: subreddit_rows
+            .iter()
+            .take(TOP_SUBREDDITS)
+            .map(|row| SubredditCount {
+                subreddit: row.get("subreddit"),
+                posts: row.get("posts"),
+            })
+            .collect(),
+        representative: first.as_ref().map(|row| row.get("link")),
+        first_post: first.as_ref().map(|row| row.get("created_utc")),
+    })
+}
+
+/// Groups the trie's hashes into connected components where each link is within `distance`
+pub async fn clusters(
+    path: &str,
+    output: &str,
+    distance: u8,
+    min_size: usize,
+    format: Format,
+) -> Result<(), UserError> {
+    let trie = HashTrie::open_readonly(path)?;
+
+    // Sorted, so a hash's position doubles as its id without a map
+    let mut hashes: Vec<u64> = trie.hashes().collect();
+    hashes.sort_unstable();
+    println!("Clustering {} hashes...", hashes.len());
+
+    let mut components = Components::new(hashes.len());
+
+    for (index, &hash) in hashes.iter().enumerate() {
+        for similar in trie.similar(hash, distance) {
+            // Each pair only needs joining once
+            if similar > hash {
+                let other = hashes.binary_search(&similar).unwrap();
+                components.union(index as u32, other as u32);
+            }
+        }
+    }
+
+    // Members of each component end up next to each other
+    let mut order: Vec<u32> = (0..hashes.len() as u32).collect();
+    let roots: Vec<u32> = order.iter().map(|&i| components.root(i)).collect();
+    order.sort_unstable_by_key(|&i| roots[i as usize]);
+    drop(components);
+
+    let mut out = BufWriter::new(std::fs::File::create(output)?);
+    match format {
+        Format::Csv => writeln!(
+            out,
+            "cluster,hashes,posts,subreddits,top_subreddits,representative,first_post"
+        )?,
+        Format::Ron => writeln!(out, "[")?,
+    }
+
+    let mut cluster_count = 0;
+
+    for members in runs(&order, &roots) {
+        if members.len() < min_size {
+            continue;
+        }
+
+        let member_hashes: Vec<i64> = members.iter().map(|&i| hashes[i as usize] as i64).collect();
+
+        let cluster = describe(cluster_count, &member_hashes).await?;
+        cluster_count += 1;
+
+        match format {
+            Format::Csv => writeln!(
+                out,
+                "{},{},{},{},{},{},{}",
+                cluster.id,
+                cluster.hashes,
+                cluster.posts,
+                cluster.subreddits,
+                csv_field(
+                    &cluster
+                        .top_subreddits
+                        .iter()
+                        .map(|s| format!("{}:{}", s.subreddit, s.posts))
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                ),
+                csv_field(cluster.representative.as_deref().unwrap_or("")),
+                cluster
+                    .first_post
+                    .map(|dt| dt.to_string())
+                    .unwrap_or_default(),
+            )?,
+            Format::Ron => writeln!(out, "    {},", ron::to_string(&cluster)?)?,
+        }
+    }
+
+    if let Format::Ron = format {
+        writeln!(out, "]")?;
+    }
+    out.flush()?;
+
+    println!("Wrote {} clusters to {}", cluster_count, output);
+
+    Ok(())
+}
@@ -0,0 +1,393 @@
+use common::*;
+use flate2::write::GzEncoder;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tokio_postgres::types::ToSql;
+use tokio_postgres::Row;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Int,
+    Text,
+    Bool,
+    Time,
+}
+
+type Column = (&'static str, Kind);
+
+const POSTS_COLUMNS: &[Column] = &[
+    ("id", Kind::Int),
+    ("reddit_id", Kind::Text),
+    ("link", Kind::Text),
+    ("permalink", Kind::Text),
+    ("author", Kind::Text),
+    ("score", Kind::Int),
+    ("created_utc", Kind::Time),
+    ("subreddit", Kind::Text),
+    ("title", Kind::Text),
+    ("nsfw", Kind::Bool),
+    ("spoiler", Kind::Bool),
+    ("image_id", Kind::Int),
+    ("thumbnail", Kind::Text),
+    ("thumbnail_width", Kind::Int),
+    ("thumbnail_height", Kind::Int),
+    ("crosspost_parent", Kind::Int),
+    ("is_video", Kind::Bool),
+    ("preview", Kind::Text),
+];
+
+const IMAGES_COLUMNS: &[Column] = &[
+    ("id", Kind::Int),
+    ("link", Kind::Text),
+    ("hash", Kind::Int),
+    ("hash128_hi", Kind::Int),
+    ("hash128_lo", Kind::Int),
+    ("hash_version", Kind::Int),
+    ("retrieved_on", Kind::Time),
+];
+
+const MATCHES_COLUMNS: &[Column] = &[
+    ("image_a", Kind::Int),
+    ("image_b", Kind::Int),
+    ("distance", Kind::Int),
+];
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    Csv,
+    Parquet,
+}
+
+impl Format {
+    fn extension(self) -> &'static str {
+        match self {
+            Format::Csv => "csv.gz",
+            Format::Parquet => "parquet",
+        }
+    }
+}
+
+impl std::str::FromStr for Format {
+    type Err = UserError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "parquet" if cfg!(feature = "parquet") => Ok(Format::Parquet),
+            "parquet" => Err(ue!("op was built without the parquet feature")),
+            _ => Err(ue!(format!("unknown format {}", s))),
+        }
+    }
+}
+
+pub struct Options<'a> {
+    pub dir: &'a str,
+    pub format: Format,
+    pub tables: Vec<&'a str>,
+    pub posts_columns: Option<&'a str>,
+    pub images_columns: Option<&'a str>,
+    pub distance: u8,
+    /// How many ids each chunk covers; chunks only change when rows in their range do
+    pub chunk_size: i64,
+}
+
+enum Value {
+    Null,
+    Int(i64),
+    Text(String),
+    Bool(bool),
+    Time(chrono::NaiveDateTime),
+}
+
+fn select_columns(all: &[Column], wanted: Option<&str>) -> Result<Vec<Column>, UserError> {
+    match wanted {
+        None => Ok(all.to_vec()),
+        Some(wanted) => wanted
+            .split(',')
+            .map(|name| {
+                all.iter()
+                    .find(|(column, _)| *column == name.trim())
+                    .copied()
+                    .ok_or_else(|| ue!(format!("unknown column {}", name)))
+            })
+            .collect(),
+    }
+}
+
+fn select_list(table: &str, columns: &[Column]) -> String {
+    columns
+        .iter()
+        .map(|(name, kind)| match kind {
+            // Some are integer rather than bigint
+            Kind::Int => format!("{}.{}::bigint AS {}", table, name, name),
+            _ => format!("{}.{}", table, name),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn read_row(row: &Row, columns: &[Column]) -> Vec<Value> {
+    columns
+        .iter()
+        .map(|&(name, kind)| match kind {
+            Kind::Int => row.get::<_, Option<i64>>(name).map(Value::Int),
+            Kind::Text => row.get::<_, Option<String>>(name).map(Value::Text),
+            Kind::Bool => row.get::<_, Option<bool>>(name).map(Value::Bool),
+            Kind::Time => row
+                .get::<_, Option<chrono::NaiveDateTime>>(name)
+                .map(Value::Time),
+        })
+        .map(|value| value.unwrap_or(Value::Null))
+        .collect()
+}
+
+fn csv_value(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::Int(i) => i.to_string(),
+        Value::Bool(b) => b.to_string(),
+        Value::Time(t) => t.format("%F %T").to_string(),
+        Value::Text(s) if s.contains(&[',', '"', '\n', '\r'][..]) => {
+            format!("\"{}\"", s.replace('"', "\"\""))
+        }
+        Value::Text(s) => s.clone(),
+    }
+}
+
+fn write_csv(path: &Path, columns: &[Column], rows: &[Vec<Value>]) -> Result<(), UserError> {
+    // The gzip header's timestamp is left at zero, so the same rows give the same bytes
+    let mut out = GzEncoder::new(
+        BufWriter::new(File::create(path)?),
+        flate2::Compression::default(),
+    );
+
+    writeln!(
+        out,
+        "{}",
+        columns
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(",")
+    )?;
+
+    for row in rows {
+        writeln!(
+            out,
+            "{}",
+            row.iter().map(csv_value).collect::<Vec<_>>().join(",")
+        )?;
+    }
+
+    out.finish()?.flush()?;
+
+    Ok(())
+}
+
+#[cfg(feature = "parquet")]
+fn write_parquet(path: &Path, columns: &[Column], rows: &[Vec<Value>]) -> Result<(), UserError> {
+    use parquet::basic::Compression;
+    use parquet::data_type::{BoolType, ByteArray, ByteArrayType, Int64Type};
+    use parquet::file::properties::WriterProperties;
+    use parquet::file::writer::SerializedFileWriter;
+    use parquet::schema::parser::parse_message_type;
+    use std::sync::Arc;
+
+    let fields = columns
+        .iter()
+        .map(|(name, kind)| match kind {
+            Kind::Int => format!("OPTIONAL INT64 {};", name),
+            Kind::Text => format!("OPTIONAL BYTE_ARRAY {} (UTF8);", name),
+            Kind::Bool => format!("OPTIONAL BOOLEAN {};", name),
+            Kind::Time => format!("OPTIONAL INT64 {} (TIMESTAMP_MILLIS);", name),
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let schema = Arc::new(parse_message_type(&format!(
+        "message export {{ {} }}",
+        fields
+    ))?);
+    let props = Arc::new(
+        WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build(),
+    );
+
+    let mut writer = SerializedFileWriter::new(File::create(path)?, schema, props)?;
+    let mut row_group = writer.next_row_group()?;
+    let mut index = 0;
+
+    while let Some(mut column) = row_group.next_column()? {
+        let values = || rows.iter().map(|row| &row[index]);
+        let def_levels: Vec<i16> = values()
+            .map(|value| match value {
+                Value::Null => 0,
+                _ => 1,
+            })
+            .collect();
+
+        match columns[index].1 {
+            Kind::Int | Kind::Time => {
+                let data: Vec<i64> = values()
+                    .filter_map(|value| match value {
+                        Value::Int(i) => Some(*i),
+                        Value::Time(t) => Some(t.timestamp_millis()),
+                        _ => None,
+                    })
+                    .collect();
+                column
+                    .typed::<Int64Type>()
+                    .write_batch(&data, Some(&def_levels), None)?;
+            }
+            Kind::Text => {
+                let data: Vec<ByteArray> = values()
+                    .filter_map(|value| match value {
+                        Value::Text(s) => Some(ByteArray::from(s.as_str())),
+                        _ => None,
+                    })
+                    .collect();
+                column
+                    .typed::<ByteArrayType>()
+                    .write_batch(&data, Some(&def_levels), None)?;
+            }
+            Kind::Bool => {
+                let data: Vec<bool> = values()
+                    .filter_map(|value| match value {
+                        Value::Bool(b) => Some(*b),
+                        _ => None,
+                    })
+                    .collect();
+                column
+                    .typed::<BoolType>()
+                    .write_batch(&data, Some(&def_levels), None)?;
+            }
+        }
+
+        column.close()?;
+        index += 1;
+    }
+
+    row_group.close()?;
+    writer.close()?;
+
+    Ok(())
+}
+
+#[cfg(not(feature = "parquet"))]
+fn write_parquet(_path: &Path, _columns: &[Column], _rows: &[Vec<Value>]) -> Result<(), UserError> {
+    Err(ue!("op was built without the parquet feature"))
+}
+
+/// Writes one file per `chunk_size` ids; `sql` gets the chunk's bounds as $1 and $2
+async fn export_chunks(
+    options: &Options<'_>,
+    name: &str,
+    id_table: &str,
+    columns: &[Column],
+    sql: &str,
+    extra_args: &[&(dyn ToSql + Sync)],
+) -> Result<(), UserError> {
+    let client = PG_POOL.get().await?;
+
+    let max_id: Option<i64> = client
+        .query_one(
+            format!("SELECT MAX(id) AS max FROM {}", id_table).as_str(),
+            &[],
+        )
+        .await?
+        .get("max");
+
+    let max_id = match max_id {
+        Some(max_id) => max_id,
+        None => return Ok(()),
+    };
+
+    let stmt = client.prepare(sql).await?;
+
+    for chunk in 0..=max_id / options.chunk_size {
+        let start = chunk * options.chunk_size;
+        let end = start + options.chunk_size;
+
+        let mut args: Vec<&(dyn ToSql + Sync)> = vec![&start, &end];
+        args.extend_from_slice(extra_args);
+
+        let rows = client.query(&stmt, &args).await?;
+
+        let path: PathBuf = Path::new(options.dir).join(format!(
+            "{}-{:08}.{}",
+            name,
+            chunk,
+            options.format.extension()
+        ));
+
+        if rows.is_empty() {
+            // Everything in this range was deleted since the last export
+            if path.exists() {
+                std::fs::remove_file(&path)?;
+            }
+            continue;
+        }
+
+        let values: Vec<Vec<Value>> = rows.iter().map(|row| read_row(row, columns)).collect();
+
+        // Renamed into place so a sync never picks up half a chunk
+        let temp_path = path.with_extension("tmp");
+        match options.format {
+            Format::Csv => write_csv(&temp_path, columns, &values)?,
+            Format::Parquet => write_parquet(&temp_path, columns, &values)?,
+        }
+        std::fs::rename(&temp_path, &path)?;
+
+        println!("Wrote {} rows to {}", values.len(), path.display());
+    }
+
+    Ok(())
+}
+
+pub async fn export(options: Options<'_>) -> Result<(), UserError> {
+    std::fs::create_dir_all(options.dir)?;
+
+    for &table in &options.tables {
+        match table {
+            "posts" => {
+                let columns = select_columns(POSTS_COLUMNS, options.posts_columns)?;
+                let sql = format!(
+                    "SELECT {} FROM posts WHERE id >= $1 AND id < $2 ORDER BY id",
+                    select_list("posts", &columns)
+                );
+                export_chunks(&options, "posts", "posts", &columns, &sql, &[]).await?;
+            }
+            "images" => {
+                let columns = select_columns(IMAGES_COLUMNS, options.images_columns)?;
+                let sql = format!(
+                    "SELECT {} FROM images WHERE id >= $1 AND id < $2 ORDER BY id",
+                    select_list("images", &columns)
+                );
+                export_chunks(&options, "images", "images", &columns, &sql, &[]).await?;
+            }
+            "matches" => {
+                let distance = options.distance as i64;
+                // Each edge is only written once, from its lower image id
+                export_chunks(
+                    &options,
+                    "matches",
+                    "images",
+                    MATCHES_COLUMNS,
+                    "SELECT images.id AS image_a, others.id AS image_b, \
+                     images.hash <-> others.hash AS distance \
+                     FROM images INNER JOIN images AS others \
+                     ON others.hash <@ (images.hash, $3) AND others.id > images.id \
+                     WHERE images.id >= $1 AND images.id < $2 \
+                     ORDER BY image_a, image_b",
+                    &[&distance],
+                )
+                .await?;
+            }
+            _ => return Err(ue!(format!("unknown table {}", table))),
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,106 @@
+use common::*;
+use tokio_postgres::types::ToSql;
+
+struct Check {
+    name: &'static str,
+    count_sql: &'static str,
+    /// None for problems that need a person to decide what to do
+    repair_sql: Option<&'static str>,
+    /// Bound to both queries
+    params: &'static [&'static (dyn ToSql + Sync)],
+}
+
+const CHECKS: &[Check] = &[
+    Check {
+        name: "posts linking to a missing image",
+        count_sql: "SELECT COUNT(*) FROM posts WHERE image_id IS NOT NULL \
+             AND NOT EXISTS (SELECT 1 FROM images WHERE images.id = posts.image_id)",
+        repair_sql: Some(
+            "UPDATE posts SET image_id = NULL, save_error = 'image_missing' \
+             WHERE image_id IS NOT NULL \
+             AND NOT EXISTS (SELECT 1 FROM images WHERE images.id = posts.image_id)",
+        ),
+        params: &[],
+    },
+    Check {
+        name: "comments linking to a missing image",
+        count_sql: "SELECT COUNT(*) FROM comment_images WHERE image_id IS NOT NULL \
+             AND NOT EXISTS (SELECT 1 FROM images WHERE images.id = comment_images.image_id)",
+        repair_sql: Some(
+            "UPDATE comment_images SET image_id = NULL, save_error = 'image_missing' \
+             WHERE image_id IS NOT NULL \
+             AND NOT EXISTS (SELECT 1 FROM images WHERE images.id = comment_images.image_id)",
+        ),
+        params: &[],
+    },
+    Check {
+        name: "images no post or comment links to",
+        count_sql: "SELECT COUNT(*) FROM images \
+             WHERE NOT EXISTS (SELECT 1 FROM posts WHERE posts.image_id = images.id) \
+             AND NOT EXISTS \
+             (SELECT 1 FROM comment_images WHERE comment_images.image_id = images.id)",
+        repair_sql: None,
+        params: &[],
+    },
+    Check {
+        name: "images hashed by another version of dhash",
+        count_sql: "SELECT COUNT(*) FROM images WHERE hash_version <> $1",
+        repair_sql: None,
+        params: &[&HASH_VERSION],
+    },
+    Check {
+        name: "images whose link isn't in image_links",
+        count_sql: "SELECT COUNT(*) FROM images \
+             WHERE NOT EXISTS (SELECT 1 FROM image_links WHERE image_links.link = images.link)",
+        repair_sql: Some(
+            "INSERT INTO image_links (link, image_id) SELECT link, id FROM images \
+             WHERE NOT EXISTS (SELECT 1 FROM image_links WHERE image_links.link = images.link)",
+        ),
+        params: &[],
+    },
+    Check {
+        name: "cached images whose link is also in image_links",
+        count_sql: "SELECT COUNT(*) FROM image_cache \
+             WHERE EXISTS (SELECT 1 FROM image_links WHERE image_links.link = image_cache.link)",
+        repair_sql: Some(
+            "DELETE FROM image_cache \
+             WHERE EXISTS (SELECT 1 FROM image_links WHERE image_links.link = image_cache.link)",
+        ),
+        params: &[],
+    },
+];
+
+pub async fn fsck(repair: bool) -> Result<(), UserError> {
+    let mut client = PG_POOL.get().await?;
+    let trans = client.transaction().await?;
+
+    let mut found = 0;
+    let mut repaired = 0;
+
+    for check in CHECKS {
+        let count: i64 = trans.query_one(check.count_sql, check.params).await?.get(0);
+        found += count;
+
+        let action = match (repair && count > 0, check.repair_sql) {
+            (true, Some(repair_sql)) => {
+                let fixed = trans.execute(repair_sql, check.params).await?;
+                repaired += fixed;
+                format!(" (repaired {})", fixed)
+            }
+            (true, None) => " (left alone)".to_string(),
+            (false, _) => String::new(),
+        };
+
+        println!("{:>10} {}{}", count, check.name, action);
+    }
+
+    trans.commit().await?;
+
+    if repair {
+        println!("Found {} problems and repaired {}", found, repaired);
+    } else {
+        println!("Found {} problems", found);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,140 @@
+use common::*;
+use std::time::Instant;
+use tokio_postgres::Client;
+
+/// An SP-GiST index with bktree_ops, which `<@` and `<->` on hashes need to be quick
+struct HashIndex {
+    name: &'static str,
+    table: &'static str,
+    column: &'static str,
+}
+
+const HASH_INDEXES: &[HashIndex] = &[
+    HashIndex {
+        name: "images_hash_idx",
+        table: "images",
+        column: "hash",
+    },
+    HashIndex {
+        name: "images_hash128_hi_idx",
+        table: "images",
+        column: "hash128_hi",
+    },
+    HashIndex {
+        name: "images_hash128_lo_idx",
+        table: "images",
+        column: "hash128_lo",
+    },
+    HashIndex {
+        name: "image_cache_hash_idx",
+        table: "image_cache",
+        column: "hash",
+    },
+    HashIndex {
+        name: "image_cache_hash128_hi_idx",
+        table: "image_cache",
+        column: "hash128_hi",
+    },
+    HashIndex {
+        name: "image_cache_hash128_lo_idx",
+        table: "image_cache",
+        column: "hash128_lo",
+    },
+];
+
+/// Lets the connection build indexes for as long as they take
+async fn no_timeout(client: &Client) -> Result<(), UserError> {
+    client.batch_execute("SET statement_timeout = 0").await?;
+
+    Ok(())
+}
+
+/// None if the index doesn't exist; a build that failed partway leaves it invalid
+async fn index_valid(client: &Client, name: &str) -> Result<Option<bool>, UserError> {
+    Ok(client
+        .query_opt(
+            "SELECT pg_index.indisvalid FROM pg_index \
+             INNER JOIN pg_class ON pg_class.oid = pg_index.indexrelid \
+             INNER JOIN pg_namespace ON pg_namespace.oid = pg_class.relnamespace \
+             WHERE pg_namespace.nspname = 'public' AND pg_class.relname = $1",
+            &[&name],
+        )
+        .await?
+        .map(|row| row.get("indisvalid")))
+}
+
+async fn rebuild(client: &Client, name: &str) -> Result<(), UserError> {
+    let start = Instant::now();
+    client
+        .batch_execute(&format!("REINDEX INDEX CONCURRENTLY public.{}", name))
+        .await?;
+    println!("Rebuilt {} in {:.1?}", name, start.elapsed());
+
+    Ok(())
+}
+
+/// Creates the bktree extension, which pg-spgist_hamming installs and which provides the
+/// operators and bktree_ops, then any hash index that's missing, and rebuilds any that's
+/// invalid; running it again changes nothing
+pub async fn setup() -> Result<(), UserError> {
+    let client = PG_POOL.get().await?;
+    no_timeout(&client).await?;
+
+    client
+        .batch_execute("CREATE EXTENSION IF NOT EXISTS bktree WITH SCHEMA public")
+        .await
+        .map_err(map_ue!(
+            "couldn't create the bktree extension; is pg-spgist_hamming installed?"
+        ))?;
+
+    for index in HASH_INDEXES {
+        match index_valid(&client, index.name).await? {
+            Some(true) => println!("{} is there", index.name),
+            Some(false) => rebuild(&client, index.name).await?,
+            None => {
+                let start = Instant::now();
+                client
+                    .batch_execute(&format!(
+                        "CREATE INDEX CONCURRENTLY IF NOT EXISTS {} ON public.{} \
+                         USING spgist ({} public.bktree_ops)",
+                        index.name, index.table, index.column
+                    ))
+                    .await?;
+                println!("Created {} in {:.1?}", index.name, start.elapsed());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Rebuilds the hash indexes, or only the one named, without locking out writes
+pub async fn reindex(only: Option<&str>) -> Result<(), UserError> {
+    let client = PG_POOL.get().await?;
+    no_timeout(&client).await?;
+
+    let indexes: Vec<&HashIndex> = HASH_INDEXES
+        .iter()
+        .filter(|index| only.map(|name| name == index.name).unwrap_or(true))
+        .collect();
+    if indexes.is_empty() {
+        return Err(ue!(
+            format!("{} isn't one of the hash indexes", only.unwrap_or_default()),
+            Source::User
+        ));
+    }
+
+    let start = Instant::now();
+    for index in indexes {
+        if index_valid(&client, index.name).await?.is_none() {
+            return Err(ue!(format!(
+                "{} doesn't exist; run db_setup to create it",
+                index.name
+            )));
+        }
+        rebuild(&client, index.name).await?;
+    }
+    println!("Done in {:.1?}", start.elapsed());
+
+    Ok(())
+}
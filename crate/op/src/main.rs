@@ -2,11 +2,26 @@ use clap::clap_app;
 use common::*;
 use futures::prelude::*;
 use hash_trie::HashTrie;
+use output::Output;
 use reqwest::{header::USER_AGENT, Client};
 use serde::Deserialize;
 use serde_json::Value;
 use std::io::{Read, Write};
 
+mod banned;
+mod clusters;
+mod export;
+mod fsck;
+mod indexes;
+mod output;
+mod partitions;
+mod query_report;
+mod rehash;
+mod repost_stats;
+mod save_errors;
+mod watch;
+mod why;
+
 async fn post(ids: impl Iterator<Item = &str>) -> Result<(), UserError> {
     const REDDIT_USER_AGENT: &str = concat!(
         "linux:xyz.tidder.op:v",
@@ -14,6 +29,10 @@ async fn post(ids: impl Iterator<Item = &str>) -> Result<(), UserError> {
         " (by /u/CrackedP0t)"
     );
 
+    let ids = ids
+        .map(RedditId::parse_fullname)
+        .collect::<Result<Vec<_>, _>>()?;
+
     let client = Client::new();
 
     let auth_resp = client
@@ -41,7 +60,9 @@ async fn post(ids: impl Iterator<Item = &str>) -> Result<(), UserError> {
 
         let link = format!(
             "https://oauth.reddit.com/by_id/{}",
-            ids.map(|id| format!("t3_{},", id)).collect::<String>()
+            ids.iter()
+                .map(|id| format!("t3_{},", id))
+                .collect::<String>()
         );
 
         let resp = client
@@ -69,6 +90,8 @@ async fn post(ids: impl Iterator<Item = &str>) -> Result<(), UserError> {
 }
 
 async fn save(id: &str) -> Result<(), UserError> {
+    let id = RedditId::parse_fullname(id)?;
+
     let client = Client::new();
 
     let auth_resp = client
@@ -110,10 +133,10 @@ async fn save(id: &str) -> Result<(), UserError> {
 
         let hash_saved = save_hash(&post.url, HashDest::Images).await?;
 
-        if post.save(Ok(hash_saved.id)).await? {
-            println!("already have");
-        } else {
-            println!("successfully saved");
+        match post.save(Ok(hash_saved.id)).await? {
+            Saved::Inserted => println!("successfully saved"),
+            Saved::Updated => println!("already have; updated"),
+            Saved::Skipped => println!("already have"),
         }
         Ok(())
     } else {
@@ -126,7 +149,7 @@ async fn save(id: &str) -> Result<(), UserError> {
 async fn hash(links: &[&str]) -> Result<(), UserError> {
     futures::stream::iter(links.iter())
         .fold(None, move |last, arg| async move {
-            let HashGotten { hash, end_link, .. } = match get_hash(&arg).await {
+            let HashGotten { hash, end_link, .. } = match get_hash(&arg, false).await {
                 Ok(res) => res,
                 Err(e) => {
                     warn!("{} failed: {:?}", arg, e);
@@ -147,55 +170,73 @@ async fn hash(links: &[&str]) -> Result<(), UserError> {
     Ok(())
 }
 
-async fn search(link: &str, distance: Option<i64>) -> Result<(), UserError> {
-    const DEFAULT_DISTANCE: i64 = 2;
-
-    let distance = distance.unwrap_or(DEFAULT_DISTANCE);
+async fn search(link: &str, distance: Option<u8>, output: Output) -> Result<(), UserError> {
+    let config = CONFIG.load();
+    let distance = distance.unwrap_or(config.default_distance);
+    if distance > config.search_limits(config::Tier::Admin).max_distance {
+        return Err(ue!("distance too large", Source::User));
+    }
+    let distance = i64::from(distance);
 
-    let hash = get_hash(link).await?.hash;
+    let hash = get_hash(link, false).await?.hash;
 
-    let found = PG_POOL
+    let matches: Vec<Match> = PG_POOL
         .get()
         .await?
         .query(
-            "SELECT hash <-> $1 as distance, images.link, permalink, \
-             score, author, created_utc, subreddit, title \
-             FROM posts INNER JOIN images \
-             ON hash <@ ($1, $2) \
-             AND image_id = images.id \
-             ORDER BY distance ASC, created_utc ASC",
+            format!(
+                "SELECT hash <-> $1 as distance, {} \
+                 FROM posts INNER JOIN images \
+                 ON hash <@ ($1, $2) \
+                 AND image_id = images.id \
+                 ORDER BY distance ASC, created_utc ASC",
+                output::MATCH_COLUMNS
+            )
+            .as_str(),
             &[&hash, &distance],
         )
-        .await?;
-
-    for row in found {
-        println!(
-            "{} | {} | {} | {} | {} | /r/{} | {} | {}",
-            row.get::<_, i64>("distance"),
-            row.get::<_, chrono::NaiveDateTime>("created_utc"),
-            row.get::<_, i64>("score"),
-            row.get::<_, &str>("link"),
-            row.get::<_, &str>("permalink"),
-            row.get::<_, &str>("subreddit"),
-            row.get::<_, &str>("author"),
-            row.get::<_, &str>("title")
-        );
-    }
-
-    Ok(())
+        .await?
+        .iter()
+        .map(|row| output::post_match(row, hash))
+        .collect();
+
+    output.write(
+        &mut std::io::stdout(),
+        &matches,
+        &matches,
+        output::match_line,
+    )
 }
 
-async fn rank() -> Result<(), UserError> {
+/// Writes the most common images to `path`, or stdout, leaving out what `rank_filters` does
+async fn rank(path: Option<&str>, output: Output) -> Result<(), UserError> {
+    let filters = CONFIG.load().rank_filters.clone();
+    let denylist: Vec<Hash> = filters.hash_denylist.iter().copied().map(Hash).collect();
+    let min_subreddits = i64::from(filters.min_subreddits);
+    let min_authors = i64::from(filters.min_authors);
+
     let rows = PG_POOL
         .get()
         .await?
         .query(
-            "SELECT COUNT(*) AS num,
-             (SELECT link FROM images AS images2 WHERE images.hash <@ (images2.hash, 0) LIMIT 1) AS link
-             FROM images GROUP BY hash ORDER BY num DESC LIMIT 100", &[]).await?;
+            format!(
+                "SELECT COUNT(DISTINCT images.id) AS num, \
+                 (SELECT link FROM images AS images2 WHERE images.hash <@ (images2.hash, 0) LIMIT 1) AS link \
+                 FROM images LEFT JOIN posts ON posts.image_id = images.id \
+                 WHERE NOT {} AND NOT images.hash = ANY($1) GROUP BY images.hash \
+                 HAVING COUNT(DISTINCT LOWER(posts.subreddit)) >= $2 \
+                 AND COUNT(DISTINCT NULLIF(posts.author, '[deleted]')) >= $3 \
+                 ORDER BY num DESC LIMIT 100",
+                TAKEN_DOWN_SQL
+            )
+            .as_str(),
+            &[&denylist, &min_subreddits, &min_authors],
+        )
+        .await?;
 
     let commons = CommonImages {
         as_of: chrono::offset::Utc::now(),
+        filters: Some(filters),
         common_images: rows
             .iter()
             .map(|row| CommonImage {
@@ -205,10 +246,14 @@ async fn rank() -> Result<(), UserError> {
             .collect::<Vec<_>>(),
     };
 
-    std::fs::File::create(std::env::var("HOME")? + "/stats/top100.ron")?
-        .write_all(ron::ser::to_string_pretty(&commons, Default::default())?.as_bytes())?;
+    let mut out: Box<dyn Write> = match path {
+        Some(path) => Box::new(std::fs::File::create(path)?),
+        None => Box::new(std::io::stdout()),
+    };
 
-    Ok(())
+    output.write(&mut out, &commons, &commons.common_images, |common| {
+        format!("{} | {}", common.num, common.link)
+    })
 }
 
 async fn trie_build(path: &str, id_path: &str) -> Result<(), UserError> {
@@ -246,7 +291,7 @@ async fn trie_build(path: &str, id_path: &str) -> Result<(), UserError> {
     let mut trie = HashTrie::<hash_trie::FileMap>::new(path.to_string());
 
     while let Some(row) = hashes.next().await {
-        trie.insert(row?.get::<_, i64>("hash") as u64);
+        trie.insert(row?.get::<_, Hash>("hash").0);
     }
 
     let last_id: i64 = trans
@@ -277,24 +322,219 @@ async fn trie_insert(path: &str, hashes: impl Iterator<Item = u64>) -> Result<()
     Ok(())
 }
 
+async fn stats(path: &str, bits: u8, hashes: &[u64]) -> Result<(), UserError> {
+    let trie = HashTrie::open_readonly(path)?;
+
+    println!("Nodes: {}", trie.node_count());
+    println!("Hashes: {}", trie.len_hashes());
+
+    for &hash in hashes {
+        println!(
+            "Hash {:020} shares its first {} bits with {} hashes",
+            hash,
+            bits,
+            trie.count_prefix(hash, bits)
+        );
+    }
+
+    Ok(())
+}
+
+async fn blob_purge(days: i64) -> Result<(), UserError> {
+    let cutoff = chrono::Utc::now().naive_utc() - chrono::Duration::days(days);
+
+    let client = PG_POOL.get().await?;
+
+    let keys: Vec<String> = client
+        .query(
+            "WITH cleared AS (UPDATE images SET stored_path = NULL, stored_size = NULL \
+             FROM (SELECT id, stored_path FROM images \
+             WHERE stored_path IS NOT NULL AND retrieved_on < $1) AS old \
+             WHERE images.id = old.id RETURNING old.stored_path) \
+             SELECT DISTINCT stored_path FROM cleared",
+            &[&cutoff],
+        )
+        .await?
+        .iter()
+        .map(|row| row.get("stored_path"))
+        .collect();
+
+    let mut deleted = 0;
+    for key in &keys {
+        // Identical bytes from a newer image share the blob
+        let shared = client
+            .query_opt(
+                "SELECT 1 FROM images WHERE stored_path = $1 LIMIT 1",
+                &[key],
+            )
+            .await?
+            .is_some();
+
+        if !shared {
+            delete_blob(key).await?;
+            deleted += 1;
+        }
+    }
+
+    println!("Deleted {} blobs", deleted);
+
+    Ok(())
+}
+
+async fn issue_key(name: &str) -> Result<(), UserError> {
+    use rand::distributions::{Alphanumeric, DistString};
+
+    let key = Alphanumeric.sample_string(&mut rand::thread_rng(), 32);
+
+    PG_POOL
+        .get()
+        .await?
+        .execute(
+            "INSERT INTO api_keys (key, name) VALUES ($1, $2)",
+            &[&key, &name],
+        )
+        .await?;
+
+    println!("{}", key);
+
+    Ok(())
+}
+
+async fn revoke_key(key: &str) -> Result<(), UserError> {
+    let revoked = PG_POOL
+        .get()
+        .await?
+        .execute("UPDATE api_keys SET revoked = true WHERE key = $1", &[&key])
+        .await?;
+
+    if revoked == 0 {
+        Err(ue!("No such key"))
+    } else {
+        println!("Revoked");
+        Ok(())
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), UserError> {
     setup_logging!();
 
     let matches = clap_app!(op =>
+        (@arg config: --config +takes_value +global "The path of tidder.ron, overriding TIDDER_CONFIG")
+        (@arg secrets: --secrets +takes_value +global "The path of secrets.toml, overriding TIDDER_SECRETS")
+        (@subcommand author_opt_in =>
+         (@arg NAME: +required "The author whose stats page should be shown again")
+        )
+        (@subcommand author_opt_out =>
+         (@arg NAME: +required "The author whose stats page should be hidden")
+        )
+        (@subcommand banned =>
+         (@subcommand add =>
+          (@arg KIND: +required "HostEnd, Host, AnyScheme, Full, or NoBlacklist for a host whose circuit is ignored")
+          (@arg VALUE: +required "What to ban, or the end of the host")
+         )
+         (@subcommand remove =>
+          (@arg KIND: +required "The kind it was added as")
+          (@arg VALUE: +required "What was banned, or the end of the host")
+         )
+         (@subcommand list => )
+         (@subcommand import =>
+          (@arg PATH: +required "A RON file with banned and no_blacklist lists, like an old tidder.ron")
+         )
+         (@subcommand export =>
+          (@arg PATH: "Where to write the lists as RON, instead of stdout")
+         )
+        )
+        (@subcommand blob_purge =>
+         (@arg DAYS: +required "Originals of images retrieved more than this many days ago are deleted")
+        )
+        (@subcommand clusters =>
+         (@arg PATH: +required "The path of the trie file")
+         (@arg OUTPUT: +required "The path to write the report to")
+         (@arg distance: -d --distance +takes_value "The max distance between neighbors in a cluster")
+         (@arg min_size: -m --min_size +takes_value "The fewest hashes a cluster can have to be reported")
+         (@arg format: -f --format +takes_value "csv or ron")
+        )
+        (@subcommand db_setup => )
+        (@subcommand export =>
+         (@arg DIR: +required "The directory to write chunks to")
+         (@arg format: -f --format +takes_value "csv or parquet")
+         (@arg tables: -t --tables +takes_value "Which of posts, images, and matches to export, comma-separated")
+         (@arg posts_columns: --posts_columns +takes_value "Which columns of posts to export, comma-separated")
+         (@arg images_columns: --images_columns +takes_value "Which columns of images to export, comma-separated")
+         (@arg distance: -d --distance +takes_value "The max distance between matching images")
+         (@arg chunk_size: -c --chunk_size +takes_value "How many ids each chunk covers")
+        )
+        (@subcommand first_posts => )
+        (@subcommand fsck =>
+         (@arg repair: --repair "Clears links to missing images and deletes cache rows duplicating images")
+        )
         (@subcommand hash =>
          (@arg LINKS: +required ... "The links you wish to hash")
         )
+        (@subcommand hash_popularity =>
+         (@arg limit: -l --limit +takes_value "How many of the most shared hashes to keep; 10000 by default")
+        )
+        (@subcommand issue_key =>
+         (@arg NAME: +required "Who the API key is for")
+        )
+        (@subcommand partitions =>
+         (@subcommand list => )
+         (@subcommand archive =>
+          (@arg BEFORE: +required "Months before this one, like 2019-03, are moved")
+          (@arg TABLESPACE: +required "The tablespace on cheaper storage to move them to")
+         )
+         (@subcommand detach =>
+          (@arg BEFORE: +required "Months before this one, like 2019-03, stop being searched")
+         )
+         (@subcommand attach =>
+          (@arg NAME: +required "The detached partition to search again, like posts_y2019m03")
+         )
+        )
         (@subcommand post =>
          (@arg ID: +required ... "Reddit's IDs for the posts")
         )
-        (@subcommand rank => )
+        (@subcommand query_report =>
+         (@arg days: -d --days +takes_value "Only report on this many days up to today")
+        )
+        (@subcommand rank =>
+         (@arg PATH: "Where to write the most common images, instead of stdout; the site reads ~/stats/top100.ron")
+         (@arg output: -o --output +takes_value "json, csv, ron or table; ron by default")
+        )
+        (@subcommand rehash =>
+         (@arg to_version: --("to-version") +takes_value +required "The hash version to rehash images to, which must be this build's")
+         (@arg only: --only +takes_value "Only rehash stored images, or images still linked to by a post or comment: stored or linked")
+         (@arg switch_at: --("switch-at") +takes_value "Replace the old hashes once this fraction of images is at the new version")
+         (@arg batch_size: --("batch-size") +takes_value "How many images to read at a time")
+        )
+        (@subcommand reindex =>
+         (@arg INDEX: "The one hash index to rebuild, instead of all of them")
+        )
+        (@subcommand repost_matches =>
+         (@arg days: -d --days +takes_value "Match posts made in this many days; 7 by default")
+        )
+        (@subcommand revoke_key =>
+         (@arg KEY: +required "The API key to revoke")
+        )
         (@subcommand save =>
          (@arg ID: +required "Reddit's ID for the post you wish to save")
         )
+        (@subcommand save_errors =>
+         (@arg days: -d --days +takes_value "Only count posts made in this many days")
+        )
         (@subcommand search =>
          (@arg LINK: +required "The link to the image you wish to search for")
-         (@arg distance: -d --distance +takes_value "The max distance you'll accept")
+         (@arg distance: -d --distance +takes_value "The max distance you'll accept, up to the admin tier's max_distance; default_distance by default")
+         (@arg output: -o --output +takes_value "json, csv, ron or table; table by default")
+        )
+        (@subcommand stats =>
+         (@arg PATH: +required "The path of the trie file")
+         (@arg bits: -b --bits +takes_value "How many bits of each hash must match")
+         (@arg HASHES: ... "The hashes you wish to count the neighbors of")
+        )
+        (@subcommand subreddit_stats =>
+         (@arg limit: -l --limit +takes_value "How many of the busiest subreddits to do when none are named")
+         (@arg NAMES: ... "The subreddits you wish to compute repost statistics for")
         )
         (@subcommand trie_build =>
          (@arg PATH: +required "The path to save the trie to")
@@ -304,17 +544,192 @@ async fn main() -> Result<(), UserError> {
          (@arg PATH: +required "The path of the trie file")
          (@arg HASHES: +required ... "The hashes you wish to save")
         )
+        (@subcommand watch =>
+         (@arg LINK_OR_HASH: +required "The link to an image, or a 64-bit hash in decimal")
+         (@arg distance: -d --distance +takes_value "The max distance you'll accept; 2 by default")
+         (@arg output: -o --output +takes_value "json, csv, ron or table; table by default")
+        )
+        (@subcommand why =>
+         (@arg ID: +required "Reddit's ID for the post whose ingest events you wish to see")
+        )
     )
     .get_matches();
 
+    if let Some(config_path) = matches.value_of("config") {
+        config::set_path(config_path);
+    }
+    if let Some(secrets_path) = matches.value_of("secrets") {
+        secrets::set_path(secrets_path);
+    }
+
     let (op_name, op_matches) = matches.subcommand();
     let op_matches = op_matches.ok_or_else(|| ue!("No subcommand provided"))?;
 
     match op_name {
+        "author_opt_in" => repost_stats::author_opt_in(op_matches.value_of("NAME").unwrap()).await,
+        "author_opt_out" => {
+            repost_stats::author_opt_out(op_matches.value_of("NAME").unwrap()).await
+        }
+        "banned" => {
+            let (action, action_matches) = op_matches.subcommand();
+            let action_matches =
+                action_matches.ok_or_else(|| ue!("No banned subcommand provided"))?;
+
+            match action {
+                "add" => {
+                    banned::add(
+                        action_matches.value_of("KIND").unwrap(),
+                        action_matches.value_of("VALUE").unwrap(),
+                    )
+                    .await
+                }
+                "remove" => {
+                    banned::remove(
+                        action_matches.value_of("KIND").unwrap(),
+                        action_matches.value_of("VALUE").unwrap(),
+                    )
+                    .await
+                }
+                "list" => banned::list().await,
+                "import" => banned::import(action_matches.value_of("PATH").unwrap()).await,
+                "export" => banned::export(action_matches.value_of("PATH")).await,
+                unknown => Err(ue!(format!("Unknown banned subcommand '{}'", unknown))),
+            }
+        }
+        "blob_purge" => blob_purge(op_matches.value_of("DAYS").unwrap().parse()?).await,
+        "clusters" => {
+            clusters::clusters(
+                op_matches.value_of("PATH").unwrap(),
+                op_matches.value_of("OUTPUT").unwrap(),
+                op_matches
+                    .value_of("distance")
+                    .map(|d| d.parse())
+                    .transpose()?
+                    .unwrap_or(CONFIG.load().max_distance),
+                op_matches
+                    .value_of("min_size")
+                    .map(|m| m.parse())
+                    .transpose()?
+                    .unwrap_or(2),
+                op_matches
+                    .value_of("format")
+                    .map(|f| f.parse())
+                    .transpose()?
+                    .unwrap_or(clusters::Format::Csv),
+            )
+            .await
+        }
+        "db_setup" => indexes::setup().await,
+        "export" => {
+            export::export(export::Options {
+                dir: op_matches.value_of("DIR").unwrap(),
+                format: op_matches
+                    .value_of("format")
+                    .map(|f| f.parse())
+                    .transpose()?
+                    .unwrap_or(export::Format::Csv),
+                tables: op_matches
+                    .value_of("tables")
+                    .unwrap_or("posts,images,matches")
+                    .split(',')
+                    .collect(),
+                posts_columns: op_matches.value_of("posts_columns"),
+                images_columns: op_matches.value_of("images_columns"),
+                distance: op_matches
+                    .value_of("distance")
+                    .map(|d| d.parse())
+                    .transpose()?
+                    .unwrap_or(0),
+                chunk_size: op_matches
+                    .value_of("chunk_size")
+                    .map(|c| c.parse())
+                    .transpose()?
+                    .unwrap_or(1_000_000),
+            })
+            .await
+        }
+        "first_posts" => repost_stats::first_posts().await,
+        "fsck" => fsck::fsck(op_matches.is_present("repair")).await,
         "hash" => hash(&op_matches.values_of("LINKS").unwrap().collect::<Vec<_>>()).await,
+        "hash_popularity" => {
+            repost_stats::hash_popularity(
+                op_matches
+                    .value_of("limit")
+                    .map(|l| l.parse())
+                    .transpose()?
+                    .unwrap_or(10_000),
+            )
+            .await
+        }
+        "issue_key" => issue_key(op_matches.value_of("NAME").unwrap()).await,
+        "partitions" => {
+            let (action, action_matches) = op_matches.subcommand();
+            let action_matches =
+                action_matches.ok_or_else(|| ue!("No partitions subcommand provided"))?;
+
+            match action {
+                "list" => partitions::list().await,
+                "archive" => {
+                    partitions::archive(
+                        action_matches.value_of("BEFORE").unwrap(),
+                        action_matches.value_of("TABLESPACE").unwrap(),
+                    )
+                    .await
+                }
+                "detach" => partitions::detach(action_matches.value_of("BEFORE").unwrap()).await,
+                "attach" => partitions::attach(action_matches.value_of("NAME").unwrap()).await,
+                unknown => Err(ue!(format!("Unknown partitions subcommand '{}'", unknown))),
+            }
+        }
         "post" => post(op_matches.values_of("ID").unwrap()).await,
-        "rank" => rank().await,
+        "query_report" => {
+            query_report::query_report(op_matches.value_of("days").map(|d| d.parse()).transpose()?)
+                .await
+        }
+        "rank" => {
+            rank(
+                op_matches.value_of("PATH"),
+                op_matches
+                    .value_of("output")
+                    .map(|o| o.parse())
+                    .transpose()?
+                    .unwrap_or(Output::Ron),
+            )
+            .await
+        }
+        "rehash" => {
+            rehash::rehash(rehash::Options {
+                to_version: op_matches.value_of("to_version").unwrap().parse()?,
+                only: op_matches.value_of("only").map(|o| o.parse()).transpose()?,
+                switch_at: op_matches
+                    .value_of("switch_at")
+                    .map(|s| s.parse())
+                    .transpose()?,
+                batch_size: op_matches
+                    .value_of("batch_size")
+                    .map(|b| b.parse())
+                    .transpose()?
+                    .unwrap_or(1000),
+            })
+            .await
+        }
+        "reindex" => indexes::reindex(op_matches.value_of("INDEX")).await,
+        "repost_matches" => {
+            repost_stats::repost_matches(
+                op_matches
+                    .value_of("days")
+                    .map(|d| d.parse())
+                    .transpose()?
+                    .unwrap_or(7),
+            )
+            .await
+        }
+        "revoke_key" => revoke_key(op_matches.value_of("KEY").unwrap()).await,
         "save" => save(op_matches.value_of("ID").unwrap()).await,
+        "save_errors" => {
+            save_errors::save_errors(op_matches.value_of("days").map(|d| d.parse()).transpose()?)
+                .await
+        }
         "search" => {
             search(
                 op_matches.value_of("LINK").unwrap(),
@@ -322,6 +737,41 @@ async fn main() -> Result<(), UserError> {
                     .value_of("distance")
                     .map(|d| d.parse())
                     .transpose()?,
+                op_matches
+                    .value_of("output")
+                    .map(|o| o.parse())
+                    .transpose()?
+                    .unwrap_or(Output::Table),
+            )
+            .await
+        }
+        "stats" => {
+            stats(
+                op_matches.value_of("PATH").unwrap(),
+                op_matches
+                    .value_of("bits")
+                    .map(|b| b.parse())
+                    .transpose()?
+                    .unwrap_or(16),
+                &op_matches
+                    .values_of("HASHES")
+                    .map(|hashes| hashes.map(|h| h.parse()).collect())
+                    .transpose()?
+                    .unwrap_or_default(),
+            )
+            .await
+        }
+        "subreddit_stats" => {
+            repost_stats::subreddit_stats(
+                op_matches
+                    .values_of("NAMES")
+                    .map(|names| names.map(str::to_string).collect())
+                    .unwrap_or_default(),
+                op_matches
+                    .value_of("limit")
+                    .map(|l| l.parse())
+                    .transpose()?
+                    .unwrap_or(100),
             )
             .await
         }
@@ -347,6 +797,23 @@ async fn main() -> Result<(), UserError> {
             )
             .await
         }
+        "watch" => {
+            watch::watch(
+                op_matches.value_of("LINK_OR_HASH").unwrap(),
+                op_matches
+                    .value_of("distance")
+                    .map(|d| d.parse())
+                    .transpose()?
+                    .unwrap_or(2),
+                op_matches
+                    .value_of("output")
+                    .map(|o| o.parse())
+                    .transpose()?
+                    .unwrap_or(Output::Table),
+            )
+            .await
+        }
+        "why" => why::why(op_matches.value_of("ID").unwrap()).await,
         unknown => Err(ue!(format!("Unknown subcommand '{}'", unknown))),
     }
 }
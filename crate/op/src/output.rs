@@ -0,0 +1,147 @@
+use common::*;
+use serde::Serialize;
+use serde_json::Value;
+use std::io::Write;
+use tokio_postgres::Row;
+
+/// How search and rank print what they found
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Output {
+    Json,
+    Csv,
+    /// What the site reads rankings from
+    Ron,
+    /// Columns separated by `|`, for people
+    Table,
+}
+
+impl std::str::FromStr for Output {
+    type Err = UserError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Output::Json),
+            "csv" => Ok(Output::Csv),
+            "ron" => Ok(Output::Ron),
+            "table" => Ok(Output::Table),
+            _ => Err(ue!(
+                format!("{} isn't one of json, csv, ron or table", s),
+                Source::User
+            )),
+        }
+    }
+}
+
+/// What `post_match` reads, besides `distance`
+pub const MATCH_COLUMNS: &str = "images.hash, images.link, image_id, preview, reddit_id_int, \
+     permalink, score, author, created_utc, subreddit, title";
+
+/// A row of posts joined with images, as the site would show it to someone searching for `query`
+pub fn post_match(row: &Row, query: Hash) -> Match {
+    let link: String = row.get("link");
+    let distance: i64 = row.get("distance");
+
+    Match {
+        permalink: format!("https://reddit.com{}", row.get::<_, &str>("permalink")),
+        distance,
+        exact: distance == 0 && row.get::<_, Hash>("hash") == query,
+        image_id: row.get("image_id"),
+        post_id: row.get("reddit_id_int"),
+        score: row.get("score"),
+        author: row.get("author"),
+        preview: row
+            .get::<_, Option<String>>("preview")
+            .map(|p| Submission::unescape(&p))
+            .unwrap_or_else(|| link.clone()),
+        link,
+        created_utc: row.get("created_utc"),
+        subreddit: row.get("subreddit"),
+        title: row.get("title"),
+        possibly_dead: false,
+        crossposts: Vec::new(),
+    }
+}
+
+pub fn match_line(m: &Match) -> String {
+    format!(
+        "{} | {} | {} | {} | {} | /r/{} | {} | {}",
+        m.distance,
+        m.created_utc,
+        m.score,
+        m.link,
+        m.permalink,
+        m.subreddit,
+        m.author.as_deref().unwrap_or("[deleted]"),
+        m.title
+    )
+}
+
+fn csv_value(value: &Value) -> String {
+    let text = match value {
+        Value::Null => return String::new(),
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    };
+
+    if text.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text
+    }
+}
+
+/// One line per row, with a header of the rows' fields
+fn write_csv<T: Serialize>(out: &mut dyn Write, rows: &[T]) -> Result<(), UserError> {
+    let rows = rows
+        .iter()
+        .map(serde_json::to_value)
+        .collect::<Result<Vec<Value>, _>>()?;
+
+    let columns: Vec<String> = match rows.first() {
+        Some(Value::Object(first)) => first.keys().cloned().collect(),
+        _ => return Ok(()),
+    };
+
+    writeln!(out, "{}", columns.join(","))?;
+    for row in &rows {
+        writeln!(
+            out,
+            "{}",
+            columns
+                .iter()
+                .map(|column| csv_value(&row[column]))
+                .collect::<Vec<_>>()
+                .join(",")
+        )?;
+    }
+
+    Ok(())
+}
+
+impl Output {
+    /// Writes `whole` as JSON or RON, or `rows` as CSV or a table with `table_row`
+    pub fn write<W: Serialize, T: Serialize>(
+        self,
+        out: &mut dyn Write,
+        whole: &W,
+        rows: &[T],
+        table_row: impl Fn(&T) -> String,
+    ) -> Result<(), UserError> {
+        match self {
+            Output::Json => writeln!(out, "{}", serde_json::to_string_pretty(whole)?)?,
+            Output::Ron => writeln!(
+                out,
+                "{}",
+                ron::ser::to_string_pretty(whole, Default::default())?
+            )?,
+            Output::Csv => write_csv(out, rows)?,
+            Output::Table => {
+                for row in rows {
+                    writeln!(out, "{}", table_row(row))?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
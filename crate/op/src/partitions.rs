@@ -0,0 +1,107 @@
+use chrono::NaiveDate;
+use common::*;
+use std::time::Instant;
+
+/// The first of the month given like `2019-03`
+fn parse_month(month: &str) -> Result<NaiveDate, UserError> {
+    NaiveDate::parse_from_str(&format!("{}-01", month), "%Y-%m-%d")
+        .map_err(map_ue!("months are written like 2019-03", Source::User))
+}
+
+async fn attached_before(before: &str) -> Result<Vec<Partition>, UserError> {
+    let before = parse_month(before)?;
+
+    Ok(partitions()
+        .await?
+        .into_iter()
+        .filter(|partition| partition.attached && partition.month < before)
+        .collect())
+}
+
+pub async fn list() -> Result<(), UserError> {
+    for partition in partitions().await? {
+        println!(
+            "{}  {:<8}  {:<12}  ~{} posts",
+            partition.name,
+            if partition.attached {
+                "attached"
+            } else {
+                "detached"
+            },
+            partition.tablespace.as_deref().unwrap_or("default"),
+            partition.estimated_rows
+        );
+    }
+
+    Ok(())
+}
+
+/// Moves the partitions of months before `before` to `tablespace`, where they're still
+/// searched; each is locked while it's moved
+pub async fn archive(before: &str, tablespace: &str) -> Result<(), UserError> {
+    let client = PG_POOL.get().await?;
+    client.batch_execute("SET statement_timeout = 0").await?;
+
+    for partition in attached_before(before).await? {
+        if partition.tablespace.as_deref() == Some(tablespace) {
+            continue;
+        }
+
+        let start = Instant::now();
+        client
+            .batch_execute(&format!(
+                "ALTER TABLE public.{} SET TABLESPACE \"{}\"",
+                partition.name,
+                tablespace.replace('"', "\"\"")
+            ))
+            .await?;
+        println!(
+            "Moved {} to {} in {:.1?}",
+            partition.name,
+            tablespace,
+            start.elapsed()
+        );
+    }
+
+    Ok(())
+}
+
+/// Detaches the partitions of months before `before` from posts, so they're kept but no
+/// longer searched; their images stay in images
+pub async fn detach(before: &str) -> Result<(), UserError> {
+    let client = PG_POOL.get().await?;
+    client.batch_execute("SET statement_timeout = 0").await?;
+
+    for partition in attached_before(before).await? {
+        client
+            .batch_execute(&format!(
+                "ALTER TABLE public.posts DETACH PARTITION public.{} CONCURRENTLY",
+                partition.name
+            ))
+            .await?;
+        println!("Detached {}", partition.name);
+    }
+
+    Ok(())
+}
+
+/// Puts a detached partition back in posts
+pub async fn attach(name: &str) -> Result<(), UserError> {
+    let month = partition_month(name)
+        .ok_or_else(|| ue!(format!("{} isn't a partition of posts", name), Source::User))?;
+
+    let client = PG_POOL.get().await?;
+    client.batch_execute("SET statement_timeout = 0").await?;
+
+    client
+        .batch_execute(&format!(
+            "ALTER TABLE public.posts ATTACH PARTITION public.{} FOR VALUES FROM ('{}') TO ('{}')",
+            name,
+            month,
+            next_month(month)
+        ))
+        .await?;
+    println!("Attached {}", name);
+
+    Ok(())
+}
@@ -0,0 +1,41 @@
+use common::*;
+
+/// Prints how many searches the site logged each day, how many the findings cache answered,
+/// and how long the slowest twentieth took; `days` limits it to that many recent days
+pub async fn query_report(days: Option<i64>) -> Result<(), UserError> {
+    let since = days.map(|days| chrono::Utc::now().naive_utc() - chrono::Duration::days(days));
+
+    let rows = PG_POOL
+        .get()
+        .await?
+        .query(
+            "SELECT at::date AS day, COUNT(*) AS count, \
+             COUNT(*) FILTER (WHERE cached) AS cached, \
+             percentile_cont(0.95) WITHIN GROUP (ORDER BY latency_ms) AS p95 \
+             FROM query_log WHERE $1::timestamp IS NULL OR at >= $1 \
+             GROUP BY day ORDER BY day",
+            &[&since],
+        )
+        .await?;
+
+    if rows.is_empty() {
+        println!("No searches logged; is query_log.enabled set for the site?");
+        return Ok(());
+    }
+
+    println!(
+        "{:<10}  {:>9}  {:>9}  {:>8}",
+        "day", "searches", "cached", "p95 ms"
+    );
+    for row in rows {
+        println!(
+            "{:<10}  {:>9}  {:>9}  {:>8.0}",
+            row.get::<_, chrono::NaiveDate>("day").to_string(),
+            row.get::<_, i64>("count"),
+            row.get::<_, i64>("cached"),
+            row.get::<_, f64>("p95"),
+        );
+    }
+
+    Ok(())
+}
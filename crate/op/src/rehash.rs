@@ -0,0 +1,221 @@
+use common::concurrency::BufferLimitedExt;
+use common::*;
+use futures::prelude::*;
+
+/// Narrows which images are rehashed, beyond those not at the target version yet
+#[derive(Clone, Copy, PartialEq)]
+pub enum Only {
+    /// Images whose originals are in the blob store, so nothing is downloaded
+    Stored,
+    /// Images a post or comment still links to
+    Linked,
+}
+
+impl std::str::FromStr for Only {
+    type Err = UserError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stored" => Ok(Only::Stored),
+            "linked" => Ok(Only::Linked),
+            _ => Err(ue!(format!("unknown image filter {}", s))),
+        }
+    }
+}
+
+pub struct Options {
+    pub to_version: i16,
+    pub only: Option<Only>,
+    /// The fraction of images that must be at `to_version` before the new hashes replace the
+    /// old ones; without it they're only written to the next_hash columns
+    pub switch_at: Option<f64>,
+    pub batch_size: i64,
+}
+
+async fn rehash_image(row: tokio_postgres::Row, only: Option<Only>) -> Result<(), UserError> {
+    let id: i64 = row.get("id");
+    let link: &str = row.get("link");
+
+    let bytes = match row.get::<_, Option<&str>>("stored_path") {
+        Some(key) => match fetch_blob(key).await {
+            Ok(bytes) => bytes,
+            Err(e) if only == Some(Only::Stored) => return Err(e),
+            Err(e) => {
+                warn!(
+                    "Couldn't read the blob of image {}, downloading it: {}",
+                    id, e
+                );
+                download_image(link).await?
+            }
+        },
+        None => download_image(link).await?,
+    };
+
+    // Images that had a 128-bit hash get a new one too
+    let wide = row.get::<_, Option<i64>>("hash128_hi").is_some();
+    let (hash, hash128) = hash_in_pool(bytes.into(), wide).await?;
+
+    PG_POOL
+        .get()
+        .await?
+        .execute(
+            "UPDATE images SET next_hash = $2, next_hash128_hi = $3, next_hash128_lo = $4, \
+             next_hash_version = $5 WHERE id = $1",
+            &[
+                &id,
+                &hash,
+                &hash128.map(Hash128::hi),
+                &hash128.map(Hash128::lo),
+                &HASH_VERSION,
+            ],
+        )
+        .await?;
+
+    Ok(())
+}
+
+/// How many images are at `version`, counting next_hash, and how many there are
+async fn coverage(version: i16) -> Result<(i64, i64), UserError> {
+    let row = PG_POOL
+        .get()
+        .await?
+        .query_one(
+            "SELECT COUNT(*) FILTER (WHERE hash_version = $1 OR next_hash_version = $1) \
+             AS covered, COUNT(*) AS total FROM images",
+            &[&version],
+        )
+        .await?;
+
+    Ok((row.get("covered"), row.get("total")))
+}
+
+/// Moves every next_hash at `version` into hash in one transaction, so searches go from only
+/// old hashes to only new ones
+async fn switch(version: i16) -> Result<(), UserError> {
+    let mut client = PG_POOL.get().await?;
+    let trans = client.transaction().await?;
+
+    let switched = trans
+        .execute(
+            "UPDATE images SET hash = next_hash, hash128_hi = next_hash128_hi, \
+             hash128_lo = next_hash128_lo, hash_version = next_hash_version, \
+             next_hash = NULL, next_hash128_hi = NULL, next_hash128_lo = NULL, \
+             next_hash_version = NULL WHERE next_hash_version = $1",
+            &[&version],
+        )
+        .await?;
+
+    // Cached hashes can't be compared with the new ones, and are cheap to fetch again
+    let dropped = trans
+        .execute(
+            "DELETE FROM image_cache WHERE hash_version <> $1",
+            &[&version],
+        )
+        .await?;
+
+    trans.commit().await?;
+
+    println!(
+        "Switched {} images to version {} and dropped {} cached hashes",
+        switched, version, dropped
+    );
+
+    Ok(())
+}
+
+pub async fn rehash(options: Options) -> Result<(), UserError> {
+    // Hashes can only be made with the algorithm this build has
+    if options.to_version != HASH_VERSION {
+        return Err(ue!(format!(
+            "this build hashes with version {}, not {}",
+            HASH_VERSION, options.to_version
+        )));
+    }
+    if let Some(switch_at) = options.switch_at {
+        if !(0.0..=1.0).contains(&switch_at) {
+            return Err(ue!("the switch threshold must be between 0 and 1"));
+        }
+    }
+
+    let filter = match options.only {
+        Some(Only::Stored) => "AND stored_path IS NOT NULL",
+        Some(Only::Linked) => {
+            "AND (EXISTS (SELECT 1 FROM posts WHERE posts.image_id = images.id) \
+             OR EXISTS (SELECT 1 FROM comment_images WHERE comment_images.image_id = images.id))"
+        }
+        None => "",
+    };
+    let query = format!(
+        "SELECT id, link, stored_path, hash128_hi FROM images \
+         WHERE id > $1 AND hash_version <> $2 AND next_hash_version IS DISTINCT FROM $2 {} \
+         ORDER BY id LIMIT $3",
+        filter
+    );
+
+    let mut last_id = 0_i64;
+    let mut rehashed = 0;
+    let mut failed = 0;
+
+    loop {
+        let rows = PG_POOL
+            .get()
+            .await?
+            .query(
+                query.as_str(),
+                &[&last_id, &options.to_version, &options.batch_size],
+            )
+            .await?;
+
+        last_id = match rows.last() {
+            Some(row) => row.get("id"),
+            None => break,
+        };
+
+        let only = options.only;
+        let results: Vec<_> = stream::iter(rows.into_iter().map(|row| async move {
+            let id: i64 = row.get("id");
+            rehash_image(row, only).await.map_err(|e| (id, e))
+        }))
+        .buffer_limited()
+        .collect()
+        .await;
+
+        for result in results {
+            match result {
+                Ok(()) => rehashed += 1,
+                Err((id, e)) => {
+                    warn!("Couldn't rehash image {}: {:?}", id, e);
+                    failed += 1;
+                }
+            }
+        }
+
+        println!(
+            "Rehashed {} images and failed {}, up to id {}",
+            rehashed, failed, last_id
+        );
+    }
+
+    let (covered, total) = coverage(options.to_version).await?;
+    let fraction = if total == 0 {
+        1.0
+    } else {
+        covered as f64 / total as f64
+    };
+    println!(
+        "{} of {} images ({:.2}%) are at version {}",
+        covered,
+        total,
+        fraction * 100.0,
+        options.to_version
+    );
+
+    match options.switch_at {
+        Some(switch_at) if fraction >= switch_at => switch(options.to_version).await,
+        Some(switch_at) => {
+            println!("Not switching until {:.2}% are covered", switch_at * 100.0);
+            Ok(())
+        }
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,230 @@
+use common::*;
+use std::collections::HashMap;
+
+const TOP: usize = 10;
+
+/// Most first, then alphabetically so reruns agree
+fn top<K: Ord>(counts: HashMap<K, i64>) -> Vec<(K, i64)> {
+    let mut counts: Vec<_> = counts.into_iter().collect();
+    counts.sort_unstable_by(|(a_key, a), (b_key, b)| b.cmp(a).then_with(|| a_key.cmp(b_key)));
+    counts.truncate(TOP);
+    counts
+}
+
+async fn subreddit_stats_one(subreddit: &str) -> Result<(), UserError> {
+    let client = PG_POOL.get().await?;
+
+    // A post is a repost if its image was posted anywhere earlier,
+    // and the earliest of those is where it was sourced from
+    let rows = client
+        .query(
+            format!(
+                "SELECT images.link, images.hash, \
+                 (SELECT earlier.subreddit \
+                  FROM posts AS earlier INNER JOIN images AS earlier_images \
+                  ON earlier_images.hash <@ (images.hash, 0) \
+                  AND earlier.image_id = earlier_images.id \
+                  WHERE earlier.created_utc < posts.created_utc \
+                  AND NOT {} \
+                  ORDER BY earlier.created_utc ASC LIMIT 1) AS source \
+                 FROM posts INNER JOIN images ON image_id = images.id \
+                 WHERE LOWER(posts.subreddit) = $1 \
+                 AND NOT {}",
+                taken_down_sql_as("earlier_images"),
+                TAKEN_DOWN_SQL
+            )
+            .as_str(),
+            &[&subreddit],
+        )
+        .await?;
+
+    let mut reposts: i64 = 0;
+    let mut links: HashMap<i64, String> = HashMap::new();
+    let mut by_hash: HashMap<i64, i64> = HashMap::new();
+    let mut by_source: HashMap<String, i64> = HashMap::new();
+
+    for row in &rows {
+        if let Some(source) = row.get::<_, Option<String>>("source") {
+            let hash: i64 = row.get("hash");
+
+            reposts += 1;
+            *by_hash.entry(hash).or_default() += 1;
+            *by_source.entry(source).or_default() += 1;
+            links.entry(hash).or_insert_with(|| row.get("link"));
+        }
+    }
+
+    let (top_links, top_link_reposts): (Vec<String>, Vec<i64>) = top(by_hash)
+        .into_iter()
+        .map(|(hash, count)| (links.remove(&hash).unwrap(), count))
+        .unzip();
+    let (top_sources, top_source_reposts): (Vec<String>, Vec<i64>) =
+        top(by_source).into_iter().unzip();
+
+    client
+        .execute(
+            "INSERT INTO subreddit_stats \
+             (subreddit, posts, reposts, top_links, top_link_reposts, \
+             top_sources, top_source_reposts, computed_at) \
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8) \
+             ON CONFLICT (subreddit) DO UPDATE SET \
+             posts = EXCLUDED.posts, reposts = EXCLUDED.reposts, \
+             top_links = EXCLUDED.top_links, top_link_reposts = EXCLUDED.top_link_reposts, \
+             top_sources = EXCLUDED.top_sources, \
+             top_source_reposts = EXCLUDED.top_source_reposts, \
+             computed_at = EXCLUDED.computed_at",
+            &[
+                &subreddit,
+                &(rows.len() as i64),
+                &reposts,
+                &top_links,
+                &top_link_reposts,
+                &top_sources,
+                &top_source_reposts,
+                &chrono::offset::Utc::now().naive_utc(),
+            ],
+        )
+        .await?;
+
+    println!(
+        "/r/{}: {} of {} posts are reposts",
+        subreddit,
+        reposts,
+        rows.len()
+    );
+
+    Ok(())
+}
+
+/// Meant to be run on a schedule, like `rank`; with no names, does the busiest subreddits
+pub async fn subreddit_stats(names: Vec<String>, limit: i64) -> Result<(), UserError> {
+    let names = if names.is_empty() {
+        PG_POOL
+            .get()
+            .await?
+            .query(
+                "SELECT LOWER(subreddit) AS subreddit FROM posts \
+                 GROUP BY LOWER(subreddit) ORDER BY COUNT(*) DESC LIMIT $1",
+                &[&limit],
+            )
+            .await?
+            .iter()
+            .map(|row| row.get("subreddit"))
+            .collect()
+    } else {
+        names.iter().map(|name| name.to_lowercase()).collect()
+    };
+
+    for name in &names {
+        subreddit_stats_one(name).await?;
+    }
+
+    Ok(())
+}
+
+/// Records which posts made in the last `days` had their images posted earlier in another
+/// subreddit, and the earliest such post, for the site's subreddit reposts API; meant to be run
+/// on a schedule like `subreddit_stats`, covering at least the time since the last run
+pub async fn repost_matches(days: i64) -> Result<(), UserError> {
+    let now = chrono::offset::Utc::now().naive_utc();
+
+    let matched = PG_POOL
+        .get()
+        .await?
+        .execute(
+            "INSERT INTO repost_matches \
+             (reddit_id_int, created_utc, subreddit, \
+             source_reddit_id_int, source_created_utc, computed_at) \
+             SELECT posts.reddit_id_int, posts.created_utc, LOWER(posts.subreddit), \
+             source.reddit_id_int, source.created_utc, $2 \
+             FROM posts INNER JOIN images ON posts.image_id = images.id \
+             CROSS JOIN LATERAL \
+             (SELECT earlier.reddit_id_int, earlier.created_utc \
+              FROM posts AS earlier INNER JOIN images AS earlier_images \
+              ON earlier_images.hash <@ (images.hash, 0) \
+              AND earlier.image_id = earlier_images.id \
+              WHERE earlier.created_utc < posts.created_utc \
+              AND LOWER(earlier.subreddit) <> LOWER(posts.subreddit) \
+              ORDER BY earlier.created_utc ASC LIMIT 1) AS source \
+             WHERE posts.created_utc >= $1 \
+             ON CONFLICT (reddit_id_int) DO UPDATE SET \
+             source_reddit_id_int = EXCLUDED.source_reddit_id_int, \
+             source_created_utc = EXCLUDED.source_created_utc, \
+             computed_at = EXCLUDED.computed_at",
+            &[&(now - chrono::Duration::days(days)), &now],
+        )
+        .await?;
+
+    println!(
+        "Matched {} posts from the last {} days to earlier posts",
+        matched, days
+    );
+    Ok(())
+}
+
+/// Replaces hash_popularity with the `limit` hashes shared by the most images, which the site
+/// plans searches with; meant to be run on a schedule like `subreddit_stats`
+pub async fn hash_popularity(limit: i64) -> Result<(), UserError> {
+    let mut client = PG_POOL.get().await?;
+    let trans = client.transaction().await?;
+
+    trans.execute("DELETE FROM hash_popularity", &[]).await?;
+    let kept = trans
+        .execute(
+            "INSERT INTO hash_popularity (hash, images, computed_at) \
+             SELECT hash, COUNT(*), $2 FROM images GROUP BY hash HAVING COUNT(*) > 1 \
+             ORDER BY COUNT(*) DESC LIMIT $1",
+            &[&limit, &chrono::offset::Utc::now().naive_utc()],
+        )
+        .await?;
+
+    trans.commit().await?;
+
+    println!("Kept the {} most shared hashes", kept);
+    Ok(())
+}
+
+/// Rebuilds image_first_post from posts, for after bulk loads or deletes that bypassed it
+pub async fn first_posts() -> Result<(), UserError> {
+    let rebuilt = rebuild_first_posts().await?;
+
+    println!("Recorded the first posts of {} images", rebuilt);
+    Ok(())
+}
+
+/// Hides the author's /stats/author page
+pub async fn author_opt_out(name: &str) -> Result<(), UserError> {
+    PG_POOL
+        .get()
+        .await?
+        .execute(
+            "INSERT INTO author_opt_outs (author, opted_out_at) VALUES ($1, $2) \
+             ON CONFLICT DO NOTHING",
+            &[
+                &name.to_lowercase(),
+                &chrono::offset::Utc::now().naive_utc(),
+            ],
+        )
+        .await?;
+
+    println!("Opted out");
+    Ok(())
+}
+
+pub async fn author_opt_in(name: &str) -> Result<(), UserError> {
+    let removed = PG_POOL
+        .get()
+        .await?
+        .execute(
+            "DELETE FROM author_opt_outs WHERE author = $1",
+            &[&name.to_lowercase()],
+        )
+        .await?;
+
+    if removed == 0 {
+        Err(ue!("That author hasn't opted out"))
+    } else {
+        println!("Opted back in");
+        Ok(())
+    }
+}
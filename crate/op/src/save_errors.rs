@@ -0,0 +1,59 @@
+use common::*;
+use std::collections::BTreeMap;
+
+/// Prints how many posts failed to save with each category of save error, and with each
+/// error within those; `days` limits it to posts made that recently
+pub async fn save_errors(days: Option<i64>) -> Result<(), UserError> {
+    let since = days.map(|days| chrono::Utc::now().naive_utc() - chrono::Duration::days(days));
+
+    let rows = PG_POOL
+        .get()
+        .await?
+        .query(
+            "SELECT save_error, COUNT(*) AS count FROM posts WHERE save_error IS NOT NULL \
+             AND ($1::timestamp IS NULL OR created_utc >= $1) GROUP BY save_error",
+            &[&since],
+        )
+        .await?;
+
+    let mut categories: BTreeMap<SaveErrorCategory, (i64, Vec<(SaveError, i64)>)> = BTreeMap::new();
+    let mut unknown = Vec::new();
+
+    for row in rows {
+        let name: &str = row.get("save_error");
+        let count: i64 = row.get("count");
+
+        match name.parse::<SaveError>() {
+            Ok(save_error) => {
+                let (total, errors) = categories.entry(save_error.category()).or_default();
+                *total += count;
+                errors.push((save_error, count));
+            }
+            // Written before the taxonomy, or by a newer build
+            Err(_) => unknown.push((name.to_string(), count)),
+        }
+    }
+
+    for (category, (total, mut errors)) in categories {
+        println!("{}: {}", category.as_str(), total);
+
+        errors.sort_by(|a, b| b.1.cmp(&a.1));
+        for (save_error, count) in errors {
+            println!("    {}: {}", save_error, count);
+        }
+    }
+
+    if !unknown.is_empty() {
+        println!(
+            "unknown: {}",
+            unknown.iter().map(|(_name, count)| count).sum::<i64>()
+        );
+
+        unknown.sort_by(|a, b| b.1.cmp(&a.1));
+        for (name, count) in unknown {
+            println!("    {}: {}", name, count);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,60 @@
+use crate::output::{self, Output};
+use common::*;
+
+/// A hash given in decimal, or the hash of the image at a link
+async fn target_hash(link_or_hash: &str) -> Result<Hash, UserError> {
+    match link_or_hash.parse::<u64>() {
+        Ok(hash) => Ok(Hash(hash)),
+        Err(_) => Ok(get_hash(link_or_hash, false).await?.hash),
+    }
+}
+
+/// Prints each post saved with an image within `distance` of `link_or_hash` as it's ingested,
+/// until interrupted
+pub async fn watch(link_or_hash: &str, distance: u32, output: Output) -> Result<(), UserError> {
+    if !CONFIG.load().new_image_events {
+        warn!("new_image_events is off, so unless the ingesters turn it on nothing will show up");
+    }
+
+    let hash = target_hash(link_or_hash).await?;
+    eprintln!("Watching for posts within {} of {}", distance, hash);
+
+    let mut new_images = events::subscribe::<events::NewImage>(events::NEW_IMAGE_CHANNEL);
+    while let Some(event) = new_images.recv().await {
+        let post_id = match event.post_id {
+            Some(post_id) => post_id,
+            None => continue,
+        };
+
+        if Hash(event.hash).distance(hash) > distance {
+            continue;
+        }
+
+        let row = PG_POOL
+            .get()
+            .await?
+            .query_opt(
+                format!(
+                    "SELECT hash <-> $1 as distance, {} \
+                     FROM posts INNER JOIN images ON image_id = images.id \
+                     WHERE reddit_id_int = $2",
+                    output::MATCH_COLUMNS
+                )
+                .as_str(),
+                &[&hash, &post_id],
+            )
+            .await?;
+
+        if let Some(row) = row {
+            let found = output::post_match(&row, hash);
+            output.write(
+                &mut std::io::stdout(),
+                &found,
+                std::slice::from_ref(&found),
+                output::match_line,
+            )?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,78 @@
+use common::*;
+
+/// Prints each event recorded for a post, then how it's saved now, to show why it ended up
+/// the way it did
+pub async fn why(id: &str) -> Result<(), UserError> {
+    let id = RedditId::parse_fullname(id)?;
+    let id_int = id.0;
+
+    let events = ingest_events(id_int).await?;
+
+    if events.is_empty() {
+        let config = CONFIG.load();
+        println!(
+            "No events for {}; they're kept for {} days{}",
+            id,
+            config.ingest_events.retention_days,
+            if config.ingest_events.record_rejected {
+                ""
+            } else {
+                ", and posts turned away or skipped aren't recorded"
+            }
+        );
+    }
+
+    for event in &events {
+        print!("{}  {:<7} {}", event.at, event.source, event.decision);
+        if let Some(save_error) = &event.save_error {
+            print!(" ({})", save_error);
+        }
+        if let Some(detail) = &event.detail {
+            print!(": {}", detail);
+        }
+        println!();
+    }
+
+    let row = PG_POOL
+        .get()
+        .await?
+        .query_opt(
+            "SELECT posts.image_id, posts.save_error, posts.updated, images.link \
+             FROM posts LEFT JOIN images ON images.id = posts.image_id \
+             WHERE posts.reddit_id_int = $1",
+            &[&id_int],
+        )
+        .await?;
+
+    match row {
+        None => println!("Not in posts"),
+        Some(row) => {
+            let updated: Option<chrono::NaiveDateTime> = row.get("updated");
+            let updated = updated
+                .map(|updated| updated.to_string())
+                .unwrap_or_else(|| "never".to_string());
+
+            match (
+                row.get::<_, Option<i64>>("image_id"),
+                row.get::<_, Option<String>>("link"),
+            ) {
+                (Some(image_id), Some(link)) => println!(
+                    "In posts with image {} ({}); last updated {}",
+                    image_id, link, updated
+                ),
+                (Some(image_id), None) => println!(
+                    "In posts with image {}, which is missing; last updated {}",
+                    image_id, updated
+                ),
+                (None, _) => println!(
+                    "In posts without an image ({}); last updated {}",
+                    row.get::<_, Option<String>>("save_error")
+                        .unwrap_or_else(|| "no save error".to_string()),
+                    updated
+                ),
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,526 @@
+use crate::findings_cache;
+use crate::preferences::Preferences;
+use chrono::NaiveDateTime;
+use common::*;
+use http::StatusCode;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tera::Context;
+use warp::http::{header, Response};
+use warp::{Filter, Rejection, Reply};
+
+const COOKIE: &str = "admin_session";
+/// How long a login lasts
+const SESSION_LENGTH: Duration = Duration::from_secs(12 * 60 * 60);
+/// How many of the newest posts the error and host tables cover
+const RECENT_POSTS: i64 = 10_000;
+const MAX_HOSTS: i64 = 50;
+/// How many of the newest redirected images are shown
+const REDIRECTED_IMAGES: i64 = 50;
+/// How many of the newest takedown log entries are shown
+const LOG_ENTRIES: i64 = 100;
+/// How many internal errors are kept for the dashboard
+const KEPT_ERRORS: usize = 50;
+
+/// Session IDs and when they were issued; restarting the site logs everyone out
+static SESSIONS: Lazy<Mutex<HashMap<String, Instant>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Internal errors from serving pages, newest first; restarting the site forgets them
+static RECENT_ERRORS: Lazy<Mutex<VecDeque<RecentError>>> =
+    Lazy::new(|| Mutex::new(VecDeque::new()));
+
+#[derive(Clone, Serialize)]
+struct RecentError {
+    at: NaiveDateTime,
+    user_msg: String,
+    location: Option<String>,
+    chain: Vec<String>,
+    backtrace: Option<String>,
+}
+
+/// Keeps `ue` for the dashboard if it's our fault
+pub fn record(ue: &UserError) {
+    if !matches!(ue.source, Source::Internal) {
+        return;
+    }
+
+    let mut recent = RECENT_ERRORS.lock().unwrap();
+    recent.push_front(RecentError {
+        at: chrono::Utc::now().naive_utc(),
+        user_msg: ue.user_msg.to_string(),
+        location: ue
+            .file
+            .map(|file| format!("{}#{}", file, ue.line.unwrap_or(0))),
+        chain: ue.chain(),
+        backtrace: ue.backtrace(),
+    });
+    recent.truncate(KEPT_ERRORS);
+}
+
+#[derive(Debug)]
+pub struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+/// Compares every byte so the time taken doesn't give away how much of `given` was right
+fn token_matches(given: &str) -> bool {
+    match &SECRETS.site.admin_token {
+        Some(token) if !token.is_empty() && token.len() == given.len() => {
+            token
+                .bytes()
+                .zip(given.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+        }
+        _ => false,
+    }
+}
+
+fn session_valid(id: &str) -> bool {
+    let mut sessions = SESSIONS.lock().unwrap();
+    sessions.retain(|_id, issued| issued.elapsed() < SESSION_LENGTH);
+    sessions.contains_key(id)
+}
+
+fn start_session() -> String {
+    use rand::distributions::{Alphanumeric, DistString};
+
+    let id = Alphanumeric.sample_string(&mut rand::thread_rng(), 32);
+    SESSIONS.lock().unwrap().insert(id.clone(), Instant::now());
+    id
+}
+
+/// Whether the request has a live session cookie or an `Authorization: Bearer` admin token
+pub fn is_admin() -> impl Filter<Extract = (bool,), Error = Rejection> + Clone {
+    warp::cookie::optional(COOKIE)
+        .and(warp::header::optional::<String>("authorization"))
+        .map(|session: Option<String>, authorization: Option<String>| {
+            let by_session = session.map(|id| session_valid(&id)).unwrap_or(false);
+            let by_token = authorization
+                .as_deref()
+                .and_then(|auth| auth.strip_prefix("Bearer "))
+                .map(token_matches)
+                .unwrap_or(false);
+
+            by_session || by_token
+        })
+}
+
+/// Passes only requests that `is_admin` passes
+pub fn authenticated() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    is_admin()
+        .and_then(|admin: bool| async move {
+            if admin {
+                Ok(())
+            } else {
+                Err(warp::reject::custom(Unauthorized))
+            }
+        })
+        .untuple_one()
+}
+
+fn redirect(to: &str) -> Response<String> {
+    Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(header::LOCATION, to)
+        .body(String::new())
+        .unwrap()
+}
+
+pub async fn recover(rejection: Rejection) -> Result<impl Reply, Rejection> {
+    if rejection.find::<Unauthorized>().is_some() {
+        Ok(redirect("/admin/login"))
+    } else {
+        Err(rejection)
+    }
+}
+
+fn render(
+    template: &str,
+    context: Result<Context, tera::Error>,
+    status: StatusCode,
+) -> Response<String> {
+    let tera = super::get_tera!();
+
+    let (body, status) = match context.and_then(|context| tera.render(template, &context)) {
+        Ok(body) => (body, status),
+        Err(e) => {
+            error!("{}", e);
+            (
+                "<h1>Error 500: Internal Server Error</h1>".to_string(),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        }
+    };
+
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+        .body(body)
+        .unwrap()
+}
+
+#[derive(Serialize)]
+struct LoginPage {
+    preferences: Preferences,
+    enabled: bool,
+    error: Option<&'static str>,
+}
+
+pub async fn login_response(preferences: Preferences) -> Response<String> {
+    let page = LoginPage {
+        preferences,
+        enabled: SECRETS.site.admin_token.is_some(),
+        error: None,
+    };
+    render(
+        "admin_login.html",
+        Context::from_serialize(&page),
+        StatusCode::OK,
+    )
+}
+
+pub async fn post_login_response(
+    preferences: Preferences,
+    form: HashMap<String, String>,
+) -> Response<String> {
+    let given = form.get("token").map(String::as_str).unwrap_or("");
+
+    if token_matches(given) {
+        let mut response = redirect("/admin");
+        response.headers_mut().insert(
+            header::SET_COOKIE,
+            format!(
+                // The whole site reads it, so searches by a logged-in admin get the admin tier
+                "{}={}; Path=/; Max-Age={}; SameSite=Strict; HttpOnly",
+                COOKIE,
+                start_session(),
+                SESSION_LENGTH.as_secs()
+            )
+            .parse()
+            .unwrap(),
+        );
+        response
+    } else {
+        warn!("Failed admin login");
+        let page = LoginPage {
+            preferences,
+            enabled: SECRETS.site.admin_token.is_some(),
+            error: Some("wrong token"),
+        };
+        render(
+            "admin_login.html",
+            Context::from_serialize(&page),
+            StatusCode::UNAUTHORIZED,
+        )
+    }
+}
+
+pub async fn logout_response(session: Option<String>) -> Response<String> {
+    if let Some(id) = session {
+        SESSIONS.lock().unwrap().remove(&id);
+    }
+
+    let mut response = redirect("/admin/login");
+    response.headers_mut().insert(
+        header::SET_COOKIE,
+        format!("{}=; Path=/; Max-Age=0; SameSite=Strict; HttpOnly", COOKIE)
+            .parse()
+            .unwrap(),
+    );
+    response
+}
+
+pub fn session() -> impl Filter<Extract = (Option<String>,), Error = Rejection> + Clone {
+    warp::cookie::optional(COOKIE)
+}
+
+#[derive(Serialize)]
+struct ErrorCount {
+    kind: String,
+    count: i64,
+}
+
+#[derive(Serialize)]
+struct HostFailures {
+    host: String,
+    total: i64,
+    failed: i64,
+    /// Percent of `total`
+    rate: f64,
+}
+
+#[derive(Serialize)]
+struct Redirected {
+    submitted_url: Option<String>,
+    redirect_chain: Vec<String>,
+    resolved_url: Option<String>,
+}
+
+async fn recent_redirects() -> Result<Vec<Redirected>, UserError> {
+    Ok(PG_POOL
+        .get()
+        .await?
+        .query(
+            "SELECT submitted_url, redirect_chain, resolved_url FROM images \
+             WHERE redirect_chain <> '{}' ORDER BY id DESC LIMIT $1",
+            &[&REDIRECTED_IMAGES],
+        )
+        .await?
+        .into_iter()
+        .map(|row| Redirected {
+            submitted_url: row.get("submitted_url"),
+            redirect_chain: row.get("redirect_chain"),
+            resolved_url: row.get("resolved_url"),
+        })
+        .collect())
+}
+
+async fn recent_errors() -> Result<Vec<ErrorCount>, UserError> {
+    Ok(PG_POOL
+        .get()
+        .await?
+        .query(
+            "SELECT save_error, COUNT(*) AS count FROM \
+             (SELECT save_error FROM posts ORDER BY id DESC LIMIT $1) AS recent \
+             WHERE save_error IS NOT NULL GROUP BY save_error ORDER BY count DESC",
+            &[&RECENT_POSTS],
+        )
+        .await?
+        .into_iter()
+        .map(|row| ErrorCount {
+            kind: row.get("save_error"),
+            count: row.get("count"),
+        })
+        .collect())
+}
+
+async fn host_failures() -> Result<Vec<HostFailures>, UserError> {
+    Ok(PG_POOL
+        .get()
+        .await?
+        .query(
+            "SELECT host, COUNT(*) AS total, \
+             COUNT(*) FILTER (WHERE save_error IS NOT NULL) AS failed FROM \
+             (SELECT lower(substring(link from '^[a-zA-Z]+://([^/:?#]+)')) AS host, save_error \
+             FROM posts ORDER BY id DESC LIMIT $1) AS recent \
+             WHERE host IS NOT NULL GROUP BY host \
+             HAVING COUNT(*) FILTER (WHERE save_error IS NOT NULL) > 0 \
+             ORDER BY failed DESC LIMIT $2",
+            &[&RECENT_POSTS, &MAX_HOSTS],
+        )
+        .await?
+        .into_iter()
+        .map(|row| {
+            let total: i64 = row.get("total");
+            let failed: i64 = row.get("failed");
+            HostFailures {
+                host: row.get("host"),
+                total,
+                failed,
+                rate: failed as f64 / total as f64 * 100.,
+            }
+        })
+        .collect())
+}
+
+#[derive(Serialize)]
+struct AdminPage {
+    preferences: Preferences,
+    recent_posts: i64,
+    errors: Vec<ErrorCount>,
+    hosts: Vec<HostFailures>,
+    redirects: Vec<Redirected>,
+    banned_links: Vec<BannedLink>,
+    ban_kinds: [&'static str; 4],
+    takedowns: Vec<Takedown>,
+    takedown_log: Vec<TakedownEvent>,
+    internal_errors: Vec<RecentError>,
+    message: Option<String>,
+    error: Option<UserError>,
+    /// `error`'s chain, for when the message alone doesn't say what went wrong
+    error_chain: Vec<String>,
+}
+
+async fn dashboard(
+    preferences: Preferences,
+    result: Result<String, UserError>,
+) -> Result<Response<String>, UserError> {
+    let (errors, hosts, redirects, banned_links, takedowns, takedown_log) = futures::try_join!(
+        recent_errors(),
+        host_failures(),
+        recent_redirects(),
+        banned_links(),
+        takedowns(),
+        takedown_log(LOG_ENTRIES)
+    )?;
+
+    let status = match &result {
+        Ok(_) => StatusCode::OK,
+        Err(ue) => ue.status_code(),
+    };
+    let error_chain = match &result {
+        Ok(_) => Vec::new(),
+        Err(ue) => ue.chain(),
+    };
+    let (message, error) = match result {
+        Ok(message) if message.is_empty() => (None, None),
+        Ok(message) => (Some(message), None),
+        Err(ue) => (None, Some(ue)),
+    };
+
+    let page = AdminPage {
+        preferences,
+        recent_posts: RECENT_POSTS,
+        errors,
+        hosts,
+        redirects,
+        banned_links,
+        ban_kinds: ["HostEnd", "Host", "AnyScheme", "Full"],
+        takedowns,
+        takedown_log,
+        internal_errors: RECENT_ERRORS.lock().unwrap().iter().cloned().collect(),
+        message,
+        error,
+        error_chain,
+    };
+
+    Ok(render("admin.html", Context::from_serialize(&page), status))
+}
+
+pub async fn get_response(preferences: Preferences) -> Result<Response<String>, UserError> {
+    dashboard(preferences, Ok(String::new())).await
+}
+
+fn field<'a>(form: &'a HashMap<String, String>, name: &str) -> Result<&'a str, UserError> {
+    form.get(name)
+        .map(|value| value.trim())
+        .ok_or_else(|| ue!(format!("missing {}", name), Source::User))
+}
+
+async fn ban(form: &HashMap<String, String>) -> Result<String, UserError> {
+    let banned = Banned::from_parts(field(form, "kind")?, field(form, "value")?.to_string())?;
+
+    Ok(if ban_link(&banned).await? {
+        info!("Banned {:?}", banned);
+        format!("Banned {}", banned.value())
+    } else {
+        format!("{} was already banned", banned.value())
+    })
+}
+
+async fn unban(form: &HashMap<String, String>) -> Result<String, UserError> {
+    let id: i64 = field(form, "id")?
+        .parse()
+        .map_err(map_ue!("invalid ban ID", Source::User))?;
+
+    if unban_link(id).await? {
+        info!("Removed ban {}", id);
+        Ok("Removed the ban".to_string())
+    } else {
+        Err(ue!("no such ban", Source::User))
+    }
+}
+
+async fn rehash(form: &HashMap<String, String>) -> Result<String, UserError> {
+    let link = field(form, "link")?;
+    let rehashed = rehash_link(link).await?;
+
+    info!("Rehashed {}", link);
+    let mut message = if rehashed.not_modified {
+        format!("{} hasn't changed; it wasn't hashed again", link)
+    } else {
+        format!(
+            "Rehashed {}; {} stored image{} updated",
+            link,
+            rehashed.updated,
+            if rehashed.updated == 1 { "" } else { "s" }
+        )
+    };
+    if let Some(moved_to) = rehashed.moved_to {
+        message.push_str(&format!("; it permanently redirects to {}", moved_to));
+    }
+    Ok(message)
+}
+
+async fn take_down_link(form: &HashMap<String, String>, actor: &str) -> Result<String, UserError> {
+    let link = field(form, "link")?;
+    let reason = field(form, "reason")?;
+    if link.is_empty() || reason.is_empty() {
+        return Err(ue!("a takedown needs a link and a reason", Source::User));
+    }
+
+    let target = match field(form, "scope")? {
+        "link" => TakedownTarget::Link(link.to_string()),
+        "hash" => {
+            let stored = PG_POOL
+                .get()
+                .await?
+                .query_opt("SELECT hash FROM images WHERE link = $1", &[&link])
+                .await?
+                .map(|row| row.get::<_, Hash>("hash"));
+            let hash = match stored {
+                Some(hash) => hash,
+                None => get_hash(link, true).await?.hash,
+            };
+            TakedownTarget::Hash(hash)
+        }
+        _ => return Err(ue!("unknown takedown scope", Source::User)),
+    };
+
+    let taken = take_down(&target, reason, actor).await?;
+    findings_cache::clear();
+
+    info!("{} took down {:?} as takedown {}", actor, target, taken.id);
+    Ok(format!(
+        "Took down {}: cleared thumbnails from {} post{} and deleted {} blob{}",
+        link,
+        taken.posts,
+        if taken.posts == 1 { "" } else { "s" },
+        taken.blobs_deleted,
+        if taken.blobs_deleted == 1 { "" } else { "s" }
+    ))
+}
+
+async fn lift(form: &HashMap<String, String>, actor: &str) -> Result<String, UserError> {
+    let id: i64 = field(form, "id")?
+        .parse()
+        .map_err(map_ue!("invalid takedown ID", Source::User))?;
+
+    if lift_takedown(id, actor).await? {
+        findings_cache::clear();
+        info!("{} lifted takedown {}", actor, id);
+        Ok(format!("Lifted takedown {}", id))
+    } else {
+        Err(ue!("no such takedown in effect", Source::User))
+    }
+}
+
+/// Handles /admin/<action>, then shows the dashboard with how it went
+pub async fn action_response(
+    action: String,
+    ip: Option<IpAddr>,
+    preferences: Preferences,
+    form: HashMap<String, String>,
+) -> Result<Response<String>, UserError> {
+    // Admins share a token, so where they were is all the audit log can say
+    let actor = match ip {
+        Some(ip) => format!("admin at {}", ip),
+        None => "admin".to_string(),
+    };
+
+    let result = match action.as_str() {
+        "ban" => ban(&form).await,
+        "unban" => unban(&form).await,
+        "rehash" => rehash(&form).await,
+        "takedown" => take_down_link(&form, &actor).await,
+        "lift" => lift(&form, &actor).await,
+        _ => Err(ue!("unknown action", Source::User)),
+    };
+
+    dashboard(preferences, result).await
+}
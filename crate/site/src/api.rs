@@ -0,0 +1,492 @@
+use crate::admin;
+use crate::rate_limit::{self, KEYED_LIMITER, SEARCH_LIMITER};
+use crate::search::{link_findings, Form};
+use chrono::NaiveDateTime;
+use common::config::Tier;
+use common::*;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::net::IpAddr;
+use warp::http::StatusCode;
+use warp::reply::{Json, WithStatus};
+use warp::{Filter, Rejection, Reply};
+
+const QUICK_TOP: usize = 10;
+const PROGRESS_RUNS: i64 = 50;
+/// How many reposts a page has unless `limit` says otherwise, and the most it can say
+const REPOSTS_PAGE: i64 = 100;
+const MAX_REPOSTS_PAGE: i64 = 500;
+
+#[derive(Deserialize)]
+pub struct QuickQuery {
+    url: String,
+    key: Option<String>,
+    /// `on` to leave out images whose links were found dead
+    exclude_dead: Option<String>,
+}
+
+#[derive(Serialize)]
+struct QuickMatch {
+    permalink: String,
+    distance: i64,
+    created_utc: chrono::NaiveDateTime,
+    subreddit: String,
+}
+
+/// Kept deliberately small and stable; extensions depend on this shape
+#[derive(Serialize)]
+struct Quick {
+    count: usize,
+    earliest: Option<String>,
+    matches: Vec<QuickMatch>,
+}
+
+#[derive(Serialize)]
+struct QuickError {
+    error: String,
+}
+
+/// An `x-api-key` that isn't in api_keys, or has been revoked
+#[derive(Debug)]
+pub struct InvalidKey;
+
+impl warp::reject::Reject for InvalidKey {}
+
+async fn key_id(key: &str) -> Result<Option<i64>, UserError> {
+    let client = PG_POOL.get().await?;
+
+    let row = client
+        .query_opt(
+            "SELECT id FROM api_keys WHERE key = $1 AND NOT revoked",
+            &[&key],
+        )
+        .await?;
+
+    Ok(row.map(|row| row.get("id")))
+}
+
+async fn quick(
+    link: &str,
+    exclude_dead: Option<String>,
+    tier: Tier,
+    ip: Option<IpAddr>,
+) -> Result<Quick, UserError> {
+    let limits = CONFIG.load().search_limits(tier);
+
+    let findings = link_findings(
+        link,
+        &Form {
+            link: link.to_string(),
+            exclude_dead: exclude_dead.unwrap_or_default(),
+            ..Form::default()
+        },
+        limits.max_results,
+        limits,
+        ip,
+    )
+    .await?;
+
+    let earliest = findings
+        .earliest
+        .iter()
+        .min_by_key(|e| e.post.created_utc)
+        .map(|e| e.post.permalink.clone());
+
+    Ok(Quick {
+        count: findings.match_count,
+        earliest,
+        matches: findings
+            .groups
+            .into_iter()
+            .filter_map(|g| g.matches.into_iter().next())
+            .take(QUICK_TOP)
+            .map(|m| QuickMatch {
+                permalink: m.permalink,
+                distance: m.distance,
+                created_utc: m.created_utc,
+                subreddit: m.subreddit,
+            })
+            .collect(),
+    })
+}
+
+fn error_reply(error: String, status: StatusCode) -> WithStatus<Json> {
+    warp::reply::with_status(warp::reply::json(&QuickError { error }), status)
+}
+
+/// Rate limits the client by its API key, or by its address if it sent none; the error is the
+/// reply for a key that's invalid or couldn't be looked up
+async fn limit_by_key(
+    key: Option<String>,
+    ip: Option<IpAddr>,
+) -> Result<Result<Tier, WithStatus<Json>>, Rejection> {
+    let key_id = match key {
+        None => None,
+        Some(key) => match key_id(&key).await {
+            Ok(Some(id)) => Some(id),
+            Ok(None) => {
+                return Ok(Err(error_reply(
+                    "invalid API key".to_string(),
+                    StatusCode::UNAUTHORIZED,
+                )))
+            }
+            Err(ue) => {
+                error!("Couldn't look up API key: {}", ue);
+                return Ok(Err(error_reply(ue.user_msg.to_string(), ue.status_code())));
+            }
+        },
+    };
+
+    Ok(Ok(match key_id {
+        Some(id) => {
+            KEYED_LIMITER.enforce(Some(id))?;
+            Tier::Keyed
+        }
+        None => {
+            SEARCH_LIMITER.enforce(ip)?;
+            Tier::Anonymous
+        }
+    }))
+}
+
+async fn quick_response(
+    query: QuickQuery,
+    header_key: Option<String>,
+    ip: Option<IpAddr>,
+) -> Result<impl Reply, Rejection> {
+    let tier = match limit_by_key(header_key.or(query.key), ip).await? {
+        Ok(tier) => tier,
+        Err(reply) => return Ok(reply),
+    };
+
+    Ok(
+        match quick(&query.url, query.exclude_dead, tier, ip).await {
+            Ok(quick) => warp::reply::with_status(warp::reply::json(&quick), StatusCode::OK),
+            Err(ue) => {
+                warn!("{}", ue);
+                error_reply(ue.user_msg.to_string(), ue.status_code())
+            }
+        },
+    )
+}
+
+#[derive(Serialize)]
+struct Progress {
+    ingests: Option<Vec<IngestProgress>>,
+    error: Option<UserError>,
+}
+
+/// The latest archive ingests, newest first
+pub async fn progress_json_response() -> impl Reply {
+    let (ingests, error, status) = match recent_progress(PROGRESS_RUNS).await {
+        Ok(ingests) => (Some(ingests), None, StatusCode::OK),
+        Err(ue) => {
+            warn!("{}", ue);
+            let status = ue.status_code();
+            (None, Some(ue), status)
+        }
+    };
+
+    warp::reply::with_status(warp::reply::json(&Progress { ingests, error }), status)
+}
+
+pub fn quick_filter() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("api" / "v1" / "quick")
+        .and(warp::get())
+        .and(warp::query::<QuickQuery>())
+        .and(warp::header::optional::<String>("x-api-key"))
+        .and(rate_limit::client_ip())
+        .and_then(quick_response)
+        .with(
+            warp::cors()
+                .allow_any_origin()
+                .allow_methods(vec!["GET"])
+                .allow_headers(vec!["x-api-key"]),
+        )
+}
+
+#[derive(Deserialize)]
+pub struct RepostsQuery {
+    /// Only posts made since then, in Unix seconds or RFC 3339
+    since: Option<String>,
+    /// The `next_cursor` of the page before
+    cursor: Option<String>,
+    limit: Option<i64>,
+    /// The fields of each repost to return, comma-separated; all of them by default
+    fields: Option<String>,
+    key: Option<String>,
+}
+
+/// A post whose image was posted earlier in another subreddit, from repost_matches
+#[derive(Serialize)]
+struct Repost {
+    id: String,
+    permalink: String,
+    title: String,
+    author: String,
+    score: i64,
+    created_utc: NaiveDateTime,
+    link: String,
+    source_id: String,
+    source_permalink: String,
+    source_author: String,
+    source_subreddit: String,
+    source_created_utc: NaiveDateTime,
+}
+
+/// What `fields` can pick from
+const REPOST_FIELDS: &[&str] = &[
+    "id",
+    "permalink",
+    "title",
+    "author",
+    "score",
+    "created_utc",
+    "link",
+    "source_id",
+    "source_permalink",
+    "source_author",
+    "source_subreddit",
+    "source_created_utc",
+];
+
+#[derive(Serialize)]
+struct Reposts {
+    subreddit: String,
+    reposts: Vec<Map<String, Value>>,
+    /// Passed as `cursor` for the next page; null on the last
+    next_cursor: Option<String>,
+}
+
+fn parse_since(since: &str) -> Result<NaiveDateTime, UserError> {
+    match since.parse::<i64>() {
+        Ok(secs) => NaiveDateTime::from_timestamp_opt(secs, 0),
+        Err(_) => chrono::DateTime::parse_from_rfc3339(since)
+            .ok()
+            .map(|since| since.naive_utc()),
+    }
+    .ok_or_else(|| ue!("invalid since parameter", Source::User))
+}
+
+/// Pages go newest first, so a cursor is the last repost's time and ID, like `1600000000_abc12`
+fn cursor(created_utc: NaiveDateTime, reddit_id_int: i64) -> String {
+    format!("{}_{}", created_utc.timestamp(), RedditId(reddit_id_int))
+}
+
+fn parse_cursor(cursor: &str) -> Result<(NaiveDateTime, i64), UserError> {
+    cursor
+        .split_once('_')
+        .and_then(|(secs, id)| {
+            Some((
+                NaiveDateTime::from_timestamp_opt(secs.parse().ok()?, 0)?,
+                id.parse::<RedditId>().ok()?.0,
+            ))
+        })
+        .ok_or_else(|| ue!("invalid cursor parameter", Source::User))
+}
+
+/// A page of the subreddit's posts that reposted images from elsewhere, newest first;
+/// computed ahead of time by `op repost_matches`
+async fn subreddit_reposts(subreddit: String, query: RepostsQuery) -> Result<Reposts, UserError> {
+    let since = query.since.as_deref().map(parse_since).transpose()?;
+    let (before, before_id) = match query.cursor.as_deref().map(parse_cursor).transpose()? {
+        Some((before, before_id)) => (Some(before), Some(before_id)),
+        None => (None, None),
+    };
+    let limit = query.limit.unwrap_or(REPOSTS_PAGE);
+    if limit < 1 || limit > MAX_REPOSTS_PAGE {
+        return Err(ue!(
+            format!("limit must be from 1 to {}", MAX_REPOSTS_PAGE),
+            Source::User
+        ));
+    }
+    let fields: Option<Vec<&str>> = query
+        .fields
+        .as_deref()
+        .map(|fields| fields.split(',').map(str::trim).collect());
+    if let Some(unknown) = fields
+        .iter()
+        .flatten()
+        .find(|field| !REPOST_FIELDS.contains(field))
+    {
+        return Err(ue!(format!("unknown field {}", unknown), Source::User));
+    }
+
+    // One past the page, to tell whether there's another
+    let rows = db::read_client()
+        .await?
+        .query(
+            format!(
+                "SELECT posts.reddit_id_int, posts.permalink, posts.title, posts.author, \
+                 posts.score, posts.created_utc, images.link, \
+                 source.reddit_id_int AS source_reddit_id_int, \
+                 source.permalink AS source_permalink, source.author AS source_author, \
+                 source.subreddit AS source_subreddit, source.created_utc AS source_created_utc \
+                 FROM repost_matches INNER JOIN posts \
+                 ON posts.reddit_id_int = repost_matches.reddit_id_int \
+                 AND posts.created_utc = repost_matches.created_utc \
+                 INNER JOIN posts AS source \
+                 ON source.reddit_id_int = repost_matches.source_reddit_id_int \
+                 AND source.created_utc = repost_matches.source_created_utc \
+                 INNER JOIN images ON images.id = posts.image_id \
+                 WHERE repost_matches.subreddit = $1 \
+                 AND ($2::timestamp IS NULL OR repost_matches.created_utc >= $2) \
+                 AND ($3::timestamp IS NULL \
+                 OR (repost_matches.created_utc, repost_matches.reddit_id_int) < ($3, $4)) \
+                 AND NOT {} \
+                 ORDER BY repost_matches.created_utc DESC, repost_matches.reddit_id_int DESC \
+                 LIMIT $5",
+                TAKEN_DOWN_SQL
+            )
+            .as_str(),
+            &[&subreddit, &since, &before, &before_id, &(limit + 1)],
+        )
+        .await?;
+
+    let next_cursor = if rows.len() as i64 > limit {
+        let last = &rows[limit as usize - 1];
+        Some(cursor(last.get("created_utc"), last.get("reddit_id_int")))
+    } else {
+        None
+    };
+
+    let reposts = rows
+        .iter()
+        .take(limit as usize)
+        .map(|row| {
+            let repost = Repost {
+                id: RedditId(row.get("reddit_id_int")).to_string(),
+                permalink: format!("https://reddit.com{}", row.get::<_, &str>("permalink")),
+                title: row.get("title"),
+                author: row.get("author"),
+                score: row.get("score"),
+                created_utc: row.get("created_utc"),
+                link: row.get("link"),
+                source_id: RedditId(row.get("source_reddit_id_int")).to_string(),
+                source_permalink: format!(
+                    "https://reddit.com{}",
+                    row.get::<_, &str>("source_permalink")
+                ),
+                source_author: row.get("source_author"),
+                source_subreddit: row.get("source_subreddit"),
+                source_created_utc: row.get("source_created_utc"),
+            };
+
+            let mut repost = match serde_json::to_value(repost)? {
+                Value::Object(repost) => repost,
+                _ => unreachable!("a struct serializes to an object"),
+            };
+            if let Some(fields) = &fields {
+                repost.retain(|field, _| fields.contains(&field.as_str()));
+            }
+            Ok(repost)
+        })
+        .collect::<Result<_, UserError>>()?;
+
+    Ok(Reposts {
+        subreddit,
+        reposts,
+        next_cursor,
+    })
+}
+
+async fn reposts_response(
+    name: String,
+    query: RepostsQuery,
+    header_key: Option<String>,
+    ip: Option<IpAddr>,
+) -> Result<impl Reply, Rejection> {
+    if let Err(reply) = limit_by_key(header_key.or_else(|| query.key.clone()), ip).await? {
+        return Ok(reply);
+    }
+
+    Ok(match subreddit_reposts(name.to_lowercase(), query).await {
+        Ok(reposts) => warp::reply::with_status(warp::reply::json(&reposts), StatusCode::OK),
+        Err(ue) => {
+            warn!("{}", ue);
+            error_reply(ue.user_msg.to_string(), ue.status_code())
+        }
+    })
+}
+
+/// `/api/v1/subreddit/{name}/reposts`, for dashboards following a subreddit's reposts
+pub fn reposts_filter() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("api" / "v1" / "subreddit" / String / "reposts")
+        .and(warp::get())
+        .and(warp::query::<RepostsQuery>())
+        .and(warp::header::optional::<String>("x-api-key"))
+        .and(rate_limit::client_ip())
+        .and_then(reposts_response)
+        .with(
+            warp::cors()
+                .allow_any_origin()
+                .allow_methods(vec!["GET"])
+                .allow_headers(vec!["x-api-key"]),
+        )
+}
+
+/// A site client's tier, with its key's ID if it's keyed
+#[derive(Clone, Copy, Debug)]
+pub struct Client {
+    pub tier: Tier,
+    key_id: Option<i64>,
+}
+
+impl Client {
+    /// Rate limits the client by its tier: admins aren't limited, keyed clients are limited
+    /// by their key, and anonymous ones by `ip`
+    pub fn enforce(&self, ip: Option<IpAddr>) -> Result<(), Rejection> {
+        match self.tier {
+            Tier::Admin => Ok(()),
+            Tier::Keyed => KEYED_LIMITER.enforce(self.key_id),
+            Tier::Anonymous => SEARCH_LIMITER.enforce(ip),
+        }
+    }
+}
+
+/// The client: admin by `admin::is_admin`, else keyed if it sent a valid `x-api-key`; an
+/// invalid key is rejected, but one that can't be looked up is let through as anonymous
+pub fn client() -> impl Filter<Extract = (Client,), Error = Rejection> + Clone {
+    admin::is_admin()
+        .and(warp::header::optional::<String>("x-api-key"))
+        .and_then(|admin: bool, key: Option<String>| async move {
+            if admin {
+                return Ok(Client {
+                    tier: Tier::Admin,
+                    key_id: None,
+                });
+            }
+
+            let anonymous = Client {
+                tier: Tier::Anonymous,
+                key_id: None,
+            };
+            match key {
+                None => Ok(anonymous),
+                Some(key) => match key_id(&key).await {
+                    Ok(Some(id)) => Ok(Client {
+                        tier: Tier::Keyed,
+                        key_id: Some(id),
+                    }),
+                    Ok(None) => Err(warp::reject::custom(InvalidKey)),
+                    Err(ue) => {
+                        error!("Couldn't look up API key: {}", ue);
+                        Ok(anonymous)
+                    }
+                },
+            }
+        })
+}
+
+pub async fn recover(rejection: Rejection) -> Result<impl Reply, Rejection> {
+    if rejection.find::<InvalidKey>().is_some() {
+        Ok(warp::reply::with_status(
+            warp::reply::json(&QuickError {
+                error: "invalid API key".to_string(),
+            }),
+            StatusCode::UNAUTHORIZED,
+        ))
+    } else {
+        Err(rejection)
+    }
+}
@@ -0,0 +1,112 @@
+use common::*;
+use once_cell::sync::Lazy;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::Hasher;
+use std::path::{Path, PathBuf};
+use warp::http::{header, Response, StatusCode};
+use warp::Rejection;
+
+const STATIC_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/static");
+
+pub struct Asset {
+    path: PathBuf,
+    content_type: &'static str,
+}
+
+#[derive(Default)]
+pub struct Assets {
+    // Maps the original name to the name with the content hash in it
+    hashed_names: HashMap<String, String>,
+    by_hashed_name: HashMap<String, Asset>,
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("css") => "text/css; charset=utf-8",
+        Some("js") => "application/javascript; charset=utf-8",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("ico") => "image/vnd.microsoft.icon",
+        Some("txt") => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
+
+fn hashed_name(name: &str, contents: &[u8]) -> String {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(contents);
+    let hash = format!("{:016x}", hasher.finish());
+
+    match name.rfind('.') {
+        Some(dot) => format!("{}.{}{}", &name[..dot], &hash[..10], &name[dot..]),
+        None => format!("{}.{}", name, &hash[..10]),
+    }
+}
+
+impl Assets {
+    pub fn load(dir: impl AsRef<Path>) -> Result<Self, UserError> {
+        let mut assets = Self::default();
+
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Couldn't read static asset directory: {}", e);
+                return Ok(assets);
+            }
+        };
+
+        for entry in entries {
+            let path = entry?.path();
+            if !path.is_file() {
+                continue;
+            }
+
+            let name = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .ok_or_else(|| ue!("non-UTF-8 static asset name", Source::Internal))?
+                .to_string();
+
+            let hashed = hashed_name(&name, &std::fs::read(&path)?);
+
+            assets.by_hashed_name.insert(
+                hashed.clone(),
+                Asset {
+                    content_type: content_type(&path),
+                    path,
+                },
+            );
+            assets.hashed_names.insert(name, hashed);
+        }
+
+        Ok(assets)
+    }
+
+    pub fn url(&self, name: &str) -> Option<String> {
+        self.hashed_names
+            .get(name)
+            .map(|hashed| format!("/static/{}", hashed))
+    }
+
+    pub async fn response(&self, hashed: &str) -> Result<Response<Vec<u8>>, Rejection> {
+        let asset = self
+            .by_hashed_name
+            .get(hashed)
+            .ok_or_else(warp::reject::not_found)?;
+
+        let body = tokio::fs::read(&asset.path)
+            .await
+            .map_err(|_e| warp::reject::not_found())?;
+
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, asset.content_type)
+            // The name changes whenever the contents do, so it never needs to be revalidated
+            .header(header::CACHE_CONTROL, "public, max-age=31536000, immutable")
+            .body(body)
+            .unwrap())
+    }
+}
+
+pub static ASSETS: Lazy<Assets> = Lazy::new(|| Assets::load(STATIC_DIR).unwrap());
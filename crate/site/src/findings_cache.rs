@@ -0,0 +1,110 @@
+//! Recent findings, so an image searched for over and over is only searched for once in a while
+
+use crate::search::{Findings, Params};
+use common::*;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A search as it was run
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Key {
+    /// The 64-bit hash, which new images are compared with even for 128-bit searches
+    pub hash: u64,
+    /// The halves of the 128-bit hash, for 128-bit searches
+    pub hash128: Option<(i64, i64)>,
+    pub params: Params,
+}
+
+struct Entry {
+    findings: Findings,
+    stored: Instant,
+    used: Instant,
+}
+
+static CACHE: Lazy<Mutex<HashMap<Key, Entry>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn ttl() -> Duration {
+    Duration::from_secs(CONFIG.load().findings_cache.ttl_secs)
+}
+
+/// The findings of `key`, if they were stored less than `ttl_secs` ago
+pub fn get(key: &Key) -> Option<Findings> {
+    let ttl = ttl();
+    let mut cache = CACHE.lock().unwrap();
+
+    match cache.get_mut(key) {
+        Some(entry) if entry.stored.elapsed() < ttl => {
+            entry.used = Instant::now();
+            Some(entry.findings.clone())
+        }
+        Some(_) => {
+            cache.remove(key);
+            None
+        }
+        None => None,
+    }
+}
+
+pub fn insert(key: Key, findings: &Findings) {
+    let capacity = CONFIG.load().findings_cache.capacity;
+    if capacity == 0 {
+        return;
+    }
+
+    let ttl = ttl();
+    let mut cache = CACHE.lock().unwrap();
+    cache.retain(|_key, entry| entry.stored.elapsed() < ttl);
+
+    // It's kept small enough that finding the least recently used by looking through them all
+    // is fine
+    while cache.len() >= capacity && !cache.contains_key(&key) {
+        let oldest = cache
+            .iter()
+            .min_by_key(|(_key, entry)| entry.used)
+            .map(|(key, _entry)| key.clone());
+        match oldest {
+            Some(oldest) => cache.remove(&oldest),
+            None => break,
+        };
+    }
+
+    let now = Instant::now();
+    cache.insert(
+        key,
+        Entry {
+            findings: findings.clone(),
+            stored: now,
+            used: now,
+        },
+    );
+}
+
+/// Forgets every search, for when what any of them found may have been taken down
+pub fn clear() {
+    CACHE.lock().unwrap().clear();
+}
+
+/// Forgets the searches that would find an image with `hash`; 128-bit searches are judged by
+/// their 64-bit hashes, so a few may be kept until `ttl_secs` runs out
+fn forget_near(hash: Hash) {
+    CACHE
+        .lock()
+        .unwrap()
+        .retain(|key, _entry| i64::from(Hash(key.hash).distance(hash)) > key.params.distance());
+}
+
+/// Forgets searches as new images are announced, for as long as the site runs
+pub fn start_listener() {
+    if CONFIG.load().findings_cache.capacity == 0 {
+        return;
+    }
+
+    let mut new_images = events::subscribe::<events::NewImage>(events::NEW_IMAGE_CHANNEL);
+    tokio::spawn(async move {
+        while let Some(event) = new_images.recv().await {
+            forget_near(Hash(event.hash));
+        }
+    });
+}
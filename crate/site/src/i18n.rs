@@ -0,0 +1,172 @@
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::path::Path;
+use tera::{to_value, try_get_value, Error, Result, Value};
+
+const LOCALES_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/locales");
+/// Every key must be in this locale; others fall back to it
+pub const DEFAULT_LOCALE: &str = "en";
+
+/// Each locale's messages, keyed like `search.heading`
+pub struct Catalogs(HashMap<String, HashMap<String, String>>);
+
+fn flatten(prefix: &str, value: toml::Value, messages: &mut HashMap<String, String>) {
+    match value {
+        toml::Value::Table(table) => {
+            for (key, value) in table {
+                let key = if prefix.is_empty() {
+                    key
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+                flatten(&key, value, messages);
+            }
+        }
+        toml::Value::String(message) => {
+            messages.insert(prefix.to_string(), message);
+        }
+        other => {
+            messages.insert(prefix.to_string(), other.to_string());
+        }
+    }
+}
+
+impl Catalogs {
+    /// Reads `<locale>.toml` for every locale in `dir`
+    pub fn load(dir: impl AsRef<Path>) -> std::result::Result<Self, Box<dyn std::error::Error>> {
+        let mut catalogs = HashMap::new();
+
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("toml") {
+                continue;
+            }
+
+            let locale = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .ok_or("locale file names must be UTF-8")?
+                .to_lowercase();
+
+            let mut messages = HashMap::new();
+            flatten("", std::fs::read_to_string(&path)?.parse()?, &mut messages);
+            catalogs.insert(locale, messages);
+        }
+
+        if !catalogs.contains_key(DEFAULT_LOCALE) {
+            return Err(format!("there's no catalog for {}", DEFAULT_LOCALE).into());
+        }
+
+        Ok(Self(catalogs))
+    }
+
+    pub fn has(&self, locale: &str) -> bool {
+        self.0.contains_key(locale)
+    }
+
+    /// Every locale with a catalog and its name for itself, sorted by locale
+    pub fn names(&self) -> Vec<(String, String)> {
+        let mut names: Vec<_> = self
+            .0
+            .iter()
+            .map(|(locale, messages)| {
+                (
+                    locale.clone(),
+                    messages
+                        .get("language.name")
+                        .cloned()
+                        .unwrap_or_else(|| locale.clone()),
+                )
+            })
+            .collect();
+        names.sort();
+        names
+    }
+
+    pub fn message(&self, locale: &str, key: &str) -> Option<&str> {
+        self.0
+            .get(locale)
+            .and_then(|messages| messages.get(key))
+            .or_else(|| self.0[DEFAULT_LOCALE].get(key))
+            .map(String::as_str)
+    }
+}
+
+pub static CATALOGS: Lazy<Catalogs> = Lazy::new(|| match Catalogs::load(LOCALES_DIR) {
+    Ok(catalogs) => catalogs,
+    Err(e) => {
+        println!("Couldn't load locales: {}", e);
+        std::process::exit(1);
+    }
+});
+
+/// The locale a visitor gets: the one they chose if it has a catalog, otherwise the most
+/// preferred in their Accept-Language that has one, trying `pt` for `pt-BR` as well
+pub fn negotiate(chosen: Option<&str>, accept_language: Option<&str>) -> String {
+    if let Some(chosen) = chosen {
+        if CATALOGS.has(chosen) {
+            return chosen.to_string();
+        }
+    }
+
+    let mut ranges: Vec<(f32, &str)> = accept_language
+        .unwrap_or("")
+        .split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';');
+            let tag = parts.next()?.trim();
+            let quality = parts
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .next()
+                .map(|q| q.parse().unwrap_or(0.))
+                .unwrap_or(1.);
+            if tag.is_empty() || quality <= 0. {
+                None
+            } else {
+                Some((quality, tag))
+            }
+        })
+        .collect();
+    // Stable, so equal weights keep the browser's order
+    ranges.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+
+    ranges
+        .into_iter()
+        .map(|(_, tag)| tag.to_lowercase())
+        .flat_map(|tag| {
+            let language = tag.split('-').next().unwrap_or("").to_string();
+            vec![tag, language]
+        })
+        .find(|locale| CATALOGS.has(locale))
+        .unwrap_or_else(|| DEFAULT_LOCALE.to_string())
+}
+
+/// `t(key="search.heading", lang=preferences.locale)`; any other arguments replace
+/// `{name}` in the message
+pub fn translate(args: &HashMap<String, Value>) -> Result<Value> {
+    let key = match args.get("key") {
+        Some(val) => try_get_value!("t", "key", String, val),
+        None => return Err(Error::msg("Argument 'key' missing")),
+    };
+    let lang = match args.get("lang") {
+        Some(val) => try_get_value!("t", "lang", String, val),
+        None => DEFAULT_LOCALE.to_string(),
+    };
+
+    let mut message = CATALOGS
+        .message(&lang, &key)
+        .ok_or_else(|| Error::msg(format!("Unknown message '{}'", key)))?
+        .to_string();
+
+    for (name, value) in args {
+        if name != "key" && name != "lang" {
+            let value = match value {
+                Value::String(s) => s.clone(),
+                other => other.to_string(),
+            };
+            message = message.replace(&format!("{{{}}}", name), &value);
+        }
+    }
+
+    Ok(to_value(message).unwrap())
+}
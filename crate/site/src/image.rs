@@ -0,0 +1,117 @@
+use crate::preferences::Preferences;
+use crate::search::image_links;
+use common::*;
+use http::StatusCode;
+use serde::Serialize;
+use tera::Context;
+
+#[derive(Serialize)]
+struct ImagePage {
+    image_id: i64,
+    /// None if there's no such image, or it's been taken down
+    link: Option<String>,
+    /// Every link it's known by, including `link`
+    links: Vec<String>,
+    /// Earliest first, up to `max_results`
+    posts: Vec<Match>,
+    canonical: String,
+    preferences: Preferences,
+}
+
+/// The absolute URL of image `id`'s page, which search engines are pointed to
+pub fn canonical_url(id: i64) -> String {
+    format!(
+        "{}/image/{}",
+        CONFIG.load().public_url.trim_end_matches('/'),
+        id
+    )
+}
+
+async fn image_posts(id: i64) -> Result<Option<(String, Vec<String>, Vec<Match>)>, UserError> {
+    let client = PG_POOL.get().await?;
+
+    let link: String = match client
+        .query_opt(
+            format!(
+                "SELECT link FROM images WHERE id = $1 AND NOT {}",
+                TAKEN_DOWN_SQL
+            )
+            .as_str(),
+            &[&id],
+        )
+        .await?
+    {
+        Some(row) => row.get("link"),
+        None => return Ok(None),
+    };
+
+    let posts = client
+        .query(
+            "SELECT reddit_id_int, preview, permalink, score, author, created_utc, \
+             subreddit, title FROM posts WHERE image_id = $1 \
+             ORDER BY created_utc ASC LIMIT $2",
+            &[&id, &CONFIG.load().max_results],
+        )
+        .await?
+        .into_iter()
+        .map(|row| Match {
+            permalink: format!("https://reddit.com{}", row.get::<_, &str>("permalink")),
+            distance: 0,
+            exact: true,
+            image_id: id,
+            post_id: row.get("reddit_id_int"),
+            score: row.get("score"),
+            author: row.get("author"),
+            preview: row
+                .get::<_, Option<String>>("preview")
+                .map(|p| Submission::unescape(&p))
+                .unwrap_or_else(|| link.clone()),
+            link: link.clone(),
+            created_utc: row.get("created_utc"),
+            subreddit: row.get("subreddit"),
+            title: row.get("title"),
+            possibly_dead: false,
+            crossposts: Vec::new(),
+        })
+        .collect();
+
+    let links = image_links(&client, &[id])
+        .await?
+        .remove(&id)
+        .unwrap_or_default();
+
+    Ok(Some((link, links, posts)))
+}
+
+/// Every post of image `id`, at a stable URL to link to and list in the sitemap
+pub async fn get_response(
+    id: i64,
+    preferences: Preferences,
+) -> Result<impl warp::Reply, UserError> {
+    let (link, links, posts) = match image_posts(id).await? {
+        Some((link, links, posts)) => (Some(link), links, posts),
+        None => (None, Vec::new(), Vec::new()),
+    };
+
+    let status = if link.is_some() {
+        StatusCode::OK
+    } else {
+        StatusCode::NOT_FOUND
+    };
+
+    let tera = super::get_tera!();
+
+    let out = tera.render(
+        "image.html",
+        &Context::from_serialize(&ImagePage {
+            image_id: id,
+            link,
+            links,
+            posts,
+            canonical: canonical_url(id),
+            preferences,
+        })?,
+    )?;
+
+    Ok(warp::reply::with_status(warp::reply::html(out), status))
+}
@@ -0,0 +1,254 @@
+use crate::search::hash_link;
+use common::*;
+use futures::prelude::*;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use warp::http::StatusCode;
+use warp::ws::{Message, WebSocket, Ws};
+use warp::Reply;
+
+/// How many new posts can wait for a slow socket before it starts missing them
+const CHANNEL_CAPACITY: usize = 1024;
+/// Keeps proxies from closing sockets that haven't seen a match in a while
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Deserialize)]
+pub struct LiveQuery {
+    /// The 64-bit hash to watch for, in decimal
+    hash: Option<String>,
+    /// An image to hash and watch for instead
+    imagelink: Option<String>,
+    distance: Option<u8>,
+}
+
+/// A post just saved, announced by an ingester, with its image's hash
+struct LivePost {
+    hash: Hash,
+    nsfw: bool,
+    post: Match,
+}
+
+#[derive(Serialize)]
+struct LiveEvent<'a> {
+    nsfw: bool,
+    #[serde(rename = "match")]
+    post: &'a Match,
+}
+
+#[derive(Serialize)]
+struct LiveSkipped {
+    /// Posts that came too fast for the socket to be sent
+    skipped: u64,
+}
+
+#[derive(Serialize)]
+struct LiveError {
+    error: UserError,
+}
+
+static POSTS: Lazy<broadcast::Sender<Arc<LivePost>>> =
+    Lazy::new(|| broadcast::channel(CHANNEL_CAPACITY).0);
+static CLIENTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Counts an open socket until it's dropped
+struct Client;
+
+impl Client {
+    /// `None` if `live.max_clients` are already watching
+    fn join() -> Option<Self> {
+        let max_clients = CONFIG.load().live.max_clients;
+        if CLIENTS.fetch_add(1, Ordering::SeqCst) >= max_clients {
+            CLIENTS.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(Client)
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        CLIENTS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+async fn live_post(reddit_id_int: i64) -> Result<Option<LivePost>, UserError> {
+    let row = PG_POOL
+        .get()
+        .await?
+        .query_opt(
+            format!(
+                "SELECT images.hash, images.link, image_id, preview, reddit_id_int, permalink, \
+                 score, author, created_utc, subreddit, title, nsfw \
+                 FROM posts INNER JOIN images ON image_id = images.id \
+                 WHERE reddit_id_int = $1 AND NOT {}",
+                TAKEN_DOWN_SQL
+            )
+            .as_str(),
+            &[&reddit_id_int],
+        )
+        .await?;
+
+    Ok(row.map(|row| {
+        let link: String = row.get("link");
+        LivePost {
+            hash: row.get("hash"),
+            nsfw: row.get("nsfw"),
+            post: Match {
+                permalink: format!("https://reddit.com{}", row.get::<_, &str>("permalink")),
+                distance: 0,
+                exact: true,
+                image_id: row.get("image_id"),
+                post_id: row.get("reddit_id_int"),
+                score: row.get("score"),
+                author: row.get("author"),
+                preview: row
+                    .get::<_, Option<String>>("preview")
+                    .map(|p| Submission::unescape(&p))
+                    .unwrap_or_else(|| link.clone()),
+                link,
+                created_utc: row.get("created_utc"),
+                subreddit: row.get("subreddit"),
+                title: row.get("title"),
+                possibly_dead: false,
+                crossposts: Vec::new(),
+            },
+        }
+    }))
+}
+
+/// Passes newly saved posts on to open sockets in the background, for as long as the site runs
+pub fn start_listener() {
+    if !CONFIG.load().live.enabled {
+        return;
+    }
+
+    let mut new_images = events::subscribe::<events::NewImage>(events::NEW_IMAGE_CHANNEL);
+    tokio::spawn(async move {
+        while let Some(event) = new_images.recv().await {
+            let post_id = match event.post_id {
+                Some(post_id) => post_id,
+                None => continue,
+            };
+
+            // Not worth a query when nobody's watching
+            if POSTS.receiver_count() == 0 {
+                continue;
+            }
+
+            match live_post(post_id).await {
+                Ok(Some(post)) => {
+                    let _ = POSTS.send(Arc::new(post));
+                }
+                Ok(None) => {}
+                Err(ue) => warn!("Couldn't look up live post {}: {}", post_id, ue),
+            }
+        }
+    });
+}
+
+async fn send_json<T: Serialize>(socket: &mut WebSocket, value: &T) -> Result<(), UserError> {
+    let text = serde_json::to_string(value)?;
+    socket
+        .send(Message::text(text))
+        .await
+        .map_err(map_ue!("socket closed"))
+}
+
+/// Sends each new post within `distance` of `hash` until the socket closes
+async fn watch(mut socket: WebSocket, hash: Hash, distance: u32, _client: Client) {
+    let mut posts = POSTS.subscribe();
+    let mut pings = tokio::time::interval(PING_INTERVAL);
+
+    loop {
+        let sent = tokio::select! {
+            incoming = socket.next() => match incoming {
+                Some(Ok(message)) if !message.is_close() => Ok(()),
+                _ => break,
+            },
+            post = posts.recv() => match post {
+                Ok(post) => {
+                    let post_distance = post.hash.distance(hash);
+                    if post_distance <= distance {
+                        let mut found = post.post.clone();
+                        found.distance = post_distance.into();
+                        found.exact = post_distance == 0;
+                        send_json(&mut socket, &LiveEvent { nsfw: post.nsfw, post: &found }).await
+                    } else {
+                        Ok(())
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    send_json(&mut socket, &LiveSkipped { skipped }).await
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            _ = pings.tick() => socket
+                .send(Message::ping(Vec::new()))
+                .await
+                .map_err(map_ue!("socket closed")),
+        };
+
+        if sent.is_err() {
+            break;
+        }
+    }
+}
+
+async fn watched_hash(query: &LiveQuery) -> Result<(Hash, u32), UserError> {
+    let max_distance = CONFIG.load().max_distance;
+    let distance = query.distance.unwrap_or(1);
+    if distance > max_distance {
+        return Err(ue!(
+            format!("distance can be at most {}", max_distance),
+            Source::User
+        ));
+    }
+
+    let hash = match (&query.hash, &query.imagelink) {
+        (Some(hash), _) => Hash(
+            hash.parse()
+                .map_err(map_ue!("invalid hash", Source::User))?,
+        ),
+        (None, Some(link)) => hash_link(link).await?.hash,
+        (None, None) => return Err(ue!("no hash or image link", Source::User)),
+    };
+
+    Ok((hash, distance.into()))
+}
+
+/// Opens a socket that's sent each newly ingested post matching the query
+pub async fn response(ws: Ws, query: LiveQuery) -> warp::reply::Response {
+    let watching = async {
+        if !CONFIG.load().live.enabled {
+            return Err(ue!("live search is off", Source::User));
+        }
+        let (hash, distance) = watched_hash(&query).await?;
+        Ok((hash, distance, Client::join()))
+    };
+
+    match watching.await {
+        Ok((hash, distance, Some(client))) => ws
+            .on_upgrade(move |socket| watch(socket, hash, distance, client))
+            .into_response(),
+        Ok((_, _, None)) => error_response(
+            ue!(
+                "too many people are watching; try again later",
+                Source::External
+            ),
+            StatusCode::SERVICE_UNAVAILABLE,
+        ),
+        Err(ue) => {
+            let status = ue.status_code();
+            error_response(ue, status)
+        }
+    }
+}
+
+fn error_response(ue: UserError, status: StatusCode) -> warp::reply::Response {
+    warn!("{}", ue);
+    warp::reply::with_status(warp::reply::json(&LiveError { error: ue }), status).into_response()
+}
@@ -8,9 +8,23 @@ use warp::http::{header, Response, StatusCode};
 use warp::path::path;
 use warp::{Filter, Rejection};
 
+mod admin;
+mod api;
+mod assets;
+mod findings_cache;
+mod i18n;
+mod image;
+mod live;
+mod planner;
+mod preferences;
+mod proxy;
+
 mod search;
 use search::SearchQuery;
 mod rankings;
+mod rate_limit;
+mod sitemap;
+mod stats;
 
 mod render;
 
@@ -23,35 +37,205 @@ impl warp::reject::Reject for UEReject {}
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     tracing_subscriber::fmt::init();
 
+    let args = take_path_args();
+
+    Lazy::force(&assets::ASSETS);
+    Lazy::force(&i18n::CATALOGS);
     Lazy::force(&render::TERA);
+    live::start_listener();
+    start_query_log();
+    findings_cache::start_listener();
 
     let head = method::head().map(|| StatusCode::OK);
 
     let router = warp::path::end()
         .and(
             method::get()
-                .and(query::query::<SearchQuery>().and_then(|query| async {
-                    Ok::<_, Rejection>(search::get_response(query).await)
-                }))
+                .and(query::query::<SearchQuery>())
+                .and(rate_limit::client_ip())
+                .and(preferences::preferences())
+                .and(api::client())
+                .and_then(
+                    |query: SearchQuery, ip, preferences, client: api::Client| async move {
+                        if query.is_search() {
+                            client.enforce(ip)?;
+                        }
+                        Ok::<_, Rejection>(
+                            search::get_response(query, preferences, client.tier, ip).await,
+                        )
+                    },
+                )
                 .or(method::post()
+                    .and(rate_limit::client_ip())
                     .and(multipart::form())
-                    .and_then(|form| async move {
-                        Ok::<_, Rejection>(search::post_response(form).await)
+                    .and(preferences::preferences())
+                    .and(api::client())
+                    .and_then(|ip, form, preferences, client: api::Client| async move {
+                        client.enforce(ip)?;
+                        Ok::<_, Rejection>(
+                            search::post_response(form, preferences, client.tier, ip).await,
+                        )
                     }))
                 .or(head),
         )
+        .or(warp::path!("api" / "v1" / "search").and(
+            method::get()
+                .and(query::query::<SearchQuery>())
+                .and(rate_limit::client_ip())
+                .and(api::client())
+                .and_then(|query, ip, client: api::Client| async move {
+                    client.enforce(ip)?;
+                    Ok::<_, Rejection>(search::get_json_response(query, client.tier, ip).await)
+                })
+                .or(head),
+        ))
+        .or(warp::path!("api" / "v1" / "author" / String)
+            .and(method::get())
+            .and(query::query::<stats::AuthorQuery>())
+            .and(rate_limit::limit(&rate_limit::SEARCH_LIMITER))
+            .then(stats::author_json_response))
+        .or(warp::path!("live")
+            .and(warp::ws())
+            .and(query::query::<live::LiveQuery>())
+            .and(rate_limit::limit(&rate_limit::SEARCH_LIMITER))
+            .then(live::response))
+        .or(api::quick_filter())
+        .or(api::reposts_filter())
+        .or(warp::path!("preferences").and(
+            method::get()
+                .and(preferences::preferences())
+                .then(preferences::get_response)
+                .or(method::post()
+                    .and(preferences::preferences())
+                    .and(preferences::accept_language())
+                    .and(body::form())
+                    .then(preferences::post_response))
+                .or(head),
+        ))
+        .or(path("admin")
+            .and(
+                warp::path!("login")
+                    .and(
+                        method::get()
+                            .and(preferences::preferences())
+                            .then(admin::login_response)
+                            .or(method::post()
+                                .and(rate_limit::limit(&rate_limit::SEARCH_LIMITER))
+                                .and(preferences::preferences())
+                                .and(body::form())
+                                .then(admin::post_login_response))
+                            .or(head),
+                    )
+                    .or(warp::path!("logout")
+                        .and(method::post())
+                        .and(admin::session())
+                        .then(admin::logout_response))
+                    .or(warp::path::end()
+                        .and(method::get())
+                        .and(admin::authenticated())
+                        .and(preferences::preferences())
+                        .and_then(|preferences| async move {
+                            admin::get_response(preferences)
+                                .map_err(|ue| {
+                                    println!("{:?}", ue);
+                                    admin::record(&ue);
+                                    warp::reject::custom(UEReject(ue))
+                                })
+                                .await
+                        }))
+                    .or(warp::path!(String)
+                        .and(method::post())
+                        .and(admin::authenticated())
+                        .and(rate_limit::client_ip())
+                        .and(preferences::preferences())
+                        .and(body::form())
+                        .and_then(|action: String, ip, preferences, form| async move {
+                            admin::action_response(action, ip, preferences, form)
+                                .map_err(|ue| {
+                                    println!("{:?}", ue);
+                                    admin::record(&ue);
+                                    warp::reject::custom(UEReject(ue))
+                                })
+                                .await
+                        })),
+            )
+            .recover(admin::recover))
+        .or(warp::path!("progress")
+            .and(method::get())
+            .then(api::progress_json_response))
         .or(path("rankings").and(
             method::get()
-                .and_then(|| async {
-                    rankings::get_response()
+                .and(preferences::preferences())
+                .and_then(|preferences| async move {
+                    rankings::get_response(preferences)
+                        .map_err(|ue| {
+                            println!("{:?}", ue);
+                            admin::record(&ue);
+                            warp::reject::custom(UEReject(ue))
+                        })
+                        .await
+                })
+                .or(head),
+        ))
+        .or(path("stats").and(
+            warp::path!("subreddit" / String)
+                .and(method::get())
+                .and(preferences::preferences())
+                .and_then(|name: String, preferences| async move {
+                    stats::subreddit_response(name, preferences)
+                        .map_err(|ue| {
+                            println!("{:?}", ue);
+                            admin::record(&ue);
+                            warp::reject::custom(UEReject(ue))
+                        })
+                        .await
+                })
+                .or(warp::path!("author" / String)
+                    .and(method::get())
+                    .and(query::query::<stats::AuthorQuery>())
+                    .and(rate_limit::limit(&rate_limit::SEARCH_LIMITER))
+                    .and(preferences::preferences())
+                    .and_then(|name: String, query, preferences| async move {
+                        stats::author_response(name, query, preferences)
+                            .map_err(|ue| {
+                                println!("{:?}", ue);
+                                admin::record(&ue);
+                                warp::reject::custom(UEReject(ue))
+                            })
+                            .await
+                    }))
+                .or(head),
+        ))
+        .or(warp::path!("image" / i64).and(
+            method::get()
+                .and(preferences::preferences())
+                .and_then(|id: i64, preferences| async move {
+                    image::get_response(id, preferences)
                         .map_err(|ue| {
                             println!("{:?}", ue);
+                            admin::record(&ue);
                             warp::reject::custom(UEReject(ue))
                         })
                         .await
                 })
                 .or(head),
         ))
+        .or(warp::path!("sitemap.xml").and(method::get().then(sitemap::index_response).or(head)))
+        .or(warp::path!("sitemap" / String)
+            .and(method::get().then(sitemap::file_response).or(head)))
+        .or(warp::path!("proxy" / "preview").and(
+            method::get()
+                .and(query::query::<proxy::PreviewQuery>())
+                .then(proxy::preview_response)
+                .or(head),
+        ))
+        .or(path("static").and(
+            method::get()
+                .and(path::param::<String>())
+                .and(path::end())
+                .and_then(|name: String| async move { assets::ASSETS.response(&name).await })
+                .or(head),
+        ))
         .or(path("robots.txt").and(
             method::get()
                 .and_then(|| async {
@@ -72,15 +256,19 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 })
                 .or(head),
         ))
+        .recover(rate_limit::recover)
+        .recover(api::recover)
         .with(warp::log("site"));
 
-    let ip: std::net::IpAddr = std::env::args()
-        .nth(1)
+    let ip: std::net::IpAddr = args
+        .get(1)
+        .cloned()
         .unwrap_or_else(|| "127.0.0.1".to_string())
         .parse()
         .map_err(|_| "Invalid IP address")?;
-    let port = std::env::args()
-        .nth(2)
+    let port = args
+        .get(2)
+        .cloned()
         .unwrap_or_else(|| "7878".to_string())
         .parse()
         .map_err(|_| "Invalid port number")?;
@@ -0,0 +1,206 @@
+use common::*;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long the statistics are used before they're read again
+const STATS_REFRESH: Duration = Duration::from_secs(10 * 60);
+/// The share of posts an author filter is taken to keep, since authors have no statistics
+const AUTHOR_SELECTIVITY: f64 = 0.0001;
+/// The share of posts a subreddit without statistics is taken to have
+const UNKNOWN_SUBREDDIT_SELECTIVITY: f64 = 0.001;
+
+/// Where a search's images are found
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Plan {
+    Index,
+    Database,
+}
+
+/// What searches are estimated from, read from the database every `STATS_REFRESH`
+#[derive(Default)]
+struct Stats {
+    images: f64,
+    posts: f64,
+    /// From hash_popularity
+    popular: Vec<(Hash, i64)>,
+    /// Posts by lowercased subreddit, from subreddit_stats
+    subreddit_posts: HashMap<String, i64>,
+}
+
+struct Cached {
+    read: Option<Instant>,
+    stats: Arc<Stats>,
+}
+
+static STATS: Lazy<Mutex<Cached>> = Lazy::new(|| {
+    Mutex::new(Cached {
+        read: None,
+        stats: Arc::new(Stats::default()),
+    })
+});
+
+async fn read_stats() -> Result<Stats, UserError> {
+    let client = db::read_client().await?;
+
+    // Postgres' own row estimates, as counting would take far longer than the search
+    let counts = client
+        .query_one(
+            "SELECT \
+             (SELECT GREATEST(reltuples, 0)::float8 FROM pg_class \
+              WHERE oid = 'images'::regclass) AS images, \
+             (SELECT COALESCE(sum(GREATEST(reltuples, 0)), 0)::float8 FROM pg_class \
+              WHERE oid = 'posts'::regclass OR oid IN \
+              (SELECT inhrelid FROM pg_inherits WHERE inhparent = 'posts'::regclass)) AS posts",
+            &[],
+        )
+        .await?;
+
+    let popular = client
+        .query("SELECT hash, images FROM hash_popularity", &[])
+        .await?
+        .into_iter()
+        .map(|row| (row.get("hash"), row.get("images")))
+        .collect();
+
+    let subreddit_posts = client
+        .query("SELECT subreddit, posts FROM subreddit_stats", &[])
+        .await?
+        .into_iter()
+        .map(|row| (row.get("subreddit"), row.get("posts")))
+        .collect();
+
+    Ok(Stats {
+        images: counts.get("images"),
+        posts: counts.get("posts"),
+        popular,
+        subreddit_posts,
+    })
+}
+
+/// The statistics, read again if they're older than `STATS_REFRESH`; if they can't be read,
+/// the last copy of them is used
+async fn stats() -> Arc<Stats> {
+    {
+        let cached = STATS.lock().unwrap();
+        if let Some(read) = cached.read {
+            if read.elapsed() < STATS_REFRESH {
+                return cached.stats.clone();
+            }
+        }
+    }
+
+    let stats = match read_stats().await {
+        Ok(stats) => Arc::new(stats),
+        Err(e) => {
+            warn!("Couldn't read the search planner's statistics: {}", e);
+            STATS.lock().unwrap().stats.clone()
+        }
+    };
+
+    // Failures are retried after `STATS_REFRESH` too, so a missing table doesn't cost a query
+    // per search
+    *STATS.lock().unwrap() = Cached {
+        read: Some(Instant::now()),
+        stats: stats.clone(),
+    };
+    stats
+}
+
+/// The share of all 64-bit hashes within `distance` of any one hash
+fn uniform_share(distance: u8) -> f64 {
+    let mut ways = 1.0;
+    let mut total = 1.0;
+    for k in 1..=u32::from(distance.min(64)) {
+        ways = ways * f64::from(65 - k) / f64::from(k);
+        total += ways;
+    }
+    total / 2f64.powi(64)
+}
+
+impl Stats {
+    /// How many images are expected within `distance` of `hash`: those sharing the popular
+    /// hashes that are, and a uniform share of the rest
+    fn candidates(&self, hash: Hash, distance: u8) -> f64 {
+        let popular: i64 = self
+            .popular
+            .iter()
+            .filter(|(popular, _)| hash.distance(*popular) <= u32::from(distance))
+            .map(|(_, images)| images)
+            .sum();
+
+        popular as f64 + self.images * uniform_share(distance)
+    }
+
+    /// The share of posts that pass the subreddit and author filters
+    fn selectivity(&self, subreddits: &[String], authors: &[String]) -> f64 {
+        let mut selectivity = 1.0;
+
+        if !subreddits.is_empty() {
+            selectivity *= subreddits
+                .iter()
+                .map(|subreddit| match self.subreddit_posts.get(subreddit) {
+                    Some(&posts) if self.posts > 0.0 => posts as f64 / self.posts,
+                    _ => UNKNOWN_SUBREDDIT_SELECTIVITY,
+                })
+                .sum::<f64>()
+                .min(1.0);
+        }
+        if !authors.is_empty() {
+            selectivity *= (authors.len() as f64 * AUTHOR_SELECTIVITY).min(1.0);
+        }
+
+        selectivity
+    }
+}
+
+/// Chooses where a search for images within `distance` of `hash` is done, when either
+/// would do, and logs why for tuning `search_planner`
+pub async fn plan(
+    hash: Hash,
+    distance: u8,
+    subreddits: &[String],
+    authors: &[String],
+    max_results: i64,
+) -> Plan {
+    let (enabled, index_min_distance, min_selectivity) = {
+        let planner = &CONFIG.load().search_planner;
+        (
+            planner.enabled,
+            planner.index_min_distance,
+            planner.min_selectivity,
+        )
+    };
+    if !enabled {
+        return Plan::Index;
+    }
+
+    if distance < index_min_distance {
+        debug!("Planned Database for distance {}: it's close", distance);
+        return Plan::Database;
+    }
+
+    let stats = stats().await;
+    let candidates = stats.candidates(hash, distance);
+    let selectivity = stats.selectivity(subreddits, authors);
+
+    // indexd returns at most `max_results` images, and filters are applied after
+    let (plan, reason) = if selectivity < 1.0 && candidates > max_results as f64 {
+        (
+            Plan::Database,
+            "indexd's results would be cut off before filtering",
+        )
+    } else if selectivity < min_selectivity {
+        (Plan::Database, "its filters are selective")
+    } else {
+        (Plan::Index, "it's broad")
+    };
+
+    debug!(
+        "Planned {:?} for distance {} with {:.1} candidates and {:.6} selectivity: {}",
+        plan, distance, candidates, selectivity, reason
+    );
+
+    plan
+}
@@ -0,0 +1,254 @@
+use crate::i18n::{self, CATALOGS};
+use crate::search::NSFWOption;
+use common::*;
+use http::StatusCode;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use tera::Context;
+use url::form_urlencoded;
+use warp::http::{header, Response};
+use warp::Filter;
+
+const COOKIE: &str = "preferences";
+/// A year
+const COOKIE_MAX_AGE: u32 = 365 * 24 * 60 * 60;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Theme {
+    Dark,
+    Light,
+}
+
+impl Theme {
+    fn as_str(self) -> &'static str {
+        match self {
+            Theme::Dark => "dark",
+            Theme::Light => "light",
+        }
+    }
+}
+
+impl std::str::FromStr for Theme {
+    type Err = UserError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dark" => Ok(Theme::Dark),
+            "light" => Ok(Theme::Light),
+            _ => Err(ue!("invalid theme", Source::User)),
+        }
+    }
+}
+
+/// What a visitor has chosen on /preferences, kept in a cookie as a query string
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Preferences {
+    pub theme: Theme,
+    /// The search form's default distance
+    pub distance: u8,
+    /// The search form's default NSFW option
+    pub nsfw: NSFWOption,
+    /// The most matches a search shows
+    pub per_page: i64,
+    /// The locale they chose, or None to go by Accept-Language
+    pub language: Option<String>,
+    /// The locale pages are shown in
+    pub locale: String,
+}
+
+impl Default for Preferences {
+    fn default() -> Self {
+        Self {
+            theme: Theme::Dark,
+            distance: CONFIG.load().default_distance,
+            nsfw: NSFWOption::Allow,
+            per_page: CONFIG.load().max_results,
+            language: None,
+            locale: i18n::DEFAULT_LOCALE.to_string(),
+        }
+    }
+}
+
+impl Preferences {
+    fn set(&mut self, key: &str, value: &str) -> Result<(), UserError> {
+        match key {
+            "theme" => self.theme = value.parse()?,
+            "distance" => {
+                let distance = value
+                    .parse()
+                    .map_err(map_ue!("invalid distance", Source::User))?;
+                if distance > CONFIG.load().max_distance {
+                    return Err(ue!("distance too large", Source::User));
+                }
+                self.distance = distance;
+            }
+            "nsfw" => {
+                self.nsfw = value
+                    .parse()
+                    .map_err(map_ue!("invalid NSFW option", Source::User))?
+            }
+            "per_page" => {
+                let per_page = value
+                    .parse()
+                    .map_err(map_ue!("invalid results per page", Source::User))?;
+                if per_page < 1 || per_page > CONFIG.load().max_results {
+                    return Err(ue!("results per page out of range", Source::User));
+                }
+                self.per_page = per_page;
+            }
+            "language" if value.is_empty() => self.language = None,
+            "language" => {
+                if !CATALOGS.has(value) {
+                    return Err(ue!("unknown language", Source::User));
+                }
+                self.language = Some(value.to_string());
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Anything that no longer parses, say after the limits change, is left at its default
+    fn from_cookie(cookie: &str) -> Self {
+        let mut preferences = Self::default();
+        for (key, value) in form_urlencoded::parse(cookie.as_bytes()) {
+            let _ = preferences.set(&key, &value);
+        }
+        preferences
+    }
+
+    fn negotiate_locale(&mut self, accept_language: Option<&str>) {
+        self.locale = i18n::negotiate(self.language.as_deref(), accept_language);
+    }
+
+    /// A Set-Cookie value; the query string's characters are all allowed in cookies
+    fn to_cookie(&self) -> String {
+        let mut query = form_urlencoded::Serializer::new(String::new());
+        query
+            .append_pair("theme", self.theme.as_str())
+            .append_pair("distance", &self.distance.to_string())
+            .append_pair("nsfw", self.nsfw.as_str())
+            .append_pair("per_page", &self.per_page.to_string());
+        if let Some(language) = &self.language {
+            query.append_pair("language", language);
+        }
+        let query = query.finish();
+
+        format!(
+            "{}={}; Path=/; Max-Age={}; SameSite=Lax; HttpOnly",
+            COOKIE, query, COOKIE_MAX_AGE
+        )
+    }
+}
+
+/// None if it's missing or isn't ASCII
+pub fn accept_language() -> impl Filter<Extract = (Option<String>,), Error = Infallible> + Clone {
+    warp::header::optional::<String>("accept-language")
+        .or_else(|_| async { Ok::<_, Infallible>((None,)) })
+}
+
+/// The visitor's preferences, or the defaults if they have none, in the locale they get
+pub fn preferences() -> impl Filter<Extract = (Preferences,), Error = Infallible> + Clone {
+    warp::cookie::optional(COOKIE).and(accept_language()).map(
+        |cookie: Option<String>, accept_language: Option<String>| {
+            let mut preferences = cookie
+                .map(|cookie| Preferences::from_cookie(&cookie))
+                .unwrap_or_default();
+            preferences.negotiate_locale(accept_language.as_deref());
+            preferences
+        },
+    )
+}
+
+#[derive(Serialize)]
+struct PreferencesPage {
+    preferences: Preferences,
+    default_distance: u8,
+    max_distance: u8,
+    max_results: i64,
+    languages: Vec<(String, String)>,
+    saved: bool,
+    error: Option<UserError>,
+}
+
+fn render(page: &PreferencesPage, status: StatusCode) -> Response<String> {
+    let tera = super::get_tera!();
+
+    let (body, status) = match Context::from_serialize(page)
+        .and_then(|context| tera.render("preferences.html", &context))
+    {
+        Ok(body) => (body, status),
+        Err(e) => {
+            error!("{}", e);
+            (
+                "<h1>Error 500: Internal Server Error</h1>".to_string(),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        }
+    };
+
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+        .body(body)
+        .unwrap()
+}
+
+pub async fn get_response(preferences: Preferences) -> Response<String> {
+    let config = CONFIG.load();
+
+    render(
+        &PreferencesPage {
+            preferences,
+            default_distance: config.default_distance,
+            max_distance: config.max_distance,
+            max_results: config.max_results,
+            languages: CATALOGS.names(),
+            saved: false,
+            error: None,
+        },
+        StatusCode::OK,
+    )
+}
+
+pub async fn post_response(
+    mut preferences: Preferences,
+    accept_language: Option<String>,
+    form: HashMap<String, String>,
+) -> Response<String> {
+    let config = CONFIG.load();
+
+    let result = form
+        .iter()
+        .try_for_each(|(key, value)| preferences.set(key, value));
+    preferences.negotiate_locale(accept_language.as_deref());
+
+    let status = match &result {
+        Ok(()) => StatusCode::OK,
+        Err(ue) => ue.status_code(),
+    };
+
+    let mut response = render(
+        &PreferencesPage {
+            preferences: preferences.clone(),
+            default_distance: config.default_distance,
+            max_distance: config.max_distance,
+            max_results: config.max_results,
+            languages: CATALOGS.names(),
+            saved: result.is_ok(),
+            error: result.err(),
+        },
+        status,
+    );
+
+    if status == StatusCode::OK {
+        response
+            .headers_mut()
+            .insert(header::SET_COOKIE, preferences.to_cookie().parse().unwrap());
+    }
+
+    response
+}
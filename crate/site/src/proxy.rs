@@ -0,0 +1,167 @@
+use common::*;
+use futures::prelude::*;
+use http::header;
+use http::{Response, StatusCode};
+use serde::Deserialize;
+use std::io::ErrorKind;
+use url::Url;
+use warp::hyper::Body;
+
+#[derive(Deserialize)]
+pub struct PreviewQuery {
+    /// The post whose preview to serve
+    id: i64,
+}
+
+/// Whether browsers on the site probably can't load `link` themselves, as it's plain http or
+/// on a host that blocks requests from other sites
+pub fn needs_proxy(link: &str) -> bool {
+    let config = CONFIG.load();
+    if !config.preview_proxy.enabled {
+        return false;
+    }
+
+    link.starts_with("http://")
+        || get_host(link)
+            .map(|host| {
+                config
+                    .preview_proxy
+                    .hotlink_blocked
+                    .iter()
+                    .any(|end| host == *end || host.ends_with(&format!(".{}", end)))
+            })
+            .unwrap_or(false)
+}
+
+/// Where pages load post `id`'s preview from when it needs proxying
+pub fn proxy_url(id: i64) -> String {
+    format!("/proxy/preview?id={}", id)
+}
+
+/// The mime type of an image the proxy will pass on, without any parameters
+fn allowed_type(content_type: Option<&header::HeaderValue>) -> Option<String> {
+    let mime = content_type?
+        .to_str()
+        .ok()?
+        .split(';')
+        .next()?
+        .trim()
+        .to_lowercase();
+
+    if mime != "image/*" && IMAGE_MIMES.contains(&mime.as_str()) {
+        Some(mime)
+    } else {
+        None
+    }
+}
+
+async fn proxy_preview(id: i64) -> Result<Response<Body>, UserError> {
+    let row = PG_POOL
+        .get()
+        .await?
+        .query_opt(
+            format!(
+                "SELECT posts.preview, images.link FROM posts \
+                 INNER JOIN images ON images.id = posts.image_id \
+                 WHERE posts.reddit_id_int = $1 AND NOT {}",
+                TAKEN_DOWN_SQL
+            )
+            .as_str(),
+            &[&id],
+        )
+        .await?
+        .ok_or_else(|| ue!("no such post", Source::User))?;
+
+    let link = row
+        .get::<_, Option<String>>("preview")
+        .map(|preview| Submission::unescape(&preview))
+        .unwrap_or_else(|| row.get("link"));
+
+    // Only previews the site would link through the proxy, so it can't be used to fetch
+    // anything else
+    if !needs_proxy(&link) {
+        return Err(ue!("this preview isn't proxied", Source::User));
+    }
+
+    let url = Url::parse(&link).map_err(map_ue!("invalid URL"))?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(ue!("unsupported scheme"));
+    }
+    // Guarded whatever guard_private_ips says, since anyone can make the site fetch these
+    check_public_host(&url).await?;
+
+    let resp = send_following(url.as_str(), true, |client, requested| {
+        client.get(requested)
+    })
+    .await?
+    .resp
+    .error_for_status()
+    .map_err(map_ue!("preview host returned an error", Source::External))?;
+
+    let content_type = allowed_type(resp.headers().get(header::CONTENT_TYPE))
+        .ok_or_else(|| ue!("preview isn't an image", Source::External))?;
+
+    let (max_bytes, cache_secs) = {
+        let config = CONFIG.load();
+        (
+            config.preview_proxy.max_bytes,
+            config.preview_proxy.cache_secs,
+        )
+    };
+
+    if resp.content_length().unwrap_or(0) > max_bytes {
+        return Err(ue!("preview too large", Source::External));
+    }
+
+    // Content-Length can be missing or wrong, so the size is checked as it streams too
+    let body = stream::unfold(Some((resp, 0u64)), move |state| async move {
+        let (mut resp, sent) = state?;
+        match resp.chunk().await {
+            Ok(Some(chunk)) => {
+                let sent = sent + chunk.len() as u64;
+                if sent > max_bytes {
+                    let too_large = std::io::Error::new(ErrorKind::Other, "preview too large");
+                    Some((Err(too_large), None))
+                } else {
+                    Some((Ok(chunk), Some((resp, sent))))
+                }
+            }
+            Ok(None) => None,
+            Err(e) => Some((Err(std::io::Error::new(ErrorKind::Other, e)), None)),
+        }
+    });
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, content_type)
+        .header(
+            header::CACHE_CONTROL,
+            format!("public, max-age={}", cache_secs),
+        )
+        .header(header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+        .header(
+            header::CONTENT_SECURITY_POLICY,
+            "default-src 'none'; sandbox",
+        )
+        .body(Body::wrap_stream(body))
+        .unwrap())
+}
+
+/// Streams post `id`'s preview from its host, for previews browsers can't load directly
+pub async fn preview_response(query: PreviewQuery) -> Response<Body> {
+    match proxy_preview(query.id).await {
+        Ok(resp) => resp,
+        Err(ue) => {
+            warn!("Couldn't proxy the preview of {}: {}", query.id, ue);
+
+            let status = match ue.source {
+                Source::User => StatusCode::NOT_FOUND,
+                _ => StatusCode::BAD_GATEWAY,
+            };
+            Response::builder()
+                .status(status)
+                .body(Body::empty())
+                .unwrap()
+        }
+    }
+}
@@ -1,22 +1,70 @@
+use crate::preferences::Preferences;
 use common::*;
 use http::StatusCode;
 use serde::Serialize;
+use std::collections::HashMap;
 use tera::Context;
 
+#[derive(Serialize)]
+struct Ranked {
+    #[serde(flatten)]
+    image: CommonImage,
+    first_post: Option<FirstPost>,
+}
+
 #[derive(Serialize)]
 struct Rankings {
     as_of: String,
-    common_images: Vec<CommonImage>,
+    filters: Option<config::RankFilters>,
+    common_images: Vec<Ranked>,
+    preferences: Preferences,
 }
 
-pub async fn get_response() -> Result<impl warp::Reply, UserError> {
+pub async fn get_response(preferences: Preferences) -> Result<impl warp::Reply, UserError> {
     let images: CommonImages = ron::de::from_reader(std::fs::File::open(
         std::env::var("HOME")? + "/stats/top100.ron",
     )?)?;
 
+    // The file is only rewritten now and then, so takedowns since then are left out here
+    let links: Vec<&str> = images
+        .common_images
+        .iter()
+        .map(|image| image.link.as_str())
+        .collect();
+    let client = db::read_client().await?;
+    let rows = client
+        .query(
+            format!(
+                "SELECT id, link, {} AS taken_down FROM images WHERE link = ANY($1)",
+                TAKEN_DOWN_SQL
+            )
+            .as_str(),
+            &[&links],
+        )
+        .await?;
+    let ids: HashMap<String, (i64, bool)> = rows
+        .iter()
+        .map(|row| (row.get("link"), (row.get("id"), row.get("taken_down"))))
+        .collect();
+
+    let image_ids: Vec<i64> = ids.values().map(|(id, _)| *id).collect();
+    let mut first_posts = first_posts(&client, &image_ids).await?;
+
     let rankings = Rankings {
         as_of: images.as_of.format("%F %T %Z").to_string(),
-        common_images: images.common_images,
+        filters: images.filters,
+        common_images: images
+            .common_images
+            .into_iter()
+            .filter(|image| !matches!(ids.get(&image.link), Some((_, true))))
+            .map(|image| Ranked {
+                first_post: ids
+                    .get(&image.link)
+                    .and_then(|(id, _)| first_posts.remove(id)),
+                image,
+            })
+            .collect(),
+        preferences,
     };
 
     let tera = super::get_tera!();
@@ -0,0 +1,137 @@
+use common::*;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::hash::Hash;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::Instant;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+// Past this many tracked clients, full buckets get dropped
+const MAX_TRACKED: usize = 10_000;
+
+#[derive(Debug)]
+pub struct RateLimited;
+
+impl warp::reject::Reject for RateLimited {}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+pub struct RateLimiter<K> {
+    burst: f64,
+    per_second: f64,
+    buckets: Mutex<HashMap<K, Bucket>>,
+}
+
+impl<K: Hash + Eq + Display> RateLimiter<K> {
+    pub fn new(burst: u32, per_minute: u32) -> Self {
+        Self {
+            burst: f64::from(burst),
+            per_second: f64::from(per_minute) / 60.,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn check(&self, client: K) -> bool {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() > MAX_TRACKED {
+            let (burst, per_second) = (self.burst, self.per_second);
+            buckets.retain(|_client, bucket| {
+                bucket.tokens + (now - bucket.last).as_secs_f64() * per_second < burst
+            });
+        }
+
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: self.burst,
+            last: now,
+        });
+
+        bucket.tokens =
+            (bucket.tokens + (now - bucket.last).as_secs_f64() * self.per_second).min(self.burst);
+        bucket.last = now;
+
+        if bucket.tokens >= 1. {
+            bucket.tokens -= 1.;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn enforce(&self, client: Option<K>) -> Result<(), Rejection> {
+        match client {
+            Some(client) => {
+                let shown = client.to_string();
+                if self.check(client) {
+                    Ok(())
+                } else {
+                    warn!("Rate limited {}", shown);
+                    Err(warp::reject::custom(RateLimited))
+                }
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+pub static SEARCH_LIMITER: Lazy<RateLimiter<IpAddr>> = Lazy::new(|| {
+    RateLimiter::new(
+        CONFIG.load().rate_limit.burst,
+        CONFIG.load().rate_limit.per_minute,
+    )
+});
+
+/// Clients with API keys are limited per key instead of per address
+pub static KEYED_LIMITER: Lazy<RateLimiter<i64>> = Lazy::new(|| {
+    RateLimiter::new(
+        CONFIG.load().rate_limit.keyed_burst,
+        CONFIG.load().rate_limit.keyed_per_minute,
+    )
+});
+
+fn pick_ip(remote: Option<SocketAddr>, forwarded_for: Option<String>) -> Option<IpAddr> {
+    if CONFIG.load().rate_limit.behind_proxy {
+        // The proxy appends the address it saw to the end of the list
+        if let Some(ip) = forwarded_for
+            .as_ref()
+            .and_then(|ff| ff.rsplit(',').next())
+            .and_then(|ip| ip.trim().parse().ok())
+        {
+            return Some(ip);
+        }
+    }
+
+    remote.map(|addr| addr.ip())
+}
+
+pub fn client_ip() -> impl Filter<Extract = (Option<IpAddr>,), Error = Rejection> + Clone {
+    warp::addr::remote()
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .map(pick_ip)
+}
+
+pub fn limit(
+    limiter: &'static RateLimiter<IpAddr>,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    client_ip()
+        .and_then(move |ip| async move { limiter.enforce(ip) })
+        .untuple_one()
+}
+
+pub async fn recover(rejection: Rejection) -> Result<impl Reply, Rejection> {
+    if rejection.find::<RateLimited>().is_some() {
+        Ok(warp::reply::with_status(
+            warp::reply::html("<h1>Error 429: Too Many Requests</h1>".to_string()),
+            StatusCode::TOO_MANY_REQUESTS,
+        ))
+    } else {
+        Err(rejection)
+    }
+}
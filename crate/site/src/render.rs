@@ -44,6 +44,36 @@ pub mod utils {
         arg.ok_or_else(|| Error::msg("Tester `null` was called on an undefined variable"))
             .map(Value::is_null)
     }
+
+    /// The preview `link` of post `id`, or the site's proxy for it if browsers couldn't load it
+    pub fn preview_src(args: &HashMap<String, Value>) -> Result<Value> {
+        let link = match args.get("link") {
+            Some(val) => try_get_value!("preview_src", "link", String, val),
+            None => return Err(Error::msg("Argument 'link' missing")),
+        };
+        let id = match args.get("id") {
+            Some(val) => try_get_value!("preview_src", "id", i64, val),
+            None => return Err(Error::msg("Argument 'id' missing")),
+        };
+
+        if crate::proxy::needs_proxy(&link) {
+            Ok(to_value(crate::proxy::proxy_url(id)).unwrap())
+        } else {
+            Ok(to_value(link).unwrap())
+        }
+    }
+
+    pub fn static_url(args: &HashMap<String, Value>) -> Result<Value> {
+        let name = match args.get("name") {
+            Some(val) => try_get_value!("static_url", "name", String, val),
+            None => return Err(Error::msg("Argument 'name' missing")),
+        };
+
+        crate::assets::ASSETS
+            .url(&name)
+            .map(|url| to_value(url).unwrap())
+            .ok_or_else(|| Error::msg(format!("Unknown static asset '{}'", name)))
+    }
 }
 
 pub fn create_tera() -> Tera {
@@ -52,6 +82,9 @@ pub fn create_tera() -> Tera {
             t.register_filter("tern", utils::tern);
             t.register_filter("plural", utils::pluralize);
             t.register_tester("null", utils::null);
+            t.register_function("preview_src", utils::preview_src);
+            t.register_function("static_url", utils::static_url);
+            t.register_function("t", crate::i18n::translate);
             t
         }
         Err(e) => {
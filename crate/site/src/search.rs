@@ -1,20 +1,33 @@
+use crate::findings_cache;
+use crate::planner::{self, Plan};
+use crate::preferences::Preferences;
 use bytes::Buf;
 use chrono::offset::Utc;
 use chrono::Duration;
+use common::config::{SearchLimits, Tier};
 use common::*;
 use futures::prelude::*;
 use http::StatusCode;
+use once_cell::sync::Lazy;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::error::Error as _;
 use std::io::Read;
+use std::net::IpAddr;
 use std::time::Instant;
 use std::vec::Vec;
 use tera::Context;
 use tokio_postgres::error::{DbError, SqlState};
+use tokio_postgres::types::ToSql;
 use url::Url;
 use warp::multipart::FormData;
 
+/// Deep pages make Postgres skip past more and more matches
+const MAX_PAGE: i64 = 100;
+/// The largest image a pasted data: URL may decode to
+const MAX_PASTED_BYTES: usize = 10 * 1024 * 1024;
+
 #[derive(Deserialize)]
 pub struct SearchQuery {
     imagelink: Option<String>,
@@ -22,16 +35,94 @@ pub struct SearchQuery {
     nsfw: Option<String>,
     subreddits: Option<String>,
     authors: Option<String>,
+    hash_size: Option<String>,
+    page: Option<String>,
+    /// `on` to also count matches at every distance
+    histogram: Option<String>,
+    /// `on` to leave out images that were only hashed from a post's thumbnail
+    exclude_low_quality: Option<String>,
+    /// `on` to only find images with exactly the same hash
+    exact_only: Option<String>,
+    /// `on` to leave out images whose links were found dead
+    exclude_dead: Option<String>,
+    /// A post to leave out of the matches by its permalink, such as the post being checked
+    exclude_permalink: Option<String>,
+    /// A post to leave out of the matches by its base 36 ID or `t3_` fullname
+    exclude_id: Option<String>,
+    /// `basic` for a table without previews or scripts, for text browsers and screen readers
+    format: Option<String>,
+}
+
+impl SearchQuery {
+    pub fn is_search(&self) -> bool {
+        self.imagelink
+            .as_ref()
+            .map(|link| !link.is_empty())
+            .unwrap_or(false)
+    }
+}
+
+fn check_target_host(url: &Url) -> Result<(), UserError> {
+    fn host_matches(host: &str, end: &str) -> bool {
+        host == end || host.ends_with(&format!(".{}", end))
+    }
+
+    let host = url
+        .host()
+        .ok_or_else(|| ue!("no host in URL", Source::User))?;
+
+    match host {
+        url::Host::Ipv4(ip) if !is_global_ip(ip.into()) => {
+            return Err(ue!("forbidden host", Source::User))
+        }
+        url::Host::Ipv6(ip) if !is_global_ip(ip.into()) => {
+            return Err(ue!("forbidden host", Source::User))
+        }
+        _ => {}
+    }
+
+    let host = host.to_string().to_lowercase();
+
+    let config = CONFIG.load();
+
+    if config
+        .search_host_deny
+        .iter()
+        .any(|end| host_matches(&host, end))
+    {
+        return Err(ue!("forbidden host", Source::User));
+    }
+
+    if !config.search_host_allow.is_empty()
+        && !config
+            .search_host_allow
+            .iter()
+            .any(|end| host_matches(&host, end))
+    {
+        return Err(ue!("forbidden host", Source::User));
+    }
+
+    Ok(())
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize)]
 #[serde(rename_all = "lowercase")]
-enum NSFWOption {
+pub enum NSFWOption {
     Only,
     Allow,
     Never,
 }
 
+impl NSFWOption {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NSFWOption::Only => "only",
+            NSFWOption::Allow => "allow",
+            NSFWOption::Never => "never",
+        }
+    }
+}
+
 impl std::str::FromStr for NSFWOption {
     type Err = Error;
 
@@ -52,340 +143,1266 @@ impl Default for NSFWOption {
     }
 }
 
+/// An image linked in a comment rather than posted
+#[derive(Clone, Debug, Serialize)]
+pub struct CommentMatch {
+    pub author: String,
+    pub created_utc: chrono::NaiveDateTime,
+    pub distance: i64,
+    pub exact: bool,
+    pub link: String,
+    pub permalink: String,
+    pub score: i64,
+    pub subreddit: String,
+}
+
+/// How an image's 64-bit hash differs from the one searched for
+#[derive(Clone, Debug, Serialize)]
+pub struct HashDiff {
+    /// In decimal, as a string so JavaScript doesn't round it
+    pub hash: String,
+    /// The bits that differ, numbered as `dhash` sets them, eight to a row
+    pub differing_bits: Vec<u32>,
+}
+
+impl HashDiff {
+    fn new(query: Hash, found: Hash) -> HashDiff {
+        let xor = query.0 ^ found.0;
+
+        HashDiff {
+            hash: found.to_string(),
+            differing_bits: (0..64).filter(|bit| xor >> bit & 1 == 1).collect(),
+        }
+    }
+}
+
+/// Every post of a single image, earliest first
+#[derive(Clone, Debug, Serialize)]
+pub struct ImageGroup {
+    pub image_id: i64,
+    pub distance: i64,
+    pub hash_diff: HashDiff,
+    /// Every link the image is known by, the first it was saved from first
+    pub links: Vec<String>,
+    /// Where the image was first posted, even if that post isn't among the matches
+    pub first_post: Option<FirstPost>,
+    pub matches: Vec<Match>,
+}
+
+/// The oldest post found at a given distance
+#[derive(Clone, Debug, Serialize)]
+pub struct Earliest {
+    pub distance: i64,
+    pub age: String,
+    pub post: Match,
+}
+
+/// Which of the two ways of searching found the matches
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ServedBy {
+    Index,
+    Database,
+}
+
+/// How many posts are at one distance, counting past the page and the distance searched for
+#[derive(Clone, Debug, Serialize)]
+pub struct DistanceCount {
+    pub distance: i64,
+    pub count: i64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Findings {
+    /// How long the search took, even when it's been answered from the cache since
+    pub took: String,
+    /// Whether they came from the findings cache
+    pub cached: bool,
+    /// The searched-for image's 64-bit hash, in decimal
+    pub query_hash: String,
+    pub served_by: ServedBy,
+    pub match_count: usize,
+    pub earliest: Vec<Earliest>,
+    pub groups: Vec<ImageGroup>,
+    pub comments: Vec<CommentMatch>,
+    /// Every distance up to the max, when asked for
+    pub histogram: Option<Vec<DistanceCount>>,
+}
+
+/// What was found for one of several images searched for at once
 #[derive(Debug, Serialize)]
-struct Match {
-    author: Option<String>,
-    created_utc: chrono::NaiveDateTime,
-    distance: i64,
-    link: String,
-    preview: String,
-    permalink: String,
-    score: i64,
-    subreddit: String,
-    title: String,
+pub struct ImageResult {
+    /// The link, or the uploaded file's name
+    pub target: String,
+    pub findings: Option<Findings>,
+    pub error: Option<UserError>,
 }
 
+/// Totals over all the images of a search for several
 #[derive(Debug, Serialize)]
-struct Findings {
-    took: String,
+pub struct Summary {
+    pub images: usize,
+    /// How many of them were found posted at least once
+    pub found: usize,
+    pub failed: usize,
+    pub match_count: usize,
+    /// The oldest post found for any of them
+    pub earliest: Option<Earliest>,
+}
+
+impl Summary {
+    fn new(results: &[ImageResult]) -> Summary {
+        let findings = results.iter().filter_map(|result| result.findings.as_ref());
+
+        Summary {
+            images: results.len(),
+            found: findings
+                .clone()
+                .filter(|findings| findings.match_count > 0)
+                .count(),
+            failed: results
+                .iter()
+                .filter(|result| result.error.is_some())
+                .count(),
+            match_count: findings.clone().map(|findings| findings.match_count).sum(),
+            earliest: findings
+                .flat_map(|findings| findings.earliest.iter())
+                .min_by_key(|earliest| earliest.post.created_utc)
+                .cloned(),
+        }
+    }
+}
+
+/// What a search found, for however many images it was for
+enum Found {
+    Nothing,
+    One(Findings),
+    Many(Vec<ImageResult>, Summary),
+}
+
+fn too_many_images(max_images: usize) -> UserError {
+    ue!(
+        format!("at most {} images can be searched for at once", max_images),
+        Source::User
+    )
+}
+
+/// Decodes an imagedata field, the base64 data: URL that pasting an image gives scripts
+fn pasted_image(data_url: &[u8]) -> Result<Vec<u8>, UserError> {
+    let data_url = std::str::from_utf8(data_url)
+        .map_err(map_ue!("pasted image isn't a data: URL", Source::User))?;
+
+    let mime = data_url
+        .strip_prefix("data:")
+        .and_then(|rest| rest.split(&[';', ','][..]).next())
+        .unwrap_or("");
+    if !mime.starts_with("image/") {
+        return Err(ue!(
+            format!("pasted data isn't an image: {}", mime),
+            Source::User
+        ));
+    }
+
+    // Each four characters of base64 decode to three bytes
+    if data_url.len() / 4 * 3 > MAX_PASTED_BYTES {
+        return Err(ue!(
+            format!(
+                "pasted images can be at most {} MiB",
+                MAX_PASTED_BYTES / 1024 / 1024
+            ),
+            Source::User
+        ));
+    }
+
+    let bytes = decode_data_url(data_url)?;
+    if bytes.is_empty() {
+        return Err(ue!("pasted image is empty", Source::User));
+    }
+
+    Ok(bytes)
+}
+
+/// Searches for each of `targets`, `multi_search.concurrency` at a time, keeping their order
+async fn multi_findings<T, F, Fut>(targets: Vec<(String, T)>, search: F) -> Found
+where
+    F: Fn(T) -> Fut,
+    Fut: Future<Output = Result<Findings, UserError>>,
+{
+    let concurrency = CONFIG.load().multi_search.concurrency;
+
+    let mut results = stream::iter(targets.into_iter().enumerate())
+        .map(|(index, (target, input))| {
+            let searched = search(input);
+            async move {
+                let (findings, error) = match searched.await {
+                    Ok(findings) => (Some(findings), None),
+                    Err(ue) => {
+                        warn!("{}", ue);
+                        (None, Some(ue))
+                    }
+                };
+                (
+                    index,
+                    ImageResult {
+                        target,
+                        findings,
+                        error,
+                    },
+                )
+            }
+        })
+        .buffer_unordered(concurrency)
+        .collect::<Vec<_>>()
+        .await;
+    results.sort_by_key(|(index, _result)| *index);
+
+    let results: Vec<ImageResult> = results.into_iter().map(|(_index, result)| result).collect();
+    let summary = Summary::new(&results);
+
+    Found::Many(results, summary)
+}
+
+pub fn describe_duration(age: Duration) -> String {
+    let (num, unit) = if age.num_days() >= 365 {
+        (age.num_days() / 365, "year")
+    } else if age.num_days() >= 30 {
+        (age.num_days() / 30, "month")
+    } else if age.num_days() >= 1 {
+        (age.num_days(), "day")
+    } else if age.num_hours() >= 1 {
+        (age.num_hours(), "hour")
+    } else {
+        (age.num_minutes().max(0), "minute")
+    };
+
+    format!("{} {}{}", num, unit, if num == 1 { "" } else { "s" })
+}
+
+fn describe_age(created_utc: chrono::NaiveDateTime) -> String {
+    format!(
+        "{} ago",
+        describe_duration(Utc::now().naive_utc() - created_utc)
+    )
+}
+
+fn find_earliest(matches: &[Match]) -> Vec<Earliest> {
+    let mut earliest: Vec<Earliest> = Vec::new();
+
+    // Sorted by distance then age, so the first match at each distance is the oldest
+    for m in matches {
+        if earliest.last().map(|e| e.distance) != Some(m.distance) {
+            earliest.push(Earliest {
+                distance: m.distance,
+                age: describe_age(m.created_utc),
+                post: m.clone(),
+            });
+        }
+    }
+
+    earliest
+}
+
+/// Every link known for each of `image_ids`, the first each was saved from first
+pub async fn image_links(
+    client: &tokio_postgres::Client,
+    image_ids: &[i64],
+) -> Result<HashMap<i64, Vec<String>>, UserError> {
+    let mut links: HashMap<i64, Vec<String>> = HashMap::new();
+
+    for row in client
+        .query(
+            "SELECT image_id, link FROM image_links WHERE image_id = ANY($1) \
+             ORDER BY added_at ASC, link ASC",
+            &[&image_ids],
+        )
+        .await?
+    {
+        links
+            .entry(row.get("image_id"))
+            .or_default()
+            .push(row.get("link"));
+    }
+
+    Ok(links)
+}
+
+/// Moves each match that crossposted another match under that one, or under the post that one
+/// crossposted if it was found too; `parents` maps posts to the post they crossposted
+fn collapse_crossposts(matches: Vec<Match>, parents: &HashMap<i64, i64>) -> Vec<Match> {
+    let found: HashSet<i64> = matches.iter().map(|m| m.post_id).collect();
+
+    let root = |post_id: i64| {
+        let mut root = post_id;
+        // Bounded in case of a cycle
+        for _ in 0..found.len() {
+            match parents.get(&root) {
+                Some(&parent) if parent != post_id && found.contains(&parent) => root = parent,
+                _ => break,
+            }
+        }
+        root
+    };
+
+    let (kept, crossposts): (Vec<_>, Vec<_>) = matches
+        .into_iter()
+        .map(|m| (root(m.post_id), m))
+        .partition(|(root, m)| *root == m.post_id);
+    let mut kept: Vec<Match> = kept.into_iter().map(|(_, m)| m).collect();
+
+    let indices: HashMap<i64, usize> = kept
+        .iter()
+        .enumerate()
+        .map(|(index, m)| (m.post_id, index))
+        .collect();
+    for (root, m) in crossposts {
+        match indices.get(&root) {
+            Some(&index) => kept[index].crossposts.push(Crosspost {
+                post_id: m.post_id,
+                permalink: m.permalink,
+                subreddit: m.subreddit,
+                created_utc: m.created_utc,
+            }),
+            // Only when crossposts somehow form a cycle
+            None => kept.push(m),
+        }
+    }
+
+    kept
+}
+
+/// `hashes` has the hash of each matched image, to compare with `query`
+fn group_matches(
     matches: Vec<Match>,
+    query: Hash,
+    hashes: &HashMap<i64, Hash>,
+    links: &HashMap<i64, Vec<String>>,
+    first_posts: &HashMap<i64, FirstPost>,
+) -> Vec<ImageGroup> {
+    let mut groups: Vec<ImageGroup> = Vec::new();
+    let mut group_indices: HashMap<i64, usize> = HashMap::new();
+
+    // The matches are already sorted by distance then age, so each group's first match is its
+    // earliest and the groups end up in the same order
+    for m in matches {
+        match group_indices.get(&m.image_id) {
+            Some(&index) => groups[index].matches.push(m),
+            None => {
+                group_indices.insert(m.image_id, groups.len());
+                groups.push(ImageGroup {
+                    image_id: m.image_id,
+                    distance: m.distance,
+                    hash_diff: HashDiff::new(query, hashes[&m.image_id]),
+                    links: links.get(&m.image_id).cloned().unwrap_or_default(),
+                    first_post: first_posts.get(&m.image_id).cloned(),
+                    matches: vec![m],
+                });
+            }
+        }
+    }
+
+    groups
 }
 
 #[derive(Clone, Debug, Serialize)]
-struct Form {
-    link: String,
+pub struct Form {
+    pub(crate) link: String,
     distance: String,
     nsfw: String,
     subreddits: String,
     authors: String,
+    hash_size: String,
+    page: String,
+    histogram: String,
+    exclude_low_quality: String,
+    exact_only: String,
+    pub(crate) exclude_dead: String,
+    exclude_permalink: String,
+    exclude_id: String,
 }
 
-impl Default for Form {
-    fn default() -> Form {
+impl From<&Preferences> for Form {
+    fn from(preferences: &Preferences) -> Form {
         Form {
             link: "".to_string(),
-            distance: "1".to_string(),
-            nsfw: "allow".to_string(),
+            distance: preferences.distance.to_string(),
+            nsfw: preferences.nsfw.as_str().to_string(),
             subreddits: "".to_string(),
             authors: "".to_string(),
+            hash_size: "64".to_string(),
+            page: "1".to_string(),
+            histogram: "".to_string(),
+            exclude_low_quality: "".to_string(),
+            exact_only: "".to_string(),
+            exclude_dead: "".to_string(),
+            exclude_permalink: "".to_string(),
+            exclude_id: "".to_string(),
         }
     }
 }
 
+impl Default for Form {
+    fn default() -> Form {
+        Form::from(&Preferences::default())
+    }
+}
+
 #[derive(Debug, Serialize)]
 struct Search {
     form: Form,
     default_form: Form,
     findings: Option<Findings>,
+    /// Each image's findings when several were searched for, in place of `findings`
+    results: Vec<ImageResult>,
+    summary: Option<Summary>,
     error: Option<UserError>,
     upload: bool,
+    default_distance: u8,
     max_distance: u8,
     ingest_state: Option<IngestState>,
+    preferences: Preferences,
+    /// Whether to offer saving uploads, which only admins may do
+    admin: bool,
 }
 
 impl Search {
-    async fn default() -> Search {
-        let state_string = tokio::fs::read_to_string(&CONFIG.state_file).await;
-        let state = match state_string {
-            Err(e) => {
-                warn!("Error reading ingest state file: {}", e);
-                None
-            }
-            Ok(s) => match ron::from_str::<IngestState>(&s) {
-                Err(e) => {
-                    warn!("Error parsing ingest state file: {}", e);
-                    None
-                }
-                Ok(s) => {
-                    if (Utc::now().naive_utc() - s.as_of) < Duration::minutes(2) {
-                        Some(s)
-                    } else {
-                        None
-                    }
-                }
-            },
-        };
+    async fn new(preferences: Preferences, limits: SearchLimits) -> Search {
+        let state = current_ingest().await.unwrap_or_else(|e| {
+            warn!("Error reading ingest progress: {}", e);
+            None
+        });
 
         Search {
-            form: Form::default(),
-            default_form: Form::default(),
+            form: Form::from(&preferences),
+            default_form: Form::from(&preferences),
             findings: None,
+            results: Vec::new(),
+            summary: None,
             error: None,
             upload: false,
-            max_distance: CONFIG.max_distance,
+            default_distance: CONFIG.load().default_distance,
+            max_distance: limits.max_distance,
             ingest_state: state,
+            preferences,
+            admin: false,
+        }
+    }
+
+    async fn with_found(
+        form: Form,
+        found: Result<Found, UserError>,
+        upload: bool,
+        preferences: Preferences,
+        tier: Tier,
+    ) -> Search {
+        let limits = CONFIG.load().search_limits(tier);
+        // The form's input is bounded like Params::from_form bounds the search
+        let max_distance = if form.hash_size == "128" {
+            limits.max_distance.saturating_mul(2)
+        } else {
+            limits.max_distance
+        };
+        let search = Search {
+            form,
+            upload,
+            max_distance,
+            admin: tier == Tier::Admin,
+            ..Search::new(preferences, limits).await
+        };
+
+        match found {
+            Ok(Found::Nothing) => search,
+            Ok(Found::One(findings)) => Search {
+                findings: Some(findings),
+                ..search
+            },
+            Ok(Found::Many(results, summary)) => Search {
+                results,
+                summary: Some(summary),
+                ..search
+            },
+            Err(error) => Search {
+                error: Some(error),
+                ..search
+            },
         }
     }
 }
 
-#[derive(Debug)]
-struct Params {
+/// Compared and hashed as the findings cache's key, so lists are kept sorted
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize)]
+pub struct Params {
+    hash128: bool,
     distance: i64,
     nsfw: NSFWOption,
     subreddits: Vec<String>,
     authors: Vec<String>,
+    /// The most matches to return
+    limit: i64,
+    /// How many matches earlier pages had
+    offset: i64,
+    /// The distance to count matches up to, if they're to be counted
+    histogram: Option<i64>,
+    exclude_low_quality: bool,
+    exact_only: bool,
+    exclude_dead: bool,
+    /// Posts left out of the matches
+    exclude_ids: Vec<i64>,
+    /// The client's cap, and the fewest images indexd is asked for
+    max_results: i64,
 }
 
 impl Params {
-    pub fn from_form(form: &Form) -> Result<Params, UserError> {
+    pub fn from_form(
+        form: &Form,
+        per_page: i64,
+        limits: SearchLimits,
+    ) -> Result<Params, UserError> {
+        let limit = per_page.min(limits.max_results);
+
+        let hash128 = match form.hash_size.as_str() {
+            "" | "64" => false,
+            "128" => true,
+            _ => return Err(ue!("invalid hash size parameter", Source::User)),
+        };
+
+        // Twice the bits can differ by twice as much for the same change
+        let max_distance = if hash128 {
+            limits.max_distance.saturating_mul(2)
+        } else {
+            limits.max_distance
+        };
+
+        let exact_only = match form.exact_only.as_str() {
+            "" | "off" => false,
+            "on" => true,
+            _ => return Err(ue!("invalid exact_only parameter", Source::User)),
+        };
+
         Ok(Params {
+            hash128,
             distance: {
                 let distance = if form.distance.is_empty() {
-                    1
+                    CONFIG.load().default_distance
                 } else {
                     form.distance
                         .parse()
                         .map_err(map_ue!("invalid distance parameter", Source::User))?
                 };
 
-                if distance > CONFIG.max_distance {
+                if distance > max_distance {
                     return Err(ue!("distance too large", Source::User));
                 }
 
-                distance as i64
+                if exact_only {
+                    0
+                } else {
+                    distance as i64
+                }
             },
             nsfw: form
                 .nsfw
                 .parse()
                 .map_err(map_ue!("invalid nsfw parameter", Source::User))?,
-            subreddits: form
-                .subreddits
-                .split_whitespace()
-                .map(str::to_lowercase)
-                .collect(),
-            authors: form
-                .authors
-                .split_whitespace()
-                .map(str::to_lowercase)
-                .collect(),
+            subreddits: names(&form.subreddits),
+            authors: names(&form.authors),
+            limit,
+            offset: {
+                let page: i64 = if form.page.is_empty() {
+                    1
+                } else {
+                    form.page
+                        .parse()
+                        .map_err(map_ue!("invalid page parameter", Source::User))?
+                };
+
+                if page < 1 || page > MAX_PAGE {
+                    return Err(ue!("page out of range", Source::User));
+                }
+
+                (page - 1) * limit
+            },
+            histogram: match form.histogram.as_str() {
+                "" | "off" => None,
+                "on" => Some(max_distance as i64),
+                _ => return Err(ue!("invalid histogram parameter", Source::User)),
+            },
+            exclude_low_quality: match form.exclude_low_quality.as_str() {
+                "" | "off" => false,
+                "on" => true,
+                _ => return Err(ue!("invalid exclude_low_quality parameter", Source::User)),
+            },
+            exact_only,
+            exclude_dead: match form.exclude_dead.as_str() {
+                "" | "off" => false,
+                "on" => true,
+                _ => return Err(ue!("invalid exclude_dead parameter", Source::User)),
+            },
+            exclude_ids: {
+                let mut exclude_ids = Vec::new();
+                if !form.exclude_id.is_empty() {
+                    exclude_ids.push(RedditId::parse_fullname(form.exclude_id.trim())?.0);
+                }
+                if !form.exclude_permalink.is_empty() {
+                    exclude_ids.push(permalink_id(&form.exclude_permalink)?);
+                }
+                exclude_ids.sort_unstable();
+                exclude_ids.dedup();
+                exclude_ids
+            },
+            max_results: limits.max_results,
         })
     }
+
+    pub fn distance(&self) -> i64 {
+        self.distance
+    }
 }
 
-async fn make_findings(hash: Hash, params: Params) -> Result<Findings, UserError> {
-    macro_rules! tosql {
-        ($v:expr) => {
-            (&$v as &(dyn tokio_postgres::types::ToSql + Sync))
-        };
+/// Lowercased, sorted and without repeats
+fn names(list: &str) -> Vec<String> {
+    let mut names: Vec<String> = list.split_whitespace().map(str::to_lowercase).collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// Tells the user when their search hit the statement timeout
+fn query_error(e: tokio_postgres::Error) -> UserError {
+    if let Some(dberror) = e.source().and_then(|e| e.downcast_ref::<DbError>()) {
+        if *dberror.code() == SqlState::QUERY_CANCELED
+            && dberror.message() == "canceling statement due to statement timeout"
+        {
+            return ue!("query took too long", Source::User);
+        }
+    }
+
+    e.into()
+}
+
+fn nsfw_sql(nsfw: &NSFWOption) -> &'static str {
+    match nsfw {
+        NSFWOption::Only => "AND nsfw = true",
+        NSFWOption::Allow => "",
+        NSFWOption::Never => "AND nsfw = false",
     }
+}
 
-    let client = PG_POOL.get().await?;
+/// The ID of the post at a permalink, with or without Reddit's domain
+fn permalink_id(permalink: &str) -> Result<i64, UserError> {
+    static COMMENTS_RE: Lazy<Regex> =
+        Lazy::new(|| Regex::new(r"(?i)/comments/([0-9a-z]+)(?:/|$)").unwrap());
 
-    let (s_query, a_query, args) = if params.subreddits.is_empty() && params.authors.is_empty() {
-        (
-            "",
-            "",
-            vec![
-                tosql!(hash),
-                tosql!(params.distance),
-                tosql!(CONFIG.max_results),
-            ],
+    let caps = COMMENTS_RE.captures(permalink).ok_or_else(|| {
+        ue!(
+            "invalid exclude_permalink parameter; it should look like /r/sub/comments/id/title/",
+            Source::User
         )
-    } else if params.authors.is_empty() {
-        (
-            "AND LOWER(subreddit) = ANY($4)",
-            "",
-            vec![
-                tosql!(hash),
-                tosql!(params.distance),
-                tosql!(CONFIG.max_results),
-                tosql!(params.subreddits),
-            ],
+    })?;
+
+    Ok(caps[1].to_lowercase().parse::<RedditId>()?.0)
+}
+
+/// Leaves out the posts a search asked to exclude; comments have their own IDs, so only
+/// queries of posts use it
+fn exclude_sql<'a>(args: &mut Vec<&'a (dyn ToSql + Sync)>, params: &'a Params) -> String {
+    if params.exclude_ids.is_empty() {
+        String::new()
+    } else {
+        format!(
+            " AND reddit_id_int <> ALL({})",
+            push_arg(args, &params.exclude_ids)
         )
-    } else if params.subreddits.is_empty() {
-        (
-            "",
-            "AND LOWER(author) = ANY($4)",
-            vec![
-                tosql!(hash),
-                tosql!(params.distance),
-                tosql!(CONFIG.max_results),
-                tosql!(params.authors),
-            ],
+    }
+}
+
+/// The `AND ...` conditions for the subreddit, author, hash quality and dead link filters
+fn filter_sql<'a>(args: &mut Vec<&'a (dyn ToSql + Sync)>, params: &'a Params) -> String {
+    let mut sql = String::new();
+
+    if params.exclude_low_quality {
+        sql += " AND NOT images.low_quality_hash";
+    }
+    if params.exclude_dead {
+        sql += &format!(" AND NOT {}", DEAD_LINK_SQL);
+    }
+
+    if !params.subreddits.is_empty() {
+        sql += &format!(
+            " AND LOWER(subreddit) = ANY({})",
+            push_arg(args, &params.subreddits)
+        );
+    }
+    if !params.authors.is_empty() {
+        sql += &format!(
+            " AND LOWER(author) = ANY({})",
+            push_arg(args, &params.authors)
+        );
+    }
+
+    sql
+}
+
+/// Joins a post to its own image
+const OWN_IMAGE_SQL: &str = "image_id = images.id";
+/// Joins a post to the other images of the Imgur album it links to, so that searches match it
+/// by any of them
+const ALBUM_IMAGE_SQL: &str =
+    "(posts.reddit_id_int, images.id) IN (SELECT reddit_id_int, image_id FROM post_images)";
+
+/// Counts the posts at each distance up to `max_distance` in one pass; these always come from
+/// the database, as indexd only finds images within the distance searched for
+async fn distance_histogram(
+    hash: Hash,
+    halves: Option<(i64, i64)>,
+    params: &Params,
+    max_distance: i64,
+) -> Result<Vec<DistanceCount>, UserError> {
+    let half_distance = max_distance / 2;
+    let mut args: Vec<&(dyn ToSql + Sync)> = vec![&max_distance];
+
+    let (distance, hash_cond) = match &halves {
+        None => {
+            let hash = push_arg(&mut args, &hash);
+            (
+                format!("hash <-> {}", hash),
+                format!("hash <@ ({}, $1)", hash),
+            )
+        }
+        Some((hi, lo)) => {
+            let hi = push_arg(&mut args, hi);
+            let lo = push_arg(&mut args, lo);
+            let half = push_arg(&mut args, &half_distance);
+            let distance = format!("(hash128_hi <-> {}) + (hash128_lo <-> {})", hi, lo);
+            (
+                distance.clone(),
+                format!(
+                    "(hash128_hi <@ ({}, {}) OR hash128_lo <@ ({}, {})) AND {} <= $1",
+                    hi, half, lo, half, distance
+                ),
+            )
+        }
+    };
+
+    let filters = filter_sql(&mut args, params) + &exclude_sql(&mut args, params);
+
+    let found = |image_join: &str| {
+        format!(
+            "SELECT {} AS distance, reddit_id_int \
+             FROM posts INNER JOIN images \
+             ON {} \
+             AND {} \
+             AND NOT {} \
+             {} \
+             {}",
+            distance,
+            hash_cond,
+            image_join,
+            TAKEN_DOWN_SQL,
+            nsfw_sql(&params.nsfw),
+            filters
         )
-    } else {
-        (
-            "AND LOWER(subreddit) = ANY($4)",
-            "AND LOWER(author) = ANY($5)",
-            vec![
-                tosql!(hash),
-                tosql!(params.distance),
-                tosql!(CONFIG.max_results),
-                tosql!(params.subreddits),
-                tosql!(params.authors),
-            ],
+    };
+
+    // Each post is counted once, at its closest image
+    let counts: HashMap<i64, i64> = db::read_client()
+        .await?
+        .query(
+            format!(
+                "SELECT distance, COUNT(*) AS count FROM \
+                 (SELECT DISTINCT ON (reddit_id_int) reddit_id_int, distance \
+                 FROM ({} UNION ALL {}) AS found ORDER BY reddit_id_int, distance) AS closest \
+                 GROUP BY 1",
+                found(OWN_IMAGE_SQL),
+                found(ALBUM_IMAGE_SQL)
+            )
+            .as_str(),
+            &args,
         )
+        .await
+        .map_err(query_error)?
+        .into_iter()
+        .map(|row| (row.get("distance"), row.get("count")))
+        .collect();
+
+    Ok((0..=max_distance)
+        .map(|distance| DistanceCount {
+            distance,
+            count: counts.get(&distance).copied().unwrap_or(0),
+        })
+        .collect())
+}
+
+fn push_arg<'a>(args: &mut Vec<&'a (dyn ToSql + Sync)>, arg: &'a (dyn ToSql + Sync)) -> String {
+    args.push(arg);
+    format!("${}", args.len())
+}
+
+/// The ids of images near `hash`, or `None` if indexd can't be relied on for them
+async fn index_image_ids(hash: Hash, params: &Params) -> Option<Vec<i64>> {
+    let config = CONFIG.load_full();
+
+    if !config.indexd.enabled || params.hash128 || params.exact_only {
+        return None;
+    }
+
+    // Pages are cut from the posts of the images found, after filtering, so they're only right
+    // when indexd found every image within the distance; the offset is bounded by MAX_PAGE
+    let wanted = (params.offset + params.limit).max(params.max_results);
+
+    let planned = planner::plan(
+        hash,
+        params.distance as u8,
+        &params.subreddits,
+        &params.authors,
+        wanted,
+    )
+    .await;
+    if planned == Plan::Database {
+        return None;
+    }
+
+    match indexd::similar(hash, params.distance as u8, wanted as usize).await {
+        Ok(similar) if similar.matches.len() >= wanted as usize => {
+            debug!(
+                "indexd found {} or more images, searching the database",
+                wanted
+            );
+            None
+        }
+        Ok(similar) if similar.stale_secs <= config.indexd.max_staleness_secs => {
+            Some(similar.matches.into_iter().flat_map(|m| m.ids).collect())
+        }
+        Ok(similar) => {
+            warn!(
+                "indexd is {} seconds stale, searching the database",
+                similar.stale_secs
+            );
+            None
+        }
+        Err(ue) => {
+            warn!("Couldn't query indexd, searching the database: {}", ue);
+            None
+        }
+    }
+}
+
+/// Searches for `hash`, or `hash128` for 128-bit searches, unless it's been searched for
+/// the same way recently, and logs the search for `ip`
+async fn make_findings(
+    hash: Hash,
+    hash128: Option<Hash128>,
+    params: Params,
+    ip: Option<IpAddr>,
+) -> Result<Findings, UserError> {
+    let started = Instant::now();
+    let distance = params.distance;
+    let logged_params = serde_json::to_string(&params).unwrap_or_default();
+
+    let key = findings_cache::Key {
+        hash: hash.0,
+        hash128: hash128
+            .filter(|_| params.hash128)
+            .map(|hash128| (hash128.hi(), hash128.lo())),
+        params: params.clone(),
+    };
+
+    let findings = match findings_cache::get(&key) {
+        Some(findings) => Findings {
+            cached: true,
+            ..findings
+        },
+        None => {
+            let findings = search_findings(hash, hash128, params).await?;
+            findings_cache::insert(key, &findings);
+            findings
+        }
     };
 
+    log_query(
+        hash,
+        distance,
+        logged_params,
+        ip,
+        findings.match_count,
+        started.elapsed(),
+        findings.cached,
+    );
+
+    Ok(findings)
+}
+
+async fn search_findings(
+    hash: Hash,
+    hash128: Option<Hash128>,
+    params: Params,
+) -> Result<Findings, UserError> {
     let search_start = Instant::now();
 
+    let dead_after_years = CONFIG.load().link_check.dead_after_years;
+
+    let client = db::read_client().await?;
+
+    let halves = if params.hash128 {
+        Some(
+            hash128
+                .map(|hash128| (hash128.hi(), hash128.lo()))
+                .ok_or_else(|| ue!("no 128-bit hash for this image", Source::User))?,
+        )
+    } else {
+        None
+    };
+    // If the whole hash is within the distance, at least one half is within half of it
+    let half_distance = params.distance / 2;
+
+    let image_ids = index_image_ids(hash, &params).await;
+    let served_by = if image_ids.is_some() {
+        ServedBy::Index
+    } else {
+        ServedBy::Database
+    };
+
+    let mut args: Vec<&(dyn ToSql + Sync)> = vec![&params.limit, &params.distance, &params.offset];
+
+    let (distance, hash_cond) = match &halves {
+        // Equality skips the distance operator, and the bktree index along with it
+        _ if params.exact_only => {
+            let mut hash_cond = format!("hash = {}", push_arg(&mut args, &hash));
+            if let Some((hi, lo)) = &halves {
+                hash_cond += &format!(
+                    " AND hash128_hi = {} AND hash128_lo = {}",
+                    push_arg(&mut args, hi),
+                    push_arg(&mut args, lo)
+                );
+            }
+            // The distance searched for is 0
+            ("$2::bigint".to_string(), hash_cond)
+        }
+        None => {
+            let hash = push_arg(&mut args, &hash);
+            let hash_cond = match &image_ids {
+                // The index already found them, so only their posts need looking up
+                Some(image_ids) => format!("images.id = ANY({})", push_arg(&mut args, image_ids)),
+                None => format!("hash <@ ({}, $2)", hash),
+            };
+            (format!("hash <-> {}", hash), hash_cond)
+        }
+        Some((hi, lo)) => {
+            let hi = push_arg(&mut args, hi);
+            let lo = push_arg(&mut args, lo);
+            let half = push_arg(&mut args, &half_distance);
+            let distance = format!("(hash128_hi <-> {}) + (hash128_lo <-> {})", hi, lo);
+            (
+                distance.clone(),
+                format!(
+                    "(hash128_hi <@ ({}, {}) OR hash128_lo <@ ({}, {})) AND {} <= $2",
+                    hi, half, lo, half, distance
+                ),
+            )
+        }
+    };
+
+    let filters = filter_sql(&mut args, &params);
+    // Comments are searched with `args`, so the exclusion's argument can't go in it
+    let mut post_args = args.clone();
+    let excluded = exclude_sql(&mut post_args, &params);
+    let dead_after = push_arg(&mut post_args, &dead_after_years);
+
+    // An album image's match shows that image, not the post's preview of its first
+    let found = |image_join: &str, preview: &str| {
+        format!(
+            "SELECT {} as distance, images.id as image_id, images.hash as hash, \
+             {} as preview, images.link as link, reddit_id_int, permalink, score, author, \
+             created_utc, subreddit, title, crosspost_parent, \
+             {} AND created_utc < now() AT TIME ZONE 'utc' - make_interval(years => {}) \
+             as possibly_dead \
+             FROM posts INNER JOIN images \
+             ON {} \
+             AND {} \
+             AND NOT {} \
+             {} \
+             {}{}",
+            distance,
+            preview,
+            DEAD_LINK_SQL,
+            dead_after,
+            hash_cond,
+            image_join,
+            TAKEN_DOWN_SQL,
+            nsfw_sql(&params.nsfw),
+            filters,
+            excluded,
+        )
+    };
+
     let rows = client
         .query(
             format!(
-                "SELECT hash <-> $1 as distance, preview, images.link as link, permalink, \
-                 score, author, created_utc, subreddit, title \
-                 FROM posts INNER JOIN images \
-                 ON hash <@ ($1, $2) \
+                "SELECT * FROM (SELECT DISTINCT ON (reddit_id_int) * \
+                 FROM ({} UNION ALL {}) AS found ORDER BY reddit_id_int, distance) AS closest \
+                 ORDER BY distance ASC, created_utc ASC LIMIT $1 OFFSET $3",
+                found(OWN_IMAGE_SQL, "preview"),
+                found(ALBUM_IMAGE_SQL, "NULL"),
+            )
+            .as_str(),
+            &post_args,
+        )
+        .await
+        .map_err(query_error)?;
+
+    // Comments have no NSFW flag, so that filter doesn't apply to them
+    let comment_rows = client
+        .query(
+            format!(
+                "SELECT {} as distance, images.hash as hash, images.link as link, permalink, \
+                 score, author, created_utc, subreddit \
+                 FROM comment_images INNER JOIN images \
+                 ON {} \
                  AND image_id = images.id \
+                 AND NOT {} \
                  {} \
-                 {} \
-                 {} \
-                 ORDER BY distance ASC, created_utc ASC LIMIT $3",
-                match params.nsfw {
-                    NSFWOption::Only => "AND nsfw = true",
-                    NSFWOption::Allow => "",
-                    NSFWOption::Never => "AND nsfw = false",
-                },
-                s_query,
-                a_query,
+                 ORDER BY distance ASC, created_utc ASC LIMIT $1 OFFSET $3",
+                distance, hash_cond, TAKEN_DOWN_SQL, filters,
             )
             .as_str(),
             &args,
         )
-        .await
-        .map_err(|e| {
-            if let Some(dberror) = e.source().and_then(|e| e.downcast_ref::<DbError>()) {
-                if *dberror.code() == SqlState::QUERY_CANCELED
-                    && dberror.message() == "canceling statement due to statement timeout"
-                {
-                    ue!("query took too long", Source::User)
-                } else {
-                    e.into()
-                }
-            } else {
-                e.into()
-            }
-        })?;
+        .await?;
+
+    let histogram = match params.histogram {
+        Some(max_distance) => Some(distance_histogram(hash, halves, &params, max_distance).await?),
+        None => None,
+    };
 
     let search_took = search_start.elapsed();
 
+    let comments = comment_rows
+        .iter()
+        .map(|row| CommentMatch {
+            author: row.get("author"),
+            created_utc: row.get("created_utc"),
+            distance: row.get("distance"),
+            exact: row.get::<_, i64>("distance") == 0 && row.get::<_, Hash>("hash") == hash,
+            link: row.get("link"),
+            permalink: format!("https://reddit.com{}", row.get::<_, &str>("permalink")),
+            score: row.get("score"),
+            subreddit: row.get("subreddit"),
+        })
+        .collect();
+
+    let hashes: HashMap<i64, Hash> = rows
+        .iter()
+        .map(|row| (row.get("image_id"), row.get("hash")))
+        .collect();
+
+    let image_ids: Vec<i64> = hashes.keys().copied().collect();
+    let links = image_links(&client, &image_ids).await?;
+    let first_posts = first_posts(&client, &image_ids).await?;
+
+    let parents: HashMap<i64, i64> = rows
+        .iter()
+        .filter_map(|row| Some((row.get("reddit_id_int"), row.get("crosspost_parent")?)))
+        .collect();
+
+    let matches: Vec<Match> = rows
+        .iter()
+        .map(move |row| {
+            let link: String = row.get("link");
+            let preview = row
+                .get::<_, Option<String>>("preview")
+                .map(|p| Submission::unescape(&p))
+                .unwrap_or_else(|| link.clone());
+
+            let distance: i64 = row.get("distance");
+
+            Match {
+                permalink: format!("https://reddit.com{}", row.get::<_, &str>("permalink")),
+                distance,
+                exact: distance == 0 && row.get::<_, Hash>("hash") == hash,
+                image_id: row.get("image_id"),
+                post_id: row.get("reddit_id_int"),
+                score: row.get("score"),
+                author: row.get("author"),
+                link,
+                preview,
+                created_utc: row.get("created_utc"),
+                subreddit: row.get("subreddit"),
+                title: row.get("title"),
+                possibly_dead: row.get("possibly_dead"),
+                crossposts: Vec::new(),
+            }
+        })
+        .collect();
+    let match_count = matches.len();
+    let matches = collapse_crossposts(matches, &parents);
+
     Ok(Findings {
         took: format!(
             "{}.{:03}",
             search_took.as_secs(),
             search_took.subsec_millis()
         ),
-        matches: rows
-            .iter()
-            .map(move |row| {
-                let link: String = row.get("link");
-                let preview = row
-                    .get::<_, Option<String>>("preview")
-                    .map(|p| Submission::unescape(&p))
-                    .unwrap_or_else(|| link.clone());
-
-                Match {
-                    permalink: format!("https://reddit.com{}", row.get::<_, &str>("permalink")),
-                    distance: row.get("distance"),
-                    score: row.get("score"),
-                    author: row.get("author"),
-                    link,
-                    preview,
-                    created_utc: row.get("created_utc"),
-                    subreddit: row.get("subreddit"),
-                    title: row.get("title"),
-                }
-            })
-            .collect(),
+        cached: false,
+        query_hash: hash.to_string(),
+        served_by,
+        match_count,
+        earliest: find_earliest(&matches),
+        groups: group_matches(matches, hash, &hashes, &links, &first_posts),
+        comments,
+        histogram,
     })
 }
 
-async fn get_search(qs: SearchQuery) -> Search {
-    let imagelink = qs.imagelink.clone();
+/// Hashes the image at `link`, as long as it's on a host searches may reach
+pub async fn hash_link(link: &str) -> Result<HashSaved, UserError> {
+    let url = Url::parse(link).map_err(map_ue!("invalid URL"))?;
+    if url.scheme() != "data" {
+        check_target_host(&url)?;
+    }
+
+    save_hash(link, HashDest::ImageCache).await
+}
+
+pub async fn link_findings(
+    link: &str,
+    form: &Form,
+    per_page: i64,
+    limits: SearchLimits,
+    ip: Option<IpAddr>,
+) -> Result<Findings, UserError> {
+    let params = Params::from_form(form, per_page, limits)?;
+
+    let hash_saved = hash_link(link).await?;
+
+    make_findings(hash_saved.hash, hash_saved.hash128, params, ip).await
+}
 
-    let default_form = Form::default();
+async fn get_search(
+    qs: SearchQuery,
+    preferences: Preferences,
+    tier: Tier,
+    ip: Option<IpAddr>,
+) -> Search {
+    let default_form = Form::from(&preferences);
     let form = Form {
         distance: qs.distance.unwrap_or(default_form.distance),
         nsfw: qs.nsfw.unwrap_or(default_form.nsfw),
         subreddits: qs.subreddits.unwrap_or(default_form.subreddits),
         authors: qs.authors.unwrap_or(default_form.authors),
+        hash_size: qs.hash_size.unwrap_or(default_form.hash_size),
+        page: qs.page.unwrap_or(default_form.page),
+        histogram: qs.histogram.unwrap_or(default_form.histogram),
+        exclude_low_quality: qs
+            .exclude_low_quality
+            .unwrap_or(default_form.exclude_low_quality),
+        exact_only: qs.exact_only.unwrap_or(default_form.exact_only),
+        exclude_dead: qs.exclude_dead.unwrap_or(default_form.exclude_dead),
+        exclude_permalink: qs
+            .exclude_permalink
+            .unwrap_or(default_form.exclude_permalink),
+        exclude_id: qs.exclude_id.unwrap_or(default_form.exclude_id),
         link: qs.imagelink.unwrap_or(default_form.link),
     };
 
-    let err_form = form.clone();
-
-    let findings = match imagelink {
-        None => Ok(None),
-        Some(link) => {
-            if &link != "" {
-                match Url::parse(&link).map_err(map_ue!("invalid URL")) {
-                    Ok(_url) => match Params::from_form(&form) {
-                        Ok(params) => {
-                            save_hash(&link, HashDest::ImageCache)
-                                .and_then(|hash_saved| async move {
-                                    make_findings(hash_saved.hash, params).await.map(Some)
-                                })
-                                .await
-                        }
-                        Err(e) => Err(e),
-                    },
-                    Err(e) => Err(e),
-                }
-            } else {
-                Ok(None)
+    let per_page = preferences.per_page;
+    let (max_images, limits) = {
+        let config = CONFIG.load();
+        (config.multi_search.max_images, config.search_limits(tier))
+    };
+
+    // Several links are separated by whitespace, which links can't contain
+    let links: Vec<String> = form.link.split_whitespace().map(str::to_string).collect();
+
+    let found = match links.len() {
+        0 => Ok(Found::Nothing),
+        1 => link_findings(&links[0], &form, per_page, limits, ip)
+            .await
+            .map(Found::One),
+        count if count > max_images => Err(too_many_images(max_images)),
+        _ => match Params::from_form(&form, per_page, limits) {
+            Ok(_) => {
+                let form = &form;
+                Ok(multi_findings(
+                    links.into_iter().map(|link| (link.clone(), link)).collect(),
+                    |link| async move { link_findings(&link, form, per_page, limits, ip).await },
+                )
+                .await)
             }
+            Err(error) => Err(error),
+        },
+    };
+
+    Search::with_found(form, found, false, preferences, tier).await
+}
+
+/// Hashes an uploaded image, also saving it to images if `save`, and searches for it
+async fn upload_findings(
+    bytes: Vec<u8>,
+    save: bool,
+    params: Params,
+    ip: Option<IpAddr>,
+) -> Result<Findings, UserError> {
+    let (hash, hash128) = if save {
+        let saved = save_hash_bytes(
+            &bytes,
+            &content_label(&bytes),
+            HashDest::Images,
+            params.hash128,
+        )
+        .await?;
+        match saved.hash128 {
+            // Saved before without one, so it's hashed again for this search
+            None if params.hash128 => hash_in_pool(bytes.into(), true).await?,
+            hash128 => (saved.hash, hash128),
         }
+    } else {
+        hash_in_pool(bytes.into(), params.hash128).await?
     };
 
-    match findings {
-        Ok(findings) => Search {
-            form,
-            error: None,
-            findings,
-            upload: false,
-            ..Search::default().await
-        },
-        Err(error) => Search {
-            form: err_form,
-            error: Some(error),
-            findings: None,
-            upload: false,
-            ..Search::default().await
-        },
-    }
+    make_findings(hash, hash128, params, ip).await
 }
 
-async fn post_search(mut form: FormData) -> Search {
+async fn post_search(
+    mut form: FormData,
+    preferences: Preferences,
+    tier: Tier,
+    ip: Option<IpAddr>,
+) -> Search {
     #[allow(clippy::ptr_arg)]
     fn utf8_to_string(utf8: &Vec<u8>) -> String {
         String::from_utf8_lossy(utf8.as_slice()).to_string()
     }
 
+    let per_page = preferences.per_page;
+    let limits = CONFIG.load().search_limits(tier);
+    let default_form = Form::from(&preferences);
     let do_findings = move || async move {
         let mut map: HashMap<String, Vec<u8>> = HashMap::new();
+        // Each file input sends a part, named imagefile, per file chosen, and each pasted
+        // image is sent as an imagedata part
+        let mut files: Vec<(String, Vec<u8>)> = Vec::new();
 
         while let Some(mut part) = form.try_next().await? {
             let name = part.name().to_string();
+            let filename = part.filename().unwrap_or_default().to_string();
             let mut data = Vec::<u8>::new();
 
             while let Some(b) = part.data().await {
                 b?.reader().read_to_end(&mut data)?;
             }
 
-            map.insert(name, data);
+            if name == "imagefile" {
+                if !data.is_empty() {
+                    files.push((filename, data));
+                }
+            } else if name == "imagedata" {
+                if !data.is_empty() {
+                    files.push(("pasted image".to_string(), pasted_image(&data)?));
+                }
+            } else {
+                map.insert(name, data);
+            }
         }
 
-        let default_form = Form::default();
         let form = Form {
             distance: map
                 .get("distance")
@@ -403,45 +1420,90 @@ async fn post_search(mut form: FormData) -> Search {
                 .get("authors")
                 .map(utf8_to_string)
                 .unwrap_or(default_form.authors),
-            ..Default::default()
+            hash_size: map
+                .get("hash_size")
+                .map(utf8_to_string)
+                .unwrap_or(default_form.hash_size),
+            histogram: map
+                .get("histogram")
+                .map(utf8_to_string)
+                .unwrap_or(default_form.histogram),
+            exclude_low_quality: map
+                .get("exclude_low_quality")
+                .map(utf8_to_string)
+                .unwrap_or(default_form.exclude_low_quality),
+            exact_only: map
+                .get("exact_only")
+                .map(utf8_to_string)
+                .unwrap_or(default_form.exact_only),
+            exclude_dead: map
+                .get("exclude_dead")
+                .map(utf8_to_string)
+                .unwrap_or(default_form.exclude_dead),
+            exclude_permalink: map
+                .get("exclude_permalink")
+                .map(utf8_to_string)
+                .unwrap_or(default_form.exclude_permalink),
+            exclude_id: map
+                .get("exclude_id")
+                .map(utf8_to_string)
+                .unwrap_or(default_form.exclude_id),
+            page: default_form.page,
+            link: default_form.link,
         };
 
-        let hash = map
-            .get("imagefile")
-            .map(|bytes| hash_from_memory(bytes))
-            .transpose()?;
+        let params = Params::from_form(&form, per_page, limits)?;
 
-        let params = Params::from_form(&form)?;
+        let save = map
+            .get("save")
+            .map(|v| v.as_slice() == b"on")
+            .unwrap_or(false);
 
-        Ok(match hash {
-            None => (form, None),
-            Some(hash) => (form, Some(make_findings(hash, params).await?)),
-        })
-    };
+        if save && tier != Tier::Admin {
+            return Err(ue!("only admins can save uploads", Source::User));
+        }
+
+        let max_images = CONFIG.load().multi_search.max_images;
 
-    let output = do_findings().await;
+        let found = match files.len() {
+            0 => Found::Nothing,
+            1 => Found::One(upload_findings(files.pop().unwrap().1, save, params, ip).await?),
+            count if count > max_images => return Err(too_many_images(max_images)),
+            _ => {
+                multi_findings(files, |bytes| {
+                    upload_findings(bytes, save, params.clone(), ip)
+                })
+                .await
+            }
+        };
 
-    let (form, findings, error) = match output {
-        Ok((form, findings)) => (form, findings, None),
-        Err(error) => (Form::default(), None, Some(error)),
+        Ok((form, found))
     };
 
-    Search {
-        form,
-        error,
-        findings,
-        upload: true,
-        ..Search::default().await
-    }
+    let (form, found) = match do_findings().await {
+        Ok((form, found)) => (form, Ok(found)),
+        Err(error) => (Form::from(&preferences), Err(error)),
+    };
+
+    Search::with_found(form, found, true, preferences, tier).await
 }
 
-pub async fn get_response(query: SearchQuery) -> impl warp::Reply {
-    let search = get_search(query).await;
+pub async fn get_response(
+    query: SearchQuery,
+    preferences: Preferences,
+    tier: Tier,
+    ip: Option<IpAddr>,
+) -> impl warp::Reply {
+    let template = match query.format.as_deref() {
+        Some("basic") => "search_basic.html",
+        _ => "search.html",
+    };
+
+    let search = get_search(query, preferences, tier, ip).await;
 
     let tera = super::get_tera!();
 
-    let out =
-        Context::from_serialize(&search).and_then(|context| tera.render("search.html", &context));
+    let out = Context::from_serialize(&search).and_then(|context| tera.render(template, &context));
 
     let (page, status) = match out {
         Ok(page) => (
@@ -449,7 +1511,7 @@ pub async fn get_response(query: SearchQuery) -> impl warp::Reply {
             search
                 .error
                 .map(|ue| {
-                    warn!("{}", ue.error);
+                    warn!("{}", ue);
                     ue.status_code()
                 })
                 .unwrap_or(StatusCode::OK),
@@ -466,8 +1528,56 @@ pub async fn get_response(query: SearchQuery) -> impl warp::Reply {
     warp::reply::with_status(warp::reply::html(page), status)
 }
 
-pub async fn post_response(form: FormData) -> impl warp::Reply {
-    let search = post_search(form).await;
+#[derive(Serialize)]
+struct ApiSearch<'a> {
+    findings: &'a Option<Findings>,
+    #[serde(skip_serializing_if = "<[ImageResult]>::is_empty")]
+    results: &'a [ImageResult],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    summary: Option<&'a Summary>,
+    error: &'a Option<UserError>,
+}
+
+/// API clients don't send the preferences cookie, so they always get the defaults, except that
+/// a page holds as many matches as their tier allows
+pub async fn get_json_response(
+    query: SearchQuery,
+    tier: Tier,
+    ip: Option<IpAddr>,
+) -> impl warp::Reply {
+    let preferences = Preferences {
+        per_page: CONFIG.load().search_limits(tier).max_results,
+        ..Preferences::default()
+    };
+    let search = get_search(query, preferences, tier, ip).await;
+
+    let status = search
+        .error
+        .as_ref()
+        .map(|ue| {
+            warn!("{}", ue);
+            ue.status_code()
+        })
+        .unwrap_or(StatusCode::OK);
+
+    warp::reply::with_status(
+        warp::reply::json(&ApiSearch {
+            findings: &search.findings,
+            results: &search.results,
+            summary: search.summary.as_ref(),
+            error: &search.error,
+        }),
+        status,
+    )
+}
+
+pub async fn post_response(
+    form: FormData,
+    preferences: Preferences,
+    tier: Tier,
+    ip: Option<IpAddr>,
+) -> impl warp::Reply {
+    let search = post_search(form, preferences, tier, ip).await;
 
     let tera = super::get_tera!();
 
@@ -480,7 +1590,7 @@ pub async fn post_response(form: FormData) -> impl warp::Reply {
             search
                 .error
                 .map(|ue| {
-                    warn!("{}", ue.error);
+                    warn!("{}", ue);
                     ue.status_code()
                 })
                 .unwrap_or(StatusCode::OK),
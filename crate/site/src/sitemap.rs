@@ -0,0 +1,150 @@
+use crate::image::canonical_url;
+use common::*;
+use futures::prelude::*;
+use http::header;
+use http::{Response, StatusCode};
+use std::io::ErrorKind;
+use tokio_postgres::types::ToSql;
+use warp::hyper::Body;
+
+const XML_HEADER: &str = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n";
+/// How long search engines may keep a sitemap before asking again
+const CACHE_SECS: u64 = 60 * 60;
+/// How many entries are written out together while a file streams
+const CHUNK_ENTRIES: usize = 256;
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn images_per_file() -> Result<i64, UserError> {
+    let config = CONFIG.load();
+    if config.sitemap.enabled {
+        Ok(config.sitemap.images_per_file)
+    } else {
+        Err(ue!("the sitemap is off", Source::User))
+    }
+}
+
+fn xml_response(body: Body) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/xml; charset=utf-8")
+        .header(
+            header::CACHE_CONTROL,
+            format!("public, max-age={}", CACHE_SECS),
+        )
+        .body(body)
+        .unwrap()
+}
+
+fn error_response(ue: UserError) -> Response<Body> {
+    let status = match ue.source {
+        Source::User => StatusCode::NOT_FOUND,
+        _ => {
+            println!("{:?}", ue);
+            crate::admin::record(&ue);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    };
+
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Lists a file for every `images_per_file` IDs up to the newest image
+async fn index() -> Result<Response<Body>, UserError> {
+    let per_file = images_per_file()?;
+
+    let max_id: Option<i64> = PG_POOL
+        .get()
+        .await?
+        .query_one("SELECT MAX(id) AS max_id FROM images", &[])
+        .await?
+        .get("max_id");
+
+    let base = CONFIG.load().public_url.trim_end_matches('/').to_string();
+
+    let mut out = XML_HEADER.to_string();
+    out += "<sitemapindex xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n";
+    for file in 0..=max_id.unwrap_or(-1) / per_file {
+        out += &format!(
+            "<sitemap><loc>{}</loc></sitemap>\n",
+            xml_escape(&format!("{}/sitemap/{}.xml", base, file))
+        );
+    }
+    out += "</sitemapindex>\n";
+
+    Ok(xml_response(Body::from(out)))
+}
+
+/// Streams the image pages in file `name`, so a file never has to be held whole
+async fn file(name: String) -> Result<Response<Body>, UserError> {
+    let per_file = images_per_file()?;
+
+    let file: i64 = name
+        .strip_suffix(".xml")
+        .and_then(|file| file.parse().ok())
+        .filter(|file| *file >= 0)
+        .ok_or_else(|| ue!("no such sitemap file", Source::User))?;
+    let start = file
+        .checked_mul(per_file)
+        .ok_or_else(|| ue!("no such sitemap file", Source::User))?;
+    let end = start.saturating_add(per_file);
+
+    let client = PG_POOL.get().await?;
+    let rows = client
+        .query_raw(
+            format!(
+                "SELECT images.id, MAX(posts.created_utc) AS lastmod \
+                 FROM images INNER JOIN posts ON posts.image_id = images.id \
+                 WHERE images.id >= $1 AND images.id < $2 AND NOT {} \
+                 GROUP BY images.id ORDER BY images.id",
+                TAKEN_DOWN_SQL
+            )
+            .as_str(),
+            vec![&start as &dyn ToSql, &end],
+        )
+        .await?;
+
+    let entries = rows
+        .map(move |row| {
+            // Keeps the connection out of the pool until the rows are all read
+            let _client = &client;
+
+            let row = row.map_err(|e| std::io::Error::new(ErrorKind::Other, e))?;
+            let lastmod: chrono::NaiveDateTime = row.get("lastmod");
+            Ok::<_, std::io::Error>(format!(
+                "<url><loc>{}</loc><lastmod>{}</lastmod></url>\n",
+                xml_escape(&canonical_url(row.get("id"))),
+                lastmod.format("%FT%T+00:00")
+            ))
+        })
+        .ready_chunks(CHUNK_ENTRIES)
+        .map(|chunk| chunk.into_iter().collect::<Result<String, _>>());
+
+    let body = stream::once(future::ok(format!(
+        "{}<urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n",
+        XML_HEADER
+    )))
+    .chain(entries)
+    .chain(stream::once(future::ok("</urlset>\n".to_string())));
+
+    Ok(xml_response(Body::wrap_stream(body)))
+}
+
+/// The sitemap index, pointing to a file per range of image IDs
+pub async fn index_response() -> Response<Body> {
+    index().await.unwrap_or_else(error_response)
+}
+
+/// One file of the sitemap, with each image's page and when it was last posted
+pub async fn file_response(name: String) -> Response<Body> {
+    file(name).await.unwrap_or_else(error_response)
+}
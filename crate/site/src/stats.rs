@@ -0,0 +1,292 @@
+use crate::preferences::Preferences;
+use crate::search::describe_duration;
+use common::*;
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use tera::Context;
+
+#[derive(Serialize)]
+struct Reposted {
+    link: String,
+    reposts: i64,
+}
+
+#[derive(Serialize)]
+struct Source {
+    subreddit: String,
+    reposts: i64,
+}
+
+#[derive(Serialize)]
+struct SubredditStats {
+    posts: i64,
+    reposts: i64,
+    repost_percent: String,
+    top_reposted: Vec<Reposted>,
+    top_sources: Vec<Source>,
+    as_of: String,
+}
+
+#[derive(Serialize)]
+struct SubredditPage {
+    subreddit: String,
+    stats: Option<SubredditStats>,
+    preferences: Preferences,
+}
+
+/// Computed ahead of time by `op subreddit_stats`, since it's far too slow to do per request
+async fn subreddit_stats(subreddit: &str) -> Result<Option<SubredditStats>, UserError> {
+    let row = PG_POOL
+        .get()
+        .await?
+        .query_opt(
+            "SELECT posts, reposts, top_links, top_link_reposts, \
+             top_sources, top_source_reposts, computed_at \
+             FROM subreddit_stats WHERE subreddit = $1",
+            &[&subreddit],
+        )
+        .await?;
+
+    Ok(row.map(|row| {
+        let posts: i64 = row.get("posts");
+        let reposts: i64 = row.get("reposts");
+
+        SubredditStats {
+            posts,
+            reposts,
+            repost_percent: if posts == 0 {
+                "0.0".to_string()
+            } else {
+                format!("{:.1}", reposts as f64 / posts as f64 * 100.)
+            },
+            top_reposted: row
+                .get::<_, Vec<String>>("top_links")
+                .into_iter()
+                .zip(row.get::<_, Vec<i64>>("top_link_reposts"))
+                .map(|(link, reposts)| Reposted { link, reposts })
+                .collect(),
+            top_sources: row
+                .get::<_, Vec<String>>("top_sources")
+                .into_iter()
+                .zip(row.get::<_, Vec<i64>>("top_source_reposts"))
+                .map(|(subreddit, reposts)| Source { subreddit, reposts })
+                .collect(),
+            as_of: row
+                .get::<_, chrono::NaiveDateTime>("computed_at")
+                .format("%F %T UTC")
+                .to_string(),
+        }
+    }))
+}
+
+pub async fn subreddit_response(
+    name: String,
+    preferences: Preferences,
+) -> Result<impl warp::Reply, UserError> {
+    let subreddit = name.to_lowercase();
+    let stats = subreddit_stats(&subreddit).await?;
+
+    let status = if stats.is_some() {
+        StatusCode::OK
+    } else {
+        StatusCode::NOT_FOUND
+    };
+
+    let tera = super::get_tera!();
+
+    let out = tera.render(
+        "subreddit_stats.html",
+        &Context::from_serialize(&SubredditPage {
+            subreddit,
+            stats,
+            preferences,
+        })?,
+    )?;
+
+    Ok(warp::reply::with_status(warp::reply::html(out), status))
+}
+
+#[derive(Deserialize)]
+pub struct AuthorQuery {
+    distance: Option<u8>,
+}
+
+#[derive(Serialize)]
+struct AuthorRepost {
+    permalink: String,
+    subreddit: String,
+    link: String,
+    created_utc: chrono::NaiveDateTime,
+    distance: i64,
+    earlier_permalink: String,
+    earlier_author: String,
+    earlier_subreddit: String,
+    earlier_created_utc: chrono::NaiveDateTime,
+    delta_secs: i64,
+    delta: String,
+}
+
+#[derive(Serialize)]
+struct AuthorPage {
+    author: String,
+    distance: u8,
+    reposts: Option<Vec<AuthorRepost>>,
+    preferences: Preferences,
+}
+
+#[derive(Serialize)]
+struct ApiAuthor {
+    reposts: Option<Vec<AuthorRepost>>,
+    error: Option<UserError>,
+}
+
+/// The author's posts whose images were posted earlier by someone else,
+/// or `None` if they've opted out or author stats are off
+async fn author_reposts(
+    author: &str,
+    distance: u8,
+) -> Result<Option<Vec<AuthorRepost>>, UserError> {
+    let (enabled, max_results) = {
+        let config = CONFIG.load();
+        (config.author_stats, config.max_results)
+    };
+
+    if !enabled {
+        return Ok(None);
+    }
+
+    let client = PG_POOL.get().await?;
+
+    let opted_out = client
+        .query_opt(
+            "SELECT 1 FROM author_opt_outs WHERE author = $1",
+            &[&author],
+        )
+        .await?
+        .is_some();
+
+    if opted_out {
+        return Ok(None);
+    }
+
+    let rows = client
+        .query(
+            format!(
+                "SELECT posts.permalink, posts.subreddit, images.link, posts.created_utc, \
+                 earlier.distance, earlier.permalink AS earlier_permalink, \
+                 earlier.author AS earlier_author, earlier.subreddit AS earlier_subreddit, \
+                 earlier.created_utc AS earlier_created_utc \
+                 FROM posts INNER JOIN images ON posts.image_id = images.id \
+                 CROSS JOIN LATERAL \
+                 (SELECT earlier_images.hash <-> images.hash AS distance, \
+                  earlier_posts.permalink, earlier_posts.author, earlier_posts.subreddit, \
+                  earlier_posts.created_utc \
+                  FROM posts AS earlier_posts INNER JOIN images AS earlier_images \
+                  ON earlier_images.hash <@ (images.hash, $2) \
+                  AND earlier_posts.image_id = earlier_images.id \
+                  WHERE earlier_posts.created_utc < posts.created_utc \
+                  AND LOWER(earlier_posts.author) <> $1 \
+                  AND NOT {} \
+                  ORDER BY distance ASC, earlier_posts.created_utc ASC LIMIT 1) AS earlier \
+                 WHERE LOWER(posts.author) = $1 \
+                 AND NOT {} \
+                 ORDER BY posts.created_utc DESC LIMIT $3",
+                taken_down_sql_as("earlier_images"),
+                TAKEN_DOWN_SQL
+            )
+            .as_str(),
+            &[&author, &(distance as i64), &max_results],
+        )
+        .await?;
+
+    Ok(Some(
+        rows.iter()
+            .map(|row| {
+                let created_utc: chrono::NaiveDateTime = row.get("created_utc");
+                let earlier_created_utc: chrono::NaiveDateTime = row.get("earlier_created_utc");
+                let delta = created_utc - earlier_created_utc;
+
+                AuthorRepost {
+                    permalink: format!("https://reddit.com{}", row.get::<_, &str>("permalink")),
+                    subreddit: row.get("subreddit"),
+                    link: row.get("link"),
+                    created_utc,
+                    distance: row.get("distance"),
+                    earlier_permalink: format!(
+                        "https://reddit.com{}",
+                        row.get::<_, &str>("earlier_permalink")
+                    ),
+                    earlier_author: row.get("earlier_author"),
+                    earlier_subreddit: row.get("earlier_subreddit"),
+                    earlier_created_utc,
+                    delta_secs: delta.num_seconds(),
+                    delta: describe_duration(delta),
+                }
+            })
+            .collect(),
+    ))
+}
+
+fn author_distance(query: &AuthorQuery) -> Result<u8, UserError> {
+    let distance = query.distance.unwrap_or(1);
+
+    if distance > CONFIG.load().max_distance {
+        Err(ue!("distance too large", Source::User))
+    } else {
+        Ok(distance)
+    }
+}
+
+pub async fn author_response(
+    name: String,
+    query: AuthorQuery,
+    preferences: Preferences,
+) -> Result<impl warp::Reply, UserError> {
+    let author = name.to_lowercase();
+    let distance = author_distance(&query)?;
+    let reposts = author_reposts(&author, distance).await?;
+
+    let status = if reposts.is_some() {
+        StatusCode::OK
+    } else {
+        StatusCode::NOT_FOUND
+    };
+
+    let tera = super::get_tera!();
+
+    let out = tera.render(
+        "author_stats.html",
+        &Context::from_serialize(&AuthorPage {
+            author,
+            distance,
+            reposts,
+            preferences,
+        })?,
+    )?;
+
+    Ok(warp::reply::with_status(warp::reply::html(out), status))
+}
+
+pub async fn author_json_response(name: String, query: AuthorQuery) -> impl warp::Reply {
+    let author = name.to_lowercase();
+    let result = match author_distance(&query) {
+        Ok(distance) => author_reposts(&author, distance).await,
+        Err(ue) => Err(ue),
+    };
+
+    let (reposts, error, status) = match result {
+        Ok(Some(reposts)) => (Some(reposts), None, StatusCode::OK),
+        Ok(None) => (
+            None,
+            Some(ue!("no statistics for this author", Source::User)),
+            StatusCode::NOT_FOUND,
+        ),
+        Err(ue) => {
+            warn!("{}", ue);
+            let status = ue.status_code();
+            (None, Some(ue), status)
+        }
+    };
+
+    warp::reply::with_status(warp::reply::json(&ApiAuthor { reposts, error }), status)
+}
@@ -1,3 +1,4 @@
+use common::concurrency::BufferLimitedExt;
 use common::*;
 
 use bytes::BytesMut;
@@ -13,20 +14,42 @@ const BASE_STREAM_URL: &str = "http://stream.pushshift.io?type=submissions&is_se
 
 const NEWLINE_CODE: u8 = 10;
 
+static SUBREDDITS: Lazy<SubredditFilter> =
+    Lazy::new(|| SubredditFilter::for_binary("stream").unwrap());
+
 async fn ingest_post(post: Submission) -> bool {
+    let _in_flight = HEALTH.start();
+
+    let claim = match claim_post(post.id_int).await {
+        Ok(Some(claim)) => claim,
+        Ok(None) => {
+            info!("another ingester has it");
+            return false;
+        }
+        Err(e) => {
+            eprintln!("failed to claim: {:?}", e);
+            std::process::exit(1);
+        }
+    };
+
     let post_url_res = post.choose_url();
 
     let save_res = match post_url_res {
         Ok(post_url) => save_hash(post_url.as_str(), HashDest::Images).await,
         Err(e) => Err(e),
     };
+    let save_res = match save_res {
+        Err(ue) => post.save_fallback_hash(ue).await,
+        res => res,
+    };
+    claim.release().await;
 
     let image_id = match save_res {
         Ok(hash_gotten) => Ok(hash_gotten.id),
         Err(ue) => match ue.source {
             Source::Internal => {
                 eprintln!(
-                    "{}{}{}\n{:#?}\n{:#?}",
+                    "{}{}{}\n{:?}\n{:#?}",
                     ue.file.unwrap_or(""),
                     ue.line
                         .map(|line| Cow::Owned(format!("#{}", line)))
@@ -48,15 +71,15 @@ async fn ingest_post(post: Submission) -> bool {
                             e.source().and_then(|he| he.downcast_ref::<hyper::Error>());
 
                         e.status()
-                            .map(|status| format!("http_{}", status.as_str()).into())
+                            .map(|status| SaveError::Http(status.as_u16()))
                             .or_else(|| {
                                 if e.is_timeout() {
-                                    Some("timeout".into())
+                                    Some(SaveError::Timeout)
                                 } else {
                                     None
                                 }
                             })
-                            .or_else(|| hyper_error.map(|_| "hyper".into()))
+                            .or_else(|| hyper_error.map(|_| SaveError::Hyper))
                     }
                     None => None,
                 };
@@ -69,7 +92,7 @@ async fn ingest_post(post: Submission) -> bool {
                         .as_ref()
                         .map(|se| Cow::Owned(format!(" ({})", se)))
                         .unwrap_or_else(|| Cow::Borrowed("")),
-                    ue.error
+                    ue
                 );
 
                 Err(save_error)
@@ -78,17 +101,18 @@ async fn ingest_post(post: Submission) -> bool {
     };
 
     let good = image_id.is_ok();
+    HEALTH.record(good);
 
     match post.save(image_id).await {
-        Ok(already_have) => {
+        Ok(saved) => {
             if good {
-                if already_have {
-                    info!("already have");
-                } else {
-                    info!("successfully saved");
+                match saved {
+                    Saved::Inserted => info!("successfully saved"),
+                    Saved::Updated => info!("already have; updated"),
+                    Saved::Skipped => info!("already have"),
                 }
             }
-            already_have
+            saved.already_have()
         }
         Err(e) => {
             eprintln!("failed to save: {:?}", e);
@@ -121,7 +145,7 @@ async fn process_events(data: &[u8]) -> Result<Option<i64>, UserError> {
                     .finalize()
                     .unwrap();
 
-                if post.desirable() {
+                if post.desirable() && post.allowed_by(&SUBREDDITS) {
                     Some(tokio::spawn(async move {
                         let span = info_span!(
                             "ingest_post",
@@ -146,7 +170,7 @@ async fn process_events(data: &[u8]) -> Result<Option<i64>, UserError> {
     });
 
     let last_id = futures::stream::iter(iter)
-        .buffer_unordered(CONFIG.worker_count)
+        .buffer_limited()
         .fold(None, |largest, r| async move {
             let id = r.unwrap();
             Some(if let Some(largest) = largest {
@@ -179,13 +203,6 @@ async fn stream(mut last_id: Option<i64>) -> Result<(), (Option<i64>, UserError)
         .get(&req_url)
         .send()
         .await
-        .map(|r| {
-            if r.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
-                panic!("Too many requests!")
-            } else {
-                r
-            }
-        })
         .and_then(|r| r.error_for_status())
         .map_err(|e| (last_id, e.into()))?
         .bytes_stream();
@@ -217,6 +234,10 @@ async fn stream(mut last_id: Option<i64>) -> Result<(), (Option<i64>, UserError)
             current_data.clear();
             current_data.extend_from_slice(&bytes.slice(bytes.len() - index..bytes.len()));
 
+            if over_bandwidth_cap() {
+                return Err((last_id, ue!("daily bandwidth cap reached")));
+            }
+
             info!("Done processing events; collecting chunks");
         }
     }
@@ -226,13 +247,30 @@ async fn stream(mut last_id: Option<i64>) -> Result<(), (Option<i64>, UserError)
 async fn main() -> Result<(), UserError> {
     tracing_subscriber::fmt::init();
 
-    let mut get_id = !std::env::args().skip(1).any(|a| a == "-i");
+    let args = take_path_args();
+
+    Lazy::force(&SUBREDDITS);
+    reload_config_on_sighup()?;
+    serve_health("stream").await?;
+    flush_bandwidth().await?;
+    flush_host_health().await?;
+    start_ingest_events("stream");
+
+    let mut get_id = !args.iter().skip(1).any(|a| a == "-i");
 
     let client = PG_POOL.get().await?;
 
+    // Where to pick up the stream after a bandwidth pause
+    let mut resume_from = None;
+
     loop {
-        let last_id = if get_id {
-            let last_id = client
+        wait_for_bandwidth().await;
+
+        let last_id = if let Some(resume_from) = resume_from.take() {
+            info!("Resuming from ID {}", resume_from);
+            Some(resume_from)
+        } else if get_id {
+            let last_id: RedditId = client
                 .query_one(
                     "SELECT reddit_id_int FROM posts ORDER BY reddit_id_int DESC LIMIT 1",
                     &[],
@@ -241,14 +279,18 @@ async fn main() -> Result<(), UserError> {
                 .get("reddit_id_int");
 
             info!("Last ID: {}", last_id);
-            Some(last_id)
+            Some(last_id.0)
         } else {
             get_id = true;
             None
         };
 
-        if let Err((_last_id, ue)) = stream(last_id).await {
+        if let Err((last_id, ue)) = stream(last_id).await {
             error!("{}", ue);
+
+            if over_bandwidth_cap() {
+                resume_from = last_id;
+            }
         }
 
         delay_for(Duration::from_secs(5)).await;
@@ -0,0 +1,34 @@
+-- Adds fetch_queue for ingest --defer-hashing and lets save_error be deferred.
+-- Run it with psql -v ON_ERROR_STOP=1; running it again changes nothing.
+
+BEGIN;
+
+CREATE TABLE IF NOT EXISTS public.fetch_queue (
+    reddit_id_int bigint NOT NULL,
+    created_utc timestamp without time zone NOT NULL,
+    link character varying NOT NULL,
+    queued_at timestamp without time zone DEFAULT (now() AT TIME ZONE 'utc'::text) NOT NULL,
+    claimed_at timestamp without time zone,
+    attempts integer DEFAULT 0 NOT NULL,
+    priority double precision DEFAULT 0 NOT NULL
+);
+
+ALTER TABLE public.fetch_queue ADD COLUMN IF NOT EXISTS priority double precision DEFAULT 0 NOT NULL;
+CREATE INDEX IF NOT EXISTS fetch_queue_priority_idx ON public.fetch_queue USING btree (priority DESC, queued_at);
+
+DO $$
+BEGIN
+    IF to_regclass('public.fetch_queue_pkey') IS NULL THEN
+        ALTER TABLE ONLY public.fetch_queue
+            ADD CONSTRAINT fetch_queue_pkey PRIMARY KEY (reddit_id_int);
+    END IF;
+END
+$$;
+
+ALTER TABLE public.posts DROP CONSTRAINT IF EXISTS posts_save_error_check;
+ALTER TABLE public.posts ADD CONSTRAINT posts_save_error_check CHECK (((save_error)::text ~ '^(http_[1-5][0-9]{2}|timeout|hyper|blacklisted|banned|taken_down|content_type_unsupported|data_url_bad|deferred|download_image|gfycat_json_bad|gfycat_no_id|gifsound_no_gif|gifsound_unsupported|host_failing|host_not_public|host_unresolvable|image_color_space|image_format_disabled|image_heic_invalid|image_invalid|image_jxl_invalid|image_missing|image_panic|image_svg_invalid|image_too_large|image_unsupported|imgur_album_empty|imgur_albums_disabled|imgur_json_bad|imgur_no_id|imgur_removed|url_invalid|video_no_preview|v_redd_it_no_preview)$'::text));
+
+ALTER TABLE public.comment_images DROP CONSTRAINT IF EXISTS comment_images_save_error_check;
+ALTER TABLE public.comment_images ADD CONSTRAINT comment_images_save_error_check CHECK (((save_error)::text ~ '^(http_[1-5][0-9]{2}|timeout|hyper|blacklisted|banned|taken_down|content_type_unsupported|data_url_bad|deferred|download_image|gfycat_json_bad|gfycat_no_id|gifsound_no_gif|gifsound_unsupported|host_failing|host_not_public|host_unresolvable|image_color_space|image_format_disabled|image_heic_invalid|image_invalid|image_jxl_invalid|image_missing|image_panic|image_svg_invalid|image_too_large|image_unsupported|imgur_album_empty|imgur_albums_disabled|imgur_json_bad|imgur_no_id|imgur_removed|url_invalid|video_no_preview|v_redd_it_no_preview)$'::text));
+
+COMMIT;
@@ -0,0 +1,24 @@
+-- Adds hash_popularity, which op hash_popularity fills with the hashes shared by the most
+-- images so the site can guess how many a search will find. Run it with
+-- psql -v ON_ERROR_STOP=1; running it again changes nothing.
+
+BEGIN;
+
+CREATE TABLE IF NOT EXISTS public.hash_popularity (
+    hash bigint NOT NULL,
+    images bigint NOT NULL,
+    computed_at timestamp without time zone NOT NULL
+);
+
+DO $$
+BEGIN
+    IF to_regclass('public.hash_popularity_pkey') IS NULL THEN
+        ALTER TABLE ONLY public.hash_popularity
+            ADD CONSTRAINT hash_popularity_pkey PRIMARY KEY (hash);
+    END IF;
+END
+$$;
+
+GRANT SELECT ON TABLE public.hash_popularity TO site;
+
+COMMIT;
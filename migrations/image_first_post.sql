@@ -0,0 +1,34 @@
+-- Adds image_first_post, which maps each image to the earliest post of it so searches and
+-- rankings can show where it first appeared, and fills it from posts; op first_posts does the
+-- same fill later. Run it with psql -v ON_ERROR_STOP=1; running it again changes nothing.
+
+BEGIN;
+
+CREATE TABLE IF NOT EXISTS public.image_first_post (
+    image_id bigint NOT NULL,
+    reddit_id_int bigint NOT NULL,
+    created_utc timestamp without time zone NOT NULL,
+    subreddit character varying NOT NULL
+);
+
+DO $$
+BEGIN
+    IF to_regclass('public.image_first_post_pkey') IS NULL THEN
+        ALTER TABLE ONLY public.image_first_post
+            ADD CONSTRAINT image_first_post_pkey PRIMARY KEY (image_id);
+        ALTER TABLE ONLY public.image_first_post
+            ADD CONSTRAINT image_first_post_image_id_fkey FOREIGN KEY (image_id)
+            REFERENCES public.images(id) ON DELETE CASCADE;
+    END IF;
+END
+$$;
+
+GRANT SELECT ON TABLE public.image_first_post TO site;
+
+INSERT INTO public.image_first_post (image_id, reddit_id_int, created_utc, subreddit)
+    SELECT DISTINCT ON (image_id) image_id, reddit_id_int, created_utc, subreddit
+    FROM public.posts WHERE image_id IS NOT NULL
+    ORDER BY image_id, created_utc ASC, reddit_id_int ASC
+    ON CONFLICT DO NOTHING;
+
+COMMIT;
@@ -0,0 +1,74 @@
+-- Moves the links of images into image_links, so that several links can point to one image,
+-- records how many bytes each image was downloaded as in content_length, then merges images
+-- whose hashes and lengths are the same into the oldest of them, keeping their links. Run it
+-- with psql -v ON_ERROR_STOP=1; running it again changes nothing.
+
+BEGIN;
+
+ALTER TABLE public.images
+    ADD COLUMN IF NOT EXISTS content_length bigint;
+ALTER TABLE public.image_cache
+    ADD COLUMN IF NOT EXISTS content_length bigint;
+
+-- Stored originals are the bytes as they were downloaded
+UPDATE public.images SET content_length = stored_size
+    WHERE content_length IS NULL AND stored_size IS NOT NULL;
+
+CREATE TABLE IF NOT EXISTS public.image_links (
+    link character varying NOT NULL,
+    image_id bigint NOT NULL,
+    added_at timestamp without time zone DEFAULT (now() AT TIME ZONE 'utc'::text) NOT NULL
+);
+
+-- Links promoted from image_cache before image_links had every link
+DO $$
+BEGIN
+    IF to_regclass('public.image_aliases') IS NOT NULL THEN
+        INSERT INTO public.image_links (link, image_id, added_at)
+            SELECT link, image_id, added_at FROM public.image_aliases
+            ON CONFLICT DO NOTHING;
+        DROP TABLE public.image_aliases;
+    END IF;
+END
+$$;
+
+DO $$
+BEGIN
+    IF to_regclass('public.image_links_pkey') IS NULL THEN
+        ALTER TABLE ONLY public.image_links
+            ADD CONSTRAINT image_links_pkey PRIMARY KEY (link);
+        ALTER TABLE ONLY public.image_links
+            ADD CONSTRAINT image_links_image_id_fkey FOREIGN KEY (image_id)
+            REFERENCES public.images(id) ON DELETE CASCADE;
+    END IF;
+END
+$$;
+
+CREATE INDEX IF NOT EXISTS image_links_image_id_idx ON public.image_links USING btree (image_id);
+
+GRANT SELECT,INSERT,DELETE ON TABLE public.image_links TO site;
+
+INSERT INTO public.image_links (link, image_id, added_at)
+    SELECT link, id, retrieved_on FROM public.images
+    ON CONFLICT DO NOTHING;
+
+-- Without a length there's no telling that two images are the same bytes
+CREATE TEMPORARY TABLE duplicate_images ON COMMIT DROP AS
+    SELECT id, keep_id FROM (
+        SELECT id, min(id) OVER (
+            PARTITION BY hash, hash128_hi, hash128_lo, hash_version, content_length
+        ) AS keep_id
+        FROM public.images WHERE content_length IS NOT NULL
+    ) AS grouped
+    WHERE id <> keep_id;
+
+UPDATE public.posts SET image_id = duplicate_images.keep_id FROM duplicate_images
+    WHERE posts.image_id = duplicate_images.id;
+UPDATE public.comment_images SET image_id = duplicate_images.keep_id FROM duplicate_images
+    WHERE comment_images.image_id = duplicate_images.id;
+UPDATE public.image_links SET image_id = duplicate_images.keep_id FROM duplicate_images
+    WHERE image_links.image_id = duplicate_images.id;
+
+DELETE FROM public.images USING duplicate_images WHERE images.id = duplicate_images.id;
+
+COMMIT;
@@ -0,0 +1,17 @@
+-- Adds submitted_url, resolved_url, and redirect_chain to images and image_cache, recording
+-- where each image's link led when it was downloaded. Images saved before this have them NULL.
+-- Run it with psql -v ON_ERROR_STOP=1; running it again changes nothing.
+
+BEGIN;
+
+ALTER TABLE public.image_cache
+    ADD COLUMN IF NOT EXISTS submitted_url character varying,
+    ADD COLUMN IF NOT EXISTS resolved_url character varying,
+    ADD COLUMN IF NOT EXISTS redirect_chain character varying[];
+
+ALTER TABLE public.images
+    ADD COLUMN IF NOT EXISTS submitted_url character varying,
+    ADD COLUMN IF NOT EXISTS resolved_url character varying,
+    ADD COLUMN IF NOT EXISTS redirect_chain character varying[];
+
+COMMIT;
@@ -0,0 +1,11 @@
+-- Adds link_checked_at and link_status to images, which the fetcher's link checker fills
+-- with when each image's link was last checked and what it answered. Images not yet checked
+-- have them NULL. Run it with psql -v ON_ERROR_STOP=1; running it again changes nothing.
+
+BEGIN;
+
+ALTER TABLE public.images
+    ADD COLUMN IF NOT EXISTS link_checked_at timestamp without time zone,
+    ADD COLUMN IF NOT EXISTS link_status smallint;
+
+COMMIT;
@@ -0,0 +1,71 @@
+-- Turns an existing posts table into the partitioned one in schema.sql, with a partition for
+-- each month it has posts from. Run it with psql -v ON_ERROR_STOP=1 while nothing is writing
+-- to posts; it copies every post, so it takes a while and needs room for a second copy.
+-- The old table is kept as posts_unpartitioned, to be dropped once the new one looks right.
+
+BEGIN;
+
+ALTER TABLE public.posts RENAME TO posts_unpartitioned;
+ALTER INDEX public.posts_pkey RENAME TO posts_unpartitioned_pkey;
+ALTER INDEX public.posts_permalink_key RENAME TO posts_unpartitioned_permalink_key;
+ALTER INDEX public.posts_reddit_id_int_key RENAME TO posts_unpartitioned_reddit_id_int_key;
+ALTER INDEX public.posts_reddit_id_key RENAME TO posts_unpartitioned_reddit_id_key;
+ALTER INDEX public.posts_author_idx RENAME TO posts_unpartitioned_author_idx;
+ALTER INDEX public.posts_author_lower_idx RENAME TO posts_unpartitioned_author_lower_idx;
+ALTER INDEX public.posts_image_id_idx RENAME TO posts_unpartitioned_image_id_idx;
+ALTER INDEX public.posts_subreddit_idx RENAME TO posts_unpartitioned_subreddit_idx;
+
+CREATE TABLE public.posts (
+    LIKE public.posts_unpartitioned INCLUDING DEFAULTS INCLUDING CONSTRAINTS
+)
+PARTITION BY RANGE (created_utc);
+
+-- Dropping posts_unpartitioned would otherwise drop the sequence with it
+ALTER SEQUENCE public.posts_id_seq OWNED BY public.posts.id;
+
+DO $$
+DECLARE
+    month date;
+    last_month date;
+BEGIN
+    SELECT date_trunc('month', MIN(created_utc)), date_trunc('month', MAX(created_utc))
+        INTO month, last_month FROM public.posts_unpartitioned;
+
+    WHILE month <= last_month LOOP
+        EXECUTE format(
+            'CREATE TABLE public.%I PARTITION OF public.posts FOR VALUES FROM (%L) TO (%L)',
+            to_char(month, '"posts_y"YYYY"m"MM'),
+            month,
+            month + interval '1 month'
+        );
+        month := month + interval '1 month';
+    END LOOP;
+END
+$$;
+
+CREATE TABLE public.posts_default PARTITION OF public.posts DEFAULT;
+
+INSERT INTO public.posts SELECT * FROM public.posts_unpartitioned;
+
+ALTER TABLE public.posts
+    ADD CONSTRAINT posts_permalink_key UNIQUE (permalink, created_utc);
+ALTER TABLE public.posts
+    ADD CONSTRAINT posts_pkey PRIMARY KEY (id, created_utc);
+ALTER TABLE public.posts
+    ADD CONSTRAINT posts_reddit_id_int_key UNIQUE (reddit_id_int, created_utc);
+ALTER TABLE public.posts
+    ADD CONSTRAINT posts_reddit_id_key UNIQUE (reddit_id, created_utc);
+
+CREATE INDEX posts_author_idx ON public.posts USING btree (author);
+CREATE INDEX posts_author_lower_idx ON public.posts USING btree (lower((author)::text));
+CREATE INDEX posts_image_id_idx ON public.posts USING btree (image_id);
+CREATE INDEX posts_subreddit_idx ON public.posts USING btree (subreddit);
+
+ALTER TABLE public.posts
+    ADD CONSTRAINT posts_image_id_fkey FOREIGN KEY (image_id) REFERENCES public.images(id);
+
+GRANT SELECT,INSERT,DELETE,UPDATE ON TABLE public.posts TO site;
+
+COMMIT;
+
+ANALYZE public.posts;
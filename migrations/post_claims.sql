@@ -0,0 +1,20 @@
+-- Adds post_claims, which keeps archive and live ingesters from fetching the same post's image
+-- at once. Run it with psql -v ON_ERROR_STOP=1; running it again changes nothing.
+
+BEGIN;
+
+CREATE TABLE IF NOT EXISTS public.post_claims (
+    reddit_id_int bigint NOT NULL,
+    claimed_at timestamp without time zone DEFAULT (now() AT TIME ZONE 'utc'::text) NOT NULL
+);
+
+DO $$
+BEGIN
+    IF to_regclass('public.post_claims_pkey') IS NULL THEN
+        ALTER TABLE ONLY public.post_claims
+            ADD CONSTRAINT post_claims_pkey PRIMARY KEY (reddit_id_int);
+    END IF;
+END
+$$;
+
+COMMIT;
@@ -0,0 +1,29 @@
+-- Adds post_images, which holds the images of a post's Imgur album past the first when
+-- imgur_album_images is above 1 in tidder.ron. Run it with psql -v ON_ERROR_STOP=1; running it
+-- again changes nothing.
+
+BEGIN;
+
+CREATE TABLE IF NOT EXISTS public.post_images (
+    reddit_id_int bigint NOT NULL,
+    image_id bigint NOT NULL,
+    "position" integer NOT NULL
+);
+
+DO $$
+BEGIN
+    IF to_regclass('public.post_images_pkey') IS NULL THEN
+        ALTER TABLE ONLY public.post_images
+            ADD CONSTRAINT post_images_pkey PRIMARY KEY (reddit_id_int, image_id);
+        ALTER TABLE ONLY public.post_images
+            ADD CONSTRAINT post_images_image_id_fkey FOREIGN KEY (image_id)
+            REFERENCES public.images(id) ON DELETE CASCADE;
+    END IF;
+END
+$$;
+
+CREATE INDEX IF NOT EXISTS post_images_image_id_idx ON public.post_images USING btree (image_id);
+
+GRANT SELECT ON TABLE public.post_images TO site;
+
+COMMIT;
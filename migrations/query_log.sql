@@ -0,0 +1,43 @@
+-- Adds query_log, which the site fills with searches when query_log.enabled is set in
+-- tidder.ron. Run it with psql -v ON_ERROR_STOP=1; running it again changes nothing.
+
+BEGIN;
+
+CREATE TABLE IF NOT EXISTS public.query_log (
+    id bigint NOT NULL,
+    at timestamp without time zone NOT NULL,
+    hash bigint NOT NULL,
+    distance bigint NOT NULL,
+    params jsonb NOT NULL,
+    ip_hash character varying,
+    result_count integer NOT NULL,
+    latency_ms integer NOT NULL,
+    cached boolean NOT NULL
+);
+
+CREATE SEQUENCE IF NOT EXISTS public.query_log_id_seq
+    START WITH 1
+    INCREMENT BY 1
+    NO MINVALUE
+    NO MAXVALUE
+    CACHE 1;
+
+ALTER SEQUENCE public.query_log_id_seq OWNED BY public.query_log.id;
+
+ALTER TABLE ONLY public.query_log ALTER COLUMN id SET DEFAULT nextval('public.query_log_id_seq'::regclass);
+
+DO $$
+BEGIN
+    IF to_regclass('public.query_log_pkey') IS NULL THEN
+        ALTER TABLE ONLY public.query_log
+            ADD CONSTRAINT query_log_pkey PRIMARY KEY (id);
+    END IF;
+END
+$$;
+
+CREATE INDEX IF NOT EXISTS query_log_at_idx ON public.query_log USING btree (at);
+
+GRANT SELECT,INSERT,DELETE ON TABLE public.query_log TO site;
+GRANT ALL ON SEQUENCE public.query_log_id_seq TO site;
+
+COMMIT;
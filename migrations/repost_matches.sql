@@ -0,0 +1,30 @@
+-- Adds repost_matches, which op repost_matches fills with the posts whose images were posted
+-- earlier in another subreddit, for the site's /api/v1/subreddit/{name}/reposts. Run it with
+-- psql -v ON_ERROR_STOP=1; running it again changes nothing.
+
+BEGIN;
+
+CREATE TABLE IF NOT EXISTS public.repost_matches (
+    reddit_id_int bigint NOT NULL,
+    created_utc timestamp without time zone NOT NULL,
+    subreddit character varying NOT NULL,
+    source_reddit_id_int bigint NOT NULL,
+    source_created_utc timestamp without time zone NOT NULL,
+    computed_at timestamp without time zone NOT NULL
+);
+
+DO $$
+BEGIN
+    IF to_regclass('public.repost_matches_pkey') IS NULL THEN
+        ALTER TABLE ONLY public.repost_matches
+            ADD CONSTRAINT repost_matches_pkey PRIMARY KEY (reddit_id_int);
+    END IF;
+END
+$$;
+
+CREATE INDEX IF NOT EXISTS repost_matches_subreddit_idx
+    ON public.repost_matches USING btree (subreddit, created_utc, reddit_id_int);
+
+GRANT SELECT ON TABLE public.repost_matches TO site;
+
+COMMIT;
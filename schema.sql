@@ -30,6 +30,38 @@ CREATE EXTENSION IF NOT EXISTS bktree WITH SCHEMA public;
 COMMENT ON EXTENSION bktree IS 'BK-tree implementation';
 
 
+--
+-- Name: api_keys; Type: TABLE; Schema: public; Owner: -
+--
+
+CREATE TABLE public.api_keys (
+    id bigint NOT NULL,
+    key character varying NOT NULL,
+    name character varying NOT NULL,
+    created_at timestamp without time zone DEFAULT (now() AT TIME ZONE 'utc'::text) NOT NULL,
+    revoked boolean DEFAULT false NOT NULL
+);
+
+
+--
+-- Name: api_keys_id_seq; Type: SEQUENCE; Schema: public; Owner: -
+--
+
+CREATE SEQUENCE public.api_keys_id_seq
+    START WITH 1
+    INCREMENT BY 1
+    NO MINVALUE
+    NO MAXVALUE
+    CACHE 1;
+
+
+--
+-- Name: api_keys_id_seq; Type: SEQUENCE OWNED BY; Schema: public; Owner: -
+--
+
+ALTER SEQUENCE public.api_keys_id_seq OWNED BY public.api_keys.id;
+
+
 --
 -- Name: image_cache_id_seq; Type: SEQUENCE; Schema: public; Owner: -
 --
@@ -46,6 +78,145 @@ SET default_tablespace = '';
 
 SET default_with_oids = false;
 
+--
+-- Name: author_opt_outs; Type: TABLE; Schema: public; Owner: -
+--
+
+CREATE TABLE public.author_opt_outs (
+    author character varying NOT NULL,
+    opted_out_at timestamp without time zone NOT NULL
+);
+
+
+--
+-- Name: bandwidth_usage; Type: TABLE; Schema: public; Owner: -
+--
+
+CREATE TABLE public.bandwidth_usage (
+    day date NOT NULL,
+    host character varying NOT NULL,
+    bytes bigint NOT NULL
+);
+
+
+--
+-- Name: banned_links; Type: TABLE; Schema: public; Owner: -
+--
+
+CREATE TABLE public.banned_links (
+    id bigint NOT NULL,
+    kind character varying NOT NULL,
+    value character varying NOT NULL,
+    added_at timestamp without time zone DEFAULT (now() AT TIME ZONE 'utc'::text) NOT NULL
+);
+
+
+--
+-- Name: banned_links_id_seq; Type: SEQUENCE; Schema: public; Owner: -
+--
+
+CREATE SEQUENCE public.banned_links_id_seq
+    START WITH 1
+    INCREMENT BY 1
+    NO MINVALUE
+    NO MAXVALUE
+    CACHE 1;
+
+
+--
+-- Name: banned_links_id_seq; Type: SEQUENCE OWNED BY; Schema: public; Owner: -
+--
+
+ALTER SEQUENCE public.banned_links_id_seq OWNED BY public.banned_links.id;
+
+
+--
+-- Name: comment_images; Type: TABLE; Schema: public; Owner: -
+--
+
+CREATE TABLE public.comment_images (
+    reddit_id character varying NOT NULL,
+    reddit_id_int bigint NOT NULL,
+    post_id_int bigint NOT NULL,
+    link character varying NOT NULL,
+    permalink character varying NOT NULL,
+    author character varying NOT NULL,
+    score bigint NOT NULL,
+    created_utc timestamp without time zone NOT NULL,
+    subreddit character varying NOT NULL,
+    image_id bigint,
+    save_error character varying,
+    CONSTRAINT comment_images_save_error_check CHECK (((save_error)::text ~ '^(http_[1-5][0-9]{2}|timeout|hyper|blacklisted|banned|taken_down|content_type_unsupported|data_url_bad|deferred|download_image|gfycat_json_bad|gfycat_no_id|gifsound_no_gif|gifsound_unsupported|host_failing|host_not_public|host_unresolvable|image_color_space|image_format_disabled|image_heic_invalid|image_invalid|image_jxl_invalid|image_missing|image_panic|image_svg_invalid|image_too_large|image_unsupported|imgur_album_empty|imgur_albums_disabled|imgur_json_bad|imgur_no_id|imgur_removed|url_invalid|video_no_preview|v_redd_it_no_preview)$'::text))
+);
+
+
+--
+-- Name: direct_cursors; Type: TABLE; Schema: public; Owner: -
+--
+
+CREATE TABLE public.direct_cursors (
+    name character varying NOT NULL,
+    next_id bigint NOT NULL,
+    end_id bigint,
+    updated timestamp without time zone NOT NULL
+);
+
+
+--
+-- Name: direct_missing; Type: TABLE; Schema: public; Owner: -
+--
+
+CREATE TABLE public.direct_missing (
+    reddit_id_int bigint NOT NULL,
+    retried boolean DEFAULT false NOT NULL,
+    missed_at timestamp without time zone NOT NULL
+);
+
+
+--
+-- Name: fetch_queue; Type: TABLE; Schema: public; Owner: -
+--
+
+CREATE TABLE public.fetch_queue (
+    reddit_id_int bigint NOT NULL,
+    created_utc timestamp without time zone NOT NULL,
+    link character varying NOT NULL,
+    queued_at timestamp without time zone DEFAULT (now() AT TIME ZONE 'utc'::text) NOT NULL,
+    claimed_at timestamp without time zone,
+    attempts integer DEFAULT 0 NOT NULL,
+    priority double precision DEFAULT 0 NOT NULL
+);
+
+
+--
+-- Name: hash_popularity; Type: TABLE; Schema: public; Owner: -
+--
+
+CREATE TABLE public.hash_popularity (
+    hash bigint NOT NULL,
+    images bigint NOT NULL,
+    computed_at timestamp without time zone NOT NULL
+);
+
+
+--
+-- Name: host_health; Type: TABLE; Schema: public; Owner: -
+--
+
+CREATE TABLE public.host_health (
+    host character varying NOT NULL,
+    successes bigint DEFAULT 0 NOT NULL,
+    failures bigint DEFAULT 0 NOT NULL,
+    consecutive_failures integer DEFAULT 0 NOT NULL,
+    failure_rate double precision DEFAULT 0 NOT NULL,
+    latency_ms double precision,
+    last_success_at timestamp without time zone,
+    last_failure_at timestamp without time zone,
+    open_until timestamp without time zone,
+    updated_at timestamp without time zone DEFAULT (now() AT TIME ZONE 'utc'::text) NOT NULL
+);
+
+
 --
 -- Name: image_cache; Type: TABLE; Schema: public; Owner: -
 --
@@ -54,12 +225,42 @@ CREATE TABLE public.image_cache (
     id bigint DEFAULT nextval('public.image_cache_id_seq'::regclass) NOT NULL,
     link character varying NOT NULL,
     hash bigint NOT NULL,
+    hash128_hi bigint,
+    hash128_lo bigint,
+    hash_version smallint DEFAULT 1 NOT NULL,
     no_store boolean,
     no_cache boolean,
     expires timestamp without time zone,
     etag character varying,
     must_revalidate boolean,
-    retrieved_on timestamp without time zone NOT NULL
+    retrieved_on timestamp without time zone NOT NULL,
+    submitted_url character varying,
+    resolved_url character varying,
+    redirect_chain character varying[],
+    content_length bigint
+);
+
+
+--
+-- Name: image_first_post; Type: TABLE; Schema: public; Owner: -
+--
+
+CREATE TABLE public.image_first_post (
+    image_id bigint NOT NULL,
+    reddit_id_int bigint NOT NULL,
+    created_utc timestamp without time zone NOT NULL,
+    subreddit character varying NOT NULL
+);
+
+
+--
+-- Name: image_links; Type: TABLE; Schema: public; Owner: -
+--
+
+CREATE TABLE public.image_links (
+    link character varying NOT NULL,
+    image_id bigint NOT NULL,
+    added_at timestamp without time zone DEFAULT (now() AT TIME ZONE 'utc'::text) NOT NULL
 );
 
 
@@ -71,12 +272,28 @@ CREATE TABLE public.images (
     id bigint NOT NULL,
     link character varying NOT NULL,
     hash bigint NOT NULL,
+    hash128_hi bigint,
+    hash128_lo bigint,
+    hash_version smallint DEFAULT 1 NOT NULL,
     no_store boolean,
     no_cache boolean,
     expires timestamp without time zone,
     etag character varying,
     must_revalidate boolean,
-    retrieved_on timestamp without time zone NOT NULL
+    retrieved_on timestamp without time zone NOT NULL,
+    stored_path character varying,
+    stored_size bigint,
+    next_hash bigint,
+    next_hash128_hi bigint,
+    next_hash128_lo bigint,
+    next_hash_version smallint,
+    low_quality_hash boolean DEFAULT false NOT NULL,
+    submitted_url character varying,
+    resolved_url character varying,
+    redirect_chain character varying[],
+    link_checked_at timestamp without time zone,
+    link_status smallint,
+    content_length bigint
 );
 
 
@@ -101,40 +318,25 @@ ALTER SEQUENCE public.images_id_seq OWNED BY public.images.id;
 
 
 --
--- Name: posts; Type: TABLE; Schema: public; Owner: -
+-- Name: ingest_events; Type: TABLE; Schema: public; Owner: -
 --
 
-CREATE TABLE public.posts (
+CREATE TABLE public.ingest_events (
     id bigint NOT NULL,
-    reddit_id character varying NOT NULL,
-    link character varying NOT NULL,
-    permalink character varying NOT NULL,
-    author character varying NOT NULL,
-    score bigint NOT NULL,
-    created_utc timestamp without time zone NOT NULL,
-    subreddit character varying NOT NULL,
-    title character varying NOT NULL,
-    nsfw boolean NOT NULL,
-    spoiler boolean DEFAULT false NOT NULL,
-    image_id bigint,
     reddit_id_int bigint NOT NULL,
-    thumbnail_width integer,
-    thumbnail character varying,
-    thumbnail_height integer,
-    updated timestamp without time zone,
+    source character varying NOT NULL,
+    decision character varying NOT NULL,
     save_error character varying,
-    crosspost_parent bigint,
-    is_video boolean DEFAULT false,
-    preview character varying
+    detail character varying,
+    at timestamp without time zone NOT NULL
 );
 
 
 --
--- Name: posts_id_seq; Type: SEQUENCE; Schema: public; Owner: -
+-- Name: ingest_events_id_seq; Type: SEQUENCE; Schema: public; Owner: -
 --
 
-CREATE SEQUENCE public.posts_id_seq
-    AS integer
+CREATE SEQUENCE public.ingest_events_id_seq
     START WITH 1
     INCREMENT BY 1
     NO MINVALUE
@@ -143,163 +345,944 @@ CREATE SEQUENCE public.posts_id_seq
 
 
 --
--- Name: posts_id_seq; Type: SEQUENCE OWNED BY; Schema: public; Owner: -
+-- Name: ingest_events_id_seq; Type: SEQUENCE OWNED BY; Schema: public; Owner: -
 --
 
-ALTER SEQUENCE public.posts_id_seq OWNED BY public.posts.id;
+ALTER SEQUENCE public.ingest_events_id_seq OWNED BY public.ingest_events.id;
 
 
 --
--- Name: images id; Type: DEFAULT; Schema: public; Owner: -
+-- Name: ingest_progress; Type: TABLE; Schema: public; Owner: -
 --
 
-ALTER TABLE ONLY public.images ALTER COLUMN id SET DEFAULT nextval('public.images_id_seq'::regclass);
+CREATE TABLE public.ingest_progress (
+    archive character varying NOT NULL,
+    status character varying NOT NULL,
+    month integer NOT NULL,
+    year integer NOT NULL,
+    posts bigint DEFAULT 0 NOT NULL,
+    posts_per_minute bigint DEFAULT 0 NOT NULL,
+    limited boolean DEFAULT false NOT NULL,
+    bandwidth_paused boolean DEFAULT false NOT NULL,
+    started timestamp without time zone NOT NULL,
+    finished timestamp without time zone,
+    updated timestamp without time zone NOT NULL
+);
 
 
 --
--- Name: posts id; Type: DEFAULT; Schema: public; Owner: -
+-- Name: link_resolutions; Type: TABLE; Schema: public; Owner: -
 --
 
-ALTER TABLE ONLY public.posts ALTER COLUMN id SET DEFAULT nextval('public.posts_id_seq'::regclass);
+CREATE TABLE public.link_resolutions (
+    link character varying NOT NULL,
+    resolved character varying,
+    status character varying NOT NULL,
+    resolved_at timestamp without time zone NOT NULL
+);
 
 
 --
--- Name: image_cache image_cache_link_key; Type: CONSTRAINT; Schema: public; Owner: -
+-- Name: listing_cursors; Type: TABLE; Schema: public; Owner: -
 --
 
-ALTER TABLE ONLY public.image_cache
-    ADD CONSTRAINT image_cache_link_key UNIQUE (link);
+CREATE TABLE public.listing_cursors (
+    name character varying NOT NULL,
+    after character varying,
+    stop_at bigint,
+    pass_newest bigint,
+    modhash character varying,
+    updated timestamp without time zone NOT NULL
+);
 
 
 --
--- Name: image_cache image_cache_pkey; Type: CONSTRAINT; Schema: public; Owner: -
+-- Name: no_blacklist; Type: TABLE; Schema: public; Owner: -
 --
 
-ALTER TABLE ONLY public.image_cache
-    ADD CONSTRAINT image_cache_pkey PRIMARY KEY (id);
+CREATE TABLE public.no_blacklist (
+    host_end character varying NOT NULL,
+    added_at timestamp without time zone DEFAULT (now() AT TIME ZONE 'utc'::text) NOT NULL
+);
 
 
 --
--- Name: images images_link_key; Type: CONSTRAINT; Schema: public; Owner: -
+-- Name: post_claims; Type: TABLE; Schema: public; Owner: -
 --
 
-ALTER TABLE ONLY public.images
-    ADD CONSTRAINT images_link_key UNIQUE (link);
+CREATE TABLE public.post_claims (
+    reddit_id_int bigint NOT NULL,
+    claimed_at timestamp without time zone DEFAULT (now() AT TIME ZONE 'utc'::text) NOT NULL
+);
 
 
 --
--- Name: images images_pkey; Type: CONSTRAINT; Schema: public; Owner: -
+-- Name: post_images; Type: TABLE; Schema: public; Owner: -
 --
 
-ALTER TABLE ONLY public.images
-    ADD CONSTRAINT images_pkey PRIMARY KEY (id);
+CREATE TABLE public.post_images (
+    reddit_id_int bigint NOT NULL,
+    image_id bigint NOT NULL,
+    "position" integer NOT NULL
+);
 
 
 --
--- Name: posts posts_permalink_key; Type: CONSTRAINT; Schema: public; Owner: -
+-- Name: posts; Type: TABLE; Schema: public; Owner: -
 --
 
-ALTER TABLE ONLY public.posts
-    ADD CONSTRAINT posts_permalink_key UNIQUE (permalink);
+CREATE TABLE public.posts (
+    id bigint NOT NULL,
+    reddit_id character varying NOT NULL,
+    link character varying NOT NULL,
+    permalink character varying NOT NULL,
+    author character varying NOT NULL,
+    score bigint NOT NULL,
+    created_utc timestamp without time zone NOT NULL,
+    subreddit character varying NOT NULL,
+    title character varying NOT NULL,
+    nsfw boolean NOT NULL,
+    spoiler boolean DEFAULT false NOT NULL,
+    image_id bigint,
+    reddit_id_int bigint NOT NULL,
+    thumbnail_width integer,
+    thumbnail character varying,
+    thumbnail_height integer,
+    updated timestamp without time zone,
+    save_error character varying,
+    crosspost_parent bigint,
+    is_video boolean DEFAULT false,
+    preview character varying,
+    CONSTRAINT posts_save_error_check CHECK (((save_error)::text ~ '^(http_[1-5][0-9]{2}|timeout|hyper|blacklisted|banned|taken_down|content_type_unsupported|data_url_bad|deferred|download_image|gfycat_json_bad|gfycat_no_id|gifsound_no_gif|gifsound_unsupported|host_failing|host_not_public|host_unresolvable|image_color_space|image_format_disabled|image_heic_invalid|image_invalid|image_jxl_invalid|image_missing|image_panic|image_svg_invalid|image_too_large|image_unsupported|imgur_album_empty|imgur_albums_disabled|imgur_json_bad|imgur_no_id|imgur_removed|url_invalid|video_no_preview|v_redd_it_no_preview)$'::text))
+)
+PARTITION BY RANGE (created_utc);
 
 
 --
--- Name: posts posts_pkey; Type: CONSTRAINT; Schema: public; Owner: -
+-- Name: query_log; Type: TABLE; Schema: public; Owner: -
 --
 
-ALTER TABLE ONLY public.posts
-    ADD CONSTRAINT posts_pkey PRIMARY KEY (id);
+CREATE TABLE public.query_log (
+    id bigint NOT NULL,
+    at timestamp without time zone NOT NULL,
+    hash bigint NOT NULL,
+    distance bigint NOT NULL,
+    params jsonb NOT NULL,
+    ip_hash character varying,
+    result_count integer NOT NULL,
+    latency_ms integer NOT NULL,
+    cached boolean NOT NULL
+);
 
 
 --
--- Name: posts posts_reddit_id_int_key; Type: CONSTRAINT; Schema: public; Owner: -
+-- Name: query_log_id_seq; Type: SEQUENCE; Schema: public; Owner: -
 --
 
-ALTER TABLE ONLY public.posts
-    ADD CONSTRAINT posts_reddit_id_int_key UNIQUE (reddit_id_int);
+CREATE SEQUENCE public.query_log_id_seq
+    START WITH 1
+    INCREMENT BY 1
+    NO MINVALUE
+    NO MAXVALUE
+    CACHE 1;
 
 
 --
--- Name: posts posts_reddit_id_key; Type: CONSTRAINT; Schema: public; Owner: -
+-- Name: query_log_id_seq; Type: SEQUENCE OWNED BY; Schema: public; Owner: -
 --
 
-ALTER TABLE ONLY public.posts
-    ADD CONSTRAINT posts_reddit_id_key UNIQUE (reddit_id);
+ALTER SEQUENCE public.query_log_id_seq OWNED BY public.query_log.id;
 
 
 --
--- Name: image_cache_hash_idx; Type: INDEX; Schema: public; Owner: -
+-- Name: repost_matches; Type: TABLE; Schema: public; Owner: -
 --
 
-CREATE INDEX image_cache_hash_idx ON public.image_cache USING spgist (hash public.bktree_ops);
+CREATE TABLE public.repost_matches (
+    reddit_id_int bigint NOT NULL,
+    created_utc timestamp without time zone NOT NULL,
+    subreddit character varying NOT NULL,
+    source_reddit_id_int bigint NOT NULL,
+    source_created_utc timestamp without time zone NOT NULL,
+    computed_at timestamp without time zone NOT NULL
+);
 
 
 --
--- Name: images_hash_idx; Type: INDEX; Schema: public; Owner: -
+-- Name: subreddit_stats; Type: TABLE; Schema: public; Owner: -
 --
 
-CREATE INDEX images_hash_idx ON public.images USING spgist (hash public.bktree_ops);
+CREATE TABLE public.subreddit_stats (
+    subreddit character varying NOT NULL,
+    posts bigint NOT NULL,
+    reposts bigint NOT NULL,
+    top_links character varying[] NOT NULL,
+    top_link_reposts bigint[] NOT NULL,
+    top_sources character varying[] NOT NULL,
+    top_source_reposts bigint[] NOT NULL,
+    computed_at timestamp without time zone NOT NULL
+);
 
 
 --
--- Name: posts_author_idx; Type: INDEX; Schema: public; Owner: -
+-- Name: takedown_log; Type: TABLE; Schema: public; Owner: -
 --
 
-CREATE INDEX posts_author_idx ON public.posts USING btree (author);
+CREATE TABLE public.takedown_log (
+    id bigint NOT NULL,
+    takedown_id bigint NOT NULL,
+    action character varying NOT NULL,
+    actor character varying NOT NULL,
+    note character varying,
+    at timestamp without time zone DEFAULT (now() AT TIME ZONE 'utc'::text) NOT NULL
+);
 
 
 --
--- Name: posts_image_id_idx; Type: INDEX; Schema: public; Owner: -
+-- Name: takedown_log_id_seq; Type: SEQUENCE; Schema: public; Owner: -
 --
 
-CREATE INDEX posts_image_id_idx ON public.posts USING btree (image_id);
+CREATE SEQUENCE public.takedown_log_id_seq
+    START WITH 1
+    INCREMENT BY 1
+    NO MINVALUE
+    NO MAXVALUE
+    CACHE 1;
 
 
 --
--- Name: posts_subreddit_idx; Type: INDEX; Schema: public; Owner: -
+-- Name: takedown_log_id_seq; Type: SEQUENCE OWNED BY; Schema: public; Owner: -
 --
 
-CREATE INDEX posts_subreddit_idx ON public.posts USING btree (subreddit);
+ALTER SEQUENCE public.takedown_log_id_seq OWNED BY public.takedown_log.id;
 
 
 --
--- Name: posts posts_image_id_fkey; Type: FK CONSTRAINT; Schema: public; Owner: -
+-- Name: takedowns; Type: TABLE; Schema: public; Owner: -
 --
 
-ALTER TABLE ONLY public.posts
-    ADD CONSTRAINT posts_image_id_fkey FOREIGN KEY (image_id) REFERENCES public.images(id);
+CREATE TABLE public.takedowns (
+    id bigint NOT NULL,
+    hash bigint,
+    link character varying,
+    reason character varying NOT NULL,
+    taken_down_at timestamp without time zone DEFAULT (now() AT TIME ZONE 'utc'::text) NOT NULL,
+    lifted_at timestamp without time zone,
+    CONSTRAINT takedowns_target_check CHECK (((hash IS NOT NULL) OR (link IS NOT NULL)))
+);
 
 
 --
--- Name: SEQUENCE image_cache_id_seq; Type: ACL; Schema: public; Owner: -
+-- Name: takedowns_id_seq; Type: SEQUENCE; Schema: public; Owner: -
 --
 
-GRANT ALL ON SEQUENCE public.image_cache_id_seq TO site;
+CREATE SEQUENCE public.takedowns_id_seq
+    START WITH 1
+    INCREMENT BY 1
+    NO MINVALUE
+    NO MAXVALUE
+    CACHE 1;
 
 
 --
--- Name: TABLE image_cache; Type: ACL; Schema: public; Owner: -
+-- Name: takedowns_id_seq; Type: SEQUENCE OWNED BY; Schema: public; Owner: -
 --
 
-GRANT SELECT,INSERT,DELETE,UPDATE ON TABLE public.image_cache TO site;
+ALTER SEQUENCE public.takedowns_id_seq OWNED BY public.takedowns.id;
 
 
 --
--- Name: TABLE images; Type: ACL; Schema: public; Owner: -
+-- Name: posts_default; Type: TABLE; Schema: public; Owner: -
 --
 
-GRANT SELECT,INSERT,DELETE,UPDATE ON TABLE public.images TO site;
+CREATE TABLE public.posts_default PARTITION OF public.posts DEFAULT;
 
 
 --
--- Name: SEQUENCE images_id_seq; Type: ACL; Schema: public; Owner: -
+-- Name: posts_id_seq; Type: SEQUENCE; Schema: public; Owner: -
 --
 
-GRANT ALL ON SEQUENCE public.images_id_seq TO site;
+CREATE SEQUENCE public.posts_id_seq
+    AS integer
+    START WITH 1
+    INCREMENT BY 1
+    NO MINVALUE
+    NO MAXVALUE
+    CACHE 1;
 
 
 --
--- Name: TABLE posts; Type: ACL; Schema: public; Owner: -
+-- Name: posts_id_seq; Type: SEQUENCE OWNED BY; Schema: public; Owner: -
+--
+
+ALTER SEQUENCE public.posts_id_seq OWNED BY public.posts.id;
+
+
+--
+-- Name: api_keys id; Type: DEFAULT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.api_keys ALTER COLUMN id SET DEFAULT nextval('public.api_keys_id_seq'::regclass);
+
+
+--
+-- Name: banned_links id; Type: DEFAULT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.banned_links ALTER COLUMN id SET DEFAULT nextval('public.banned_links_id_seq'::regclass);
+
+
+--
+-- Name: images id; Type: DEFAULT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.images ALTER COLUMN id SET DEFAULT nextval('public.images_id_seq'::regclass);
+
+
+--
+-- Name: ingest_events id; Type: DEFAULT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.ingest_events ALTER COLUMN id SET DEFAULT nextval('public.ingest_events_id_seq'::regclass);
+
+
+--
+-- Name: posts id; Type: DEFAULT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.posts ALTER COLUMN id SET DEFAULT nextval('public.posts_id_seq'::regclass);
+
+
+--
+-- Name: query_log id; Type: DEFAULT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.query_log ALTER COLUMN id SET DEFAULT nextval('public.query_log_id_seq'::regclass);
+
+
+--
+-- Name: takedown_log id; Type: DEFAULT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.takedown_log ALTER COLUMN id SET DEFAULT nextval('public.takedown_log_id_seq'::regclass);
+
+
+--
+-- Name: takedowns id; Type: DEFAULT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.takedowns ALTER COLUMN id SET DEFAULT nextval('public.takedowns_id_seq'::regclass);
+
+
+--
+-- Name: api_keys api_keys_key_key; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.api_keys
+    ADD CONSTRAINT api_keys_key_key UNIQUE (key);
+
+
+--
+-- Name: api_keys api_keys_pkey; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.api_keys
+    ADD CONSTRAINT api_keys_pkey PRIMARY KEY (id);
+
+
+--
+-- Name: author_opt_outs author_opt_outs_pkey; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.author_opt_outs
+    ADD CONSTRAINT author_opt_outs_pkey PRIMARY KEY (author);
+
+
+--
+-- Name: bandwidth_usage bandwidth_usage_pkey; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.bandwidth_usage
+    ADD CONSTRAINT bandwidth_usage_pkey PRIMARY KEY (day, host);
+
+
+--
+-- Name: banned_links banned_links_kind_value_key; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.banned_links
+    ADD CONSTRAINT banned_links_kind_value_key UNIQUE (kind, value);
+
+
+--
+-- Name: banned_links banned_links_pkey; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.banned_links
+    ADD CONSTRAINT banned_links_pkey PRIMARY KEY (id);
+
+
+--
+-- Name: comment_images comment_images_pkey; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.comment_images
+    ADD CONSTRAINT comment_images_pkey PRIMARY KEY (reddit_id_int, link);
+
+
+--
+-- Name: direct_cursors direct_cursors_pkey; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.direct_cursors
+    ADD CONSTRAINT direct_cursors_pkey PRIMARY KEY (name);
+
+
+--
+-- Name: direct_missing direct_missing_pkey; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.direct_missing
+    ADD CONSTRAINT direct_missing_pkey PRIMARY KEY (reddit_id_int);
+
+
+--
+-- Name: fetch_queue fetch_queue_pkey; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.fetch_queue
+    ADD CONSTRAINT fetch_queue_pkey PRIMARY KEY (reddit_id_int);
+
+
+--
+-- Name: hash_popularity hash_popularity_pkey; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.hash_popularity
+    ADD CONSTRAINT hash_popularity_pkey PRIMARY KEY (hash);
+
+
+--
+-- Name: host_health host_health_pkey; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.host_health
+    ADD CONSTRAINT host_health_pkey PRIMARY KEY (host);
+
+
+--
+-- Name: image_cache image_cache_link_key; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.image_cache
+    ADD CONSTRAINT image_cache_link_key UNIQUE (link);
+
+
+--
+-- Name: image_cache image_cache_pkey; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.image_cache
+    ADD CONSTRAINT image_cache_pkey PRIMARY KEY (id);
+
+
+--
+-- Name: image_first_post image_first_post_pkey; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.image_first_post
+    ADD CONSTRAINT image_first_post_pkey PRIMARY KEY (image_id);
+
+
+--
+-- Name: image_links image_links_pkey; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.image_links
+    ADD CONSTRAINT image_links_pkey PRIMARY KEY (link);
+
+
+--
+-- Name: images images_link_key; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.images
+    ADD CONSTRAINT images_link_key UNIQUE (link);
+
+
+--
+-- Name: images images_pkey; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.images
+    ADD CONSTRAINT images_pkey PRIMARY KEY (id);
+
+
+--
+-- Name: ingest_events ingest_events_pkey; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.ingest_events
+    ADD CONSTRAINT ingest_events_pkey PRIMARY KEY (id);
+
+
+--
+-- Name: ingest_progress ingest_progress_pkey; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.ingest_progress
+    ADD CONSTRAINT ingest_progress_pkey PRIMARY KEY (archive);
+
+
+--
+-- Name: link_resolutions link_resolutions_pkey; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.link_resolutions
+    ADD CONSTRAINT link_resolutions_pkey PRIMARY KEY (link);
+
+
+--
+-- Name: listing_cursors listing_cursors_pkey; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.listing_cursors
+    ADD CONSTRAINT listing_cursors_pkey PRIMARY KEY (name);
+
+
+--
+-- Name: no_blacklist no_blacklist_pkey; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.no_blacklist
+    ADD CONSTRAINT no_blacklist_pkey PRIMARY KEY (host_end);
+
+
+--
+-- Name: post_claims post_claims_pkey; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.post_claims
+    ADD CONSTRAINT post_claims_pkey PRIMARY KEY (reddit_id_int);
+
+
+--
+-- Name: post_images post_images_pkey; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.post_images
+    ADD CONSTRAINT post_images_pkey PRIMARY KEY (reddit_id_int, image_id);
+
+
+--
+-- Name: posts posts_permalink_key; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE public.posts
+    ADD CONSTRAINT posts_permalink_key UNIQUE (permalink, created_utc);
+
+
+--
+-- Name: posts posts_pkey; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE public.posts
+    ADD CONSTRAINT posts_pkey PRIMARY KEY (id, created_utc);
+
+
+--
+-- Name: posts posts_reddit_id_int_key; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE public.posts
+    ADD CONSTRAINT posts_reddit_id_int_key UNIQUE (reddit_id_int, created_utc);
+
+
+--
+-- Name: posts posts_reddit_id_key; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE public.posts
+    ADD CONSTRAINT posts_reddit_id_key UNIQUE (reddit_id, created_utc);
+
+
+--
+-- Name: query_log query_log_pkey; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.query_log
+    ADD CONSTRAINT query_log_pkey PRIMARY KEY (id);
+
+
+--
+-- Name: repost_matches repost_matches_pkey; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.repost_matches
+    ADD CONSTRAINT repost_matches_pkey PRIMARY KEY (reddit_id_int);
+
+
+--
+-- Name: subreddit_stats subreddit_stats_pkey; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.subreddit_stats
+    ADD CONSTRAINT subreddit_stats_pkey PRIMARY KEY (subreddit);
+
+
+--
+-- Name: takedown_log takedown_log_pkey; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.takedown_log
+    ADD CONSTRAINT takedown_log_pkey PRIMARY KEY (id);
+
+
+--
+-- Name: takedowns takedowns_pkey; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.takedowns
+    ADD CONSTRAINT takedowns_pkey PRIMARY KEY (id);
+
+
+--
+-- Name: comment_images_created_utc_idx; Type: INDEX; Schema: public; Owner: -
+--
+
+CREATE INDEX comment_images_created_utc_idx ON public.comment_images USING btree (created_utc);
+
+
+--
+-- Name: comment_images_image_id_idx; Type: INDEX; Schema: public; Owner: -
+--
+
+CREATE INDEX comment_images_image_id_idx ON public.comment_images USING btree (image_id);
+
+
+--
+-- Name: fetch_queue_priority_idx; Type: INDEX; Schema: public; Owner: -
+--
+
+CREATE INDEX fetch_queue_priority_idx ON public.fetch_queue USING btree (priority DESC, queued_at);
+
+
+--
+-- Name: image_cache_hash_idx; Type: INDEX; Schema: public; Owner: -
+--
+
+CREATE INDEX image_cache_hash_idx ON public.image_cache USING spgist (hash public.bktree_ops);
+
+
+--
+-- Name: image_cache_hash128_hi_idx; Type: INDEX; Schema: public; Owner: -
+--
+
+CREATE INDEX image_cache_hash128_hi_idx ON public.image_cache USING spgist (hash128_hi public.bktree_ops);
+
+
+--
+-- Name: image_cache_hash128_lo_idx; Type: INDEX; Schema: public; Owner: -
+--
+
+CREATE INDEX image_cache_hash128_lo_idx ON public.image_cache USING spgist (hash128_lo public.bktree_ops);
+
+
+--
+-- Name: image_links_image_id_idx; Type: INDEX; Schema: public; Owner: -
+--
+
+CREATE INDEX image_links_image_id_idx ON public.image_links USING btree (image_id);
+
+
+--
+-- Name: images_hash_idx; Type: INDEX; Schema: public; Owner: -
+--
+
+CREATE INDEX images_hash_idx ON public.images USING spgist (hash public.bktree_ops);
+
+
+--
+-- Name: images_hash128_hi_idx; Type: INDEX; Schema: public; Owner: -
+--
+
+CREATE INDEX images_hash128_hi_idx ON public.images USING spgist (hash128_hi public.bktree_ops);
+
+
+--
+-- Name: images_hash128_lo_idx; Type: INDEX; Schema: public; Owner: -
+--
+
+CREATE INDEX images_hash128_lo_idx ON public.images USING spgist (hash128_lo public.bktree_ops);
+
+
+--
+-- Name: images_stored_path_idx; Type: INDEX; Schema: public; Owner: -
+--
+
+CREATE INDEX images_stored_path_idx ON public.images USING btree (stored_path) WHERE (stored_path IS NOT NULL);
+
+
+--
+-- Name: ingest_events_at_idx; Type: INDEX; Schema: public; Owner: -
+--
+
+CREATE INDEX ingest_events_at_idx ON public.ingest_events USING btree (at);
+
+
+--
+-- Name: ingest_events_reddit_id_int_idx; Type: INDEX; Schema: public; Owner: -
+--
+
+CREATE INDEX ingest_events_reddit_id_int_idx ON public.ingest_events USING btree (reddit_id_int);
+
+
+--
+-- Name: post_images_image_id_idx; Type: INDEX; Schema: public; Owner: -
+--
+
+CREATE INDEX post_images_image_id_idx ON public.post_images USING btree (image_id);
+
+
+--
+-- Name: posts_author_idx; Type: INDEX; Schema: public; Owner: -
+--
+
+CREATE INDEX posts_author_idx ON public.posts USING btree (author);
+
+
+--
+-- Name: posts_author_lower_idx; Type: INDEX; Schema: public; Owner: -
+--
+
+CREATE INDEX posts_author_lower_idx ON public.posts USING btree (lower((author)::text));
+
+
+--
+-- Name: posts_image_id_idx; Type: INDEX; Schema: public; Owner: -
+--
+
+CREATE INDEX posts_image_id_idx ON public.posts USING btree (image_id);
+
+
+--
+-- Name: posts_subreddit_idx; Type: INDEX; Schema: public; Owner: -
+--
+
+CREATE INDEX posts_subreddit_idx ON public.posts USING btree (subreddit);
+
+
+--
+-- Name: query_log_at_idx; Type: INDEX; Schema: public; Owner: -
+--
+
+CREATE INDEX query_log_at_idx ON public.query_log USING btree (at);
+
+
+--
+-- Name: repost_matches_subreddit_idx; Type: INDEX; Schema: public; Owner: -
+--
+
+CREATE INDEX repost_matches_subreddit_idx ON public.repost_matches USING btree (subreddit, created_utc, reddit_id_int);
+
+
+--
+-- Name: takedowns_hash_idx; Type: INDEX; Schema: public; Owner: -
+--
+
+CREATE INDEX takedowns_hash_idx ON public.takedowns USING btree (hash) WHERE (lifted_at IS NULL);
+
+
+--
+-- Name: takedowns_link_idx; Type: INDEX; Schema: public; Owner: -
+--
+
+CREATE INDEX takedowns_link_idx ON public.takedowns USING btree (link) WHERE (lifted_at IS NULL);
+
+
+--
+-- Name: comment_images comment_images_image_id_fkey; Type: FK CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.comment_images
+    ADD CONSTRAINT comment_images_image_id_fkey FOREIGN KEY (image_id) REFERENCES public.images(id);
+
+
+--
+-- Name: image_first_post image_first_post_image_id_fkey; Type: FK CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.image_first_post
+    ADD CONSTRAINT image_first_post_image_id_fkey FOREIGN KEY (image_id) REFERENCES public.images(id) ON DELETE CASCADE;
+
+
+--
+-- Name: image_links image_links_image_id_fkey; Type: FK CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.image_links
+    ADD CONSTRAINT image_links_image_id_fkey FOREIGN KEY (image_id) REFERENCES public.images(id) ON DELETE CASCADE;
+
+
+--
+-- Name: post_images post_images_image_id_fkey; Type: FK CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.post_images
+    ADD CONSTRAINT post_images_image_id_fkey FOREIGN KEY (image_id) REFERENCES public.images(id) ON DELETE CASCADE;
+
+
+--
+-- Name: posts posts_image_id_fkey; Type: FK CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE public.posts
+    ADD CONSTRAINT posts_image_id_fkey FOREIGN KEY (image_id) REFERENCES public.images(id);
+
+
+--
+-- Name: takedown_log takedown_log_takedown_id_fkey; Type: FK CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.takedown_log
+    ADD CONSTRAINT takedown_log_takedown_id_fkey FOREIGN KEY (takedown_id) REFERENCES public.takedowns(id);
+
+
+--
+-- Name: TABLE api_keys; Type: ACL; Schema: public; Owner: -
+--
+
+GRANT SELECT ON TABLE public.api_keys TO site;
+
+
+--
+-- Name: TABLE author_opt_outs; Type: ACL; Schema: public; Owner: -
+--
+
+GRANT SELECT ON TABLE public.author_opt_outs TO site;
+
+
+--
+-- Name: TABLE bandwidth_usage; Type: ACL; Schema: public; Owner: -
+--
+
+GRANT SELECT,INSERT,UPDATE ON TABLE public.bandwidth_usage TO site;
+
+
+--
+-- Name: TABLE banned_links; Type: ACL; Schema: public; Owner: -
+--
+
+GRANT SELECT,INSERT,DELETE ON TABLE public.banned_links TO site;
+
+
+--
+-- Name: SEQUENCE banned_links_id_seq; Type: ACL; Schema: public; Owner: -
+--
+
+GRANT ALL ON SEQUENCE public.banned_links_id_seq TO site;
+
+
+--
+-- Name: TABLE comment_images; Type: ACL; Schema: public; Owner: -
+--
+
+GRANT SELECT ON TABLE public.comment_images TO site;
+
+
+--
+-- Name: TABLE hash_popularity; Type: ACL; Schema: public; Owner: -
+--
+
+GRANT SELECT ON TABLE public.hash_popularity TO site;
+
+
+--
+-- Name: TABLE host_health; Type: ACL; Schema: public; Owner: -
+--
+
+GRANT SELECT,INSERT,UPDATE ON TABLE public.host_health TO site;
+
+
+--
+-- Name: SEQUENCE image_cache_id_seq; Type: ACL; Schema: public; Owner: -
+--
+
+GRANT ALL ON SEQUENCE public.image_cache_id_seq TO site;
+
+
+--
+-- Name: TABLE image_cache; Type: ACL; Schema: public; Owner: -
+--
+
+GRANT SELECT,INSERT,DELETE,UPDATE ON TABLE public.image_cache TO site;
+
+
+--
+-- Name: TABLE image_first_post; Type: ACL; Schema: public; Owner: -
+--
+
+GRANT SELECT ON TABLE public.image_first_post TO site;
+
+
+--
+-- Name: TABLE image_links; Type: ACL; Schema: public; Owner: -
+--
+
+GRANT SELECT,INSERT,DELETE ON TABLE public.image_links TO site;
+
+
+--
+-- Name: TABLE images; Type: ACL; Schema: public; Owner: -
+--
+
+GRANT SELECT,INSERT,DELETE,UPDATE ON TABLE public.images TO site;
+
+
+--
+-- Name: SEQUENCE images_id_seq; Type: ACL; Schema: public; Owner: -
+--
+
+GRANT ALL ON SEQUENCE public.images_id_seq TO site;
+
+
+--
+-- Name: TABLE ingest_events; Type: ACL; Schema: public; Owner: -
+--
+
+GRANT SELECT ON TABLE public.ingest_events TO site;
+
+
+--
+-- Name: TABLE ingest_progress; Type: ACL; Schema: public; Owner: -
+--
+
+GRANT SELECT ON TABLE public.ingest_progress TO site;
+
+
+--
+-- Name: TABLE link_resolutions; Type: ACL; Schema: public; Owner: -
+--
+
+GRANT SELECT,INSERT,DELETE,UPDATE ON TABLE public.link_resolutions TO site;
+
+
+--
+-- Name: TABLE no_blacklist; Type: ACL; Schema: public; Owner: -
+--
+
+GRANT SELECT ON TABLE public.no_blacklist TO site;
+
+
+--
+-- Name: TABLE post_images; Type: ACL; Schema: public; Owner: -
+--
+
+GRANT SELECT ON TABLE public.post_images TO site;
+
+
+--
+-- Name: TABLE posts; Type: ACL; Schema: public; Owner: -
 --
 
 GRANT SELECT,INSERT,DELETE,UPDATE ON TABLE public.posts TO site;
@@ -312,6 +1295,62 @@ GRANT SELECT,INSERT,DELETE,UPDATE ON TABLE public.posts TO site;
 GRANT ALL ON SEQUENCE public.posts_id_seq TO site;
 
 
+--
+-- Name: TABLE query_log; Type: ACL; Schema: public; Owner: -
+--
+
+GRANT SELECT,INSERT,DELETE ON TABLE public.query_log TO site;
+
+
+--
+-- Name: SEQUENCE query_log_id_seq; Type: ACL; Schema: public; Owner: -
+--
+
+GRANT ALL ON SEQUENCE public.query_log_id_seq TO site;
+
+
+--
+-- Name: TABLE repost_matches; Type: ACL; Schema: public; Owner: -
+--
+
+GRANT SELECT ON TABLE public.repost_matches TO site;
+
+
+--
+-- Name: TABLE subreddit_stats; Type: ACL; Schema: public; Owner: -
+--
+
+GRANT SELECT ON TABLE public.subreddit_stats TO site;
+
+
+--
+-- Name: TABLE takedown_log; Type: ACL; Schema: public; Owner: -
+--
+
+GRANT SELECT,INSERT ON TABLE public.takedown_log TO site;
+
+
+--
+-- Name: SEQUENCE takedown_log_id_seq; Type: ACL; Schema: public; Owner: -
+--
+
+GRANT ALL ON SEQUENCE public.takedown_log_id_seq TO site;
+
+
+--
+-- Name: TABLE takedowns; Type: ACL; Schema: public; Owner: -
+--
+
+GRANT SELECT,INSERT,UPDATE ON TABLE public.takedowns TO site;
+
+
+--
+-- Name: SEQUENCE takedowns_id_seq; Type: ACL; Schema: public; Owner: -
+--
+
+GRANT ALL ON SEQUENCE public.takedowns_id_seq TO site;
+
+
 --
 -- PostgreSQL database dump complete
 --